@@ -1,8 +1,137 @@
 mod raft;
+use crate::raft::error::RaftError;
 use log::LevelFilter;
 use simplelog::{Config, TermLogger, TerminalMode};
+use std::net::SocketAddrV4;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
 
 fn main() {
     TermLogger::init(LevelFilter::Trace, Config::default(), TerminalMode::Stdout).unwrap();
-    crate::raft::demo::start_demo();
+
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("snapshot") => snapshot(args.get(2)),
+        Some("membership-history") => membership_history(&args[2..]),
+        Some("status") => status(&args[2..]),
+        _ => crate::raft::demo::start_demo(),
+    }
+}
+
+/// `rsraft snapshot <address>`: has the server at `address` take a
+/// snapshot now.
+fn snapshot(address: Option<&String>) {
+    let address = parse_or_exit(address, "usage: rsraft snapshot <ip:port>");
+
+    match crate::raft::tcp_rpc::request_snapshot(address, Duration::from_secs(60)) {
+        Ok(metadata) => println!(
+            "snapshot up to index {} (term {}) taken at {}: {} bytes in {:?}",
+            metadata.last_included_index,
+            metadata.last_included_term,
+            metadata.taken_at,
+            metadata.size,
+            metadata.duration
+        ),
+        Err(RaftError::Snapshot(e)) => {
+            eprintln!("snapshot rejected: {:?}", e);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("could not reach {}: {}", address, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// `rsraft membership-history <address> [from_index] [to_index]`: every
+/// configuration in the log of the server at `address`.
+fn membership_history(args: &[String]) {
+    let usage = "usage: rsraft membership-history <ip:port> [from_index] [to_index]";
+    let address: SocketAddrV4 = parse_or_exit(args.first(), usage);
+    let from_index = args.get(1).map_or(1, |_| parse_or_exit(args.get(1), usage));
+    let to_index = args
+        .get(2)
+        .map_or(u64::MAX, |_| parse_or_exit(args.get(2), usage));
+
+    match crate::raft::tcp_rpc::request_membership_history(
+        address,
+        from_index,
+        to_index,
+        Duration::from_secs(60),
+    ) {
+        Ok(records) => {
+            for record in records {
+                let voters: Vec<&str> = record
+                    .membership
+                    .voters
+                    .iter()
+                    .map(|p| p.id.as_str())
+                    .collect();
+                let learners: Vec<&str> = record
+                    .membership
+                    .learners
+                    .iter()
+                    .map(|p| p.id.as_str())
+                    .collect();
+                println!(
+                    "{} (term {}): {}; voters: [{}], learners: [{}]",
+                    record.index,
+                    record.term,
+                    record.change,
+                    voters.join(", "),
+                    learners.join(", ")
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!("could not reach {}: {}", address, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// `rsraft status <address>...`: the status of each server, as its status
+/// endpoint would serve it, one per line. Warns when the servers run more
+/// versions than a rolling upgrade should leave behind.
+fn status(args: &[String]) {
+    let usage = "usage: rsraft status <ip:port>...";
+    if args.is_empty() {
+        eprintln!("{}", usage);
+        process::exit(2);
+    }
+
+    let mut builds = Vec::new();
+    for arg in args {
+        let address: SocketAddrV4 = parse_or_exit(Some(arg), usage);
+
+        match crate::raft::tcp_rpc::request_status(address, Duration::from_secs(5)) {
+            Ok(status) => {
+                println!("{}", status.to_json());
+                builds.push(status.build);
+            }
+            Err(e) => {
+                eprintln!("could not reach {}: {}", address, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(versions) = crate::raft::build_info::version_skew(&builds) {
+        eprintln!(
+            "warning: {} versions are running: {}",
+            versions.len(),
+            versions.join(", ")
+        );
+    }
+}
+
+fn parse_or_exit<T: FromStr>(arg: Option<&String>, usage: &str) -> T {
+    match arg.map(|a| a.parse()) {
+        Some(Ok(value)) => value,
+        _ => {
+            eprintln!("{}", usage);
+            process::exit(2);
+        }
+    }
 }
@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Beyond this many versions in one cluster, an upgrade has most likely
+/// been left half done.
+const MAX_VERSIONS_PER_CLUSTER: usize = 2;
+
+/// What a server is running, to tell the nodes of a cluster apart while an
+/// upgrade rolls through it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BuildInfo {
+    pub version: String,
+    /// "unknown" when the binary was not built from a git checkout.
+    pub git_hash: String,
+    pub features: Vec<String>,
+}
+
+impl BuildInfo {
+    /// The build of this binary.
+    pub fn current() -> Self {
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: option_env!("RSRAFT_GIT_HASH")
+                .unwrap_or("unknown")
+                .to_string(),
+            features: option_env!("RSRAFT_FEATURES")
+                .unwrap_or("")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(|feature| feature.to_string())
+                .collect(),
+        }
+    }
+
+    /// An info-style gauge: always 1, the build is in the labels.
+    pub fn render(&self) -> String {
+        format!(
+            "build_info{{version=\"{}\",git_hash=\"{}\",features=\"{}\"}} 1\n",
+            self.version,
+            self.git_hash,
+            self.features.join(",")
+        )
+    }
+}
+
+/// The distinct versions running in a cluster, sorted, if there are more
+/// of them than a rolling upgrade should ever leave behind.
+pub fn version_skew(builds: &[BuildInfo]) -> Option<Vec<String>> {
+    let versions: BTreeSet<&str> = builds.iter().map(|b| b.version.as_str()).collect();
+
+    if versions.len() > MAX_VERSIONS_PER_CLUSTER {
+        Some(versions.into_iter().map(|v| v.to_string()).collect())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_info_describes_this_binary() {
+        let build = BuildInfo::current();
+
+        assert_eq!(build.version, env!("CARGO_PKG_VERSION"));
+        assert!(!build.git_hash.is_empty());
+        assert!(build.features.iter().all(|f| !f.is_empty()));
+        assert!(build
+            .render()
+            .starts_with(&format!("build_info{{version=\"{}\",", build.version)));
+    }
+
+    #[test]
+    fn build_info_flags_more_than_two_versions() {
+        let build = |version: &str| BuildInfo {
+            version: version.to_string(),
+            git_hash: "unknown".to_string(),
+            features: Vec::new(),
+        };
+
+        let upgrading = vec![build("0.1.0"), build("0.2.0"), build("0.2.0")];
+        assert_eq!(version_skew(&upgrading), None);
+
+        let mixed = vec![
+            build("0.2.0"),
+            build("0.1.0"),
+            build("0.3.0"),
+            build("0.1.0"),
+        ];
+        assert_eq!(
+            version_skew(&mixed),
+            Some(vec![
+                "0.1.0".to_string(),
+                "0.2.0".to_string(),
+                "0.3.0".to_string()
+            ])
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Asks a long-running operation to stop early. The operation checks the
+/// token at the points where it can stop cleanly, and returns its own
+/// `Cancelled` error from there. Clones share the same flag.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cancelled;
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    /// Run once, by `cancel`, to wake up whoever waits on the operation.
+    callbacks: Mutex<Vec<Box<dyn FnOnce() + Send>>>,
+    /// Tokens that are cancelled along with this one.
+    children: Mutex<Vec<Weak<Inner>>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// A new token, cancelled as soon as any of `parents` is. Cancelling it
+    /// does not cancel them.
+    pub fn any(parents: &[&CancelToken]) -> Self {
+        let token = CancelToken::new();
+
+        for parent in parents {
+            let mut children = parent.inner.children.lock().unwrap();
+            children.retain(|child| child.strong_count() > 0);
+            children.push(Arc::downgrade(&token.inner));
+        }
+        if parents.iter().any(|parent| parent.is_cancelled()) {
+            token.cancel();
+        }
+
+        token
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn check(&self) -> Result<(), Cancelled> {
+        match self.is_cancelled() {
+            true => Err(Cancelled),
+            false => Ok(()),
+        }
+    }
+
+    /// Cancels the token and every token derived from it, and runs their
+    /// callbacks on this thread.
+    pub fn cancel(&self) {
+        Inner::cancel(&self.inner);
+    }
+
+    /// Runs `callback` once the token is cancelled, right away if it
+    /// already is. A callback that takes a lock must not be registered on
+    /// a token that is cancelled while holding it.
+    pub fn on_cancel(&self, callback: impl FnOnce() + Send + 'static) {
+        {
+            let mut callbacks = self.inner.callbacks.lock().unwrap();
+            if !self.is_cancelled() {
+                callbacks.push(Box::new(callback));
+                return;
+            }
+        }
+
+        callback();
+    }
+}
+
+impl Inner {
+    fn cancel(inner: &Arc<Inner>) {
+        if inner.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let callbacks: Vec<_> = inner.callbacks.lock().unwrap().drain(..).collect();
+        for callback in callbacks {
+            callback();
+        }
+
+        let children: Vec<_> = inner.children.lock().unwrap().drain(..).collect();
+        for child in children.iter().filter_map(Weak::upgrade) {
+            Inner::cancel(&child);
+        }
+    }
+}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn cancel_token_runs_callbacks_and_cancels_derived_tokens() {
+        let shutdown = CancelToken::new();
+        let admin = CancelToken::new();
+        let operation = CancelToken::any(&[&shutdown, &admin]);
+
+        let called = Arc::new(AtomicUsize::new(0));
+        {
+            let called = Arc::clone(&called);
+            operation.on_cancel(move || {
+                called.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // cancelled from another thread
+        assert_eq!(operation.check(), Ok(()));
+        thread::spawn(move || admin.cancel()).join().unwrap();
+
+        assert_eq!(operation.check(), Err(Cancelled));
+        assert!(!shutdown.is_cancelled());
+
+        // callbacks run once, and right away once cancelled
+        shutdown.cancel();
+        operation.cancel();
+        assert_eq!(called.load(Ordering::SeqCst), 1);
+        operation.on_cancel({
+            let called = Arc::clone(&called);
+            move || {
+                called.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        assert_eq!(called.load(Ordering::SeqCst), 2);
+
+        // derived from a cancelled token, a token starts out cancelled
+        assert!(CancelToken::any(&[&shutdown]).is_cancelled());
+        assert!(!CancelToken::new().is_cancelled());
+    }
+}
@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where a server reads the time its timeouts and heartbeats are due at,
+/// see `ServerConfig::clock`.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic clock of the system, `Instant::now`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+/// A clock that stands still until `advance` moves it, so that tests can
+/// step over a timeout instead of sleeping through it. Clones share the
+/// same time.
+///
+/// Only what a server decides by the clock follows it: the background
+/// task still sleeps in real time between rounds.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<Instant>>);
+
+/// A wall-clock time, for humans: audit records, status and admin
+/// responses. Scheduling and correctness never look at it, they use
+/// `Instant`, which the system clock being set cannot move. The two do not
+/// compare, so one cannot be fed where the other is expected; convert with
+/// `Timestamp::of_instant` at the edge, where the value leaves the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(SystemTime);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Timestamp(SystemTime::now())
+    }
+
+    pub fn from_unix_millis(millis: u64) -> Self {
+        Timestamp(UNIX_EPOCH + Duration::from_millis(millis))
+    }
+
+    /// Milliseconds since the Unix epoch, 0 for times before it.
+    pub fn unix_millis(&self) -> u64 {
+        self.0
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    }
+
+    /// The wall-clock time of `instant`, given that `now` and `wall_now`
+    /// were read at the same moment.
+    pub fn of_instant(instant: Instant, now: Instant, wall_now: Timestamp) -> Self {
+        if instant >= now {
+            Timestamp(wall_now.0 + (instant - now))
+        } else {
+            Timestamp(wall_now.0 - (now - instant))
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    /// RFC 3339, in UTC, to the millisecond.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let millis = self.unix_millis();
+        let seconds = millis / 1000;
+        let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+        let seconds_of_day = seconds % 86_400;
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            seconds_of_day / 3600,
+            seconds_of_day / 60 % 60,
+            seconds_of_day % 60,
+            millis % 1000
+        )
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+impl ManualClock {
+    /// Starts at the current time of the system.
+    pub fn new() -> Self {
+        ManualClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// The proleptic Gregorian date `days` after 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_only_moves_when_advanced() {
+        let clock = ManualClock::new();
+        let shared = clock.clone();
+        let started = clock.now();
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(clock.now(), started);
+
+        shared.advance(Duration::from_secs(3));
+        assert_eq!(clock.now(), started + Duration::from_secs(3));
+        assert!(SystemClock.now() < clock.now());
+    }
+
+    #[test]
+    fn timestamp_display() {
+        assert_eq!(
+            Timestamp::from_unix_millis(0).to_string(),
+            "1970-01-01T00:00:00.000Z"
+        );
+        assert_eq!(
+            Timestamp::from_unix_millis(951_782_400_000).to_string(),
+            "2000-02-29T00:00:00.000Z"
+        );
+        assert_eq!(
+            Timestamp::from_unix_millis(1_700_000_000_123).to_string(),
+            "2023-11-14T22:13:20.123Z"
+        );
+    }
+
+    #[test]
+    fn timestamp_of_instant() {
+        let now = Instant::now();
+        let wall_now = Timestamp::from_unix_millis(10_000);
+
+        let later = Timestamp::of_instant(now + Duration::from_millis(1500), now, wall_now);
+        assert_eq!(later.unix_millis(), 11_500);
+
+        let earlier = Timestamp::of_instant(now - Duration::from_millis(2500), now, wall_now);
+        assert_eq!(earlier.unix_millis(), 7_500);
+
+        assert_eq!(Timestamp::of_instant(now, now, wall_now), wall_now);
+    }
+
+    #[test]
+    fn timestamp_round_trips() {
+        let timestamp = Timestamp::from_unix_millis(1_700_000_000_123);
+        let bytes = bincode::serialize(&timestamp).unwrap();
+
+        assert_eq!(
+            bincode::deserialize::<Timestamp>(&bytes).unwrap(),
+            timestamp
+        );
+        assert_eq!(timestamp.unix_millis(), 1_700_000_000_123);
+    }
+}
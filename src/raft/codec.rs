@@ -0,0 +1,40 @@
+use crate::raft::tcp_rpc::RpcMessage;
+use std::io::{self, ErrorKind};
+
+/// How an `RpcMessage` is turned into the body of a frame and back. Both
+/// ends of a connection must use the same codec.
+pub trait Codec: Send + Sync {
+    fn encode(&self, message: &RpcMessage) -> io::Result<Vec<u8>>;
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<RpcMessage>;
+}
+
+/// Compact and fast, the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BincodeCodec;
+
+/// Plain JSON bodies, to follow a conversation on the wire with `nc`.
+#[cfg(feature = "json")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonCodec;
+
+impl Codec for BincodeCodec {
+    fn encode(&self, message: &RpcMessage) -> io::Result<Vec<u8>> {
+        bincode::serialize(message).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<RpcMessage> {
+        bincode::deserialize(bytes).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(feature = "json")]
+impl Codec for JsonCodec {
+    fn encode(&self, message: &RpcMessage) -> io::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(message)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<RpcMessage> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
@@ -1,408 +1,5738 @@
 extern crate log;
 extern crate simplelog;
+use crate::raft::cancel::CancelToken;
+use crate::raft::clock::Timestamp;
+use crate::raft::error::RaftError;
+use crate::raft::events::RaftEvent;
+use crate::raft::quorum::{self, VoteTally};
+use crate::raft::snapshot::{Snapshot, SnapshotError, SnapshotMetadata};
+use crate::raft::status::StatusEndpoint;
 use crate::raft::types::{
-    Leader, LogEntry, Peer, RpcClient, Server, State, VoteRequest, VoteResponse,
+    AbandonReason, AppendEntriesRequest, AppendEntriesResponse, ElectionRecord, ElectionResult,
+    HeartbeatResponse, Leader, LogEntry, NodeKind, Output, Peer, PreVoteRequest, PreVoteResponse,
+    ProposeError, RpcClient, RpcError, Server, State, Term, TimeoutNowRequest, TimeoutNowResponse,
+    VoteRequest, VoteResponse, WaitError,
 };
-use log::info;
-use math::round;
-use rand::Rng;
+use log::{info, warn};
+use std::collections::HashSet;
+use std::io;
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use std::thread;
-use std::time::Duration;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
+/// A running server, returned by `start_server`.
+pub struct ServerHandle {
+    server: Arc<Mutex<Server>>,
+    shutdown: Arc<AtomicBool>,
+    background_task: JoinHandle<()>,
+    status_endpoint: Option<StatusEndpoint>,
+}
+
+/// Starts the background task of the server, and its status endpoint if
+/// it has a `status_address`. Fails if the thread cannot be spawned or the
+/// address cannot be bound.
 pub fn start_server(
     server: Arc<Mutex<Server>>,
     rpc_client: impl RpcClient + std::marker::Send + 'static,
-) {
-    server.lock().unwrap().start();
+) -> Result<ServerHandle, RaftError> {
+    let (name, status_address) = {
+        let mut tmp_server = lock_server(&server);
+        // Its peers may have been set since `Server::new` checked them.
+        tmp_server.check_peers()?;
+        tmp_server.start();
+        (
+            format!("raft-{}", tmp_server.id),
+            tmp_server.config.status_address,
+        )
+    };
+    let status_endpoint = match status_address {
+        Some(address) => Some(StatusEndpoint::spawn(Arc::clone(&server), address)?),
+        None => None,
+    };
 
-    let background_task_handle = thread::spawn(move || {
-        background_task(server, &rpc_client);
-    });
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let background_task_handle = {
+        let server = Arc::clone(&server);
+        let shutdown = Arc::clone(&shutdown);
 
-    background_task_handle.join().unwrap();
-}
+        thread::Builder::new().name(name).spawn(move || {
+            background_task(server, &rpc_client, &shutdown);
+        })?
+    };
 
-pub fn handle_vote_request(server: Arc<Mutex<Server>>, request: VoteRequest) -> VoteResponse {
-    let mut tmp_server = server.lock().unwrap();
+    Ok(ServerHandle {
+        server: server,
+        shutdown: shutdown,
+        background_task: background_task_handle,
+        status_endpoint: status_endpoint,
+    })
+}
 
-    match tmp_server.voted_for {
-        Some(_) => VoteResponse {
-            term: request.term,
-            vote_granted: false,
-        },
-        None => {
-            if request.term > tmp_server.term {
-                tmp_server.voted_for = Some(Peer {
-                    id: request.candidate_id,
-                    // Fake address for now.
-                    address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 7879),
-                });
+/// Proposes a bulk command, returning its index once it is durable. A
+/// follower answers with the leader it knows of.
+pub fn propose_command(server: &Arc<Mutex<Server>>, data: Vec<u8>) -> Result<u64, RaftError> {
+    let (index, acks) = {
+        let mut tmp_server = lock_server(server);
 
-                VoteResponse {
-                    term: request.term,
-                    vote_granted: true,
-                }
-            } else {
-                VoteResponse {
-                    term: request.term,
-                    vote_granted: false,
-                }
+        match tmp_server.submit(data) {
+            Ok(index) => (index, tmp_server.log.acks()),
+            Err(ProposeError::NotLeader) => {
+                return Err(RaftError::NotLeader {
+                    leader: tmp_server.known_leader(),
+                })
             }
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    // Not under the lock, so that concurrent proposals share a sync.
+    if let Err(e) = acks.wait(index) {
+        return Err(ProposeError::LogUnwritable {
+            error: e.to_string(),
         }
+        .into());
     }
+
+    // The leader counts its own log towards a majority from now on.
+    lock_server(server).notify();
+    Ok(index)
 }
 
-pub fn handle_log_entry(server: Arc<Mutex<Server>>, entry: LogEntry) -> u64 {
-    let mut server = server.lock().unwrap();
+impl ServerHandle {
+    /// Stops the background task once it is done with its current round,
+    /// waits for it, and persists what must survive a restart.
+    pub fn shutdown(self) {
+        {
+            // Under the lock, so that the background task cannot miss the
+            // wake-up between checking the flag and going to sleep.
+            let tmp_server = lock_server(&self.server);
+            self.shutdown.store(true, Ordering::SeqCst);
+            tmp_server.notify();
+        }
 
-    if let LogEntry::Heartbeat { term, peer_id } = entry {
-        info!(
-            "Server {} with term {}, received heartbeat from {} with term {}",
-            server.id, server.term, peer_id, term
-        );
+        self.background_task.join().unwrap();
+        if let Some(status_endpoint) = self.status_endpoint {
+            status_endpoint.stop();
+        }
 
-        server.refresh_timeout();
+        // Not under the lock: waiters are woken up under it.
+        let cancel = lock_server(&self.server).cancel.clone();
+        cancel.cancel();
 
-        if term > server.term {
-            info!(
-                "Server {} becoming follower. The new leader is: {}",
-                server.id, peer_id
-            );
+        let mut tmp_server = lock_server(&self.server);
+        tmp_server.flush_metrics();
+        info!("Server {} has shut down.", tmp_server.id);
+    }
 
-            server.term = term;
-            server.state = State::FOLLOWER;
-            server.voted_for = None;
-            server.current_leader = Some(Leader {
-                id: peer_id.to_string(),
-                term: term,
-            })
-        }
-    };
+    /// Takes a snapshot right away, whatever `snapshot_threshold` says.
+    pub fn trigger_snapshot(&self) -> Result<SnapshotMetadata, SnapshotError> {
+        take_snapshot(&self.server)
+    }
+
+    /// Blocks for as long as the server runs.
+    pub fn join(self) {
+        self.background_task.join().unwrap();
+    }
+}
 
-    let current_term = server.term;
+/// Locks the server, the way every caller in this crate does. A thread
+/// that panicked while holding the lock leaves it poisoned; the server is
+/// then taken as that thread left it rather than every later caller
+/// panicking too, so that a fault in one handler does not take the whole
+/// node down with it.
+pub fn lock_server(server: &Mutex<Server>) -> MutexGuard<'_, Server> {
+    server.lock().unwrap_or_else(|e| {
+        warn!("Recovering a server whose lock a panicking thread held");
+        server.clear_poison();
+        e.into_inner()
+    })
+}
 
-    current_term
+/// Blocks until the entry at `index` has been applied locally, so that
+/// what is read from the state machine afterwards includes it.
+pub fn wait_for_applied(
+    server: &Arc<Mutex<Server>>,
+    index: u64,
+    timeout: Duration,
+) -> Result<(), WaitError> {
+    wait_for_applied_cancellable(server, index, timeout, &CancelToken::new())
 }
 
-fn background_task(server: Arc<Mutex<Server>>, rpc_client: &impl RpcClient) {
-    loop {
-        handle_timeout(Arc::clone(&server), rpc_client);
-        broadcast_heartbeat(Arc::clone(&server), rpc_client);
+/// Blocks until the command proposed here at `index`, see
+/// `propose_command`, has been applied, and returns what the state machine
+/// returned for it. A command the leader lost along with its leadership is
+/// never applied: the wait then fails with `NotLeader`, and the command
+/// can be proposed again.
+pub fn wait_for_output(
+    server: &Arc<Mutex<Server>>,
+    index: u64,
+    timeout: Duration,
+) -> Result<Vec<u8>, RaftError> {
+    wait_for_applied(server, index, timeout)?;
+
+    let mut tmp_server = lock_server(server);
+    match tmp_server.take_output(index) {
+        Some(Output::Applied(output)) => Ok(output),
+        Some(Output::Superseded) => Err(RaftError::NotLeader {
+            leader: tmp_server.known_leader(),
+        }),
+        _ => Err(RaftError::OutputUnavailable { index: index }),
     }
 }
 
-fn broadcast_heartbeat(server: Arc<Mutex<Server>>, rpc_client: &impl RpcClient) {
-    let is_leader = server.lock().unwrap().state == State::LEADER;
+/// Like `wait_for_applied`, but gives up with `Cancelled` as soon as
+/// `cancel` is cancelled or the server shuts down. `cancel` must not be
+/// cancelled while holding the server's lock.
+pub fn wait_for_applied_cancellable(
+    server: &Arc<Mutex<Server>>,
+    index: u64,
+    timeout: Duration,
+    cancel: &CancelToken,
+) -> Result<(), WaitError> {
+    // By the server's clock, which a test may be stepping.
+    let (deadline, cancel) = {
+        let tmp_server = lock_server(server);
+        let cancel = CancelToken::any(&[cancel, &tmp_server.cancel]);
+        (tmp_server.now() + timeout, cancel)
+    };
+    {
+        // Under the lock, so that the waiter cannot miss the wake-up
+        // between checking the token and going to sleep.
+        let server = Arc::clone(server);
+        cancel.on_cancel(move || lock_server(&server).applied.notify_all());
+    }
+    let mut tmp_server = lock_server(server);
 
-    if is_leader {
-        let term = server.lock().unwrap().term;
-        let id = server.lock().unwrap().id.to_string();
+    while tmp_server.last_applied() < index {
+        if cancel.is_cancelled() {
+            return Err(WaitError::Cancelled);
+        }
 
-        rpc_client.broadcast_log_entry(LogEntry::Heartbeat {
-            term: term,
-            peer_id: id,
-        });
+        if let Some((failed, error)) = tmp_server.apply_failure() {
+            return Err(WaitError::ApplyFailed {
+                index: *failed,
+                error: error.clone(),
+            });
+        }
+
+        let now = tmp_server.now();
+        if now >= deadline {
+            return Err(WaitError::Timeout {
+                last_applied: tmp_server.last_applied(),
+            });
+        }
 
-        // A touch of randomness, so that we can get the chance
-        // to have other leader elections.
-        let mut rng = rand::thread_rng();
-        thread::sleep(Duration::new(rng.gen_range(1..7), 0));
+        let applied = Arc::clone(&tmp_server.applied);
+        tmp_server = applied
+            .wait_timeout(tmp_server, deadline - now)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0;
     }
-}
 
-fn handle_timeout(server: Arc<Mutex<Server>>, rpc_client: &impl RpcClient) {
-    let server_id = server.lock().unwrap().id.to_string();
-    let has_timed_out = server.lock().unwrap().has_timed_out();
+    Ok(())
+}
 
-    if has_timed_out {
-        info!("Server {} has timed out.", server_id);
+/// Writes a snapshot of the state machine as of the last applied entry to
+/// the server's storage. The state machine is captured under the lock, but
+/// written out without holding it, so the server keeps serving meanwhile.
+pub fn take_snapshot(server: &Arc<Mutex<Server>>) -> Result<SnapshotMetadata, SnapshotError> {
+    take_snapshot_cancellable(server, &CancelToken::new())
+}
 
-        new_election(Arc::clone(&server), rpc_client);
+/// Like `take_snapshot`, but gives up with `Cancelled`, writing nothing,
+/// if `cancel` is cancelled or the server shuts down before the snapshot
+/// is written out.
+pub fn take_snapshot_cancellable(
+    server: &Arc<Mutex<Server>>,
+    cancel: &CancelToken,
+) -> Result<SnapshotMetadata, SnapshotError> {
+    let cancel = CancelToken::any(&[cancel, &lock_server(server).cancel]);
+    if cancel.is_cancelled() {
+        return Err(SnapshotError::Cancelled);
     }
-}
 
-fn new_election(server: Arc<Mutex<Server>>, rpc_client: &impl RpcClient) {
-    let vote_request = prepare_vote_request(Arc::clone(&server));
-    let server_id = server.lock().unwrap().id.to_string();
-    let server_current_term = server.lock().unwrap().term;
+    let started = Instant::now();
+    let taken_at = Timestamp::now();
+
+    let (storage, snapshot) = {
+        let mut tmp_server = lock_server(server);
+        let storage = match tmp_server.config.storage() {
+            Some(storage) => storage,
+            None => return Err(SnapshotError::NoDataDir),
+        };
+
+        let last_applied = tmp_server.last_applied();
+        tmp_server.snapshots.begin(last_applied)?;
+
+        let data = match &tmp_server.state_machine {
+            Some(state_machine) => state_machine.snapshot(),
+            None => Vec::new(),
+        };
+
+        (
+            storage,
+            Snapshot {
+                last_included_index: last_applied,
+                last_included_term: tmp_server.term_at(last_applied).unwrap_or_default(),
+                data: data,
+                sessions: tmp_server.sessions.clone(),
+            },
+        )
+    };
+    let index = snapshot.last_included_index;
+    let term = snapshot.last_included_term;
+
+    // Capturing a large state machine takes a while; there is no point
+    // writing it out if it is no longer wanted.
+    let result = match cancel.check() {
+        Ok(()) => storage
+            .save_snapshot(&snapshot)
+            .map(|size| SnapshotMetadata {
+                last_included_index: index,
+                last_included_term: term,
+                size: size,
+                taken_at: taken_at,
+                duration: started.elapsed(),
+            })
+            .map_err(|e| SnapshotError::Failed(e.to_string())),
+        Err(_) => Err(SnapshotError::Cancelled),
+    };
 
+    let mut tmp_server = lock_server(server);
+    tmp_server.snapshots.finished(&result);
     info!(
-        "Server {}, with term {}, started the election process.",
-        server_id, server_current_term
+        "Server {} snapshot up to {}: {:?}",
+        tmp_server.id, index, result
     );
 
-    let vote_response = match vote_request {
-        Some(request) => Some(rpc_client.request_vote(request)),
-        None => None,
-    };
+    result
+}
 
-    if let Some(r) = vote_response {
-        let own_election;
-        {
-            let mut server = server.lock().unwrap();
-            own_election = has_won_the_election(&server, r) && !server.has_timed_out();
-        }
+/// Delivers the events queued while the server was locked to its
+/// observer. Must be called without holding the lock.
+fn deliver_events(server: &Arc<Mutex<Server>>) {
+    let events = lock_server(server).take_events();
 
-        if own_election {
-            become_leader(Arc::clone(&server), rpc_client);
+    if let Some((observer, events)) = events {
+        for event in events {
+            observer.notify(event);
         }
     }
 }
 
-fn prepare_vote_request(server: Arc<Mutex<Server>>) -> Option<VoteRequest> {
-    if server.lock().unwrap().state == State::LEADER {
-        return None;
+pub fn handle_vote_request(server: Arc<Mutex<Server>>, request: VoteRequest) -> VoteResponse {
+    let response = vote(&mut lock_server(&server), request);
+    deliver_events(&server);
+    response
+}
+
+fn vote(tmp_server: &mut Server, request: VoteRequest) -> VoteResponse {
+    // A follower that still hears from its leader keeps it, the candidate
+    // is the one cut off. Its term is not taken either, that alone would
+    // depose the leader.
+    if !request.leadership_transfer && hears_from_leader(tmp_server) {
+        info!(
+            "Server {} heard from its leader lately, denying {} its vote in term {}",
+            tmp_server.id, request.candidate_id, request.term
+        );
+        tmp_server.emit(RaftEvent::VoteDenied {
+            term: request.term,
+            candidate_id: request.candidate_id,
+        });
+
+        return VoteResponse {
+            term: tmp_server.term,
+            vote_granted: false,
+            voter_id: tmp_server.id.to_string(),
+        };
     }
 
-    {
-        let mut server_tmp = server.lock().unwrap();
-        server_tmp.state = State::CANDIDATE;
-        server_tmp.term = server_tmp.term + 1;
-        server_tmp.refresh_timeout();
-        server_tmp.voted_for = Some(Peer {
-            id: server_tmp.id.to_string(),
-            address: server_tmp.address,
+    // A vote is per term: a new term frees it, whatever was voted before,
+    // a candidate's or a leader's vote for itself included.
+    if request.term > tmp_server.term {
+        step_down(tmp_server, &request.candidate_id, request.term);
+    }
+
+    // A candidate retrying its request gets the same vote again.
+    let already_granted = request.term == tmp_server.term
+        && tmp_server
+            .voted_for
+            .as_ref()
+            .is_some_and(|p| p.id == request.candidate_id);
+    let outranked = outranks_candidate(tmp_server, &request);
+    if outranked {
+        info!(
+            "Server {} outranks {}, denying it its vote in term {}",
+            tmp_server.id, request.candidate_id, request.term
+        );
+    }
+    let mut vote_granted =
+        candidate_log_is_up_to_date(tmp_server, request.last_log_index, request.last_log_term)
+            && !outranked
+            && (already_granted
+                || (tmp_server.voted_for.is_none() && request.term == tmp_server.term));
+
+    if vote_granted && !already_granted {
+        tmp_server.voted_for = Some(Peer {
+            id: request.candidate_id.to_string(),
+            // Fake address for now.
+            address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 7879),
+            kind: NodeKind::Voter,
         });
+
+        // The vote only counts once it cannot be forgotten.
+        if let Err(e) = tmp_server.persist_hard_state() {
+            info!(
+                "Server {} could not persist its vote for {}, denying it: {}",
+                tmp_server.id, request.candidate_id, e
+            );
+            tmp_server.voted_for = None;
+            vote_granted = false;
+        }
     }
 
-    let new_term = server.lock().unwrap().term;
-    let id = server.lock().unwrap().id.to_string();
+    // Granting a vote gives the candidate a whole election timeout to win,
+    // before this server stands itself and disrupts the election.
+    if vote_granted {
+        tmp_server.refresh_timeout();
+        tmp_server.emit(RaftEvent::VoteGranted {
+            term: request.term,
+            candidate_id: request.candidate_id,
+        });
+    } else {
+        tmp_server.emit(RaftEvent::VoteDenied {
+            term: request.term,
+            candidate_id: request.candidate_id,
+        });
+    }
 
-    Some(VoteRequest {
-        term: new_term,
-        candidate_id: id,
+    VoteResponse {
+        term: tmp_server.term,
+        vote_granted: vote_granted,
+        voter_id: tmp_server.id.to_string(),
+    }
+}
+
+/// Whether a leader was heard from less than `election_timeout_min` ago.
+fn hears_from_leader(server: &Server) -> bool {
+    server.last_leader_contact.is_some_and(|contact| {
+        server.now().saturating_duration_since(contact) < server.config.election_timeout_min
     })
 }
 
-fn has_won_the_election(server: &Server, response: Vec<VoteResponse>) -> bool {
-    let number_of_servers = server.number_of_peers + 1; // All peers + current server
+/// The election restriction: a candidate whose log ends in an older term,
+/// or is shorter with the same last term, could be missing committed
+/// entries, and must not lead.
+fn candidate_log_is_up_to_date(
+    server: &Server,
+    candidate_last_log_index: u64,
+    candidate_last_log_term: Term,
+) -> bool {
+    let last_log_index = server.last_log_index();
+    let last_log_term = server.term_at(last_log_index).unwrap_or_default();
 
-    let votes = response.iter().filter(|r| r.vote_granted).count();
+    (candidate_last_log_term, candidate_last_log_index) >= (last_log_term, last_log_index)
+}
 
-    let min_quorum = round::floor((number_of_servers / 2) as f64, 0);
+/// Priority elections: a voter with a higher priority than the candidate
+/// would rather lead, and can unless the candidate's log is ahead of its
+/// own. Candidates the leader handed over to are never refused.
+fn outranks_candidate(server: &Server, request: &VoteRequest) -> bool {
+    let last_log_index = server.last_log_index();
+    let last_log_term = server.term_at(last_log_index).unwrap_or_default();
+    let candidate_is_ahead =
+        (request.last_log_term, request.last_log_index) > (last_log_term, last_log_index);
 
-    (votes + 1) > min_quorum as usize && State::CANDIDATE == server.state
+    !request.leadership_transfer
+        && server.config.election_priority > request.election_priority
+        && server.is_voter()
+        && !candidate_is_ahead
 }
 
-fn become_leader(server: Arc<Mutex<Server>>, rpc_client: &impl RpcClient) {
-    let mut server = server.lock().unwrap();
+/// Answers whether this server would vote for the candidate in the term it
+/// asks about, without changing its term, its vote or its timeout.
+pub fn handle_pre_vote_request(
+    server: Arc<Mutex<Server>>,
+    request: PreVoteRequest,
+) -> PreVoteResponse {
+    pre_vote(&mut lock_server(&server), request)
+}
 
-    server.become_leader();
+/// A vote would be granted in a later term, to a candidate whose log is up
+/// to date, unless this server still hears from a leader: then the
+/// candidate is the one cut off, and must not depose it.
+fn pre_vote(server: &mut Server, request: PreVoteRequest) -> PreVoteResponse {
+    let has_leader = server.state == State::LEADER
+        || (server.current_leader.is_some() && !server.has_timed_out());
+    let vote_granted = request.term > server.term
+        && !has_leader
+        && candidate_log_is_up_to_date(server, request.last_log_index, request.last_log_term);
 
-    let log_entry = LogEntry::Heartbeat {
-        term: server.term,
-        peer_id: server.id.to_string(),
-    };
+    info!(
+        "Server {} with term {} would {}vote for {} in term {}",
+        server.id,
+        server.term,
+        if vote_granted { "" } else { "not " },
+        request.candidate_id,
+        request.term
+    );
 
-    rpc_client.broadcast_log_entry(log_entry);
+    PreVoteResponse {
+        term: server.term,
+        vote_granted: vote_granted,
+        voter_id: server.id.to_string(),
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::raft::types::ServerConfig;
-    use std::net::{Ipv4Addr, SocketAddrV4};
-    use std::thread::sleep;
-    use std::time::{Duration, Instant};
-
-    #[test]
-    fn raft_new_election() {
-        // When the server gets the vote from its peers
-        let server = Arc::new(Mutex::new(build_server()));
-        let rpc_client = FakeRpc {
-            granted_vote: true,
-            sleeps_for: Duration::new(0, 0),
-            peers: create_peers(2),
-        };
+/// The leader of our term hands over to this server: it stands at once,
+/// without waiting for its timeout nor asking for a pre-vote, which the
+/// other followers would deny while they still hear from that leader.
+pub fn handle_timeout_now(
+    server: Arc<Mutex<Server>>,
+    request: TimeoutNowRequest,
+) -> TimeoutNowResponse {
+    let mut tmp_server = lock_server(&server);
 
-        new_election(Arc::clone(&server), &rpc_client);
+    if request.term == tmp_server.term
+        && tmp_server.state == State::FOLLOWER
+        && tmp_server.can_lead()
+    {
+        info!(
+            "Server {} told by {} to stand for election in term {}.",
+            tmp_server.id,
+            request.leader_id,
+            request.term.increment()
+        );
+        tmp_server.timeout_now = true;
+        tmp_server.notify();
+    }
 
-        {
-            let tmp_server = server.lock().unwrap();
-            assert_eq!(tmp_server.state, State::LEADER);
-            assert_eq!(tmp_server.term, 1);
-        }
+    TimeoutNowResponse {
+        term: tmp_server.term,
+        peer_id: tmp_server.id.to_string(),
+    }
+}
 
-        // When the server does not get the vote from its peers
-        let server = Arc::new(Mutex::new(build_server()));
-        let rpc_client = FakeRpc {
-            granted_vote: false,
-            sleeps_for: Duration::new(0, 0),
-            peers: create_peers(2),
-        };
+pub fn handle_log_entry(server: Arc<Mutex<Server>>, entry: LogEntry) -> HeartbeatResponse {
+    let response = log_entry(&mut lock_server(&server), entry);
+    deliver_events(&server);
+    response
+}
 
-        new_election(Arc::clone(&server), &rpc_client);
+fn log_entry(server: &mut Server, entry: LogEntry) -> HeartbeatResponse {
+    if let LogEntry::Heartbeat { term, peer_id } = entry {
+        info!(
+            "Server {} with term {}, received heartbeat from {} with term {}",
+            server.id, server.term, peer_id, term
+        );
 
-        {
-            let tmp_server = server.lock().unwrap();
-            assert_eq!(tmp_server.state, State::CANDIDATE);
-            assert_eq!(tmp_server.term, 1);
+        // A leader of an older term was superseded: it must neither hold
+        // back our election nor pass for the leader.
+        if term < server.term {
+            return HeartbeatResponse {
+                term: server.term,
+                peer_id: server.id.to_string(),
+                success: false,
+            };
         }
 
-        // When the server is alredy leader.
-        let server = Arc::new(Mutex::new(build_server()));
-        let rpc_client = FakeRpc {
-            granted_vote: false,
-            sleeps_for: Duration::new(0, 0),
-            peers: create_peers(2),
-        };
+        server.refresh_timeout();
+        server.last_leader_contact = Some(server.now());
+        server.metrics.counters.heartbeats_received_total += 1;
 
-        server.lock().unwrap().state = State::LEADER;
-        server.lock().unwrap().term = 10;
+        // A heartbeat in our own term comes from the leader of that term:
+        // a candidate lost the election, a follower learns who won it.
+        let new_term = term > server.term;
+        let same_term = term == server.term && server.state != State::LEADER;
 
-        new_election(Arc::clone(&server), &rpc_client);
+        if new_term || same_term {
+            if new_term || server.state != State::FOLLOWER {
+                info!(
+                    "Server {} becoming follower. The new leader is: {}",
+                    server.id, peer_id
+                );
+            }
 
-        {
-            let tmp_server = server.lock().unwrap();
-            assert_eq!(tmp_server.state, State::LEADER);
-            // term does not change
-            assert_eq!(tmp_server.term, 10);
+            server.become_follower(term, Some(Leader { id: peer_id }));
         }
+    };
 
-        // When the server times out again, it should not
-        // become leader even when getting votes.
-        let server = Arc::new(Mutex::new(build_server()));
-        let rpc_client = FakeRpc {
-            granted_vote: true,
-            sleeps_for: Duration::new(1, 0),
-            peers: create_peers(2),
-        };
+    HeartbeatResponse {
+        term: server.term,
+        peer_id: server.id.to_string(),
+        success: true,
+    }
+}
 
-        server.lock().unwrap().start();
+pub fn handle_append_entries(
+    server: Arc<Mutex<Server>>,
+    request: AppendEntriesRequest,
+) -> AppendEntriesResponse {
+    let response = append_entries(&mut lock_server(&server), request);
+    deliver_events(&server);
+    response
+}
 
-        new_election(Arc::clone(&server), &rpc_client);
+fn append_entries(server: &mut Server, request: AppendEntriesRequest) -> AppendEntriesResponse {
+    if request.term < server.term {
+        return AppendEntriesResponse {
+            term: server.term,
+            peer_id: server.id.to_string(),
+            success: false,
+            match_index: 0,
+            conflict_term: None,
+            conflict_index: 0,
+            last_applied: server.last_applied(),
+            election_priority: server.config.election_priority,
+        };
+    }
 
-        {
-            let tmp_server = server.lock().unwrap();
-            assert_eq!(tmp_server.state, State::CANDIDATE);
-            assert_eq!(tmp_server.term, 1);
-        }
+    server.refresh_timeout();
+    server.last_leader_contact = Some(server.now());
+
+    if request.term > server.term || server.state != State::FOLLOWER {
+        server.become_follower(
+            request.term,
+            Some(Leader {
+                id: request.leader_id.to_string(),
+            }),
+        );
     }
 
-    #[test]
-    fn raft_handle_log_entry() {
-        // When the heartbeat contains a higher term
-        let server = Arc::new(Mutex::new(build_server()));
-        server.lock().unwrap().term = 10;
+    // The leader never accepts such entries, so they can only come from a
+    // misbehaving or misconfigured peer. None of the request is applied.
+    if let Some(entry) = request
+        .entries
+        .iter()
+        .find(|e| e.payload_size() > server.config.max_entry_bytes)
+    {
+        info!(
+            "Server {} rejecting an entry of {} bytes from {}, the limit is {}",
+            server.id,
+            entry.payload_size(),
+            request.leader_id,
+            server.config.max_entry_bytes
+        );
 
-        let log_entry = LogEntry::Heartbeat {
-            term: 19,
-            peer_id: "server_3".to_string(),
+        return AppendEntriesResponse {
+            term: server.term,
+            peer_id: server.id.to_string(),
+            success: false,
+            match_index: 0,
+            conflict_term: None,
+            conflict_index: request.prev_log_index + 1,
+            last_applied: server.last_applied(),
+            election_priority: server.config.election_priority,
         };
+    }
 
-        server.lock().unwrap().start();
-
-        handle_log_entry(Arc::clone(&server), log_entry);
+    // Everything up to the last snapshot is applied, so it is known to
+    // match the leader's log: point the leader right past it rather than
+    // checking entries one by one.
+    let snapshot_index = server.snapshots.last_included_index();
+    if request.prev_log_index < snapshot_index {
+        info!(
+            "Server {} already applied up to {}, past {} from {}",
+            server.id, snapshot_index, request.prev_log_index, request.leader_id
+        );
 
-        {
-            let tmp_server = server.lock().unwrap();
-            assert_eq!(tmp_server.state, State::FOLLOWER);
-            assert_eq!(tmp_server.term, 19);
-            assert!(tmp_server.next_timeout.as_ref().unwrap() > &Instant::now());
-        }
+        return AppendEntriesResponse {
+            term: server.term,
+            peer_id: server.id.to_string(),
+            success: false,
+            match_index: 0,
+            conflict_term: None,
+            conflict_index: snapshot_index + 1,
+            last_applied: server.last_applied(),
+            election_priority: server.config.election_priority,
+        };
+    }
 
-        // When the heartbeat contains a higher term
-        // and the current server is a Leader, then it
-        // becomes a follower.
-        let server = Arc::new(Mutex::new(build_server()));
-        server.lock().unwrap().state = State::LEADER;
-        server.lock().unwrap().term = 10;
+    if server.term_at(request.prev_log_index) != Some(request.prev_log_term) {
+        let (conflict_term, conflict_index) = find_conflict(server, request.prev_log_index);
 
-        let log_entry = LogEntry::Heartbeat {
-            term: 19,
-            peer_id: "server_3".to_string(),
+        return AppendEntriesResponse {
+            term: server.term,
+            peer_id: server.id.to_string(),
+            success: false,
+            match_index: 0,
+            conflict_term: conflict_term,
+            conflict_index: conflict_index,
+            last_applied: server.last_applied(),
+            election_priority: server.config.election_priority,
         };
+    }
 
-        server.lock().unwrap().start();
+    let mut index = request.prev_log_index;
+    let mut entries = Vec::new();
+    for entry in request.entries {
+        index += 1;
 
-        handle_log_entry(Arc::clone(&server), log_entry);
+        let truncated = match server.term_at(index) {
+            Some(term) if term == entry.term() => continue,
+            Some(_) => server.log.truncate_from(index),
+            None => Ok(()),
+        };
+        if let Err(e) = truncated {
+            return not_written(server, &request.leader_id, e);
+        }
 
-        {
-            let tmp_server = server.lock().unwrap();
-            assert_eq!(tmp_server.state, State::FOLLOWER);
-            assert_eq!(tmp_server.term, 19);
-            assert!(tmp_server.next_timeout.as_ref().unwrap() > &Instant::now());
+        // A witness only keeps where the commands are.
+        if server.config.kind == NodeKind::Witness {
+            entries.push(entry.without_payload());
+        } else {
+            entries.push(entry);
         }
     }
 
-    #[test]
-    fn raft_handle_vote_request() {
-        let server = Arc::new(Mutex::new(build_server()));
-        server.lock().unwrap().start();
+    // The entries are only acknowledged once they are durable, all
+    // together.
+    if let Err(e) = server.log.append_all(entries) {
+        return not_written(server, &request.leader_id, e);
+    }
 
-        let candidate_id = "server_2";
+    if request.leader_commit > server.commit_index {
+        let commit_index = request.leader_commit.min(index);
+        if commit_index > server.commit_index {
+            server.commit_index = commit_index;
+            server.emit(RaftEvent::CommitAdvanced {
+                term: server.term,
+                commit_index: commit_index,
+            });
+        }
+    }
+
+    AppendEntriesResponse {
+        term: server.term,
+        peer_id: server.id.to_string(),
+        success: true,
+        match_index: index,
+        conflict_term: None,
+        conflict_index: 0,
+        last_applied: server.last_applied(),
+        election_priority: server.config.election_priority,
+    }
+}
+
+/// Refuses entries that could not be written to the log. The leader
+/// retries from wherever the log now ends.
+fn not_written(server: &Server, leader_id: &str, e: io::Error) -> AppendEntriesResponse {
+    info!(
+        "Server {} could not write entries from {}: {}",
+        server.id, leader_id, e
+    );
+
+    AppendEntriesResponse {
+        term: server.term,
+        peer_id: server.id.to_string(),
+        success: false,
+        match_index: 0,
+        conflict_term: None,
+        conflict_index: server.last_log_index() + 1,
+        last_applied: server.last_applied(),
+        election_priority: server.config.election_priority,
+    }
+}
+
+/// Where the follower's log stops agreeing with an AppendEntries whose
+/// `prev_log_index` did not match.
+fn find_conflict(server: &Server, prev_log_index: u64) -> (Option<Term>, u64) {
+    let conflict_term = match server.term_at(prev_log_index) {
+        Some(term) => term,
+        None => return (None, server.last_log_index() + 1),
+    };
+
+    let mut first_index = prev_log_index;
+    while first_index > 1 && server.term_at(first_index - 1) == Some(conflict_term) {
+        first_index -= 1;
+    }
+
+    (Some(conflict_term), first_index)
+}
+
+/// Where the leader retries after a rejection. If the leader has entries
+/// for the conflicting term it continues right after its last one,
+/// otherwise the whole term is skipped.
+fn next_index_after_conflict(server: &Server, response: &AppendEntriesResponse) -> u64 {
+    let last_in_conflict_term = response.conflict_term.and_then(|conflict_term| {
+        (1..=server.last_log_index())
+            .rev()
+            .find(|i| server.term_at(*i) == Some(conflict_term))
+    });
+
+    match last_in_conflict_term {
+        Some(index) => index + 1,
+        None => response.conflict_index.max(1),
+    }
+}
+
+/// How often a leader checks for acknowledgements while entries are being
+/// replicated. They arrive through the RpcClient, which cannot wake up
+/// the background task.
+const REPLICATION_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn background_task(server: Arc<Mutex<Server>>, rpc_client: &impl RpcClient, shutdown: &AtomicBool) {
+    while !shutdown.load(Ordering::SeqCst) {
+        run_round(&server, rpc_client, shutdown);
+        wait_for_next_event(&server, shutdown);
+    }
+}
+
+/// One round of the background task: whatever is due by the server's
+/// clock gets done, without waiting for anything to become due. Tests
+/// stepping servers by hand call it, see `testing::TestCluster`.
+pub fn run_round(server: &Arc<Mutex<Server>>, rpc_client: &impl RpcClient, shutdown: &AtomicBool) {
+    handle_timeout(Arc::clone(server), rpc_client, shutdown);
+    replicate_log(Arc::clone(server), rpc_client);
+    advance_transfer(Arc::clone(server), rpc_client);
+    broadcast_heartbeat(Arc::clone(server), rpc_client);
+
+    let snapshot_due = {
+        let mut tmp_server = lock_server(server);
+        tmp_server.sync_peers();
+        check_quorum(&mut tmp_server);
+        yield_to_higher_priority(&mut tmp_server);
+        tmp_server.apply_committed();
+        tmp_server.check_soft_limits();
+        tmp_server.flush_metrics_if_due();
+        tmp_server.snapshot_due()
+    };
+    deliver_events(server);
+
+    if snapshot_due {
+        let server = Arc::clone(server);
+        thread::spawn(move || take_snapshot(&server));
+    }
+}
+
+/// Sleeps until something is due: the election timeout of a follower or
+/// candidate, the next heartbeat of a leader, or acknowledgements of what
+/// a leader is replicating. `Server::notify` wakes it up early.
+fn wait_for_next_event(server: &Arc<Mutex<Server>>, shutdown: &AtomicBool) {
+    let tmp_server = lock_server(server);
+    let now = tmp_server.now();
+
+    // more was committed than applied in the previous round
+    if shutdown.load(Ordering::SeqCst) || tmp_server.apply_pending() {
+        return;
+    }
+
+    let deadline = match tmp_server.state {
+        State::LEADER => {
+            let heartbeat = tmp_server.next_heartbeat.unwrap_or(now);
+            let replicating = tmp_server
+                .progress
+                .values()
+                .any(|p| p.inflight() > 0 || p.next_index <= tmp_server.last_log_index());
+
+            if replicating {
+                heartbeat.min(now + REPLICATION_POLL_INTERVAL)
+            } else {
+                heartbeat
+            }
+        }
+        _ => tmp_server
+            .next_timeout
+            .unwrap_or(now + tmp_server.config.heartbeat_interval),
+    };
+
+    if deadline > now {
+        let wakeup = Arc::clone(&tmp_server.wakeup);
+        let _ = wakeup
+            .wait_timeout(tmp_server, deadline - now)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+fn replicate_log(server: Arc<Mutex<Server>>, rpc_client: &impl RpcClient) {
+    let responses = rpc_client.receive_append_entries_responses();
+
+    let requests = {
+        let mut server = lock_server(&server);
+
+        for response in responses {
+            handle_append_entries_response(&mut server, response);
+        }
+
+        if server.state != State::LEADER {
+            return;
+        }
+
+        // a leader without followers commits on its own
+        advance_commit_index(&mut server);
+        server.check_soft_limits();
+        prepare_append_entries(&mut server)
+    };
+
+    for (peer_id, request) in requests {
+        rpc_client.send_append_entries(&peer_id, request);
+    }
+}
+
+/// Fills every follower's pipeline up to `max_inflight_append_entries`,
+/// within the follower's flow control limits. A follower whose log state
+/// is unknown gets an empty probe first.
+fn prepare_append_entries(server: &mut Server) -> Vec<(String, AppendEntriesRequest)> {
+    let last_log_index = server.last_log_index();
+    let max_inflight = server.config.max_inflight_append_entries;
+    let max_entries_per_append = server.config.max_entries_per_append;
+    let max_inflight_entries = server.config.max_inflight_entries;
+    let max_inflight_bytes = server.config.max_inflight_bytes;
+    let mut requests = Vec::new();
+
+    let peer_ids: Vec<String> = server.progress.keys().cloned().collect();
+
+    // Followers that are far behind share the catch-up budget earned
+    // since the last round. What a follower cannot use yet is kept for
+    // it, up to a second's worth (or one entry, if that is larger).
+    let now = server.now();
+    let horizon = server.config.catch_up_horizon;
+    let lagging: Vec<String> = peer_ids
+        .iter()
+        .filter(|id| server.progress[*id].next_index + horizon <= last_log_index)
+        .cloned()
+        .collect();
+    let budget = server
+        .catch_up
+        .refill(now, server.config.catch_up_bytes_per_second);
+    let max_deficit = server
+        .config
+        .catch_up_bytes_per_second
+        .max(server.config.max_entry_bytes);
+
+    for (peer_id, progress) in server.progress.iter_mut() {
+        if lagging.contains(peer_id) {
+            let deficit = progress.catch_up_deficit + budget / lagging.len();
+            progress.catch_up_deficit = deficit.min(max_deficit);
+        } else if progress.is_catching_up() {
+            progress.stopped_catching_up();
+        }
+    }
+
+    for peer_id in peer_ids {
+        let catching_up = lagging.contains(&peer_id);
+
+        if server.progress[&peer_id].retry_due(
+            now,
+            server.config.append_entries_timeout,
+            server.config.max_append_entries_timeout,
+        ) {
+            info!(
+                "Server {} got no answer from {}, sending its entries again.",
+                server.id, peer_id
+            );
+            server.progress.get_mut(&peer_id).unwrap().retry();
+        }
+
+        loop {
+            let progress = &server.progress[&peer_id];
+
+            // A follower with every entry only gets an empty request to
+            // tell it what has been committed since.
+            if progress.match_index >= last_log_index
+                && (progress.inflight() > 0 || progress.commit_index_sent >= server.commit_index)
+            {
+                break;
+            }
+
+            let next_index = progress.next_index;
+            if next_index > last_log_index && progress.inflight() > 0 {
+                break;
+            }
+
+            let inflight_entries = progress.inflight_entries();
+            let inflight_bytes = progress.inflight_bytes();
+
+            if !progress.can_send(max_inflight)
+                || inflight_entries >= max_inflight_entries
+                || inflight_bytes >= max_inflight_bytes
+            {
+                set_paused(server, &peer_id, true);
+                break;
+            }
+
+            // Take as many entries as the limits allow. An entry larger than
+            // the byte limit still goes out once nothing else is in flight,
+            // otherwise it would never be sent.
+            let prev_log_index = next_index - 1;
+            let max_entries = max_entries_per_append.min(max_inflight_entries - inflight_entries);
+            let deficit = progress.catch_up_deficit;
+            let mut last_index = prev_log_index;
+            let mut bytes = 0;
+            let mut out_of_budget = false;
+
+            while last_index < last_log_index
+                && ((last_index - prev_log_index) as usize) < max_entries
+            {
+                let size = server.log.payload_size_at(last_index + 1).unwrap_or(0);
+
+                if inflight_bytes + bytes + size > max_inflight_bytes
+                    && (last_index > prev_log_index || inflight_bytes > 0)
+                {
+                    break;
+                }
+
+                if catching_up && bytes + size > deficit {
+                    out_of_budget = true;
+                    break;
+                }
+
+                bytes += size;
+                last_index += 1;
+            }
+
+            if last_index == prev_log_index && next_index <= last_log_index {
+                if !out_of_budget {
+                    set_paused(server, &peer_id, true);
+                }
+                break;
+            }
+
+            let mut entries = server.log.entries(prev_log_index + 1, last_index);
+            if entries.len() as u64 != last_index - prev_log_index {
+                info!(
+                    "Server {} could not read entries {} to {} for {}",
+                    server.id,
+                    prev_log_index + 1,
+                    last_index,
+                    peer_id
+                );
+                break;
+            }
+            let number_of_entries = entries.len();
+            if server
+                .membership()
+                .is_some_and(|(_, m)| m.is_witness(&peer_id))
+            {
+                entries = entries.into_iter().map(LogEntry::without_payload).collect();
+            }
+
+            requests.push((
+                peer_id.to_string(),
+                AppendEntriesRequest {
+                    term: server.term,
+                    leader_id: server.id.to_string(),
+                    prev_log_index: prev_log_index,
+                    prev_log_term: server.term_at(prev_log_index).unwrap_or_default(),
+                    entries: entries,
+                    leader_commit: server.commit_index,
+                },
+            ));
+
+            let commit_index = server.commit_index;
+            let progress = server.progress.get_mut(&peer_id).unwrap();
+            progress.sent(last_index, number_of_entries, bytes, now);
+            progress.commit_index_sent = commit_index;
+            if catching_up {
+                progress.caught_up_with(bytes, now);
+            }
+            set_paused(server, &peer_id, false);
+        }
+    }
+
+    requests
+}
+
+fn set_paused(server: &mut Server, peer_id: &str, paused: bool) {
+    let changed = server.progress.get_mut(peer_id).unwrap().set_paused(paused);
+
+    if changed && paused {
+        info!(
+            "Server {} pausing replication to {}, too much data in flight.",
+            server.id, peer_id
+        );
+    } else if changed {
+        info!("Server {} resuming replication to {}.", server.id, peer_id);
+    }
+}
+
+fn handle_append_entries_response(server: &mut Server, response: AppendEntriesResponse) {
+    if response.term > server.term {
+        step_down(server, &response.peer_id, response.term);
+        return;
+    }
+
+    if server.state != State::LEADER {
+        return;
+    }
+
+    let next_index = next_index_after_conflict(server, &response);
+
+    let now = server.now();
+    let current_term = response.term == server.term;
+    match server.progress.get_mut(&response.peer_id) {
+        Some(progress) => {
+            if current_term {
+                progress.last_contact = Some(now);
+            }
+            progress.last_applied = response.last_applied;
+            progress.election_priority = response.election_priority;
+            if response.success {
+                progress.acknowledged(response.match_index);
+            } else {
+                progress.rejected(next_index);
+            }
+        }
+        None => return,
+    }
+
+    advance_commit_index(server);
+    promote_caught_up_learner(server, &response.peer_id);
+}
+
+/// Moves a leadership transfer on: the target is told to stand once it
+/// has every entry, and the transfer is given up once its deadline passed.
+/// A target that could not be told is told again in the next round.
+fn advance_transfer(server: Arc<Mutex<Server>>, rpc_client: &impl RpcClient) {
+    let (target, request) = {
+        let mut tmp_server = lock_server(&server);
+        let transfer = match &tmp_server.transfer {
+            Some(transfer) if tmp_server.state == State::LEADER => transfer.clone(),
+            _ => return,
+        };
+
+        if tmp_server.now() > transfer.deadline {
+            info!(
+                "Server {} gave up handing leadership over to {}.",
+                tmp_server.id, transfer.target
+            );
+            tmp_server.transfer = None;
+            return;
+        }
+
+        let last_log_index = tmp_server.last_log_index();
+        let caught_up = tmp_server
+            .progress
+            .get(&transfer.target)
+            .is_some_and(|p| p.match_index == last_log_index);
+        if transfer.timeout_now_sent || !caught_up {
+            return;
+        }
+
+        if let Some(transfer) = tmp_server.transfer.as_mut() {
+            transfer.timeout_now_sent = true;
+        }
+        let request = TimeoutNowRequest {
+            term: tmp_server.term,
+            leader_id: tmp_server.id.to_string(),
+        };
+        (transfer.target, request)
+    };
+    let term = request.term;
+
+    match rpc_client.send_timeout_now(&target, request) {
+        Ok(response) => {
+            let mut tmp_server = lock_server(&server);
+            if response.term > tmp_server.term {
+                step_down(&mut tmp_server, &response.peer_id, response.term);
+            }
+        }
+        Err(e) => {
+            info!("TimeoutNow to {} got no response: {:?}", target, e);
+            let mut tmp_server = lock_server(&server);
+            let still_leading = tmp_server.term == term;
+            if let Some(transfer) = tmp_server.transfer.as_mut().filter(|_| still_leading) {
+                transfer.timeout_now_sent = false;
+            }
+        }
+    }
+}
+
+/// A peer answered with a higher term, so someone else was elected since:
+/// this server goes back to being a follower in that term.
+fn step_down(server: &mut Server, peer_id: &str, term: Term) {
+    info!(
+        "Server {} stepping down, {} has a higher term {}",
+        server.id, peer_id, term
+    );
+
+    server.become_follower(term, None);
+}
+
+fn handle_heartbeat_responses(server: &Arc<Mutex<Server>>, responses: Vec<HeartbeatResponse>) {
+    {
+        let mut tmp_server = lock_server(server);
+
+        let highest = responses.iter().max_by_key(|r| r.term);
+        if let Some(response) = highest.filter(|r| r.term > tmp_server.term) {
+            step_down(&mut tmp_server, &response.peer_id, response.term);
+        }
+
+        let now = tmp_server.now();
+        let term = tmp_server.term;
+        for response in responses.iter().filter(|r| r.success && r.term == term) {
+            if let Some(progress) = tmp_server.progress.get_mut(&response.peer_id) {
+                progress.last_contact = Some(now);
+            }
+        }
+    }
+
+    deliver_events(server);
+}
+
+/// CheckQuorum: a leader that no majority of the voters answered within an
+/// election timeout steps down. Cut off from them, it could not commit
+/// anything it accepts, and a new leader may well have been elected
+/// behind its back.
+fn check_quorum(server: &mut Server) {
+    if server.state != State::LEADER {
+        return;
+    }
+
+    // Without a configuration in the log the peers are not known.
+    let voters = match server.membership() {
+        Some((_, membership)) => membership.voters.clone(),
+        None => return,
+    };
+
+    let now = server.now();
+    let window = server.config.election_timeout_min;
+    let active = voters
+        .iter()
+        .filter(|p| {
+            p.id == server.id
+                || server
+                    .progress
+                    .get(&p.id)
+                    .and_then(|progress| progress.last_contact)
+                    .is_some_and(|t| now.saturating_duration_since(t) <= window)
+        })
+        .count();
+
+    if active >= quorum::majority(voters.len()) {
+        return;
+    }
+
+    info!(
+        "Server {} heard from {} of {} voters within {:?}, stepping down.",
+        server.id,
+        active,
+        voters.len(),
+        window
+    );
+
+    let term = server.term;
+    server.become_follower(term, None);
+}
+
+/// Priority elections: a leader hands over to the voter that outranks it
+/// the most among those holding every entry and heard from within
+/// `election_timeout_min`. Ties go to the lowest id.
+fn yield_to_higher_priority(server: &mut Server) {
+    if server.state != State::LEADER || server.transfer.is_some() {
+        return;
+    }
+
+    let voters = match server.membership() {
+        Some((_, membership)) => membership.voters.clone(),
+        None => return,
+    };
+
+    let now = server.now();
+    let window = server.config.election_timeout_min;
+    let last_log_index = server.last_log_index();
+    let target = voters
+        .iter()
+        .filter(|p| p.kind == NodeKind::Voter)
+        .filter_map(|p| server.progress.get(&p.id).map(|progress| (&p.id, progress)))
+        .filter(|(_, progress)| {
+            progress.election_priority > server.config.election_priority
+                && progress.match_index == last_log_index
+                && progress
+                    .last_contact
+                    .is_some_and(|t| now.saturating_duration_since(t) <= window)
+        })
+        .max_by(|(a_id, a), (b_id, b)| {
+            a.election_priority
+                .cmp(&b.election_priority)
+                .then_with(|| b_id.cmp(a_id))
+        })
+        .map(|(id, _)| id.to_string());
+
+    if let Some(target) = target {
+        info!(
+            "Server {} is outranked by {}, which is caught up.",
+            server.id, target
+        );
+        let _ = server.transfer_leadership(&target);
+    }
+}
+
+/// The highest index stored on a majority of the voters becomes committed,
+/// as long as it belongs to the current term. A witness stores none of the
+/// commands, but counts like any voter: it holds their terms and indexes,
+/// which is all a candidate's log is checked against.
+fn advance_commit_index(server: &mut Server) {
+    let mut match_indexes: Vec<u64> = server
+        .current_membership()
+        .voters
+        .iter()
+        .map(|p| match server.progress.get(&p.id) {
+            Some(progress) => progress.match_index,
+            None if p.id == server.id => server.log.durable_index(),
+            None => 0,
+        })
+        .collect();
+
+    let voters = server.voter_count();
+    match_indexes.resize(voters.max(match_indexes.len()), 0);
+    match_indexes.sort_unstable_by(|a, b| b.cmp(a));
+    let majority_index = match_indexes[quorum::majority(voters) - 1];
+
+    if majority_index > server.commit_index && server.term_at(majority_index) == Some(server.term) {
+        server.commit_index = majority_index;
+        server.emit(RaftEvent::CommitAdvanced {
+            term: server.term,
+            commit_index: majority_index,
+        });
+        commit_membership(server);
+    }
+}
+
+/// Once a configuration is committed the leader stops replicating to the
+/// servers it removed, and steps down if it removed itself.
+fn commit_membership(server: &mut Server) {
+    let membership = match server.membership() {
+        Some((index, membership)) if index <= server.commit_index => membership.clone(),
+        _ => return,
+    };
+
+    if membership.contains(&server.id) {
+        server.progress.retain(|id, _| membership.contains(id));
+    } else {
+        info!(
+            "Server {} is no longer part of the cluster, stepping down.",
+            server.id
+        );
+
+        let term = server.term;
+        server.become_follower(term, None);
+    }
+}
+
+fn promote_caught_up_learner(server: &mut Server, peer_id: &str) {
+    let is_learner = match server.membership() {
+        Some((_, membership)) => membership.learners.iter().any(|p| p.id == peer_id),
+        None => false,
+    };
+
+    if is_learner {
+        let match_index = server.progress[peer_id].match_index;
+        let _ = server.promote_learner(peer_id, match_index);
+    }
+}
+
+fn broadcast_heartbeat(server: Arc<Mutex<Server>>, rpc_client: &impl RpcClient) {
+    let heartbeat = {
+        let mut tmp_server = lock_server(&server);
+        let now = tmp_server.now();
+        let due = tmp_server.next_heartbeat.is_none_or(|t| t <= now);
+
+        if tmp_server.state != State::LEADER || !due {
+            return;
+        }
+
+        tmp_server.next_heartbeat = Some(now + tmp_server.config.heartbeat_interval);
+        tmp_server.metrics.counters.heartbeats_sent_total += 1;
+
+        LogEntry::Heartbeat {
+            term: tmp_server.term,
+            peer_id: tmp_server.id.to_string(),
+        }
+    };
+
+    let responses = rpc_client
+        .broadcast_log_entry(heartbeat)
+        .unwrap_or_else(|e| no_responses("Heartbeat", e));
+    handle_heartbeat_responses(&server, responses);
+}
+
+/// How an election this server stood in ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ElectionOutcome {
+    /// It did not stand: it leads already, a majority would not vote for
+    /// it, it heard from a leader meanwhile, or it could not persist its
+    /// term.
+    NotStood,
+    Won,
+    /// Someone else leads, or is about to: a heartbeat of the term came in
+    /// meanwhile, or a voter was in a later term.
+    SteppedDown,
+    /// Nobody got a majority. The server stays a candidate and stands
+    /// again, in the next term, once a fresh random timeout, backed off by
+    /// `ServerConfig::split_vote_backoff`, expires.
+    Split,
+}
+
+/// The election loop of a follower or candidate whose timeout expired:
+///
+/// - it stands for election in the next term, see `new_election`;
+/// - after a split vote it stays a candidate, and stands again in the term
+///   after once the fresh random timeout drawn by `count_votes` expires, so
+///   that candidates that collided are unlikely to collide again. That
+///   timeout grows with every split vote in a row, as
+///   `ServerConfig::split_vote_backoff` says;
+/// - a heartbeat of the leader of the term, or a vote request of a later
+///   one, before that timeout expires makes it a follower and ends the loop.
+fn handle_timeout(server: Arc<Mutex<Server>>, rpc_client: &impl RpcClient, shutdown: &AtomicBool) {
+    let server_id = {
+        let mut tmp_server = lock_server(&server);
+        if !(tmp_server.timeout_now || tmp_server.has_timed_out()) {
+            return;
+        }
+        // Learners and witnesses never stand. They look again after
+        // another timeout, rather than right away over and over, and no
+        // longer hold off pre-votes for the leader they lost.
+        if !tmp_server.can_lead() {
+            tmp_server.current_leader = None;
+            tmp_server.refresh_timeout();
+            return;
+        }
+        tmp_server.id.to_string()
+    };
+    info!("Server {} has timed out.", server_id);
+
+    while new_election(Arc::clone(&server), rpc_client) == ElectionOutcome::Split {
+        if !wait_for_next_election(&server, shutdown) {
+            return;
+        }
+        info!("Server {} stands again after a split vote.", server_id);
+    }
+}
+
+/// Sleeps until the timeout of a candidate expires, and tells whether it
+/// should stand again: not if it stopped being a candidate meanwhile, or
+/// the server is shutting down.
+fn wait_for_next_election(server: &Arc<Mutex<Server>>, shutdown: &AtomicBool) -> bool {
+    let mut tmp_server = lock_server(server);
+
+    loop {
+        if shutdown.load(Ordering::SeqCst) || tmp_server.state != State::CANDIDATE {
+            return false;
+        }
+        if tmp_server.has_timed_out() {
+            return true;
+        }
+
+        let now = tmp_server.now();
+        let deadline = tmp_server.next_timeout.unwrap_or(now);
+        let wakeup = Arc::clone(&tmp_server.wakeup);
+        tmp_server = wakeup
+            .wait_timeout(tmp_server, deadline.saturating_duration_since(now))
+            .unwrap_or_else(PoisonError::into_inner)
+            .0;
+    }
+}
+
+fn new_election(server: Arc<Mutex<Server>>, rpc_client: &impl RpcClient) -> ElectionOutcome {
+    // A leadership transfer needs no pre-vote, the leader handed over.
+    let transfer = {
+        let mut tmp_server = lock_server(&server);
+        let transfer = tmp_server.timeout_now;
+        tmp_server.timeout_now = false;
+        transfer.then(|| tmp_server.term.increment())
+    };
+    let request = match transfer {
+        Some(term) => prepare_vote_request(&server, term, true),
+        None => match win_pre_vote(&server, rpc_client) {
+            Some(term) => prepare_vote_request(&server, term, false),
+            None => None,
+        },
+    };
+    let request = match request {
+        Some(request) => request,
+        None => return ElectionOutcome::NotStood,
+    };
+    let term = request.term;
+
+    info!(
+        "Server {}, with term {}, started the election process.",
+        request.candidate_id, term
+    );
+
+    let (tally, started_at, stepped_down) = {
+        let tmp_server = lock_server(&server);
+        (
+            vote_tally(&tmp_server, term),
+            tmp_server.now(),
+            tmp_server.stepped_down.clone(),
+        )
+    };
+    // Once a leader was heard from, the votes still out do not matter.
+    let responses = rpc_client
+        .request_vote_until(request, &|responses| {
+            stepped_down.is_cancelled() || record_votes(&tally, responses).is_decided()
+        })
+        .unwrap_or_else(|e| no_responses("Vote request", e));
+    let outcome = count_votes(
+        &mut lock_server(&server),
+        &record_votes(&tally, &responses),
+        started_at,
+    );
+
+    // The lock was let go in between: a leader may have been heard from.
+    if outcome == ElectionOutcome::Won && !become_leader(Arc::clone(&server), term, rpc_client) {
+        return ElectionOutcome::SteppedDown;
+    }
+
+    outcome
+}
+
+/// The tally of the election the server stands in, in `term`, among the
+/// voters of its current membership.
+fn vote_tally(server: &Server, term: Term) -> VoteTally {
+    VoteTally::of(&server.id, term, &server.current_membership())
+}
+
+/// `tally` with the votes the peers sent back so far.
+fn record_votes(tally: &VoteTally, responses: &[VoteResponse]) -> VoteTally {
+    let mut tally = tally.clone();
+    for response in responses {
+        tally.record(&response.voter_id, response.term, response.vote_granted);
+    }
+    tally
+}
+
+/// Decides the election from its tally, and keeps a record of it for
+/// `Server::last_election`.
+fn count_votes(server: &mut Server, tally: &VoteTally, started_at: Instant) -> ElectionOutcome {
+    let term = tally.term();
+    let record = |server: &mut Server, outcome: ElectionResult| {
+        server.record_election(ElectionRecord {
+            term: term,
+            started_at: started_at,
+            votes_granted_from: tally.granted_from(),
+            outcome: outcome,
+        })
+    };
+
+    // A voter in a later term means this election is already over.
+    if let Some(higher_term) = tally.higher_term().filter(|t| *t > server.term) {
+        step_down(server, "a voter", higher_term);
+        record(
+            server,
+            ElectionResult::Abandoned(AbandonReason::HigherTerm(higher_term)),
+        );
+        return ElectionOutcome::SteppedDown;
+    }
+
+    if server.state != State::CANDIDATE || server.term != term {
+        record(
+            server,
+            ElectionResult::Abandoned(AbandonReason::SteppedDown),
+        );
+        return ElectionOutcome::SteppedDown;
+    }
+
+    if tally.has_quorum() && !server.has_timed_out() {
+        record(server, ElectionResult::Won);
+        return ElectionOutcome::Won;
+    }
+
+    // Votes still missing might have made a majority, had they come in
+    // before the timeout.
+    if server.has_timed_out() && !tally.is_lost() {
+        record(server, ElectionResult::Abandoned(AbandonReason::TimedOut));
+    } else {
+        record(server, ElectionResult::Lost);
+    }
+
+    // Counted from now rather than from when the election started, so
+    // that a slow election does not leave the next one due right away.
+    server.refresh_timeout_after_split_vote();
+    info!(
+        "Server {} got {} votes, no majority in term {}, standing again in {:?}.",
+        server.id,
+        tally.granted(),
+        term,
+        server.next_timeout.map(|t| t - server.now())
+    );
+
+    ElectionOutcome::Split
+}
+
+/// Whether the votes so far settle a round among `voters` for a candidate
+/// in `term`, so that the peers yet to answer need not be waited for: the
+/// granted ones make a majority with the candidate's own vote, or so many
+/// refused that no majority is left, or one is in a later term already.
+fn votes_are_decided<'a>(
+    voters: usize,
+    term: Term,
+    votes: impl Iterator<Item = (Term, bool, &'a String)>,
+) -> bool {
+    let mut granted = HashSet::new();
+    let mut refused = HashSet::new();
+    for (voter_term, vote_granted, voter_id) in votes {
+        if voter_term > term {
+            return true;
+        }
+        if vote_granted {
+            granted.insert(voter_id);
+        } else {
+            refused.insert(voter_id);
+        }
+    }
+
+    let majority = quorum::majority(voters);
+    granted.len() + 1 >= majority || refused.len() + majority > voters
+}
+
+/// Asks the peers whether they would vote for this server in the next
+/// term, before it moves to that term: a server cut off from the others
+/// would otherwise drive its term up with every election it cannot win,
+/// and depose a healthy leader with it once it is back. Returns the term
+/// it may stand in; a lost pre-vote waits for another timeout, still in
+/// the current term.
+fn win_pre_vote(server: &Arc<Mutex<Server>>, rpc_client: &impl RpcClient) -> Option<Term> {
+    let (request, voters, current_term) = {
+        let mut tmp_server = lock_server(server);
+        if tmp_server.state == State::LEADER {
+            return None;
+        }
+
+        // It timed out on its leader, so it no longer holds back the
+        // pre-votes of others.
+        tmp_server.current_leader = None;
+
+        let last_log_index = tmp_server.last_log_index();
+        let request = PreVoteRequest {
+            term: tmp_server.term.increment(),
+            candidate_id: tmp_server.id.to_string(),
+            last_log_index: last_log_index,
+            last_log_term: tmp_server.term_at(last_log_index).unwrap_or_default(),
+        };
+        (request, tmp_server.voter_count(), tmp_server.term)
+    };
+    let term = request.term;
+
+    // Voters answer with their own term, below `term` if they granted.
+    let responses = rpc_client
+        .request_pre_vote_until(request, &|responses| {
+            let votes = responses
+                .iter()
+                .map(|r| (r.term, r.vote_granted, &r.voter_id));
+            votes_are_decided(voters, current_term, votes)
+        })
+        .unwrap_or_else(|e| no_responses("Pre-vote request", e));
+
+    let mut tmp_server = lock_server(server);
+
+    // Only a voter that refused can be in a later term: the one it learnt
+    // from a leader this server missed.
+    let highest = responses.iter().map(|r| r.term).max().unwrap_or_default();
+    if highest > tmp_server.term {
+        step_down(&mut tmp_server, "a voter", highest);
+        return None;
+    }
+
+    // Also lost if the term moved on meanwhile, a leader may be known now.
+    if tmp_server.term.increment() == term && has_won_the_pre_vote(&tmp_server, responses) {
+        return Some(term);
+    }
+
+    tmp_server.refresh_timeout();
+    info!(
+        "Server {} would not win an election in term {}, staying in term {}.",
+        tmp_server.id, term, tmp_server.term
+    );
+    None
+}
+
+/// Whether the granted pre-votes, with the server's own, make a quorum
+/// of its current membership.
+fn has_won_the_pre_vote(server: &Server, responses: Vec<PreVoteResponse>) -> bool {
+    let voters: HashSet<String> = responses
+        .into_iter()
+        .filter(|r| r.vote_granted)
+        .map(|r| r.voter_id)
+        .chain(std::iter::once(server.id.to_string()))
+        .collect();
+
+    quorum::is_quorum(&server.current_membership(), &voters)
+}
+
+/// A request no peer answered is as good as one every peer ignored.
+fn no_responses<T>(request: &str, error: RpcError) -> Vec<T> {
+    info!("{} got no response: {:?}", request, error);
+    Vec::new()
+}
+
+/// Moves the server to `term`, the one it won the pre-vote for, as a
+/// candidate that voted for itself. All under one lock, so that nothing
+/// slips in between deciding to stand and standing: not if it leads
+/// already, nor if it heard from a leader or of a later term since. In a
+/// `leadership_transfer`, hearing from the leader that handed over is
+/// expected.
+fn prepare_vote_request(
+    server: &Arc<Mutex<Server>>,
+    term: Term,
+    leadership_transfer: bool,
+) -> Option<VoteRequest> {
+    let mut tmp_server = lock_server(server);
+
+    if !tmp_server.can_lead() {
+        info!(
+            "Server {} may not lead, not standing in term {}.",
+            tmp_server.id, term
+        );
+        return None;
+    }
+
+    if tmp_server.state == State::LEADER
+        || (tmp_server.current_leader.is_some() && !leadership_transfer)
+        || tmp_server.term.increment() != term
+    {
+        info!(
+            "Server {} heard from a leader since its pre-vote, not standing in term {}.",
+            tmp_server.id, term
+        );
+        return None;
+    }
+
+    if let Err(e) = tmp_server.become_candidate() {
+        info!(
+            "Server {} could not persist its term {}, not standing for election: {}",
+            tmp_server.id, term, e
+        );
+        return None;
+    }
+
+    let last_log_index = tmp_server.last_log_index();
+    Some(VoteRequest {
+        term: term,
+        candidate_id: tmp_server.id.to_string(),
+        last_log_index: last_log_index,
+        last_log_term: tmp_server.term_at(last_log_index).unwrap_or_default(),
+        leadership_transfer: leadership_transfer,
+        election_priority: tmp_server.config.election_priority,
+    })
+}
+
+/// Makes the candidate that won the election of `term` its leader, and
+/// announces it. Returns false, and leaves the server as it is, if it is
+/// no longer that candidate: another leader was heard from meanwhile, and
+/// two leaders of one term must never be.
+fn become_leader(server: Arc<Mutex<Server>>, term: Term, rpc_client: &impl RpcClient) -> bool {
+    // Not broadcast under the lock: an in-process peer handles it on this
+    // thread, and might be waiting for this server itself.
+    let log_entry = {
+        let mut server = lock_server(&server);
+
+        if server.state != State::CANDIDATE || server.term != term {
+            info!(
+                "Server {} won the election of term {}, but is now {:?} in term {}.",
+                server.id, term, server.state, server.term
+            );
+            if let Some(mut record) = server.last_election().filter(|r| r.term == term).cloned() {
+                record.outcome = ElectionResult::Abandoned(AbandonReason::SteppedDown);
+                server.record_election(record);
+            }
+            return false;
+        }
+
+        server.become_leader();
+        if server.state != State::LEADER {
+            return false;
+        }
+        server.metrics.counters.heartbeats_sent_total += 1;
+
+        LogEntry::Heartbeat {
+            term: server.term,
+            peer_id: server.id.to_string(),
+        }
+    };
+
+    let responses = rpc_client
+        .broadcast_log_entry(log_entry)
+        .unwrap_or_else(|e| no_responses("Heartbeat", e));
+    handle_heartbeat_responses(&server, responses);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::build_info::BuildInfo;
+    use crate::raft::clock::ManualClock;
+    use crate::raft::counter::{Counter, CounterCommand};
+    use crate::raft::events::Observer;
+    use crate::raft::memory_rpc::{Faults, MemoryNetwork};
+    use crate::raft::metrics::RaftMetrics;
+    use crate::raft::retry::{Jitter, RetryPolicy};
+    use crate::raft::state_machine::{ApplyError, StateMachine};
+    use crate::raft::storage::FileStorage;
+    use crate::raft::testing::{Cluster, TestCluster};
+    use crate::raft::types::{
+        ApplyLagPolicy, ConfigError, Limit, Membership, Priority, ProposeError, ServerConfig,
+        TransferError,
+    };
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn raft_server_that_no_peer_answers_does_not_stand() {
+        let server = Arc::new(Mutex::new(build_server()));
+
+        // however often it times out, it cannot win the pre-vote
+        for _ in 0..3 {
+            assert_eq!(
+                new_election(Arc::clone(&server), &UnreachableRpc),
+                ElectionOutcome::NotStood
+            );
+        }
+
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::FOLLOWER);
+            assert_eq!(tmp_server.term, Term(0));
+            assert!(tmp_server.voted_for.is_none());
+            assert!(tmp_server.next_timeout.is_some());
+        }
+
+        // the next election, with peers back, is won in the very next term
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+        new_election(Arc::clone(&server), &rpc_client);
+        assert_eq!(server.lock().unwrap().state, State::LEADER);
+
+        // and a leader that cannot reach anyone keeps heartbeating
+        server.lock().unwrap().next_heartbeat = None;
+        broadcast_heartbeat(Arc::clone(&server), &UnreachableRpc);
+
+        let tmp_server = server.lock().unwrap();
+        assert_eq!(tmp_server.state, State::LEADER);
+        assert_eq!(tmp_server.term, Term(1));
+    }
+
+    #[test]
+    fn raft_new_election() {
+        // When the server gets the vote from its peers
+        let server = Arc::new(Mutex::new(build_server()));
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+
+        new_election(Arc::clone(&server), &rpc_client);
+
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::LEADER);
+            assert_eq!(tmp_server.term, Term(1));
+        }
+
+        // When the server does not get the vote from its peers
+        let server = Arc::new(Mutex::new(build_server()));
+        let rpc_client = FakeRpc {
+            granted_vote: false,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+
+        new_election(Arc::clone(&server), &rpc_client);
+
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::CANDIDATE);
+            assert_eq!(tmp_server.term, Term(1));
+        }
+
+        // When the server is alredy leader.
+        let server = Arc::new(Mutex::new(build_server()));
+        let rpc_client = FakeRpc {
+            granted_vote: false,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+
+        server.lock().unwrap().state = State::LEADER;
+        server.lock().unwrap().term = Term(10);
+
+        new_election(Arc::clone(&server), &rpc_client);
+
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::LEADER);
+            // term does not change
+            assert_eq!(tmp_server.term, Term(10));
+        }
+
+        // When the server times out again, it should not
+        // become leader even when getting votes.
+        let clock = ManualClock::new();
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().config.clock = Arc::new(clock.clone());
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(1, 1),
+            clock: Some(clock),
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+
+        server.lock().unwrap().start();
+
+        new_election(Arc::clone(&server), &rpc_client);
+
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::CANDIDATE);
+            assert_eq!(tmp_server.term, Term(1));
+        }
+    }
+
+    #[test]
+    fn raft_slow_peer_does_not_hold_up_the_election() {
+        let network = MemoryNetwork::new();
+        let mut tmp_server = build_server();
+        tmp_server.id = "server_2".to_string();
+        network.serve(Arc::new(Mutex::new(tmp_server)));
+        network.serve_unresponsive("server_3");
+
+        let rpc_client = network.client(
+            vec!["server_2".to_string(), "server_3".to_string()],
+            Duration::from_secs(5),
+        );
+        let candidate = build_server();
+        candidate.peers().set(vec![
+            build_peer("server_2", 9091),
+            build_peer("server_3", 9092),
+        ]);
+        let candidate = Arc::new(Mutex::new(candidate));
+
+        // Its first heartbeat as a leader still waits for server_3, so the
+        // election is left to finish on its own thread.
+        let started = Instant::now();
+        let server = Arc::clone(&candidate);
+        thread::spawn(move || new_election(server, &rpc_client));
+
+        while candidate.lock().unwrap().state != State::LEADER {
+            assert!(started.elapsed() < Duration::from_secs(1));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(candidate.lock().unwrap().term, Term(1));
+    }
+
+    #[test]
+    fn raft_votes_are_decided() {
+        let vote = |term: Term, vote_granted: bool, voter_id: &str| VoteResponse {
+            term: term,
+            vote_granted: vote_granted,
+            voter_id: voter_id.to_string(),
+        };
+        let decided = |voters: usize, responses: &[VoteResponse]| {
+            let votes = responses
+                .iter()
+                .map(|r| (r.term, r.vote_granted, &r.voter_id));
+            votes_are_decided(voters, Term(2), votes)
+        };
+
+        assert!(!decided(5, &[]));
+        assert!(!decided(5, &[vote(Term(2), true, "a")]));
+        assert!(decided(
+            5,
+            &[vote(Term(2), true, "a"), vote(Term(2), true, "b")]
+        ));
+        // the same voter twice is one vote
+        assert!(!decided(
+            5,
+            &[vote(Term(2), true, "a"), vote(Term(2), true, "a")]
+        ));
+        assert!(!decided(
+            5,
+            &[vote(Term(2), false, "a"), vote(Term(2), false, "b")]
+        ));
+        assert!(decided(
+            5,
+            &[
+                vote(Term(2), false, "a"),
+                vote(Term(2), false, "b"),
+                vote(Term(2), false, "c")
+            ]
+        ));
+        assert!(decided(5, &[vote(Term(3), false, "a")]));
+        // alone, its own vote is the majority
+        assert!(decided(1, &[]));
+    }
+
+    #[test]
+    fn raft_leader_steps_down_on_higher_term_heartbeat_response() {
+        let network = MemoryNetwork::new();
+
+        let leader = Arc::new(Mutex::new(build_server()));
+        {
+            let mut tmp_server = leader.lock().unwrap();
+            tmp_server.term = Term(1);
+            tmp_server.state = State::CANDIDATE;
+            tmp_server.become_leader();
+            tmp_server.next_heartbeat = None;
+        }
+
+        // elected in a later term while partitioned from the leader
+        let mut tmp_server = build_server();
+        tmp_server.id = "server_2".to_string();
+        tmp_server.term = Term(5);
+        network.serve(Arc::new(Mutex::new(tmp_server)));
+
+        let rpc_client = network.client(vec!["server_2".to_string()], Duration::from_secs(1));
+        broadcast_heartbeat(Arc::clone(&leader), &rpc_client);
+
+        let tmp_server = leader.lock().unwrap();
+        assert_eq!(tmp_server.state, State::FOLLOWER);
+        assert_eq!(tmp_server.term, Term(5));
+        assert!(tmp_server.voted_for.is_none());
+    }
+
+    #[test]
+    fn raft_follower_times_out_despite_a_deposed_leader() {
+        let network = MemoryNetwork::new();
+
+        let deposed = Arc::new(Mutex::new(build_server()));
+        {
+            let mut tmp_server = deposed.lock().unwrap();
+            tmp_server.term = Term(2);
+            tmp_server.state = State::CANDIDATE;
+            tmp_server.become_leader();
+            tmp_server.next_heartbeat = None;
+        }
+
+        let clock = ManualClock::new();
+        let follower = {
+            let mut tmp_server = build_server();
+            tmp_server.id = "server_2".to_string();
+            tmp_server.config.clock = Arc::new(clock.clone());
+            tmp_server.start();
+            tmp_server.term = Term(3);
+            Arc::new(Mutex::new(tmp_server))
+        };
+        network.serve(Arc::clone(&follower));
+        let deadline = follower.lock().unwrap().next_timeout;
+
+        let rpc_client = network.client(vec!["server_2".to_string()], Duration::from_secs(1));
+        broadcast_heartbeat(Arc::clone(&deposed), &rpc_client);
+
+        let response = handle_append_entries(
+            Arc::clone(&follower),
+            AppendEntriesRequest {
+                term: Term(2),
+                leader_id: "server_1".to_string(),
+                prev_log_index: 0,
+                prev_log_term: Term(0),
+                entries: Vec::new(),
+                leader_commit: 0,
+            },
+        );
+        assert!(!response.success);
+        assert_eq!(response.term, Term(3));
+
+        // the deposed leader learns the newer term and steps down
+        {
+            let tmp_server = deposed.lock().unwrap();
+            assert_eq!(tmp_server.state, State::FOLLOWER);
+            assert_eq!(tmp_server.term, Term(3));
+        }
+
+        // while the follower keeps its deadline, and times out on it
+        {
+            let mut tmp_server = follower.lock().unwrap();
+            assert_eq!(tmp_server.next_timeout, deadline);
+            assert!(tmp_server.current_leader.is_none());
+            assert!(!tmp_server.has_timed_out());
+        }
+        clock.advance(Duration::from_millis(1001));
+        assert!(follower.lock().unwrap().has_timed_out());
+    }
+
+    #[test]
+    fn raft_leader_without_a_quorum_steps_down() {
+        let clock = ManualClock::new();
+        let leader = Arc::new(Mutex::new(build_server()));
+        {
+            let mut tmp_server = leader.lock().unwrap();
+            tmp_server.config.clock = Arc::new(clock.clone());
+            tmp_server
+                .bootstrap(vec![
+                    build_peer("server_2", 9091),
+                    build_peer("server_3", 9092),
+                ])
+                .unwrap();
+            tmp_server.term = Term(1);
+            tmp_server.state = State::CANDIDATE;
+            tmp_server.become_leader();
+        }
+
+        let network = MemoryNetwork::new();
+        for id in ["server_2", "server_3"] {
+            let mut tmp_server = build_server();
+            tmp_server.id = id.to_string();
+            network.serve(Arc::new(Mutex::new(tmp_server)));
+        }
+        let rpc_client = network.client(
+            vec!["server_2".to_string(), "server_3".to_string()],
+            Duration::from_secs(1),
+        );
+
+        // as long as followers answer, it leads for as long as it likes
+        for _ in 0..5 {
+            clock.advance(Duration::from_millis(600));
+            heartbeat_and_check_quorum(&leader, &rpc_client);
+        }
+        assert_eq!(leader.lock().unwrap().state, State::LEADER);
+
+        // every answer is lost from now on, it holds on for one election
+        // timeout
+        clock.advance(Duration::from_millis(600));
+        heartbeat_and_check_quorum(&leader, &UnreachableRpc);
+        clock.advance(Duration::from_millis(400));
+        heartbeat_and_check_quorum(&leader, &UnreachableRpc);
+        assert_eq!(leader.lock().unwrap().state, State::LEADER);
+
+        clock.advance(Duration::from_millis(1));
+        heartbeat_and_check_quorum(&leader, &UnreachableRpc);
+
+        let tmp_server = leader.lock().unwrap();
+        assert_eq!(tmp_server.state, State::FOLLOWER);
+        assert_eq!(tmp_server.term, Term(1));
+        assert!(tmp_server.current_leader.is_none());
+        assert!(tmp_server.progress.is_empty());
+        assert!(tmp_server.next_timeout.is_some());
+    }
+
+    /// A round of the background task of a leader, as far as CheckQuorum
+    /// is concerned.
+    fn heartbeat_and_check_quorum(leader: &Arc<Mutex<Server>>, rpc_client: &impl RpcClient) {
+        leader.lock().unwrap().next_heartbeat = None;
+        broadcast_heartbeat(Arc::clone(leader), rpc_client);
+        check_quorum(&mut leader.lock().unwrap());
+    }
+
+    #[test]
+    fn raft_observer_sees_an_election() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observer = {
+            let events = Arc::clone(&events);
+            Observer::new(move |event| events.lock().unwrap().push(event))
+        };
+
+        let mut tmp_server = build_server();
+        tmp_server.config.observer = Some(observer.clone());
+        let candidate = Arc::new(Mutex::new(tmp_server));
+
+        let mut tmp_server = build_server();
+        tmp_server.id = "server_2".to_string();
+        tmp_server.config.observer = Some(observer);
+        let voter = Arc::new(Mutex::new(tmp_server));
+
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+
+        new_election(Arc::clone(&candidate), &rpc_client);
+        deliver_events(&candidate);
+
+        let vote_request = |candidate_id: &str| VoteRequest {
+            term: Term(1),
+            candidate_id: candidate_id.to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+        handle_vote_request(Arc::clone(&voter), vote_request("server_1"));
+        handle_vote_request(Arc::clone(&voter), vote_request("server_3"));
+
+        handle_log_entry(
+            Arc::clone(&candidate),
+            LogEntry::Heartbeat {
+                term: Term(2),
+                peer_id: "server_3".to_string(),
+            },
+        );
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                RaftEvent::BecameCandidate { term: Term(1) },
+                RaftEvent::BecameLeader { term: Term(1) },
+                RaftEvent::VoteGranted {
+                    term: Term(1),
+                    candidate_id: "server_1".to_string()
+                },
+                RaftEvent::VoteDenied {
+                    term: Term(1),
+                    candidate_id: "server_3".to_string()
+                },
+                RaftEvent::BecameFollower {
+                    term: Term(2),
+                    leader_id: Some("server_3".to_string())
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn raft_metrics_count_elections() {
+        let server = Arc::new(Mutex::new(build_server()));
+        let rpc_client = FakeRpc {
+            granted_vote: false,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+
+        new_election(Arc::clone(&server), &rpc_client);
+
+        let metrics = server.lock().unwrap().metrics();
+        assert_eq!(metrics.state, State::CANDIDATE);
+        assert_eq!(metrics.term, Term(1));
+        assert_eq!(metrics.leader_id, None);
+        assert_eq!(metrics.counters.elections_started_total, 1);
+        assert_eq!(metrics.counters.elections_won_total, 0);
+
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+
+        new_election(Arc::clone(&server), &rpc_client);
+
+        let metrics = server.lock().unwrap().metrics();
+        assert_eq!(metrics.state, State::LEADER);
+        assert_eq!(metrics.term, Term(2));
+        assert_eq!(metrics.leader_id, Some("server_1".to_string()));
+        assert_eq!(metrics.counters.elections_started_total, 2);
+        assert_eq!(metrics.counters.elections_won_total, 1);
+        assert_eq!(metrics.counters.heartbeats_sent_total, 1);
+        // one of the three can fail
+        assert_eq!(metrics.fault_tolerance, 1);
+
+        // What it runs travels with what it counted.
+        let bytes = bincode::serialize(&metrics).unwrap();
+        let metrics: RaftMetrics = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(metrics.build, BuildInfo::current());
+    }
+
+    #[test]
+    fn raft_trigger_snapshot() {
+        let data_dir =
+            std::env::temp_dir().join(format!("rsraft-core-snapshot-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        let mut tmp_server = build_server();
+        tmp_server.config.election_timeout_min = Duration::from_secs(60);
+        tmp_server.config.election_timeout_max = Duration::from_secs(60);
+        tmp_server.config.data_dir = Some(data_dir.clone());
+        tmp_server.state_machine = Some(Box::new(Counter::default()));
+        let server = Arc::new(Mutex::new(tmp_server));
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+        let handle = start_server(Arc::clone(&server), rpc_client).unwrap();
+
+        let apply = |count: u64| {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.state = State::LEADER;
+            for _ in 0..count {
+                tmp_server.propose(CounterCommand::Incr.encode()).unwrap();
+            }
+            tmp_server.commit_index = tmp_server.last_log_index();
+            tmp_server.apply_committed();
+        };
+
+        // with new applied entries
+        apply(3);
+        let metadata = handle.trigger_snapshot().unwrap();
+        assert_eq!(metadata.last_included_index, 3);
+        assert_eq!(metadata.last_included_term, Term(0));
+        assert!(metadata.size > 0);
+        assert!(data_dir.join("snapshot.bin").exists());
+
+        // without
+        assert_eq!(
+            handle.trigger_snapshot(),
+            Err(SnapshotError::NothingNewApplied { last_applied: 3 })
+        );
+
+        // while another one is being written
+        apply(1);
+        server.lock().unwrap().snapshots.begin(4).unwrap();
+        assert_eq!(handle.trigger_snapshot(), Err(SnapshotError::InProgress));
+
+        handle.shutdown();
+    }
+
+    /// Takes its time to capture a snapshot.
+    #[derive(Debug)]
+    struct SlowSnapshot(Duration);
+
+    impl StateMachine for SlowSnapshot {
+        fn apply(&mut self, _command: &[u8]) -> Result<Vec<u8>, ApplyError> {
+            Ok(Vec::new())
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            sleep(self.0);
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn raft_cancelled_snapshot_is_not_written() {
+        let data_dir = std::env::temp_dir().join(format!(
+            "rsraft-core-snapshot-cancel-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        let mut tmp_server = build_server();
+        tmp_server.config.data_dir = Some(data_dir.clone());
+        tmp_server.state_machine = Some(Box::new(SlowSnapshot(Duration::from_millis(200))));
+        let server = Arc::new(Mutex::new(tmp_server));
+
+        let cancel = CancelToken::new();
+        let snapshot = {
+            let server = Arc::clone(&server);
+            let cancel = cancel.clone();
+            thread::spawn(move || take_snapshot_cancellable(&server, &cancel))
+        };
+
+        // while the state machine is being captured
+        sleep(Duration::from_millis(50));
+        cancel.cancel();
+
+        assert_eq!(snapshot.join().unwrap(), Err(SnapshotError::Cancelled));
+        assert!(!data_dir.join("snapshot.bin").exists());
+        {
+            let tmp_server = server.lock().unwrap();
+            assert!(!tmp_server.snapshots.is_in_progress());
+            assert!(tmp_server.snapshots.last.is_none());
+        }
+
+        // a later one goes through
+        assert!(take_snapshot(&server).is_ok());
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn raft_cancelled_wait_for_applied_returns_promptly() {
+        let server = Arc::new(Mutex::new(build_server()));
+        let wait = |cancel: CancelToken| {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                let started = Instant::now();
+                let result =
+                    wait_for_applied_cancellable(&server, 5, Duration::from_secs(10), &cancel);
+                (result, started.elapsed())
+            })
+        };
+
+        let cancel = CancelToken::new();
+        let waiter = wait(cancel.clone());
+        sleep(Duration::from_millis(50));
+        cancel.cancel();
+
+        let (result, elapsed) = waiter.join().unwrap();
+        assert_eq!(result, Err(WaitError::Cancelled));
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+
+        // shutting the server down cancels every wait on it
+        let rpc_client = FakeRpc {
+            granted_vote: false,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+        let handle = start_server(Arc::clone(&server), rpc_client).unwrap();
+        let waiter = wait(CancelToken::new());
+        sleep(Duration::from_millis(50));
+        handle.shutdown();
+
+        let (result, elapsed) = waiter.join().unwrap();
+        assert_eq!(result, Err(WaitError::Cancelled));
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn raft_snapshot_when_threshold_reached() {
+        let data_dir = std::env::temp_dir().join(format!(
+            "rsraft-core-snapshot-threshold-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        let mut tmp_server = build_server();
+        tmp_server.config.election_timeout_min = Duration::from_secs(60);
+        tmp_server.config.election_timeout_max = Duration::from_secs(60);
+        tmp_server.config.data_dir = Some(data_dir.clone());
+        tmp_server.config.snapshot_threshold = 2;
+        tmp_server.state = State::LEADER;
+        for i in 0..3 {
+            tmp_server.propose(vec![i]).unwrap();
+        }
+        tmp_server.commit_index = 1;
+        let server = Arc::new(Mutex::new(tmp_server));
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+        let handle = start_server(Arc::clone(&server), rpc_client).unwrap();
+
+        // one entry applied is below the threshold
+        sleep(Duration::from_millis(100));
+        assert!(server.lock().unwrap().snapshots.last.is_none());
+
+        {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.commit_index = 3;
+            tmp_server.notify();
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while server.lock().unwrap().snapshots.last.is_none() && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+        }
+
+        let tmp_server = server.lock().unwrap();
+        assert_eq!(
+            tmp_server
+                .snapshots
+                .last
+                .as_ref()
+                .unwrap()
+                .last_included_index,
+            3
+        );
+        drop(tmp_server);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn raft_steady_heartbeats_keep_followers_from_timing_out() {
+        let cluster = Cluster::start(3, |_| Box::new(Counter::default()));
+        let leader = cluster.leader();
+        let (leader_id, term) = {
+            let tmp_server = leader.lock().unwrap();
+            (tmp_server.id.to_string(), tmp_server.term)
+        };
+        let elections_started = |cluster: &Cluster| -> u64 {
+            cluster
+                .servers()
+                .iter()
+                .map(|s| s.lock().unwrap().metrics.counters.elections_started_total)
+                .sum()
+        };
+        let started = elections_started(&cluster);
+
+        // Several election timeouts of every follower.
+        sleep(Duration::from_millis(1500));
+
+        assert_eq!(elections_started(&cluster), started);
+        for server in cluster.servers() {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.term, term);
+            assert_eq!(
+                tmp_server.state == State::LEADER,
+                tmp_server.id == leader_id
+            );
+            if tmp_server.state == State::FOLLOWER {
+                assert_eq!(tmp_server.current_leader.as_ref().unwrap().id, leader_id);
+            }
+        }
+
+        cluster.shutdown();
+    }
+
+    #[test]
+    fn raft_replication_resumes_after_follower_returns() {
+        let cluster = Cluster::start(3, |_| Box::new(Counter::default()));
+        let leader = cluster.leader();
+        let leader_id = leader.lock().unwrap().id.to_string();
+        let follower = cluster
+            .servers()
+            .into_iter()
+            .find(|s| s.lock().unwrap().id != leader_id)
+            .unwrap();
+        let follower_id = follower.lock().unwrap().id.to_string();
+
+        {
+            let mut tmp_server = leader.lock().unwrap();
+            tmp_server.config.append_entries_timeout = Duration::from_millis(100);
+            tmp_server.config.max_append_entries_timeout = Duration::from_secs(1);
+        }
+
+        cluster.disconnect(&follower_id);
+        for sequence in 1..=20 {
+            cluster.propose_in_session("client_1", sequence, CounterCommand::Incr.encode());
+        }
+        sleep(Duration::from_secs(10));
+        cluster.reconnect(&follower_id);
+
+        // nothing new is proposed, the leader sends what is missing again
+        let last_log_index = leader.lock().unwrap().last_log_index();
+        let commit_index = leader.lock().unwrap().commit_index;
+        assert_eq!(
+            wait_for_applied(&follower, commit_index, Duration::from_secs(5)),
+            Ok(())
+        );
+        assert_eq!(follower.lock().unwrap().last_log_index(), last_log_index);
+
+        cluster.shutdown();
+    }
+
+    #[test]
+    fn raft_vote_survives_restart() {
+        let data_dir =
+            std::env::temp_dir().join(format!("rsraft-core-vote-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        let start = |data_dir: &std::path::Path| {
+            let mut server = build_server();
+            server.config.data_dir = Some(data_dir.to_path_buf());
+            server.start();
+            Arc::new(Mutex::new(server))
+        };
+        let vote_request = |candidate_id: &str| VoteRequest {
+            term: Term(1),
+            candidate_id: candidate_id.to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+
+        let server = start(&data_dir);
+        assert!(handle_vote_request(Arc::clone(&server), vote_request("server_2")).vote_granted);
+
+        // the server crashes right after answering, and comes back
+        drop(server);
+        let server = start(&data_dir);
+        assert_eq!(
+            server.lock().unwrap().voted_for.as_ref().unwrap().id,
+            "server_2"
+        );
+        assert!(!handle_vote_request(Arc::clone(&server), vote_request("server_3")).vote_granted);
+    }
+
+    #[test]
+    fn raft_candidate_self_vote_survives_restart() {
+        let data_dir =
+            std::env::temp_dir().join(format!("rsraft-core-candidate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        let start = || {
+            let mut server = build_server();
+            server.config.data_dir = Some(data_dir.clone());
+            server.start();
+            server
+        };
+
+        // it crashes mid-election, having voted for itself
+        let mut server = start();
+        server.become_candidate().unwrap();
+        let term = server.term;
+        drop(server);
+
+        let server = Arc::new(Mutex::new(start()));
+        assert_eq!(server.lock().unwrap().term, term);
+        let response = handle_vote_request(
+            Arc::clone(&server),
+            VoteRequest {
+                term: term,
+                candidate_id: "server_2".to_string(),
+                last_log_index: 0,
+                last_log_term: Term(0),
+                leadership_transfer: false,
+                election_priority: 0,
+            },
+        );
+        assert!(!response.vote_granted);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[test]
+    fn raft_vote_denied_when_it_cannot_be_persisted() {
+        // a file where the data directory should be
+        let data_dir =
+            std::env::temp_dir().join(format!("rsraft-core-vote-file-{}", std::process::id()));
+        std::fs::write(&data_dir, b"").unwrap();
+
+        let mut tmp_server = build_server();
+        tmp_server.config.data_dir = Some(data_dir);
+        let server = Arc::new(Mutex::new(tmp_server));
+
+        let response = handle_vote_request(
+            Arc::clone(&server),
+            VoteRequest {
+                term: Term(1),
+                candidate_id: "server_2".to_string(),
+                last_log_index: 0,
+                last_log_term: Term(0),
+                leadership_transfer: false,
+                election_priority: 0,
+            },
+        );
+
+        assert!(!response.vote_granted);
+        assert!(server.lock().unwrap().voted_for.is_none());
+    }
+
+    #[test]
+    fn raft_counters_survive_restart() {
+        let data_dir =
+            std::env::temp_dir().join(format!("rsraft-core-counters-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        let start = |data_dir: &std::path::Path| {
+            let mut server = build_server();
+            server.config.data_dir = Some(data_dir.to_path_buf());
+            server.start();
+            Arc::new(Mutex::new(server))
+        };
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+
+        let server = start(&data_dir);
+        new_election(Arc::clone(&server), &rpc_client);
+        server.lock().unwrap().flush_metrics_if_due();
+
+        // the node comes back and keeps counting from where it was
+        let server = start(&data_dir);
+        {
+            let counters = &server.lock().unwrap().metrics.counters;
+            assert_eq!(counters.elections_started_total, 1);
+            assert_eq!(counters.elections_won_total, 1);
+            assert_eq!(counters.process_restarts_total, 1);
+        }
+
+        new_election(Arc::clone(&server), &rpc_client);
+        assert_eq!(
+            server
+                .lock()
+                .unwrap()
+                .metrics
+                .counters
+                .elections_started_total,
+            2
+        );
+
+        // a node with a fresh data directory starts from zero
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let server = start(&data_dir);
+        assert_eq!(server.lock().unwrap().metrics.counters, Default::default());
+    }
+
+    #[test]
+    fn raft_background_task_sleeps_until_due() {
+        // A follower sleeps until its election timeout...
+        let mut tmp_server = build_server();
+        tmp_server.config.election_timeout_min = Duration::from_millis(300);
+        tmp_server.config.election_timeout_max = Duration::from_millis(300);
+        tmp_server.start();
+        let server = Arc::new(Mutex::new(tmp_server));
+
+        let started = Instant::now();
+        wait_for_next_event(&server, &AtomicBool::new(false));
+        assert!(started.elapsed() >= Duration::from_millis(250));
+
+        // ...unless something happens in the meantime.
+        server.lock().unwrap().refresh_timeout();
+        let notifier = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                sleep(Duration::from_millis(50));
+                server.lock().unwrap().notify();
+            })
+        };
+
+        let started = Instant::now();
+        wait_for_next_event(&server, &AtomicBool::new(false));
+        assert!(started.elapsed() < Duration::from_millis(250));
+        notifier.join().unwrap();
+
+        // A leader with nothing to replicate sleeps until its next
+        // heartbeat.
+        {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.state = State::CANDIDATE;
+            tmp_server.become_leader();
+            tmp_server.config.heartbeat_interval = Duration::from_millis(200);
+
+            // the followers already have the no-op
+            let last_log_index = tmp_server.last_log_index();
+            for progress in tmp_server.progress.values_mut() {
+                progress.match_index = last_log_index;
+                progress.next_index = last_log_index + 1;
+            }
+        }
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+        broadcast_heartbeat(Arc::clone(&server), &rpc_client);
+
+        let started = Instant::now();
+        wait_for_next_event(&server, &AtomicBool::new(false));
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn raft_server_handle_shutdown() {
+        let data_dir =
+            std::env::temp_dir().join(format!("rsraft-core-shutdown-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        let mut tmp_server = build_server();
+        tmp_server.config.election_timeout_min = Duration::from_secs(60);
+        tmp_server.config.election_timeout_max = Duration::from_secs(60);
+        tmp_server.config.data_dir = Some(data_dir.clone());
+        let server = Arc::new(Mutex::new(tmp_server));
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+
+        let handle = start_server(Arc::clone(&server), rpc_client).unwrap();
+        sleep(Duration::from_millis(50));
+
+        // The background task is asleep until its (distant) timeout, and
+        // still stops right away.
+        let started = Instant::now();
+        handle.shutdown();
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        assert_eq!(Arc::strong_count(&server), 1);
+        assert!(data_dir.join("counters.bin").exists());
+    }
+
+    #[test]
+    fn raft_wait_for_applied() {
+        let mut tmp_server = build_server();
+        tmp_server.state = State::LEADER;
+        for i in 0..3 {
+            tmp_server.propose(vec![i]).unwrap();
+        }
+        tmp_server.commit_index = 1;
+        tmp_server.apply_committed();
+        let server = Arc::new(Mutex::new(tmp_server));
+
+        // already applied
+        let started = Instant::now();
+        assert_eq!(wait_for_applied(&server, 1, Duration::from_secs(5)), Ok(()));
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        // nothing gets committed
+        assert_eq!(
+            wait_for_applied(&server, 2, Duration::from_millis(100)),
+            Err(WaitError::Timeout { last_applied: 1 })
+        );
+
+        // applied while waiting
+        let applier = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                sleep(Duration::from_millis(50));
+                let mut tmp_server = server.lock().unwrap();
+                tmp_server.commit_index = 3;
+                tmp_server.apply_committed();
+            })
+        };
+
+        let started = Instant::now();
+        assert_eq!(wait_for_applied(&server, 3, Duration::from_secs(5)), Ok(()));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        applier.join().unwrap();
+    }
+
+    #[test]
+    fn raft_wait_for_applied_times_out_by_the_server_clock() {
+        let clock = ManualClock::new();
+        let mut tmp_server = build_server();
+        tmp_server.config.clock = Arc::new(clock.clone());
+        tmp_server.state = State::LEADER;
+        tmp_server.propose(vec![1]).unwrap();
+        let server = Arc::new(Mutex::new(tmp_server));
+
+        let waiter = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || wait_for_applied(&server, 1, Duration::from_secs(60)))
+        };
+
+        // A minute passes on the clock, and the waiter wakes up to see it.
+        let started = Instant::now();
+        sleep(Duration::from_millis(50));
+        clock.advance(Duration::from_secs(61));
+        lock_server(&server).applied.notify_all();
+
+        assert_eq!(
+            waiter.join().unwrap(),
+            Err(WaitError::Timeout { last_applied: 0 })
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn raft_wait_for_output() {
+        let mut tmp_server = build_server();
+        tmp_server.state = State::LEADER;
+        tmp_server.state_machine = Some(Box::new(Counter::default()));
+        tmp_server.config.max_retained_outputs = 2;
+        let server = Arc::new(Mutex::new(tmp_server));
+
+        // applied while waiting
+        let index = propose_command(&server, CounterCommand::Incr.encode()).unwrap();
+        let applier = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                sleep(Duration::from_millis(50));
+                let mut tmp_server = server.lock().unwrap();
+                tmp_server.commit_index = index;
+                tmp_server.apply_committed();
+            })
+        };
+        let output = wait_for_output(&server, index, Duration::from_secs(5)).unwrap();
+        assert_eq!(Counter::decode_value(&output), Some(1));
+        applier.join().unwrap();
+
+        // taken once
+        assert!(matches!(
+            wait_for_output(&server, index, Duration::from_secs(5)),
+            Err(RaftError::OutputUnavailable { index: 1 })
+        ));
+        assert!(matches!(
+            wait_for_output(&server, 2, Duration::from_millis(100)),
+            Err(RaftError::Timeout)
+        ));
+
+        // lost with the leadership: a new leader put another entry there
+        let index = propose_command(&server, CounterCommand::Incr.encode()).unwrap();
+        {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.log.truncate_from(index).unwrap();
+            tmp_server.term = Term(1);
+            tmp_server
+                .log
+                .append(LogEntry::Command {
+                    term: Term(1),
+                    data: CounterCommand::Decr.encode(),
+                })
+                .unwrap();
+            tmp_server.commit_index = index;
+            tmp_server.apply_committed();
+        }
+        assert!(matches!(
+            wait_for_output(&server, index, Duration::from_secs(5)),
+            Err(RaftError::NotLeader { .. })
+        ));
+
+        // only the latest outputs are kept
+        let indexes: Vec<u64> = (0..3)
+            .map(|_| propose_command(&server, CounterCommand::Incr.encode()).unwrap())
+            .collect();
+        {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.commit_index = indexes[2];
+            tmp_server.apply_committed();
+        }
+        assert!(matches!(
+            wait_for_output(&server, indexes[0], Duration::from_secs(5)),
+            Err(RaftError::OutputUnavailable { .. })
+        ));
+        for (index, value) in indexes[1..].iter().zip(2..) {
+            let output = wait_for_output(&server, *index, Duration::from_secs(5)).unwrap();
+            assert_eq!(Counter::decode_value(&output), Some(value));
+        }
+    }
+
+    #[test]
+    fn raft_proposal_answered_by_the_cluster() {
+        let cluster = Cluster::start(3, |_| Box::new(Counter::default()));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let output = loop {
+            let leader = cluster.leader();
+            let proposed = propose_command(&leader, CounterCommand::Incr.encode())
+                .and_then(|index| wait_for_output(&leader, index, Duration::from_secs(1)));
+            match proposed {
+                Ok(output) => break output,
+                Err(e) => assert!(Instant::now() < deadline, "not applied: {}", e),
+            }
+        };
+        assert_eq!(Counter::decode_value(&output), Some(1));
+
+        cluster.shutdown();
+    }
+
+    #[test]
+    fn raft_poisoned_lock_still_serves_requests() {
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().start();
+
+        // a handler panics while holding the lock
+        let handler = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                let _tmp_server = lock_server(&server);
+                panic!("the handler failed");
+            })
+        };
+        assert!(handler.join().is_err());
+        assert!(server.is_poisoned());
+
+        let vote_request = VoteRequest {
+            term: Term(1),
+            candidate_id: "server_2".to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+        assert!(handle_vote_request(Arc::clone(&server), vote_request).vote_granted);
+        assert!(!server.is_poisoned());
+
+        let log_entry = LogEntry::Heartbeat {
+            term: Term(1),
+            peer_id: "server_2".to_string(),
+        };
+        assert!(handle_log_entry(Arc::clone(&server), log_entry).success);
+        assert_eq!(
+            lock_server(&server)
+                .current_leader
+                .as_ref()
+                .map(|l| l.id.as_str()),
+            Some("server_2")
+        );
+    }
+
+    #[test]
+    fn raft_handle_log_entry() {
+        // When the heartbeat contains a higher term
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().term = Term(10);
+
+        let log_entry = LogEntry::Heartbeat {
+            term: Term(19),
+            peer_id: "server_3".to_string(),
+        };
+
+        server.lock().unwrap().start();
+
+        handle_log_entry(Arc::clone(&server), log_entry);
+
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::FOLLOWER);
+            assert_eq!(tmp_server.term, Term(19));
+            assert!(tmp_server.next_timeout.as_ref().unwrap() > &Instant::now());
+        }
+
+        // When the heartbeat contains a higher term
+        // and the current server is a Leader, then it
+        // becomes a follower.
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().state = State::LEADER;
+        server.lock().unwrap().term = Term(10);
+
+        let log_entry = LogEntry::Heartbeat {
+            term: Term(19),
+            peer_id: "server_3".to_string(),
+        };
+
+        server.lock().unwrap().start();
+
+        handle_log_entry(Arc::clone(&server), log_entry);
+
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::FOLLOWER);
+            assert_eq!(tmp_server.term, Term(19));
+            assert!(tmp_server.next_timeout.as_ref().unwrap() > &Instant::now());
+        }
+    }
+
+    #[test]
+    fn raft_candidate_steps_down_on_a_heartbeat_of_its_term() {
+        let server = Arc::new(Mutex::new(build_server()));
+        {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.start();
+            tmp_server.term = Term(5);
+            tmp_server.state = State::CANDIDATE;
+            tmp_server.voted_for = Some(Peer {
+                id: tmp_server.id.to_string(),
+                address: tmp_server.address,
+                kind: NodeKind::Voter,
+            });
+        }
+
+        let log_entry = LogEntry::Heartbeat {
+            term: Term(5),
+            peer_id: "server_3".to_string(),
+        };
+
+        assert_eq!(
+            handle_log_entry(Arc::clone(&server), log_entry).term,
+            Term(5)
+        );
+
+        let tmp_server = server.lock().unwrap();
+        assert_eq!(tmp_server.state, State::FOLLOWER);
+        assert_eq!(tmp_server.term, Term(5));
+        // still the vote it cast in this term
+        assert_eq!(tmp_server.voted_for.as_ref().unwrap().id, tmp_server.id);
+        assert_eq!(
+            tmp_server.current_leader.as_ref().map(|l| l.id.as_str()),
+            Some("server_3")
+        );
+    }
+
+    #[test]
+    fn raft_candidate_stands_again_until_a_heartbeat() {
+        let server = Arc::new(Mutex::new(build_server()));
+        {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.config.election_timeout_min = Duration::from_millis(20);
+            tmp_server.config.election_timeout_max = Duration::from_millis(40);
+            tmp_server.start();
+            tmp_server.next_timeout = Some(tmp_server.now());
+        }
+        let rpc_client = FakeRpc {
+            granted_vote: false,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+        let shutdown = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            let election =
+                scope.spawn(|| handle_timeout(Arc::clone(&server), &rpc_client, &shutdown));
+
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while server.lock().unwrap().term < Term(3) {
+                assert!(Instant::now() < deadline, "never stood again");
+                sleep(Duration::from_millis(5));
+            }
+
+            // the leader of whatever term the candidate is in by now
+            while !election.is_finished() {
+                {
+                    let mut tmp_server = server.lock().unwrap();
+                    let term = tmp_server.term;
+                    log_entry(
+                        &mut tmp_server,
+                        LogEntry::Heartbeat {
+                            term: term,
+                            peer_id: "server_3".to_string(),
+                        },
+                    );
+                }
+                sleep(Duration::from_millis(5));
+            }
+        });
+
+        let tmp_server = server.lock().unwrap();
+        assert_eq!(tmp_server.state, State::FOLLOWER);
+        assert_eq!(
+            tmp_server.current_leader.as_ref().map(|l| l.id.as_str()),
+            Some("server_3")
+        );
+        assert_eq!(
+            tmp_server.metrics.counters.elections_started_total,
+            tmp_server.term.0
+        );
+    }
+
+    #[test]
+    fn raft_split_votes_converge_thanks_to_the_jitter() {
+        let servers = run_two_candidates(
+            Duration::from_millis(150),
+            Duration::from_millis(300),
+            ServerConfig::default().split_vote_backoff,
+        );
+
+        let (leaders, followers): (Vec<_>, Vec<_>) = servers
+            .iter()
+            .map(|s| s.lock().unwrap())
+            .partition(|s| s.state == State::LEADER);
+        assert_eq!(leaders.len(), 1);
+        assert_eq!(followers.len(), 1);
+
+        // the first election, held by both at once, split
+        assert!(leaders[0].term >= Term(2));
+        assert_eq!(followers[0].state, State::FOLLOWER);
+        assert_eq!(followers[0].term, leaders[0].term);
+    }
+
+    #[test]
+    fn raft_split_votes_go_on_without_jitter() {
+        let servers = run_two_candidates(
+            Duration::from_millis(200),
+            Duration::from_millis(200),
+            RetryPolicy::exponential(Duration::from_millis(10), Duration::from_millis(50))
+                .with_jitter(Jitter::None),
+        );
+
+        for server in servers.iter() {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::CANDIDATE);
+            assert!(tmp_server.term > Term(10));
+        }
+    }
+
+    #[test]
+    fn raft_split_votes_back_off() {
+        let servers = run_two_candidates(
+            Duration::from_millis(200),
+            Duration::from_millis(200),
+            RetryPolicy::exponential(Duration::from_millis(100), Duration::from_millis(1600))
+                .with_jitter(Jitter::None),
+        );
+
+        // 10s are 50 elections without a backoff, but only 9 with one:
+        // the first, then after 300ms, 400ms, 600ms, 1s and every 1.8s
+        for server in servers.iter() {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::CANDIDATE);
+            assert_eq!(tmp_server.term, Term(9));
+        }
+    }
+
+    /// Two servers that time out at once at first, for up to 10s of their
+    /// clock or until one of them leads. Whoever times out within the same
+    /// 10ms stands at the same time, and votes for itself before the other
+    /// one's request reaches it.
+    fn run_two_candidates(
+        election_timeout_min: Duration,
+        election_timeout_max: Duration,
+        split_vote_backoff: RetryPolicy,
+    ) -> Vec<Arc<Mutex<Server>>> {
+        let clock = ManualClock::new();
+        let servers: Vec<Arc<Mutex<Server>>> = ["server_1", "server_2"]
+            .iter()
+            .map(|id| {
+                let config = ServerConfig {
+                    election_timeout_min: election_timeout_min,
+                    election_timeout_max: election_timeout_max,
+                    split_vote_backoff: split_vote_backoff.clone(),
+                    heartbeat_interval: Duration::from_millis(50),
+                    clock: Arc::new(clock.clone()),
+                    ..ServerConfig::default()
+                };
+                let (port, other_id, other_port) = if *id == "server_1" {
+                    (9091, "server_2", 9092)
+                } else {
+                    (9092, "server_1", 9091)
+                };
+                let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+                let other = Peer {
+                    id: other_id.to_string(),
+                    address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, other_port),
+                    kind: NodeKind::Voter,
+                };
+                let mut server = Server::new(config, vec![other], address, id.to_string()).unwrap();
+                server.start();
+                server.next_timeout = Some(server.now());
+                Arc::new(Mutex::new(server))
+            })
+            .collect();
+
+        for _ in 0..1000 {
+            clock.advance(Duration::from_millis(10));
+
+            let requests: Vec<(usize, VoteRequest)> = (0..servers.len())
+                .filter(|&i| servers[i].lock().unwrap().has_timed_out())
+                .filter_map(|i| {
+                    let term = servers[i].lock().unwrap().term.increment();
+                    prepare_vote_request(&servers[i], term, false).map(|r| (i, r))
+                })
+                .collect();
+
+            for (i, request) in requests {
+                let responses: Vec<VoteResponse> = servers
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, peer)| {
+                        handle_vote_request(
+                            Arc::clone(peer),
+                            VoteRequest {
+                                term: request.term,
+                                candidate_id: request.candidate_id.to_string(),
+                                last_log_index: request.last_log_index,
+                                last_log_term: request.last_log_term,
+                                leadership_transfer: request.leadership_transfer,
+                                election_priority: request.election_priority,
+                            },
+                        )
+                    })
+                    .collect();
+
+                let mut candidate = servers[i].lock().unwrap();
+                let tally = record_votes(&vote_tally(&candidate, request.term), &responses);
+                if count_votes(&mut candidate, &tally, Instant::now()) == ElectionOutcome::Won {
+                    candidate.become_leader();
+                }
+            }
+
+            if servers
+                .iter()
+                .any(|s| s.lock().unwrap().state == State::LEADER)
+            {
+                break;
+            }
+        }
+
+        servers
+    }
+
+    #[test]
+    fn raft_heartbeat_during_the_pre_vote_stops_the_election() {
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().term = Term(4);
+        let rpc_client = LeaderAppearsRpc {
+            server: Arc::clone(&server),
+            peers: create_peers(2),
+        };
+
+        assert_eq!(
+            new_election(Arc::clone(&server), &rpc_client),
+            ElectionOutcome::NotStood
+        );
+
+        let tmp_server = server.lock().unwrap();
+        assert_eq!(tmp_server.state, State::FOLLOWER);
+        assert_eq!(tmp_server.term, Term(4));
+        assert!(tmp_server.voted_for.is_none());
+        assert_eq!(tmp_server.metrics.counters.elections_started_total, 0);
+        assert_eq!(
+            tmp_server.current_leader.as_ref().map(|l| l.id.as_str()),
+            Some("server_3")
+        );
+    }
+
+    #[test]
+    fn raft_heartbeat_during_the_vote_stops_the_election() {
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().term = Term(4);
+        let rpc_client = NewLeaderRpc {
+            server: Arc::clone(&server),
+            peers: create_peers(2),
+            broadcasts: Cell::new(0),
+        };
+
+        assert_eq!(
+            new_election(Arc::clone(&server), &rpc_client),
+            ElectionOutcome::SteppedDown
+        );
+
+        let tmp_server = server.lock().unwrap();
+        assert_eq!(tmp_server.state, State::FOLLOWER);
+        assert_eq!(tmp_server.term, Term(6));
+        assert_eq!(tmp_server.metrics.counters.elections_won_total, 0);
+        assert_eq!(
+            tmp_server.current_leader.as_ref().map(|l| l.id.as_str()),
+            Some("server_3")
+        );
+        assert_eq!(rpc_client.broadcasts.get(), 0);
+    }
+
+    #[test]
+    fn raft_start_server_refuses_bad_peers() {
+        let start = |peers: Vec<Peer>| {
+            let server = build_server();
+            server.peers().set(peers);
+            let rpc_client = FakeRpc {
+                granted_vote: true,
+                sleeps_for: Duration::new(0, 0),
+                clock: None,
+                peers: create_peers(2),
+                voter_terms: Vec::new(),
+            };
+            match start_server(Arc::new(Mutex::new(server)), rpc_client) {
+                Err(RaftError::BadConfig(e)) => e,
+                Err(e) => panic!("{:?}", e),
+                Ok(_) => panic!("started"),
+            }
+        };
+
+        let peer = create_peers(1).remove(0);
+        assert_eq!(
+            start(vec![peer.clone(), peer.clone()]),
+            ConfigError::DuplicatePeer {
+                peer_id: peer.id.to_string()
+            }
+        );
+
+        let itself = Peer {
+            id: "server_1".to_string(),
+            ..peer
+        };
+        assert_eq!(
+            start(vec![itself.clone()]),
+            ConfigError::SelfAmongPeers {
+                peer_id: itself.id,
+                address: itself.address,
+            }
+        );
+    }
+
+    #[test]
+    fn raft_candidate_stops_waiting_for_votes_once_it_steps_down() {
+        let server = Arc::new(Mutex::new(build_server()));
+        {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.peers().set(create_peers(4));
+            tmp_server.term = Term(4);
+            // handed over to, so that it stands without a pre-vote
+            tmp_server.timeout_now = true;
+        }
+        let rpc_client = SlowVotersRpc {
+            server: Arc::clone(&server),
+            peers: create_peers(4),
+            answered: Cell::new(0),
+        };
+
+        assert_eq!(
+            new_election(Arc::clone(&server), &rpc_client),
+            ElectionOutcome::SteppedDown
+        );
+        // the refusals of the others were not waited for
+        assert_eq!(rpc_client.answered.get(), 1);
+
+        let mut tmp_server = server.lock().unwrap();
+        assert_eq!(tmp_server.state, State::FOLLOWER);
+        assert!(tmp_server.stepped_down.is_cancelled());
+
+        // standing again starts afresh
+        tmp_server.become_candidate().unwrap();
+        assert!(!tmp_server.stepped_down.is_cancelled());
+    }
+
+    #[test]
+    fn raft_candidate_turned_follower_does_not_become_leader() {
+        let server = Arc::new(Mutex::new(build_server()));
+        {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.term = Term(5);
+            tmp_server.state = State::FOLLOWER;
+        }
+        let rpc_client = NewLeaderRpc {
+            server: Arc::clone(&server),
+            peers: create_peers(2),
+            broadcasts: Cell::new(0),
+        };
+
+        assert!(!become_leader(Arc::clone(&server), Term(5), &rpc_client));
+        assert_eq!(server.lock().unwrap().state, State::FOLLOWER);
+
+        // nor does a candidate of a later term, on the votes of an earlier one
+        server.lock().unwrap().state = State::CANDIDATE;
+        assert!(!become_leader(Arc::clone(&server), Term(4), &rpc_client));
+        assert_eq!(server.lock().unwrap().state, State::CANDIDATE);
+
+        assert_eq!(rpc_client.broadcasts.get(), 0);
+
+        assert!(become_leader(Arc::clone(&server), Term(5), &rpc_client));
+        assert_eq!(server.lock().unwrap().state, State::LEADER);
+        assert_eq!(rpc_client.broadcasts.get(), 1);
+    }
+
+    #[test]
+    fn raft_stale_heartbeat_does_not_hold_back_the_election() {
+        let clock = ManualClock::new();
+        let server = Arc::new(Mutex::new(build_server()));
+        let deadline = {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.config.clock = Arc::new(clock.clone());
+            tmp_server.start();
+            tmp_server.term = Term(7);
+            tmp_server.next_timeout
+        };
+
+        let log_entry = LogEntry::Heartbeat {
+            term: Term(3),
+            peer_id: "server_3".to_string(),
+        };
+        let response = handle_log_entry(Arc::clone(&server), log_entry);
+
+        assert_eq!(response.term, Term(7));
+        assert!(!response.success);
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.next_timeout, deadline);
+            assert!(tmp_server.current_leader.is_none());
+            assert_eq!(tmp_server.metrics.counters.heartbeats_received_total, 0);
+        }
+
+        // at the original deadline, one election timeout after the start
+        clock.advance(Duration::from_millis(1001));
+        assert!(server.lock().unwrap().has_timed_out());
+    }
+
+    #[test]
+    fn raft_propose_command_on_a_follower_names_the_leader() {
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().bootstrap(create_peers(2)).unwrap();
+
+        let error = propose_command(&server, vec![1]).unwrap_err();
+        assert!(matches!(error, RaftError::NotLeader { leader: None }));
+
+        server.lock().unwrap().current_leader = Some(Leader {
+            id: "1".to_string(),
+        });
+        match propose_command(&server, vec![1]) {
+            Err(RaftError::NotLeader {
+                leader: Some(leader),
+            }) => assert_eq!(leader.id, "1"),
+            other => panic!("expected NotLeader, got {:?}", other),
+        }
+
+        // the leader itself takes it, or says why not
+        {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.state = State::LEADER;
+            tmp_server.config.max_entry_bytes = 1;
+        }
+        assert_eq!(propose_command(&server, vec![1]).unwrap(), 2);
+        assert!(matches!(
+            propose_command(&server, vec![1, 2]),
+            Err(RaftError::Rejected(ProposeError::EntryTooLarge {
+                size: 2,
+                max: 1
+            }))
+        ));
+    }
+
+    #[test]
+    fn raft_vote_denied_while_the_leader_is_heard() {
+        let clock = ManualClock::new();
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().config.clock = Arc::new(clock.clone());
+        server.lock().unwrap().start();
+
+        heartbeat_from("server_2", Term(1), &server);
+        clock.advance(Duration::from_millis(999));
+
+        let vote_request = |leadership_transfer: bool| VoteRequest {
+            candidate_id: "server_3".to_string(),
+            term: Term(2),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: leadership_transfer,
+            election_priority: 0,
+        };
+        let vote_response = handle_vote_request(Arc::clone(&server), vote_request(false));
+
+        assert!(!vote_response.vote_granted);
+        assert_eq!(vote_response.term, Term(1));
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.term, Term(1));
+            assert!(tmp_server.voted_for.is_none());
+            assert_eq!(tmp_server.current_leader.as_ref().unwrap().id, "server_2");
+        }
+
+        // unless the leader hands over to the candidate
+        let vote_response = handle_vote_request(Arc::clone(&server), vote_request(true));
+
+        assert!(vote_response.vote_granted);
+        assert_eq!(server.lock().unwrap().term, Term(2));
+    }
+
+    #[test]
+    fn raft_granting_a_vote_restarts_the_election_timeout() {
+        let clock = ManualClock::new();
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().config.clock = Arc::new(clock.clone());
+        server.lock().unwrap().start();
+        server.lock().unwrap().term = Term(1);
+
+        let vote_request = |candidate_id: &str| VoteRequest {
+            candidate_id: candidate_id.to_string(),
+            term: Term(1),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+
+        // just before it would stand itself
+        clock.advance(Duration::from_millis(900));
+        assert!(handle_vote_request(Arc::clone(&server), vote_request("server_2")).vote_granted);
+
+        clock.advance(Duration::from_millis(900));
+        assert!(!server.lock().unwrap().has_timed_out());
+
+        // a denied vote leaves the timeout alone
+        let next_timeout = server.lock().unwrap().next_timeout;
+        assert!(!handle_vote_request(Arc::clone(&server), vote_request("server_3")).vote_granted);
+        assert_eq!(server.lock().unwrap().next_timeout, next_timeout);
+
+        clock.advance(Duration::from_millis(101));
+        assert!(server.lock().unwrap().has_timed_out());
+    }
+
+    #[test]
+    fn raft_vote_denied_to_a_candidate_it_outranks() {
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().config.election_priority = 2;
+
+        let vote_request = |term: Term, last_log_term: Term, election_priority: u8| VoteRequest {
+            candidate_id: "server_3".to_string(),
+            term: term,
+            last_log_index: last_log_term.0,
+            last_log_term: last_log_term,
+            leadership_transfer: false,
+            election_priority: election_priority,
+        };
+
+        // it would rather lead, its log being as up to date
+        let vote_response =
+            handle_vote_request(Arc::clone(&server), vote_request(Term(1), Term(0), 1));
+        assert!(!vote_response.vote_granted);
+        assert_eq!(vote_response.term, Term(1));
+        assert!(server.lock().unwrap().voted_for.is_none());
+
+        // but not over a candidate with more of the log
+        assert!(
+            handle_vote_request(Arc::clone(&server), vote_request(Term(1), Term(1), 1))
+                .vote_granted
+        );
+
+        // nor over one that ranks as high
+        assert!(
+            handle_vote_request(Arc::clone(&server), vote_request(Term(2), Term(0), 2))
+                .vote_granted
+        );
+    }
+
+    #[test]
+    fn raft_vote_granted_after_the_leader_went_silent() {
+        let clock = ManualClock::new();
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().config.clock = Arc::new(clock.clone());
+        server.lock().unwrap().start();
+
+        heartbeat_from("server_2", Term(1), &server);
+        clock.advance(Duration::from_secs(1));
+
+        let vote_response = handle_vote_request(
+            Arc::clone(&server),
+            VoteRequest {
+                candidate_id: "server_3".to_string(),
+                term: Term(2),
+                last_log_index: 0,
+                last_log_term: Term(0),
+                leadership_transfer: false,
+                election_priority: 0,
+            },
+        );
+
+        assert!(vote_response.vote_granted);
+        assert_eq!(vote_response.term, Term(2));
+        assert_eq!(
+            server.lock().unwrap().voted_for.as_ref().unwrap().id,
+            "server_3"
+        );
+    }
+
+    fn heartbeat_from(leader_id: &str, term: Term, server: &Arc<Mutex<Server>>) {
+        let response = handle_log_entry(
+            Arc::clone(server),
+            LogEntry::Heartbeat {
+                term: term,
+                peer_id: leader_id.to_string(),
+            },
+        );
+        assert!(response.success);
+    }
+
+    #[test]
+    fn raft_handle_vote_request() {
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().start();
+
+        let candidate_id = "server_2";
+
+        let vote_request = VoteRequest {
+            candidate_id: candidate_id.to_string(),
+            term: Term(1),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+
+        let vote_response = handle_vote_request(Arc::clone(&server), vote_request);
+
+        assert!(vote_response.vote_granted);
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(vote_response.term, tmp_server.term);
+            assert_eq!(
+                tmp_server.voted_for.as_ref().unwrap().id,
+                candidate_id.to_string()
+            );
+        }
+
+        // Now the server has already voted for that term
+        let new_candidate_id = "server_3";
+
+        let vote_request = VoteRequest {
+            candidate_id: new_candidate_id.to_string(),
+            term: Term(1),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+
+        let vote_response = handle_vote_request(Arc::clone(&server), vote_request);
+
+        assert!(!vote_response.vote_granted);
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_ne!(
+                tmp_server.voted_for.as_ref().unwrap().id,
+                new_candidate_id.to_string()
+            );
+            assert_eq!(vote_response.term, tmp_server.term);
+        }
+
+        // When the server did not vote yet, but the candidate's term is older
+        // than the current server's.
+        server.lock().unwrap().voted_for = None;
+        server.lock().unwrap().term = Term(5);
+
+        let another_candidate_id = "server_4";
 
         let vote_request = VoteRequest {
+            candidate_id: another_candidate_id.to_string(),
+            term: Term(4),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+
+        let vote_response = handle_vote_request(Arc::clone(&server), vote_request);
+
+        {
+            let tmp_server = server.lock().unwrap();
+            assert!(tmp_server.voted_for.as_ref().is_none());
+            assert_eq!(tmp_server.term, Term(5));
+            assert_eq!(vote_response.term, Term(5));
+            assert!(!vote_response.vote_granted);
+        }
+    }
+
+    #[test]
+    fn raft_handle_pre_vote_request() {
+        let clock = ManualClock::new();
+        let server = Arc::new(Mutex::new(build_server()));
+        let deadline = {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.config.clock = Arc::new(clock.clone());
+            tmp_server.start();
+            tmp_server.next_timeout
+        };
+        let pre_vote_request = |term: Term| PreVoteRequest {
+            term: term,
+            candidate_id: "server_2".to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+        };
+
+        // granted, without the server changing anything
+        let response = handle_pre_vote_request(Arc::clone(&server), pre_vote_request(Term(1)));
+        assert!(response.vote_granted);
+        assert_eq!(response.term, Term(0));
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.term, Term(0));
+            assert!(tmp_server.voted_for.is_none());
+            assert_eq!(tmp_server.next_timeout, deadline);
+        }
+
+        // not while it hears from a leader
+        let log_entry = LogEntry::Heartbeat {
+            term: Term(3),
+            peer_id: "server_3".to_string(),
+        };
+        handle_log_entry(Arc::clone(&server), log_entry);
+        let response = handle_pre_vote_request(Arc::clone(&server), pre_vote_request(Term(4)));
+        assert!(!response.vote_granted);
+        assert_eq!(response.term, Term(3));
+
+        // but once it has timed out on it
+        clock.advance(Duration::from_millis(1001));
+        assert!(
+            handle_pre_vote_request(Arc::clone(&server), pre_vote_request(Term(4))).vote_granted
+        );
+
+        // and never in a term it is in already
+        assert!(
+            !handle_pre_vote_request(Arc::clone(&server), pre_vote_request(Term(3))).vote_granted
+        );
+
+        // nor to a candidate whose log is behind
+        server.lock().unwrap().log.append(command(Term(3))).unwrap();
+        assert!(
+            !handle_pre_vote_request(Arc::clone(&server), pre_vote_request(Term(4))).vote_granted
+        );
+
+        let tmp_server = server.lock().unwrap();
+        assert_eq!(tmp_server.term, Term(3));
+        assert!(tmp_server.voted_for.is_none());
+    }
+
+    #[test]
+    fn raft_leadership_transfer_lands_on_the_target() {
+        let cluster = Cluster::start(3, |_| Box::new(Counter::default()));
+        cluster.propose_in_session("client", 1, CounterCommand::Incr.encode());
+
+        let leader = cluster.leader();
+        let (leader_id, term) = {
+            let tmp_server = leader.lock().unwrap();
+            (tmp_server.id.to_string(), tmp_server.term)
+        };
+        let target = cluster
+            .servers()
+            .into_iter()
+            .find(|s| s.lock().unwrap().id != leader_id)
+            .unwrap();
+        let target_id = target.lock().unwrap().id.to_string();
+
+        {
+            let mut tmp_server = leader.lock().unwrap();
+            assert_eq!(tmp_server.transfer_leadership(&target_id), Ok(()));
+            assert_eq!(
+                tmp_server.propose(vec![1]),
+                Err(ProposeError::TransferringLeadership {
+                    target: target_id.to_string()
+                })
+            );
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while target.lock().unwrap().state != State::LEADER {
+            assert!(Instant::now() < deadline, "{} did not take over", target_id);
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(target.lock().unwrap().term, term.increment());
+        assert_eq!(leader.lock().unwrap().state, State::FOLLOWER);
+
+        // and it takes proposals
+        cluster.propose_in_session("client", 2, CounterCommand::Incr.encode());
+        assert_eq!(cluster.leader().lock().unwrap().id, target_id);
+
+        cluster.shutdown();
+    }
+
+    #[test]
+    fn raft_leadership_converges_on_the_highest_priority() {
+        let cluster = Cluster::start(5, |_| Box::new(Counter::default()));
+        let first_leader_id = cluster.leader().lock().unwrap().id.to_string();
+
+        // the first leader ranks second, two servers far away rank last
+        let ids: Vec<String> = cluster
+            .servers()
+            .iter()
+            .map(|s| s.lock().unwrap().id.to_string())
+            .filter(|id| *id != first_leader_id)
+            .collect();
+        let priorities = [(&first_leader_id, 1), (&ids[0], 3), (&ids[1], 1)];
+        for (id, priority) in priorities {
+            cluster.server(id).lock().unwrap().config.election_priority = priority;
+        }
+        // followers report their priority as they acknowledge entries
+        cluster.propose_in_session("client", 1, CounterCommand::Incr.encode());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while cluster.leader().lock().unwrap().id != ids[0] {
+            assert!(Instant::now() < deadline, "{} did not take over", ids[0]);
+            sleep(Duration::from_millis(10));
+        }
+
+        // and keeps leading
+        sleep(Duration::from_secs(1));
+        let leader = cluster.leader();
+        assert_eq!(leader.lock().unwrap().id, ids[0]);
+        assert!(leader.lock().unwrap().transfer.is_none());
+        cluster.propose_in_session("client", 2, CounterCommand::Incr.encode());
+
+        cluster.shutdown();
+    }
+
+    #[test]
+    fn raft_leadership_transfer_to_an_unreachable_target_is_given_up() {
+        let cluster = Cluster::start(3, |_| Box::new(Counter::default()));
+        let leader = cluster.leader();
+        let (leader_id, term) = {
+            let tmp_server = leader.lock().unwrap();
+            (tmp_server.id.to_string(), tmp_server.term)
+        };
+        let target_id = cluster
+            .servers()
+            .into_iter()
+            .map(|s| s.lock().unwrap().id.to_string())
+            .find(|id| *id != leader_id)
+            .unwrap();
+
+        cluster.disconnect(&target_id);
+        leader
+            .lock()
+            .unwrap()
+            .transfer_leadership(&target_id)
+            .unwrap();
+
+        // given up after election_timeout_max, the leader carries on
+        sleep(Duration::from_millis(800));
+        let mut tmp_server = leader.lock().unwrap();
+        assert_eq!(tmp_server.state, State::LEADER);
+        assert_eq!(tmp_server.term, term);
+        assert!(tmp_server.transfer.is_none());
+        assert!(tmp_server.propose(vec![1]).is_ok());
+        drop(tmp_server);
+
+        cluster.shutdown();
+    }
+
+    #[test]
+    fn raft_rejoining_server_does_not_depose_the_leader() {
+        let cluster = Cluster::start(3, |_| Box::new(Counter::default()));
+        let leader = cluster.leader();
+        let (leader_id, term) = {
+            let tmp_server = leader.lock().unwrap();
+            (tmp_server.id.to_string(), tmp_server.term)
+        };
+        let follower = cluster
+            .servers()
+            .into_iter()
+            .find(|s| s.lock().unwrap().id != leader_id)
+            .unwrap();
+        let follower_id = follower.lock().unwrap().id.to_string();
+        let elections = follower
+            .lock()
+            .unwrap()
+            .metrics
+            .counters
+            .elections_started_total;
+
+        // cut off for several election timeouts, it keeps asking whether
+        // it could win, and is told it could not
+        cluster.disconnect(&follower_id);
+        sleep(Duration::from_secs(3));
+        {
+            let tmp_server = follower.lock().unwrap();
+            assert_eq!(tmp_server.term, term);
+            assert_eq!(
+                tmp_server.metrics.counters.elections_started_total,
+                elections
+            );
+        }
+
+        cluster.reconnect(&follower_id);
+        sleep(Duration::from_secs(1));
+
+        let tmp_leader = leader.lock().unwrap();
+        assert_eq!(tmp_leader.state, State::LEADER);
+        assert_eq!(tmp_leader.term, term);
+        drop(tmp_leader);
+
+        let tmp_server = follower.lock().unwrap();
+        assert_eq!(tmp_server.state, State::FOLLOWER);
+        assert_eq!(
+            tmp_server.current_leader.as_ref().map(|l| l.id.as_str()),
+            Some(leader_id.as_str())
+        );
+        drop(tmp_server);
+
+        cluster.shutdown();
+    }
+
+    #[test]
+    fn raft_deposed_candidate_learns_the_current_term() {
+        let servers: Vec<Arc<Mutex<Server>>> = vec!["server_1", "server_2", "server_3"]
+            .into_iter()
+            .map(|id| {
+                let mut server = build_server();
+                server.id = id.to_string();
+                server.term = Term(5);
+                Arc::new(Mutex::new(server))
+            })
+            .collect();
+
+        // server_1 was cut off since it led in term 2.
+        servers[0].lock().unwrap().term = Term(2);
+
+        let rpc_client = LoopbackRpc::new(servers[1..].iter().map(Arc::clone).collect());
+        new_election(Arc::clone(&servers[0]), &rpc_client);
+
+        let tmp_server = servers[0].lock().unwrap();
+        assert_eq!(tmp_server.state, State::FOLLOWER);
+        assert_eq!(tmp_server.term, Term(5));
+        assert!(tmp_server.voted_for.is_none());
+    }
+
+    #[test]
+    fn raft_candidate_with_a_majority_steps_down_on_a_higher_term() {
+        let tmp_server = build_server();
+        tmp_server.peers().set(create_peers(4));
+        let server = Arc::new(Mutex::new(tmp_server));
+
+        // three of four peers grant the vote for term 1, which is a
+        // majority with the candidate's own, but one is already in term 7
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(4),
+            voter_terms: vec![1, 7, 1, 1],
+        };
+        new_election(Arc::clone(&server), &rpc_client);
+
+        let tmp_server = server.lock().unwrap();
+        assert_eq!(tmp_server.state, State::FOLLOWER);
+        assert_eq!(tmp_server.term, Term(7));
+        assert!(tmp_server.voted_for.is_none());
+        assert!(tmp_server.current_leader.is_none());
+    }
+
+    #[test]
+    fn raft_grants_of_an_earlier_election_do_not_count() {
+        let election = |voter_terms: Vec<u64>| {
+            let mut tmp_server = build_server();
+            tmp_server.peers().set(create_peers(4));
+            tmp_server.term = Term(5);
+            let server = Arc::new(Mutex::new(tmp_server));
+
+            let rpc_client = FakeRpc {
+                granted_vote: true,
+                sleeps_for: Duration::new(0, 0),
+                clock: None,
+                peers: create_peers(4),
+                voter_terms: voter_terms,
+            };
+            let outcome = new_election(Arc::clone(&server), &rpc_client);
+            let tmp_server = server.lock().unwrap();
+            (outcome, tmp_server.state, tmp_server.term)
+        };
+
+        // stragglers of the election in term 5 leave a single grant in
+        // term 6, two votes of five with the candidate's own
+        assert_eq!(
+            election(vec![5, 5, 5, 6]),
+            (ElectionOutcome::Split, State::CANDIDATE, Term(6))
+        );
+
+        assert_eq!(
+            election(vec![5, 5, 6, 6]),
+            (ElectionOutcome::Won, State::LEADER, Term(6))
+        );
+    }
+
+    #[test]
+    fn raft_vote_requires_an_up_to_date_log() {
+        // A voter whose log holds entries of terms 1, 1 and 2.
+        let voter = || {
+            let mut tmp_server = build_server();
+            tmp_server.term = Term(2);
+            for term in [1, 1, 2] {
+                tmp_server
+                    .log
+                    .append(LogEntry::Command {
+                        term: Term(term),
+                        data: Vec::new(),
+                    })
+                    .unwrap();
+            }
+            Arc::new(Mutex::new(tmp_server))
+        };
+        let vote_request = |last_log_index: u64, last_log_term: Term| VoteRequest {
+            candidate_id: "server_2".to_string(),
+            term: Term(3),
+            last_log_index: last_log_index,
+            last_log_term: last_log_term,
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+
+        // same last term, shorter log
+        let server = voter();
+        assert!(!handle_vote_request(Arc::clone(&server), vote_request(2, Term(2))).vote_granted);
+        assert!(server.lock().unwrap().voted_for.is_none());
+
+        // longer log, but ending in an older term
+        assert!(!handle_vote_request(voter(), vote_request(5, Term(1))).vote_granted);
+
+        // as up-to-date as the voter, or more
+        assert!(handle_vote_request(voter(), vote_request(3, Term(2))).vote_granted);
+        assert!(handle_vote_request(voter(), vote_request(4, Term(2))).vote_granted);
+        assert!(handle_vote_request(voter(), vote_request(1, Term(3))).vote_granted);
+
+        // in the voter's own term, with its vote still free, the log alone
+        // decides
+        let request = VoteRequest {
+            term: Term(2),
+            ..vote_request(2, Term(2))
+        };
+        let server = voter();
+        assert!(!handle_vote_request(Arc::clone(&server), request.clone()).vote_granted);
+        assert_eq!(server.lock().unwrap().term, Term(2));
+        let request = VoteRequest {
+            last_log_index: 3,
+            ..request
+        };
+        assert!(handle_vote_request(server, request).vote_granted);
+    }
+
+    #[test]
+    fn raft_vote_is_granted_again_to_a_retrying_candidate() {
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().start();
+
+        let vote_request = || VoteRequest {
+            candidate_id: "server_2".to_string(),
+            term: Term(1),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+
+        // The first response was lost, the candidate asks again.
+        let first = handle_vote_request(Arc::clone(&server), vote_request());
+        let retry = handle_vote_request(Arc::clone(&server), vote_request());
+
+        assert!(first.vote_granted);
+        assert!(retry.vote_granted);
+        assert_eq!(retry.term, Term(1));
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.term, Term(1));
+            assert_eq!(tmp_server.voted_for.as_ref().unwrap().id, "server_2");
+        }
+    }
+
+    #[test]
+    fn raft_votes_again_in_a_higher_term() {
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().start();
+
+        let vote_request = |candidate_id: &str, term: Term| VoteRequest {
             candidate_id: candidate_id.to_string(),
-            term: 1,
+            term: term,
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+
+        assert!(
+            handle_vote_request(Arc::clone(&server), vote_request("server_2", Term(1)))
+                .vote_granted
+        );
+        assert!(
+            !handle_vote_request(Arc::clone(&server), vote_request("server_3", Term(1)))
+                .vote_granted
+        );
+
+        // Term 2 is a new election: the vote of term 1 does not hold it back.
+        let vote_response =
+            handle_vote_request(Arc::clone(&server), vote_request("server_3", Term(2)));
+
+        assert!(vote_response.vote_granted);
+        assert_eq!(vote_response.term, Term(2));
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.term, Term(2));
+            assert_eq!(tmp_server.state, State::FOLLOWER);
+            assert_eq!(tmp_server.voted_for.as_ref().unwrap().id, "server_3");
+        }
+
+        // And the vote of term 2 is as binding as the first one was.
+        assert!(
+            !handle_vote_request(Arc::clone(&server), vote_request("server_2", Term(2)))
+                .vote_granted
+        );
+
+        // A candidate stepping down for a higher term votes in it too.
+        server.lock().unwrap().state = State::CANDIDATE;
+        assert!(
+            handle_vote_request(Arc::clone(&server), vote_request("server_2", Term(3)))
+                .vote_granted
+        );
+        assert_eq!(server.lock().unwrap().state, State::FOLLOWER);
+    }
+
+    #[test]
+    fn raft_candidate_and_leader_step_down_for_a_higher_term_vote() {
+        let vote_request = |term: Term, last_log_index: u64, last_log_term: Term| VoteRequest {
+            candidate_id: "server_2".to_string(),
+            term: term,
+            last_log_index: last_log_index,
+            last_log_term: last_log_term,
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+
+        // A rival candidate of the same term is denied, its own vote holds.
+        let server = Arc::new(Mutex::new(build_server()));
+        {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.start();
+            tmp_server.term = Term(3);
+            tmp_server.state = State::CANDIDATE;
+            tmp_server.voted_for = Some(build_peer("server_1", 9090));
+        }
+        assert!(
+            !handle_vote_request(Arc::clone(&server), vote_request(Term(3), 0, Term(0)))
+                .vote_granted
+        );
+        assert_eq!(server.lock().unwrap().state, State::CANDIDATE);
+
+        // One of a later term frees the vote before it is weighed.
+        let vote_response =
+            handle_vote_request(Arc::clone(&server), vote_request(Term(4), 0, Term(0)));
+        assert!(vote_response.vote_granted);
+        assert_eq!(vote_response.term, Term(4));
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::FOLLOWER);
+            assert_eq!(tmp_server.term, Term(4));
+            assert_eq!(tmp_server.voted_for.as_ref().unwrap().id, "server_2");
+        }
+
+        // A leader steps down too, and votes only for a log up to date:
+        // its own holds the no-op of term 3.
+        let server = Arc::new(Mutex::new(build_server()));
+        {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.start();
+            tmp_server.term = Term(3);
+            tmp_server.state = State::CANDIDATE;
+            tmp_server.become_leader();
+        }
+        let vote_response =
+            handle_vote_request(Arc::clone(&server), vote_request(Term(4), 0, Term(0)));
+        assert!(!vote_response.vote_granted);
+        assert_eq!(vote_response.term, Term(4));
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::FOLLOWER);
+            assert_eq!(tmp_server.term, Term(4));
+            assert!(tmp_server.voted_for.is_none());
+            assert!(tmp_server.progress.is_empty());
+        }
+        assert!(
+            handle_vote_request(Arc::clone(&server), vote_request(Term(4), 1, Term(3)))
+                .vote_granted
+        );
+    }
+
+    #[test]
+    fn raft_vote_tally_reads_membership() {
+        let mut server = build_server();
+        let tally = |server: &Server, grants: usize| {
+            let responses: Vec<VoteResponse> = (0..grants)
+                .map(|i| VoteResponse {
+                    term: Term(1),
+                    vote_granted: true,
+                    voter_id: i.to_string(),
+                })
+                .collect();
+            record_votes(&vote_tally(server, Term(1)), &responses)
+        };
+
+        // 3 servers from its peers: 1 grant + own vote are plenty
+        assert!(tally(&server, 1).has_quorum());
+
+        // 5 servers from the configuration in the log: 2 grants + own vote
+        // are still a majority, a single grant is not.
+        server.bootstrap(create_peers(4)).unwrap();
+        assert!(tally(&server, 2).has_quorum());
+        assert!(!tally(&server, 1).has_quorum());
+    }
+
+    #[test]
+    fn raft_peer_set_sets_the_quorum_of_an_election() {
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().peers().set(create_peers(6));
+
+        // the two peers that answer grant their votes
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+
+        // 3 votes of 7 servers, not even enough to stand
+        assert_eq!(
+            new_election(Arc::clone(&server), &rpc_client),
+            ElectionOutcome::NotStood
+        );
+
+        // 3 votes of 5 servers
+        server.lock().unwrap().peers().set(create_peers(4));
+        assert_eq!(
+            new_election(Arc::clone(&server), &rpc_client),
+            ElectionOutcome::Won
+        );
+        assert_eq!(server.lock().unwrap().state, State::LEADER);
+    }
+
+    #[test]
+    fn raft_count_votes_needs_the_candidate_of_the_term() {
+        let mut server = build_server();
+        server.peers().set(vec![
+            build_peer("server_2", 9091),
+            build_peer("server_3", 9092),
+        ]);
+        server.state = State::CANDIDATE;
+        server.term = Term(1);
+        let mut tally = vote_tally(&server, Term(1));
+        tally.record("server_2", Term(1), true);
+
+        server.state = State::FOLLOWER;
+        assert_eq!(
+            count_votes(&mut server, &tally, Instant::now()),
+            ElectionOutcome::SteppedDown
+        );
+
+        server.state = State::CANDIDATE;
+        server.term = Term(2);
+        assert_eq!(
+            count_votes(&mut server, &tally, Instant::now()),
+            ElectionOutcome::SteppedDown
+        );
+
+        server.term = Term(1);
+        assert_eq!(
+            count_votes(&mut server, &tally, Instant::now()),
+            ElectionOutcome::Won
+        );
+    }
+
+    #[test]
+    fn raft_last_election_records_the_outcome() {
+        let election = |granted_vote: bool, voter_terms: Vec<u64>, sleeps_for: Duration| {
+            let clock = ManualClock::new();
+            let server = Arc::new(Mutex::new(build_server()));
+            server.lock().unwrap().config.clock = Arc::new(clock.clone());
+            server.lock().unwrap().start();
+            let started_at = server.lock().unwrap().now();
+
+            let rpc_client = FakeRpc {
+                granted_vote: granted_vote,
+                sleeps_for: sleeps_for,
+                clock: Some(clock),
+                peers: create_peers(2),
+                voter_terms: voter_terms,
+            };
+            new_election(Arc::clone(&server), &rpc_client);
+
+            let record = server.lock().unwrap().last_election().cloned().unwrap();
+            assert_eq!(record.term, Term(1));
+            assert_eq!(record.started_at, started_at);
+            (record.outcome, record.votes_granted_from)
+        };
+        let no_time = Duration::new(0, 0);
+
+        assert_eq!(
+            election(true, Vec::new(), no_time),
+            (
+                ElectionResult::Won,
+                vec!["0".into(), "1".into(), "server_1".into()]
+            )
+        );
+        assert_eq!(
+            election(false, Vec::new(), no_time),
+            (ElectionResult::Lost, vec!["server_1".into()])
+        );
+        assert_eq!(
+            election(true, vec![1, 7], no_time),
+            (
+                ElectionResult::Abandoned(AbandonReason::HigherTerm(Term(7))),
+                vec!["0".into(), "server_1".into()]
+            )
+        );
+        // the votes came in after the election timeout
+        assert_eq!(
+            election(true, Vec::new(), Duration::new(1, 1)),
+            (
+                ElectionResult::Abandoned(AbandonReason::TimedOut),
+                vec!["0".into(), "1".into(), "server_1".into()]
+            )
+        );
+
+        let server = build_server();
+        assert!(server.last_election().is_none());
+    }
+
+    #[test]
+    fn raft_single_server_wins_with_its_own_vote() {
+        let config = ServerConfig {
+            election_timeout_min: Duration::new(1, 0),
+            election_timeout_max: Duration::new(1, 0),
+            heartbeat_interval: Duration::from_millis(200),
+            ..ServerConfig::default()
+        };
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+        let server = Arc::new(Mutex::new(
+            Server::new(config, Vec::new(), address, "server_1".to_string()).unwrap(),
+        ));
+
+        let rpc_client = MemoryNetwork::new().client(Vec::new(), Duration::from_secs(1));
+        new_election(Arc::clone(&server), &rpc_client);
+
+        let tmp_server = server.lock().unwrap();
+        assert_eq!(tmp_server.state, State::LEADER);
+        assert_eq!(tmp_server.term, Term(1));
+    }
+
+    #[test]
+    fn raft_test_cluster_elects_and_replicates() {
+        let mut counters = Vec::new();
+        let cluster = TestCluster::new(5, |_| {
+            let counter = Counter::default();
+            counters.push(counter.clone());
+            Box::new(counter)
+        });
+
+        // server_1 times out first, and wins
+        let ticks = cluster.tick_until(100, |c| c.leader().is_some());
+        assert_eq!(ticks, 31);
+        let leader = cluster.leader().unwrap();
+        assert_eq!(leader.lock().unwrap().id, "server_1");
+        assert_eq!(leader.lock().unwrap().term, Term(1));
+
+        // server_5 is cut off, the majority goes on without it
+        cluster.partition(&["server_5"]);
+        let index = propose_command(&leader, CounterCommand::Incr.encode()).unwrap();
+        cluster.tick_until(100, |c| {
+            c.servers()[..4]
+                .iter()
+                .all(|s| s.lock().unwrap().last_applied() >= index)
+        });
+        assert_eq!(
+            counters.iter().map(|c| c.value()).collect::<Vec<_>>(),
+            [1, 1, 1, 1, 0]
+        );
+
+        // and it catches up once it is back, without disrupting the leader
+        cluster.heal();
+        let server_5 = cluster.server("server_5");
+        cluster.tick_until(100, |_| server_5.lock().unwrap().last_applied() >= index);
+        assert_eq!(counters[4].value(), 1);
+        assert_eq!(leader.lock().unwrap().state, State::LEADER);
+    }
+
+    #[test]
+    fn raft_partitioned_leader_rejoins_as_follower() {
+        let mut counters = Vec::new();
+        let cluster = TestCluster::new(5, |_| {
+            let counter = Counter::default();
+            counters.push(counter.clone());
+            Box::new(counter)
+        });
+        cluster.tick_until(100, |c| c.leader().is_some());
+        let old_leader = cluster.leader().unwrap();
+
+        // cut off, it still accepts a command it can never commit
+        cluster.partitions().isolate("server_1");
+        let lost = propose_command(&old_leader, CounterCommand::Incr.encode()).unwrap();
+
+        // the majority elects a leader of its own, whose no-op takes the
+        // index of the lost command
+        cluster.tick_until(100, |c| {
+            c.leader()
+                .is_some_and(|l| l.lock().unwrap().id != "server_1")
+        });
+        let new_leader = cluster.leader().unwrap();
+        assert!(new_leader.lock().unwrap().term > old_leader.lock().unwrap().term);
+        let index = propose_command(&new_leader, CounterCommand::Incr.encode()).unwrap();
+        assert_eq!(index, lost + 1);
+        cluster.tick_until(100, |c| {
+            c.servers()[1..]
+                .iter()
+                .all(|s| s.lock().unwrap().last_applied() >= index)
+        });
+        assert!(old_leader.lock().unwrap().commit_index < lost);
+
+        // back, it follows the new leader and its entry is replaced
+        cluster.heal();
+        cluster.tick_until(100, |_| old_leader.lock().unwrap().last_applied() >= index);
+        {
+            let tmp_server = old_leader.lock().unwrap();
+            let new_leader = new_leader.lock().unwrap();
+            assert_eq!(tmp_server.state, State::FOLLOWER);
+            assert_eq!(tmp_server.term, new_leader.term);
+            assert_eq!(
+                tmp_server.log.entries(lost, index),
+                new_leader.log.entries(lost, index)
+            );
+        }
+        assert_eq!(new_leader.lock().unwrap().state, State::LEADER);
+        assert!(counters.iter().all(|c| c.value() == 1));
+    }
+
+    #[test]
+    fn raft_witness_cluster_survives_one_voter_failure() {
+        let mut counters = Vec::new();
+        let cluster = TestCluster::with_kinds(
+            &[NodeKind::Voter, NodeKind::Voter, NodeKind::Witness],
+            |_| {
+                let counter = Counter::default();
+                counters.push(counter.clone());
+                Box::new(counter)
+            },
+        );
+        let witness = Arc::clone(&cluster.servers()[2]);
+        cluster.tick_until(100, |c| c.leader().is_some());
+        let leader = cluster.leader().unwrap();
+        assert_eq!(leader.lock().unwrap().id, "server_1");
+
+        // server_2 is down, the witness's ack alone makes a majority
+        cluster.partitions().isolate("server_2");
+        let index = propose_command(&leader, CounterCommand::Incr.encode()).unwrap();
+        cluster.tick_until(100, |_| leader.lock().unwrap().last_applied() >= index);
+        assert!(witness.lock().unwrap().commit_index >= index);
+        cluster.heal();
+        let server_2 = Arc::clone(&cluster.servers()[1]);
+        cluster.tick_until(100, |_| server_2.lock().unwrap().last_applied() >= index);
+
+        // server_1 is down, server_2 is elected with the witness's vote
+        cluster.partitions().isolate("server_1");
+        cluster.tick_until(100, |c| {
+            c.leader()
+                .is_some_and(|l| l.lock().unwrap().id == "server_2")
+        });
+        let new_leader = cluster.leader().unwrap();
+        let index = propose_command(&new_leader, CounterCommand::Incr.encode()).unwrap();
+        cluster.tick_until(100, |_| new_leader.lock().unwrap().last_applied() >= index);
+        assert_eq!(counters[1].value(), 2);
+
+        // the witness never led, and kept no commands
+        let tmp_server = witness.lock().unwrap();
+        assert_eq!(tmp_server.state, State::FOLLOWER);
+        assert_eq!(tmp_server.log.last_index(), index);
+        assert_eq!(
+            tmp_server.log.entry_at(index),
+            Some(LogEntry::Command {
+                term: new_leader.lock().unwrap().term,
+                data: Vec::new(),
+            })
+        );
+        assert_eq!(counters[2].value(), 0);
+    }
+
+    #[test]
+    fn raft_test_cluster_converges_under_message_loss() {
+        for seed in 0..20 {
+            let mut counters = Vec::new();
+            let cluster = TestCluster::new(5, |_| {
+                let counter = Counter::default();
+                counters.push(counter.clone());
+                Box::new(counter)
+            });
+            cluster.set_faults(Some(Faults {
+                seed: seed,
+                loss: 0.1,
+                max_delay: Duration::ZERO,
+            }));
+
+            let mut leaders = HashMap::new();
+            for tick in 0..300 {
+                if let Some(leader) = cluster.leader() {
+                    let tmp_server = leader.lock().unwrap();
+                    let id = leaders
+                        .entry(tmp_server.term)
+                        .or_insert_with(|| tmp_server.id.to_string());
+                    assert_eq!(id, &tmp_server.id, "seed {}: two leaders", seed);
+                }
+                if tick % 10 == 0 {
+                    if let Some(leader) = cluster.leader() {
+                        let _ = propose_command(&leader, CounterCommand::Incr.encode());
+                    }
+                }
+                cluster.tick();
+            }
+            assert_committed_prefixes_agree(seed, &cluster);
+
+            // without the loss, every server catches up with one leader
+            cluster.set_faults(None);
+            cluster.tick_until(200, |c| {
+                let commit_index = c.leader().map(|l| l.lock().unwrap().commit_index);
+                c.servers()
+                    .iter()
+                    .all(|s| Some(s.lock().unwrap().last_applied()) == commit_index)
+            });
+            assert_committed_prefixes_agree(seed, &cluster);
+            let leader_count = cluster
+                .servers()
+                .iter()
+                .filter(|s| s.lock().unwrap().state == State::LEADER)
+                .count();
+            assert_eq!(leader_count, 1, "seed {}", seed);
+            let value = counters[0].value();
+            assert!(value > 0, "seed {}: nothing was committed", seed);
+            assert!(counters.iter().all(|c| c.value() == value), "seed {}", seed);
+        }
+    }
+
+    fn assert_committed_prefixes_agree(seed: u64, cluster: &TestCluster) {
+        let servers = cluster.servers();
+        for (a, b) in servers.iter().zip(servers.iter().skip(1)) {
+            let (a, b) = (a.lock().unwrap(), b.lock().unwrap());
+            // Each bootstraps itself with a configuration listing its own
+            // id first, the same in any other order.
+            for index in 2..=a.commit_index.min(b.commit_index) {
+                assert_eq!(
+                    a.log.entry_at(index),
+                    b.log.entry_at(index),
+                    "seed {}: {} and {} committed different entries at {}",
+                    seed,
+                    a.id,
+                    b.id,
+                    index
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn raft_single_server_leads_right_away() {
+        let config = ServerConfig {
+            election_timeout_min: Duration::new(10, 0),
+            election_timeout_max: Duration::new(10, 0),
+            heartbeat_interval: Duration::new(1, 0),
+            ..ServerConfig::default()
+        };
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+        let server = Arc::new(Mutex::new(
+            Server::new(config, Vec::new(), address, "server_1".to_string()).unwrap(),
+        ));
+
+        let rpc_client = MemoryNetwork::new().client(Vec::new(), Duration::from_secs(1));
+        let handle = start_server(Arc::clone(&server), rpc_client).unwrap();
+
+        // well before its election timeout
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while server.lock().unwrap().state != State::LEADER {
+            assert!(Instant::now() < deadline, "waited for a timeout");
+            sleep(Duration::from_millis(10));
+        }
+
+        let index = propose_command(&server, vec![1]).unwrap();
+        wait_for_output(&server, index, Duration::from_secs(5)).unwrap();
+        assert_eq!(server.lock().unwrap().commit_index, index);
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn raft_single_server_elects_itself_after_a_timeout() {
+        let config = ServerConfig {
+            election_timeout_min: Duration::from_millis(100),
+            election_timeout_max: Duration::from_millis(100),
+            heartbeat_interval: Duration::from_millis(20),
+            ..ServerConfig::default()
+        };
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+        let server = Arc::new(Mutex::new(
+            Server::new(config, Vec::new(), address, "server_1".to_string()).unwrap(),
+        ));
+
+        let rpc_client = MemoryNetwork::new().client(Vec::new(), Duration::from_secs(1));
+        let handle = start_server(Arc::clone(&server), rpc_client).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.lock().unwrap().state != State::LEADER {
+            assert!(Instant::now() < deadline, "never became leader");
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.lock().unwrap().term, Term(1));
+
+        // and commits on its own
+        let index = server.lock().unwrap().propose(vec![1]).unwrap();
+        wait_for_applied(&server, index, Duration::from_secs(5)).unwrap();
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn raft_joining_server_does_not_stand() {
+        let clock = ManualClock::new();
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().config.clock = Arc::new(clock.clone());
+        server.lock().unwrap().join_as_learner();
+        server.lock().unwrap().start();
+        let rpc_client = FakeRpc {
+            granted_vote: true,
+            sleeps_for: Duration::new(0, 0),
+            clock: None,
+            peers: create_peers(2),
+            voter_terms: Vec::new(),
+        };
+        let shutdown = AtomicBool::new(false);
+
+        // it waits for another timeout instead
+        clock.advance(Duration::from_millis(1001));
+        handle_timeout(Arc::clone(&server), &rpc_client, &shutdown);
+        {
+            let mut tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.state, State::FOLLOWER);
+            assert_eq!(tmp_server.term, Term(0));
+            assert!(!tmp_server.has_timed_out());
+        }
+
+        // still a learner in the configuration the leader sent
+        let configuration = |voters: Vec<Peer>, learners: Vec<Peer>| LogEntry::Configuration {
+            term: Term(0),
+            membership: Membership { voters, learners },
+        };
+        let own = build_peer("server_1", 9090);
+        let others = vec![build_peer("0", 9091), build_peer("1", 9092)];
+        server
+            .lock()
+            .unwrap()
+            .log
+            .append(configuration(others.clone(), vec![own.clone()]))
+            .unwrap();
+        clock.advance(Duration::from_millis(1001));
+        handle_timeout(Arc::clone(&server), &rpc_client, &shutdown);
+        assert_eq!(server.lock().unwrap().term, Term(0));
+
+        // and stands like any voter once promoted
+        let mut voters = others;
+        voters.push(own);
+        server
+            .lock()
+            .unwrap()
+            .log
+            .append(configuration(voters, Vec::new()))
+            .unwrap();
+        clock.advance(Duration::from_millis(1001));
+        handle_timeout(Arc::clone(&server), &rpc_client, &shutdown);
+        assert_eq!(server.lock().unwrap().state, State::LEADER);
+    }
+
+    #[test]
+    fn raft_learner_catches_up_then_votes() {
+        let mut cluster = Cluster::start(3, |_| Box::new(Counter::default()));
+        cluster.propose_in_session("client", 1, CounterCommand::Incr.encode());
+
+        let counter = Counter::default();
+        let learner = cluster.join(Box::new(counter.clone()));
+
+        // it gets the log, and is promoted once it holds all of it
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let leader = cluster.leader();
+            let tmp_leader = leader.lock().unwrap();
+            let (index, membership) = tmp_leader.membership().unwrap();
+            if membership.voters.len() == 4 && tmp_leader.commit_index >= index {
+                break;
+            }
+            drop(tmp_leader);
+            assert!(Instant::now() < deadline, "server_4 was not promoted");
+            sleep(Duration::from_millis(10));
+        }
+        let commit_index = cluster.leader().lock().unwrap().commit_index;
+        wait_for_applied(&learner, commit_index, Duration::from_secs(10)).unwrap();
+        assert_eq!(counter.value(), 1);
+        let term = cluster.leader().lock().unwrap().term;
+        assert_eq!(learner.lock().unwrap().term, term);
+
+        // a voter like the others, which can lead
+        assert!(learner.lock().unwrap().is_voter());
+        let leader = cluster.leader();
+        while leader.lock().unwrap().transfer_leadership("server_4")
+            == Err(TransferError::InProgress)
+        {
+            sleep(Duration::from_millis(10));
+        }
+        while learner.lock().unwrap().state != State::LEADER {
+            assert!(Instant::now() < deadline, "server_4 did not take over");
+            sleep(Duration::from_millis(10));
+        }
+        cluster.propose_in_session("client", 2, CounterCommand::Incr.encode());
+        assert_eq!(counter.value(), 2);
+
+        cluster.shutdown();
+    }
+
+    #[test]
+    fn raft_handle_append_entries() {
+        let server = Arc::new(Mutex::new(build_server()));
+
+        let request = AppendEntriesRequest {
+            term: Term(2),
+            leader_id: "server_2".to_string(),
+            prev_log_index: 0,
+            prev_log_term: Term(0),
+            entries: vec![command(Term(1)), command(Term(2))],
+            leader_commit: 1,
+        };
+
+        let response = handle_append_entries(Arc::clone(&server), request);
+
+        assert!(response.success);
+        assert_eq!(response.match_index, 2);
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.term, Term(2));
+            assert_eq!(tmp_server.last_log_index(), 2);
+            assert_eq!(tmp_server.commit_index, 1);
+            assert_eq!(tmp_server.current_leader.as_ref().unwrap().id, "server_2");
+        }
+
+        // prev_log_index past the end of the log is rejected, and the
+        // follower points the leader at its last entry.
+        let request = AppendEntriesRequest {
+            term: Term(2),
+            leader_id: "server_2".to_string(),
+            prev_log_index: 7,
+            prev_log_term: Term(2),
+            entries: vec![command(Term(2))],
+            leader_commit: 1,
+        };
+
+        let response = handle_append_entries(Arc::clone(&server), request);
+
+        assert!(!response.success);
+        assert_eq!(response.conflict_term, None);
+        assert_eq!(response.conflict_index, 3);
+
+        // a conflicting entry is replaced, together with everything after it
+        let request = AppendEntriesRequest {
+            term: Term(3),
+            leader_id: "server_3".to_string(),
+            prev_log_index: 1,
+            prev_log_term: Term(1),
+            entries: vec![command(Term(3))],
+            leader_commit: 2,
+        };
+
+        let response = handle_append_entries(Arc::clone(&server), request);
+
+        assert!(response.success);
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(
+                tmp_server.log.entries(1, u64::MAX),
+                vec![command(Term(1)), command(Term(3))]
+            );
+            assert_eq!(tmp_server.commit_index, 2);
+        }
+
+        // requests from an older term are rejected
+        let request = AppendEntriesRequest {
+            term: Term(1),
+            leader_id: "server_2".to_string(),
+            prev_log_index: 0,
+            prev_log_term: Term(0),
+            entries: Vec::new(),
+            leader_commit: 0,
+        };
+
+        let response = handle_append_entries(Arc::clone(&server), request);
+
+        assert!(!response.success);
+        assert_eq!(response.term, Term(3));
+    }
+
+    #[test]
+    fn raft_handle_append_entries_rejects_oversized_entries() {
+        let server = Arc::new(Mutex::new(build_server()));
+        server.lock().unwrap().config.max_entry_bytes = 4;
+
+        let entry = |size: usize| LogEntry::Command {
+            term: Term(1),
+            data: vec![0; size],
+        };
+        let request = |entries: Vec<LogEntry>| AppendEntriesRequest {
+            term: Term(1),
+            leader_id: "server_2".to_string(),
+            prev_log_index: 0,
+            prev_log_term: Term(0),
+            entries: entries,
+            leader_commit: 0,
+        };
+
+        let response = handle_append_entries(Arc::clone(&server), request(vec![entry(4)]));
+        assert!(response.success);
+
+        let response =
+            handle_append_entries(Arc::clone(&server), request(vec![entry(4), entry(5)]));
+        assert!(!response.success);
+        assert_eq!(server.lock().unwrap().last_log_index(), 1);
+    }
+
+    #[test]
+    fn raft_replicate_log_pipelines_append_entries() {
+        let mut tmp_server = build_server();
+        tmp_server.config.max_inflight_append_entries = 2;
+        tmp_server.config.max_entries_per_append = 1;
+        tmp_server.term = Term(1);
+        tmp_server.bootstrap(create_peers(2)).unwrap();
+        for _ in 0..4 {
+            tmp_server.log.append(command(Term(1))).unwrap();
+        }
+        tmp_server.state = State::CANDIDATE;
+        tmp_server.become_leader();
+
+        let server = Arc::new(Mutex::new(tmp_server));
+        let rpc_client = PipelineRpc {
+            sent: RefCell::new(Vec::new()),
+            responses: RefCell::new(Vec::new()),
+        };
+
+        // The followers' logs are unknown, so each gets a single probe,
+        // with the leader's no-op.
+        replicate_log(Arc::clone(&server), &rpc_client);
+
+        let sent = rpc_client.take_sent();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|(_, r)| r.prev_log_index == 5));
+        assert!(sent.iter().all(|(_, r)| r.entries.len() == 1));
+
+        // "0" has an empty log, "1" has the first two entries.
+        rpc_client.respond("0", false, 0);
+        rpc_client.respond("1", false, 2);
+        replicate_log(Arc::clone(&server), &rpc_client);
+
+        // Both pipelines are filled up to the window of two requests.
+        let sent = rpc_client.take_sent();
+        let sent_to = |peer: &str| -> Vec<u64> {
+            sent.iter()
+                .filter(|(id, _)| id == peer)
+                .map(|(_, r)| r.prev_log_index)
+                .collect()
+        };
+        assert_eq!(sent_to("0"), vec![0, 1]);
+        assert_eq!(sent_to("1"), vec![2, 3]);
+
+        // Responses arrive out of order: the second one acknowledges
+        // both requests to "1".
+        rpc_client.respond("1", true, 4);
+        rpc_client.respond("1", true, 3);
+        rpc_client.respond("0", true, 1);
+        replicate_log(Arc::clone(&server), &rpc_client);
+
+        {
+            let tmp_server = server.lock().unwrap();
+            assert_eq!(tmp_server.progress["1"].match_index, 4);
+            assert_eq!(tmp_server.progress["0"].match_index, 1);
+            // server_1 and "1" store index 4, a majority of 3 voters.
+            assert_eq!(tmp_server.commit_index, 4);
+        }
+
+        // "0" still has one request in flight, so only one more is sent.
+        // "1" gets the last two entries, the leader's no-op last.
+        let sent = rpc_client.take_sent();
+        assert_eq!(sent.len(), 3);
+
+        // A rejection resets the pipeline of "0" right after the hint.
+        rpc_client.respond("0", false, 1);
+        replicate_log(Arc::clone(&server), &rpc_client);
+
+        let sent = rpc_client.take_sent();
+        let sent_to_0: Vec<u64> = sent
+            .iter()
+            .filter(|(id, _)| id == "0")
+            .map(|(_, r)| r.prev_log_index)
+            .collect();
+        assert_eq!(sent_to_0, vec![1, 2]);
+    }
+
+    #[test]
+    fn raft_lagging_follower_gets_entries_spilled_to_disk() {
+        let data_dir =
+            std::env::temp_dir().join(format!("rsraft-core-log-spill-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        let config = ServerConfig {
+            election_timeout_min: Duration::new(1, 0),
+            election_timeout_max: Duration::new(1, 0),
+            heartbeat_interval: Duration::from_millis(200),
+            // the log in memory, spilling to files
+            storage: Some(Arc::new(FileStorage::new(data_dir.clone()))),
+            max_cached_log_entries: 4,
+            ..ServerConfig::default()
+        };
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+        let mut leader =
+            Server::new(config, create_peers(1), address, "server_1".to_string()).unwrap();
+        leader.bootstrap(create_peers(1)).unwrap();
+        for term in 1..=20 {
+            leader.log.append(command(Term(term))).unwrap();
+        }
+        leader.term = Term(20);
+        leader.state = State::CANDIDATE;
+        leader.become_leader();
+        assert_eq!(leader.log.cached(), 4);
+
+        let mut follower = build_server();
+        follower.id = "0".to_string();
+        follower.term = Term(20);
+
+        let leader = Arc::new(Mutex::new(leader));
+        let follower = Arc::new(Mutex::new(follower));
+        let rpc_client = LoopbackRpc::new(vec![Arc::clone(&follower)]);
+
+        for _ in 0..10 {
+            replicate_log(Arc::clone(&leader), &rpc_client);
+        }
+
+        assert_eq!(leader.lock().unwrap().progress["0"].match_index, 22);
+        assert_eq!(
+            follower.lock().unwrap().log.entries(1, u64::MAX),
+            leader.lock().unwrap().log.entries(1, u64::MAX)
+        );
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn raft_append_entries_before_the_snapshot_points_past_it() {
+        let mut tmp_server = build_server();
+        tmp_server.term = Term(2);
+        for term in [1, 1, 2, 2] {
+            tmp_server.log.append(command(Term(term))).unwrap();
+        }
+        tmp_server.commit_index = 4;
+        tmp_server.apply_committed();
+        tmp_server.snapshots.finished(&Ok(SnapshotMetadata {
+            last_included_index: 3,
+            last_included_term: Term(2),
+            size: 0,
+            taken_at: Timestamp::now(),
+            duration: Duration::new(0, 0),
+        }));
+        let server = Arc::new(Mutex::new(tmp_server));
+
+        let request = |prev_log_index: u64, prev_log_term: Term| AppendEntriesRequest {
+            term: Term(2),
+            leader_id: "server_2".to_string(),
+            prev_log_index: prev_log_index,
+            prev_log_term: prev_log_term,
+            entries: vec![command(Term(2))],
+            leader_commit: 4,
+        };
+
+        // inside the snapshot
+        let response = handle_append_entries(Arc::clone(&server), request(1, Term(1)));
+        assert!(!response.success);
+        assert_eq!(response.conflict_term, None);
+        assert_eq!(response.conflict_index, 4);
+        assert_eq!(server.lock().unwrap().last_log_index(), 4);
+
+        // beyond the end of the log
+        let response = handle_append_entries(Arc::clone(&server), request(9, Term(2)));
+        assert!(!response.success);
+        assert_eq!(response.conflict_term, None);
+        assert_eq!(response.conflict_index, 5);
+
+        // right at the snapshot, where the leader retries
+        let response = handle_append_entries(Arc::clone(&server), request(3, Term(2)));
+        assert!(response.success);
+        assert_eq!(response.match_index, 4);
+        assert_eq!(server.lock().unwrap().last_log_index(), 4);
+    }
+
+    #[test]
+    fn raft_replicate_log_skips_conflicting_terms() {
+        // Leader and follower agree on the first 500 entries. The follower
+        // then has 800 entries from a term the leader never saw, while
+        // the leader has 500 entries from a later term.
+        let mut leader = build_server();
+        leader.config.max_inflight_append_entries = 1;
+        leader.config.max_entries_per_append = 1000;
+        leader.bootstrap(create_peers(1)).unwrap();
+        let mut follower = build_server();
+        follower.id = "0".to_string();
+
+        for _ in 1..500 {
+            leader.log.append(command(Term(0))).unwrap();
+        }
+        for entry in leader.log.entries(1, u64::MAX) {
+            follower.log.append(entry).unwrap();
+        }
+        for _ in 0..500 {
+            leader.log.append(command(Term(3))).unwrap();
+        }
+        for _ in 0..800 {
+            follower.log.append(command(Term(2))).unwrap();
+        }
+
+        leader.term = Term(3);
+        leader.state = State::CANDIDATE;
+        leader.become_leader();
+        follower.term = Term(2);
+
+        let leader = Arc::new(Mutex::new(leader));
+        let follower = Arc::new(Mutex::new(follower));
+        let rpc_client = LoopbackRpc::new(vec![Arc::clone(&follower)]);
+
+        let mut rounds = 0;
+        while leader.lock().unwrap().progress["0"].match_index < 1000 {
+            replicate_log(Arc::clone(&leader), &rpc_client);
+            rounds += 1;
+            assert!(rounds < 10, "did not converge after {} rounds", rounds);
+        }
+
+        // probe, retry from the start of the conflicting term, acknowledge
+        assert_eq!(rounds, 3);
+        assert_eq!(
+            follower.lock().unwrap().log.entries(1, u64::MAX),
+            leader.lock().unwrap().log.entries(1, u64::MAX)
+        );
+    }
+
+    #[test]
+    fn raft_leader_no_op_commits_entries_of_earlier_terms() {
+        let servers: Vec<Arc<Mutex<Server>>> = vec!["server_1", "server_2", "server_3"]
+            .into_iter()
+            .map(|id| {
+                let mut server = build_server();
+                server.id = id.to_string();
+                Arc::new(Mutex::new(server))
+            })
+            .collect();
+
+        // Three entries of term 1 reached every server, but were never
+        // committed.
+        {
+            let mut leader = servers[0].lock().unwrap();
+            leader
+                .bootstrap(vec![
+                    build_peer("server_2", 9091),
+                    build_peer("server_3", 9092),
+                ])
+                .unwrap();
+            for _ in 0..3 {
+                leader.log.append(command(Term(1))).unwrap();
+            }
+            for follower in &servers[1..] {
+                let mut follower = follower.lock().unwrap();
+                for entry in leader.log.entries(1, u64::MAX) {
+                    follower.log.append(entry).unwrap();
+                }
+            }
+
+            leader.term = Term(2);
+            leader.state = State::CANDIDATE;
+            leader.become_leader();
+            assert_eq!(leader.last_log_index(), 5);
+            assert_eq!(
+                leader.log.entry_at(5),
+                Some(LogEntry::NoOp {
+                    term: Term(2),
+                    leader_id: "server_1".to_string(),
+                })
+            );
+
+            // Stored on every server, they are still not of this term.
+            for progress in leader.progress.values_mut() {
+                progress.match_index = 4;
+            }
+            advance_commit_index(&mut leader);
+            assert_eq!(leader.commit_index, 0);
+        }
+
+        // The no-op replicates like any entry, and commits them with it.
+        let leader_rpc = LoopbackRpc::new(servers[1..].iter().map(Arc::clone).collect());
+        let mut rounds = 0;
+        while servers[0].lock().unwrap().commit_index < 5 {
+            replicate_log(Arc::clone(&servers[0]), &leader_rpc);
+            rounds += 1;
+            assert!(rounds < 10, "did not commit after {} rounds", rounds);
+        }
+        for follower in &servers[1..] {
+            assert_eq!(
+                follower.lock().unwrap().log.entries(1, u64::MAX),
+                servers[0].lock().unwrap().log.entries(1, u64::MAX)
+            );
+        }
+    }
+
+    #[test]
+    fn raft_replicate_log_walks_back_over_several_conflicting_terms() {
+        // leader: 0 0 0 5 5 5 and its no-op, follower: 0 0 0 2 2 4 4 4 4
+        let mut leader = build_server();
+        leader.config.max_inflight_append_entries = 1;
+        leader.bootstrap(create_peers(1)).unwrap();
+        let mut follower = build_server();
+        follower.id = "0".to_string();
+
+        for _ in 1..3 {
+            leader.log.append(command(Term(0))).unwrap();
+        }
+        for entry in leader.log.entries(1, u64::MAX) {
+            follower.log.append(entry).unwrap();
+        }
+        for term in [5, 5, 5] {
+            leader.log.append(command(Term(term))).unwrap();
+        }
+        for term in [2, 2, 4, 4, 4, 4] {
+            follower.log.append(command(Term(term))).unwrap();
+        }
+
+        leader.term = Term(5);
+        leader.state = State::CANDIDATE;
+        leader.become_leader();
+        follower.term = Term(4);
+
+        let leader = Arc::new(Mutex::new(leader));
+        let follower = Arc::new(Mutex::new(follower));
+        let rpc_client = LoopbackRpc::new(vec![Arc::clone(&follower)]);
+
+        let mut rounds = 0;
+        while leader.lock().unwrap().progress["0"].match_index < 7 {
+            replicate_log(Arc::clone(&leader), &rpc_client);
+            rounds += 1;
+            assert!(rounds < 10, "did not converge after {} rounds", rounds);
+        }
+
+        // probe, back past the 4s, back past the 2s, acknowledge: one
+        // round per conflicting term rather than per conflicting entry
+        assert_eq!(rounds, 4);
+        {
+            let tmp_leader = leader.lock().unwrap();
+            assert_eq!(tmp_leader.progress["0"].match_index, 7);
+            assert_eq!(
+                follower.lock().unwrap().log.entries(1, u64::MAX),
+                tmp_leader.log.entries(1, u64::MAX)
+            );
+        }
+    }
+
+    #[test]
+    fn raft_remove_follower_then_leader() {
+        let peers = vec![
+            build_peer("server_2", 9091),
+            build_peer("server_3", 9092),
+            build_peer("server_4", 9093),
+        ];
+
+        let servers: Vec<Arc<Mutex<Server>>> = vec!["server_1", "server_2", "server_3", "server_4"]
+            .into_iter()
+            .map(|id| {
+                let mut server = build_server();
+                server.id = id.to_string();
+                Arc::new(Mutex::new(server))
+            })
+            .collect();
+
+        {
+            let mut leader = servers[0].lock().unwrap();
+            leader.term = Term(1);
+            leader.bootstrap(peers).unwrap();
+            leader.state = State::CANDIDATE;
+            leader.become_leader();
+        }
+
+        let leader_rpc = LoopbackRpc::new(servers[1..].iter().map(Arc::clone).collect());
+        let replicate = |rounds| {
+            for _ in 0..rounds {
+                replicate_log(Arc::clone(&servers[0]), &leader_rpc);
+            }
+        };
+
+        replicate(5);
+
+        // Removing a follower: the cluster shrinks to three voters.
+        let index = servers[0]
+            .lock()
+            .unwrap()
+            .remove_server("server_4")
+            .unwrap();
+        replicate(5);
+        {
+            let leader = servers[0].lock().unwrap();
+            assert!(leader.commit_index >= index);
+            assert_eq!(leader.voter_count(), 3);
+            assert!(!leader.progress.contains_key("server_4"));
+        }
+
+        // Removing the leader: it keeps replicating until the change is
+        // committed by the two remaining voters, then steps down.
+        let index = servers[0]
+            .lock()
+            .unwrap()
+            .remove_server("server_1")
+            .unwrap();
+        assert_eq!(servers[0].lock().unwrap().state, State::LEADER);
+        replicate(5);
+        {
+            let old_leader = servers[0].lock().unwrap();
+            assert!(old_leader.commit_index >= index);
+            assert_eq!(old_leader.state, State::FOLLOWER);
+            assert!(!old_leader.is_voter());
+        }
+
+        // The remaining voters elect a new leader among themselves, once
+        // server_3 has timed out on the removed one too.
+        {
+            let mut follower = servers[2].lock().unwrap();
+            follower.current_leader = None;
+            follower.last_leader_contact = None;
+        }
+        let rpc_client = LoopbackRpc::new(vec![Arc::clone(&servers[2])]);
+        new_election(Arc::clone(&servers[1]), &rpc_client);
+        assert_eq!(servers[1].lock().unwrap().state, State::LEADER);
+
+        for _ in 0..5 {
+            replicate_log(Arc::clone(&servers[1]), &rpc_client);
+        }
+
+        let new_leader = servers[1].lock().unwrap();
+        let follower = servers[2].lock().unwrap();
+        assert_eq!(new_leader.voter_count(), 2);
+        assert_eq!(
+            follower.log.entries(1, u64::MAX),
+            new_leader.log.entries(1, u64::MAX)
+        );
+        assert_eq!(follower.commit_index, new_leader.commit_index);
+    }
+
+    fn build_peer(id: &str, port: u16) -> Peer {
+        Peer {
+            id: id.to_string(),
+            address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+            kind: NodeKind::Voter,
+        }
+    }
+
+    #[test]
+    fn raft_replicate_log_flow_control() {
+        let mut tmp_server = build_server();
+        tmp_server.config.max_inflight_bytes = 250;
+        tmp_server.term = Term(1);
+        tmp_server.bootstrap(create_peers(2)).unwrap();
+        for _ in 0..10 {
+            tmp_server
+                .log
+                .append(LogEntry::Command {
+                    term: Term(1),
+                    data: vec![0; 100],
+                })
+                .unwrap();
+        }
+        tmp_server.state = State::CANDIDATE;
+        tmp_server.become_leader();
+
+        let server = Arc::new(Mutex::new(tmp_server));
+        let rpc_client = PipelineRpc {
+            sent: RefCell::new(Vec::new()),
+            responses: RefCell::new(Vec::new()),
+        };
+
+        // Both followers have an empty log.
+        replicate_log(Arc::clone(&server), &rpc_client);
+        rpc_client.take_sent();
+        rpc_client.respond("0", false, 0);
+        rpc_client.respond("1", false, 0);
+
+        // "0" never answers again, "1" acknowledges everything it gets.
+        for _ in 0..10 {
+            replicate_log(Arc::clone(&server), &rpc_client);
+
+            for (peer_id, request) in rpc_client.take_sent() {
+                if peer_id == "1" {
+                    let last_index = request.prev_log_index + request.entries.len() as u64;
+                    rpc_client.respond("1", true, last_index);
+                }
+            }
+        }
+
+        {
+            let tmp_server = server.lock().unwrap();
+
+            // The configuration and two commands of 100 bytes fit within
+            // the limit, a third command does not.
+            let paused = &tmp_server.progress["0"];
+            assert!(paused.is_paused());
+            assert_eq!(paused.inflight_entries(), 3);
+            assert_eq!(paused.inflight_bytes(), 200);
+
+            // The slow follower does not hold back the healthy one.
+            let active = &tmp_server.progress["1"];
+            assert!(!active.is_paused());
+            assert_eq!(active.match_index, 12);
+            assert_eq!(tmp_server.commit_index, 12);
+        }
+
+        // Once "0" acknowledges, it gets the next entries.
+        rpc_client.respond("0", true, 3);
+        replicate_log(Arc::clone(&server), &rpc_client);
+
+        let sent = rpc_client.take_sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1.prev_log_index, 3);
+        assert_eq!(sent[0].1.entries.len(), 2);
+    }
+
+    #[test]
+    fn raft_replicate_log_budgets_catch_up() {
+        let mut tmp_server = build_server();
+        tmp_server.config.catch_up_horizon = 50;
+        tmp_server.config.catch_up_bytes_per_second = 10_000;
+        tmp_server.term = Term(1);
+        tmp_server.bootstrap(create_peers(6)).unwrap();
+        for _ in 0..200 {
+            tmp_server
+                .log
+                .append(LogEntry::Command {
+                    term: Term(1),
+                    data: vec![0; 100],
+                })
+                .unwrap();
+        }
+        tmp_server.state = State::CANDIDATE;
+        tmp_server.become_leader();
+
+        // "0", "1" and "2" have an empty log, the others are up to date.
+        let last_log_index = tmp_server.last_log_index();
+        for (peer_id, progress) in tmp_server.progress.iter_mut() {
+            match peer_id.as_str() {
+                "0" | "1" | "2" => progress.rejected(1),
+                _ => progress.acknowledged(last_log_index),
+            }
+        }
+        tmp_server.commit_index = last_log_index;
+
+        let server = Arc::new(Mutex::new(tmp_server));
+        let rpc_client = PipelineRpc {
+            sent: RefCell::new(Vec::new()),
+            responses: RefCell::new(Vec::new()),
         };
 
-        let vote_response = handle_vote_request(Arc::clone(&server), vote_request);
+        // Every round earns a tenth of a second of budget, 1000 bytes.
+        let round = || {
+            {
+                let mut tmp_server = server.lock().unwrap();
+                tmp_server
+                    .catch_up
+                    .rewind(Instant::now(), Duration::from_millis(100));
+            }
+            replicate_log(Arc::clone(&server), &rpc_client);
+
+            for (peer_id, request) in rpc_client.take_sent() {
+                let last_index = request.prev_log_index + request.entries.len() as u64;
+                rpc_client.respond(&peer_id, true, last_index);
+            }
+        };
+
+        for _ in 0..10 {
+            round();
+        }
 
-        assert!(vote_response.vote_granted);
         {
             let tmp_server = server.lock().unwrap();
-            assert_eq!(vote_response.term, tmp_server.term + 1);
-            assert_eq!(
-                tmp_server.voted_for.as_ref().unwrap().id,
-                candidate_id.to_string()
-            );
+            let stats = tmp_server.peer_stats();
+            let lagging = &stats[0..3];
+
+            // Ten rounds of 1000 bytes shared by three: about 33 entries
+            // of 100 bytes each, and no one much ahead of the others.
+            for stats in lagging {
+                assert!(stats.catching_up);
+                assert!(stats.match_index >= 30 && stats.match_index <= 35);
+                assert_eq!(stats.catch_up_budget, 10_000);
+            }
+            let max = lagging.iter().map(|s| s.match_index).max().unwrap();
+            let min = lagging.iter().map(|s| s.match_index).min().unwrap();
+            assert!(max - min <= 1);
+
+            assert!(stats[3..].iter().all(|s| !s.catching_up));
         }
 
-        // Now the server has already voted for that term
-        let new_candidate_id = "server_3";
+        // A live proposal commits right away through the followers that
+        // are up to date.
+        let index = {
+            let mut tmp_server = server.lock().unwrap();
+            tmp_server.propose(vec![1; 100]).unwrap()
+        };
+        round();
+        round();
+        assert_eq!(server.lock().unwrap().commit_index, index);
+    }
 
-        let vote_request = VoteRequest {
-            candidate_id: new_candidate_id.to_string(),
-            term: 1,
+    #[test]
+    fn raft_leader_without_a_configuration_replicates_to_its_peers() {
+        // as in the demo: the peers it was started with, no bootstrap
+        let mut leader = build_server();
+        leader.term = Term(1);
+        leader.state = State::CANDIDATE;
+        leader.become_leader();
+
+        let mut peer_ids: Vec<&String> = leader.progress.keys().collect();
+        peer_ids.sort();
+        assert_eq!(peer_ids, vec!["0", "1"]);
+        let requests = prepare_append_entries(&mut leader);
+        assert_eq!(requests.len(), 2);
+
+        // the no-op commits once one of them has it
+        advance_commit_index(&mut leader);
+        assert_eq!(leader.commit_index, 0);
+        handle_append_entries_response(
+            &mut leader,
+            AppendEntriesResponse {
+                term: Term(1),
+                peer_id: "0".to_string(),
+                success: true,
+                match_index: 1,
+                conflict_term: None,
+                conflict_index: 0,
+                last_applied: 0,
+                election_priority: 0,
+            },
+        );
+        advance_commit_index(&mut leader);
+        assert_eq!(leader.commit_index, 1);
+    }
+
+    #[test]
+    fn raft_leader_throttles_on_a_lagging_state_machine() {
+        let mut leader = build_server();
+        leader.config.max_apply_lag = 4;
+        leader.config.apply_lag_policy = ApplyLagPolicy::Throttle;
+        leader.term = Term(1);
+        leader
+            .bootstrap(vec![
+                build_peer("server_2", 9091),
+                build_peer("server_3", 9092),
+            ])
+            .unwrap();
+        leader.state = State::CANDIDATE;
+        leader.become_leader();
+
+        for _ in 0..10 {
+            leader.propose(vec![1]).unwrap();
+        }
+        leader.commit_index = leader.last_log_index();
+        leader.apply_committed();
+
+        let response = |peer_id: &str, last_applied: u64| AppendEntriesResponse {
+            term: Term(1),
+            peer_id: peer_id.to_string(),
+            success: true,
+            match_index: 11,
+            conflict_term: None,
+            conflict_index: 0,
+            last_applied: last_applied,
+            election_priority: 0,
         };
 
-        let vote_response = handle_vote_request(Arc::clone(&server), vote_request);
+        // server_3 holds every committed entry but applied only two
+        handle_append_entries_response(&mut leader, response("server_2", 11));
+        handle_append_entries_response(&mut leader, response("server_3", 2));
+        assert_eq!(leader.metrics().apply_gap, 0);
+        assert_eq!(
+            leader.metrics().apply_lag_mode,
+            Some(ApplyLagPolicy::Throttle)
+        );
+        assert_eq!(
+            leader.propose(vec![1]),
+            Err(ProposeError::ApplyLag { gap: 9 })
+        );
+        assert!(leader
+            .propose_with_priority(vec![1], Priority::Control)
+            .is_ok());
 
-        assert!(!vote_response.vote_granted);
-        {
-            let tmp_server = server.lock().unwrap();
-            assert_ne!(
-                tmp_server.voted_for.as_ref().unwrap().id,
-                new_candidate_id.to_string()
-            );
-            assert_eq!(vote_response.term, tmp_server.term + 1);
+        // proposals resume once it caught up
+        handle_append_entries_response(&mut leader, response("server_3", 8));
+        assert_eq!(leader.metrics().apply_lag_mode, None);
+        assert!(leader.propose(vec![1]).is_ok());
+    }
+
+    #[test]
+    fn raft_proposals_resume_once_followers_catch_up() {
+        let servers = three_server_leader(|config| config.max_uncommitted_bytes = 30);
+
+        // With the followers paused nothing commits, not even the
+        // configuration or the leader's no-op, and the leader stops taking
+        // proposals once 30 bytes are waiting.
+        let leader_rpc = LoopbackRpc::new(servers[1..].iter().map(Arc::clone).collect());
+        leader_rpc.paused.set(true);
+        for _ in 0..3 {
+            servers[0].lock().unwrap().propose(vec![0; 10]).unwrap();
+            replicate_log(Arc::clone(&servers[0]), &leader_rpc);
         }
+        assert_eq!(
+            servers[0].lock().unwrap().propose(vec![0; 10]),
+            Err(ProposeError::Backpressure {
+                uncommitted: 5,
+                uncommitted_bytes: 30
+            })
+        );
 
-        // When the server did not vote yet, but the candidate's term is the same
-        // as the current server.
-        server.lock().unwrap().voted_for = None;
+        // Once they are back and the lost requests are retried, the
+        // backlog commits and proposals resume.
+        leader_rpc.paused.set(false);
+        for progress in servers[0].lock().unwrap().progress.values_mut() {
+            progress.retry();
+        }
+        replicate_log(Arc::clone(&servers[0]), &leader_rpc);
+        replicate_log(Arc::clone(&servers[0]), &leader_rpc);
+        assert_eq!(servers[0].lock().unwrap().commit_index, 5);
+        assert!(servers[0].lock().unwrap().propose(vec![0; 10]).is_ok());
+    }
 
-        let another_candidate_id = "server_4";
+    #[test]
+    fn raft_warns_before_pushing_back() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observer = {
+            let events = Arc::clone(&events);
+            Observer::new(move |event| events.lock().unwrap().push(event))
+        };
+        let warnings = || {
+            events
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|e| matches!(e, RaftEvent::ApproachingLimit { .. }))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
 
-        let vote_request = VoteRequest {
-            candidate_id: another_candidate_id.to_string(),
-            term: server.lock().unwrap().term,
+        let servers = three_server_leader(|config| {
+            config.max_uncommitted_entries = 10;
+            config.observer = Some(observer);
+        });
+
+        // The followers stall: the warning comes at 8 uncommitted entries,
+        // before the first proposal is refused at 10.
+        let leader_rpc = LoopbackRpc::new(servers[1..].iter().map(Arc::clone).collect());
+        leader_rpc.paused.set(true);
+        loop {
+            let proposed = servers[0].lock().unwrap().propose(vec![0; 10]);
+            deliver_events(&servers[0]);
+
+            if let Err(error) = proposed {
+                assert!(matches!(error, ProposeError::Backpressure { .. }));
+                break;
+            }
+            replicate_log(Arc::clone(&servers[0]), &leader_rpc);
+        }
+        assert_eq!(
+            warnings(),
+            vec![RaftEvent::ApproachingLimit {
+                which: Limit::UncommittedEntries,
+                current: 8,
+                limit: 10,
+            }]
+        );
+        assert!(servers[0]
+            .lock()
+            .unwrap()
+            .approaching_limits()
+            .contains(&Limit::UncommittedEntries));
+
+        // Once they catch up the condition clears.
+        leader_rpc.paused.set(false);
+        for progress in servers[0].lock().unwrap().progress.values_mut() {
+            progress.retry();
+        }
+        replicate_log(Arc::clone(&servers[0]), &leader_rpc);
+        replicate_log(Arc::clone(&servers[0]), &leader_rpc);
+        assert!(servers[0].lock().unwrap().approaching_limits().is_empty());
+
+        // Staying below the threshold afterwards reports nothing more.
+        leader_rpc.paused.set(true);
+        for _ in 0..7 {
+            servers[0].lock().unwrap().propose(vec![0; 10]).unwrap();
+            replicate_log(Arc::clone(&servers[0]), &leader_rpc);
+        }
+        deliver_events(&servers[0]);
+
+        assert_eq!(warnings().len(), 1);
+    }
+
+    #[test]
+    fn raft_proposals_are_replicated_in_batches() {
+        let servers = three_server_leader(|config| config.max_entries_per_append = 16);
+        let applied = servers[0].lock().unwrap().subscribe_applied();
+
+        // Proposed faster than they can be replicated one by one.
+        let indexes: Vec<u64> = (0..40)
+            .map(|i| servers[0].lock().unwrap().propose(vec![i]).unwrap())
+            .collect();
+
+        // A probe, then three batches of up to 16 entries per follower in
+        // a single round, and a last round to read the acknowledgements.
+        let leader_rpc = LoopbackRpc::new(servers[1..].iter().map(Arc::clone).collect());
+        let mut rounds = 0;
+        while servers[0].lock().unwrap().commit_index < indexes[39] {
+            replicate_log(Arc::clone(&servers[0]), &leader_rpc);
+            rounds += 1;
+            assert!(rounds <= 3, "not committed after {} rounds", rounds);
+        }
+
+        servers[0].lock().unwrap().apply_committed();
+        let data: Vec<Vec<u8>> = applied
+            .try_iter()
+            .map(|(_, entry)| match entry {
+                LogEntry::Command { data, .. } => data,
+                entry => panic!("applied {:?}", entry),
+            })
+            .collect();
+        assert_eq!(data, (0..40).map(|i| vec![i]).collect::<Vec<_>>());
+        for follower in servers[1..].iter() {
+            assert_eq!(follower.lock().unwrap().last_log_index(), indexes[39]);
+        }
+    }
+
+    #[test]
+    fn raft_control_proposals_bypass_backpressure() {
+        let servers = three_server_leader(|config| {
+            config.max_uncommitted_entries = 8;
+            config.max_entries_per_append = 2;
+            config.max_inflight_append_entries = 1;
+        });
+
+        // Fill the log with bulk proposals until they are pushed back,
+        // after the configuration and the leader's no-op.
+        let control_index = {
+            let mut leader = servers[0].lock().unwrap();
+            while leader.propose(vec![0; 10]).is_ok() {}
+
+            assert_eq!(
+                leader.propose(vec![0; 10]),
+                Err(ProposeError::Backpressure {
+                    uncommitted: 8,
+                    uncommitted_bytes: 60
+                })
+            );
+
+            leader
+                .propose_with_priority(vec![1], Priority::Control)
+                .unwrap()
         };
+        assert_eq!(control_index, 9);
 
-        let vote_response = handle_vote_request(Arc::clone(&server), vote_request);
+        // One AppendEntries of two entries per round and per follower: a
+        // probe, five batches for the nine entries, and a last round to
+        // read the acknowledgements. The backlog ahead of the control
+        // entry is bounded, so so is its wait.
+        let leader_rpc = LoopbackRpc::new(servers[1..].iter().map(Arc::clone).collect());
+        let mut rounds = 0;
+        while servers[0].lock().unwrap().commit_index < control_index {
+            replicate_log(Arc::clone(&servers[0]), &leader_rpc);
+            rounds += 1;
+            assert!(rounds <= 7, "not committed after {} rounds", rounds);
+        }
 
-        {
-            let tmp_server = server.lock().unwrap();
-            assert!(tmp_server.voted_for.as_ref().is_none());
-            assert_eq!(vote_response.term, tmp_server.term);
-            assert!(!vote_response.vote_granted);
+        let applied = servers[1].lock().unwrap().log.entry_at(9).unwrap();
+        assert_eq!(
+            applied,
+            LogEntry::Command {
+                term: Term(1),
+                data: vec![1]
+            }
+        );
+    }
+
+    fn command(term: Term) -> LogEntry {
+        LogEntry::Command {
+            term: term,
+            data: vec![term.0 as u8],
         }
     }
 
     fn build_server() -> Server {
         let config = ServerConfig {
-            timeout: Duration::new(1, 0),
+            election_timeout_min: Duration::new(1, 0),
+            election_timeout_max: Duration::new(1, 0),
+            heartbeat_interval: Duration::from_millis(200),
+            ..ServerConfig::default()
         };
 
-        let number_of_peers = 2;
         let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
         let id = "server_1".to_string();
 
-        Server::new(config, number_of_peers, address, id)
+        Server::new(config, create_peers(2), address, id).unwrap()
+    }
+
+    /// `server_1` to `server_3`, the first of them bootstrapped and leading
+    /// in term 1 with its config changed by `tweak`. Nothing is replicated
+    /// yet.
+    fn three_server_leader(tweak: impl FnOnce(&mut ServerConfig)) -> Vec<Arc<Mutex<Server>>> {
+        let servers: Vec<Arc<Mutex<Server>>> = vec!["server_1", "server_2", "server_3"]
+            .into_iter()
+            .map(|id| {
+                let mut server = build_server();
+                server.id = id.to_string();
+                Arc::new(Mutex::new(server))
+            })
+            .collect();
+
+        {
+            let mut leader = servers[0].lock().unwrap();
+            tweak(&mut leader.config);
+            leader.term = Term(1);
+            leader
+                .bootstrap(vec![
+                    build_peer("server_2", 9091),
+                    build_peer("server_3", 9092),
+                ])
+                .unwrap();
+            leader.state = State::CANDIDATE;
+            leader.become_leader();
+        }
+
+        servers
     }
 
     fn create_peers(n: usize) -> Vec<Peer> {
@@ -411,7 +5741,8 @@ mod tests {
         for i in 0..n {
             peers.push(Peer {
                 id: i.to_string(),
-                address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090),
+                address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9091 + i as u16),
+                kind: NodeKind::Voter,
             });
         }
 
@@ -421,25 +5752,488 @@ mod tests {
     struct FakeRpc {
         granted_vote: bool,
         sleeps_for: Duration,
+        /// Advanced by `sleeps_for` instead of sleeping, when set.
+        clock: Option<ManualClock>,
         peers: Vec<Peer>,
+        /// The term each peer answers with, the request's when missing. An
+        /// earlier one stands for a grant left over from a past election.
+        voter_terms: Vec<u64>,
     }
 
     impl RpcClient for FakeRpc {
-        fn request_vote(&self, request: VoteRequest) -> Vec<VoteResponse> {
+        fn request_vote(&self, request: VoteRequest) -> Result<Vec<VoteResponse>, RpcError> {
             let mut response = Vec::new();
 
-            for _peer in self.peers.iter() {
+            for (i, peer) in self.peers.iter().enumerate() {
+                let term = self
+                    .voter_terms
+                    .get(i)
+                    .map(|&t| Term(t))
+                    .unwrap_or(request.term);
                 response.push(VoteResponse {
-                    term: request.term,
-                    vote_granted: self.granted_vote,
+                    term: term,
+                    // a voter in a later term does not grant
+                    vote_granted: self.granted_vote && term <= request.term,
+                    voter_id: peer.id.to_string(),
                 });
             }
-            sleep(self.sleeps_for);
-            response
+            match &self.clock {
+                Some(clock) => clock.advance(self.sleeps_for),
+                None => sleep(self.sleeps_for),
+            }
+            Ok(response)
+        }
+
+        /// There is no leader around, so every peer would vote, whoever
+        /// then gets the actual votes.
+        fn request_pre_vote(
+            &self,
+            request: PreVoteRequest,
+        ) -> Result<Vec<PreVoteResponse>, RpcError> {
+            Ok(self
+                .peers
+                .iter()
+                .map(|peer| PreVoteResponse {
+                    term: Term(request.term.0 - 1),
+                    vote_granted: true,
+                    voter_id: peer.id.to_string(),
+                })
+                .collect())
         }
 
-        fn broadcast_log_entry(&self, _log_entry: LogEntry) {
+        fn broadcast_log_entry(
+            &self,
+            _log_entry: LogEntry,
+        ) -> Result<Vec<HeartbeatResponse>, RpcError> {
             info!("broadcast");
+            Ok(Vec::new())
+        }
+
+        fn send_timeout_now(
+            &self,
+            peer_id: &str,
+            _request: TimeoutNowRequest,
+        ) -> Result<TimeoutNowResponse, RpcError> {
+            Err(RpcError::Unreachable {
+                peer_ids: vec![peer_id.to_string()],
+            })
+        }
+
+        fn send_append_entries(&self, _peer_id: &str, _request: AppendEntriesRequest) {}
+
+        fn receive_append_entries_responses(&self) -> Vec<AppendEntriesResponse> {
+            Vec::new()
+        }
+    }
+
+    /// None of the peers ever answers.
+    struct UnreachableRpc;
+
+    impl RpcClient for UnreachableRpc {
+        fn request_vote(&self, _request: VoteRequest) -> Result<Vec<VoteResponse>, RpcError> {
+            Err(RpcError::Unreachable {
+                peer_ids: vec!["server_2".to_string(), "server_3".to_string()],
+            })
+        }
+
+        fn request_pre_vote(
+            &self,
+            _request: PreVoteRequest,
+        ) -> Result<Vec<PreVoteResponse>, RpcError> {
+            Err(RpcError::Unreachable {
+                peer_ids: vec!["server_2".to_string(), "server_3".to_string()],
+            })
+        }
+
+        fn broadcast_log_entry(
+            &self,
+            _log_entry: LogEntry,
+        ) -> Result<Vec<HeartbeatResponse>, RpcError> {
+            Err(RpcError::Unreachable {
+                peer_ids: vec!["server_2".to_string(), "server_3".to_string()],
+            })
+        }
+
+        fn send_timeout_now(
+            &self,
+            peer_id: &str,
+            _request: TimeoutNowRequest,
+        ) -> Result<TimeoutNowResponse, RpcError> {
+            Err(RpcError::Unreachable {
+                peer_ids: vec![peer_id.to_string()],
+            })
+        }
+
+        fn send_append_entries(&self, _peer_id: &str, _request: AppendEntriesRequest) {}
+
+        fn receive_append_entries_responses(&self) -> Vec<AppendEntriesResponse> {
+            Vec::new()
+        }
+    }
+
+    /// The leader of the server's term makes itself heard while the
+    /// pre-vote is out, which every peer grants anyway, and so would they
+    /// the vote.
+    struct LeaderAppearsRpc {
+        server: Arc<Mutex<Server>>,
+        peers: Vec<Peer>,
+    }
+
+    impl RpcClient for LeaderAppearsRpc {
+        fn request_vote(&self, request: VoteRequest) -> Result<Vec<VoteResponse>, RpcError> {
+            Ok(self
+                .peers
+                .iter()
+                .map(|peer| VoteResponse {
+                    term: request.term,
+                    vote_granted: true,
+                    voter_id: peer.id.to_string(),
+                })
+                .collect())
+        }
+
+        fn request_pre_vote(
+            &self,
+            request: PreVoteRequest,
+        ) -> Result<Vec<PreVoteResponse>, RpcError> {
+            let log_entry = LogEntry::Heartbeat {
+                term: Term(request.term.0 - 1),
+                peer_id: "server_3".to_string(),
+            };
+            handle_log_entry(Arc::clone(&self.server), log_entry);
+
+            Ok(self
+                .peers
+                .iter()
+                .map(|peer| PreVoteResponse {
+                    term: Term(request.term.0 - 1),
+                    vote_granted: true,
+                    voter_id: peer.id.to_string(),
+                })
+                .collect())
+        }
+
+        fn broadcast_log_entry(
+            &self,
+            _log_entry: LogEntry,
+        ) -> Result<Vec<HeartbeatResponse>, RpcError> {
+            Ok(Vec::new())
+        }
+
+        fn send_timeout_now(
+            &self,
+            peer_id: &str,
+            _request: TimeoutNowRequest,
+        ) -> Result<TimeoutNowResponse, RpcError> {
+            Err(RpcError::Unreachable {
+                peer_ids: vec![peer_id.to_string()],
+            })
+        }
+
+        fn send_append_entries(&self, _peer_id: &str, _request: AppendEntriesRequest) {}
+
+        fn receive_append_entries_responses(&self) -> Vec<AppendEntriesResponse> {
+            Vec::new()
+        }
+    }
+
+    /// Peers that refuse one at a time, the leader of the term making
+    /// itself heard after the first one.
+    struct SlowVotersRpc {
+        server: Arc<Mutex<Server>>,
+        peers: Vec<Peer>,
+        answered: Cell<usize>,
+    }
+
+    impl RpcClient for SlowVotersRpc {
+        fn request_vote(&self, request: VoteRequest) -> Result<Vec<VoteResponse>, RpcError> {
+            self.request_vote_until(request, &|_| false)
+        }
+
+        fn request_vote_until(
+            &self,
+            request: VoteRequest,
+            decided: &dyn Fn(&[VoteResponse]) -> bool,
+        ) -> Result<Vec<VoteResponse>, RpcError> {
+            let mut responses = Vec::new();
+            for peer in self.peers.iter() {
+                if decided(&responses) {
+                    break;
+                }
+                responses.push(VoteResponse {
+                    term: request.term,
+                    vote_granted: false,
+                    voter_id: peer.id.to_string(),
+                });
+                self.answered.set(self.answered.get() + 1);
+
+                let log_entry = LogEntry::Heartbeat {
+                    term: request.term,
+                    peer_id: "server_3".to_string(),
+                };
+                handle_log_entry(Arc::clone(&self.server), log_entry);
+            }
+            Ok(responses)
+        }
+
+        fn request_pre_vote(
+            &self,
+            _request: PreVoteRequest,
+        ) -> Result<Vec<PreVoteResponse>, RpcError> {
+            Ok(Vec::new())
+        }
+
+        fn broadcast_log_entry(
+            &self,
+            _log_entry: LogEntry,
+        ) -> Result<Vec<HeartbeatResponse>, RpcError> {
+            Ok(Vec::new())
+        }
+
+        fn send_timeout_now(
+            &self,
+            peer_id: &str,
+            _request: TimeoutNowRequest,
+        ) -> Result<TimeoutNowResponse, RpcError> {
+            Err(RpcError::Unreachable {
+                peer_ids: vec![peer_id.to_string()],
+            })
+        }
+
+        fn send_append_entries(&self, _peer_id: &str, _request: AppendEntriesRequest) {}
+
+        fn receive_append_entries_responses(&self) -> Vec<AppendEntriesResponse> {
+            Vec::new()
+        }
+    }
+
+    /// A leader of the next term makes itself heard while the votes are
+    /// out, which every peer grants anyway.
+    struct NewLeaderRpc {
+        server: Arc<Mutex<Server>>,
+        peers: Vec<Peer>,
+        broadcasts: Cell<usize>,
+    }
+
+    impl RpcClient for NewLeaderRpc {
+        fn request_vote(&self, request: VoteRequest) -> Result<Vec<VoteResponse>, RpcError> {
+            let log_entry = LogEntry::Heartbeat {
+                term: request.term.increment(),
+                peer_id: "server_3".to_string(),
+            };
+            handle_log_entry(Arc::clone(&self.server), log_entry);
+
+            Ok(self
+                .peers
+                .iter()
+                .map(|peer| VoteResponse {
+                    term: request.term,
+                    vote_granted: true,
+                    voter_id: peer.id.to_string(),
+                })
+                .collect())
+        }
+
+        fn request_pre_vote(
+            &self,
+            request: PreVoteRequest,
+        ) -> Result<Vec<PreVoteResponse>, RpcError> {
+            Ok(self
+                .peers
+                .iter()
+                .map(|peer| PreVoteResponse {
+                    term: Term(request.term.0 - 1),
+                    vote_granted: true,
+                    voter_id: peer.id.to_string(),
+                })
+                .collect())
+        }
+
+        fn broadcast_log_entry(
+            &self,
+            _log_entry: LogEntry,
+        ) -> Result<Vec<HeartbeatResponse>, RpcError> {
+            self.broadcasts.set(self.broadcasts.get() + 1);
+            Ok(Vec::new())
+        }
+
+        fn send_timeout_now(
+            &self,
+            peer_id: &str,
+            _request: TimeoutNowRequest,
+        ) -> Result<TimeoutNowResponse, RpcError> {
+            Err(RpcError::Unreachable {
+                peer_ids: vec![peer_id.to_string()],
+            })
+        }
+
+        fn send_append_entries(&self, _peer_id: &str, _request: AppendEntriesRequest) {}
+
+        fn receive_append_entries_responses(&self) -> Vec<AppendEntriesResponse> {
+            Vec::new()
+        }
+    }
+
+    struct PipelineRpc {
+        sent: RefCell<Vec<(String, AppendEntriesRequest)>>,
+        responses: RefCell<Vec<AppendEntriesResponse>>,
+    }
+
+    impl PipelineRpc {
+        fn take_sent(&self) -> Vec<(String, AppendEntriesRequest)> {
+            self.sent.replace(Vec::new())
+        }
+
+        fn respond(&self, peer_id: &str, success: bool, match_index: u64) {
+            // A rejection claims the follower's log ends at match_index.
+            self.responses.borrow_mut().push(AppendEntriesResponse {
+                term: Term(1),
+                peer_id: peer_id.to_string(),
+                success: success,
+                match_index: match_index,
+                conflict_term: None,
+                conflict_index: match_index + 1,
+                last_applied: 0,
+                election_priority: 0,
+            });
+        }
+    }
+
+    impl RpcClient for PipelineRpc {
+        fn request_vote(&self, _request: VoteRequest) -> Result<Vec<VoteResponse>, RpcError> {
+            Ok(Vec::new())
+        }
+
+        fn request_pre_vote(
+            &self,
+            _request: PreVoteRequest,
+        ) -> Result<Vec<PreVoteResponse>, RpcError> {
+            Ok(Vec::new())
+        }
+
+        fn broadcast_log_entry(
+            &self,
+            _log_entry: LogEntry,
+        ) -> Result<Vec<HeartbeatResponse>, RpcError> {
+            Ok(Vec::new())
+        }
+
+        fn send_timeout_now(
+            &self,
+            peer_id: &str,
+            _request: TimeoutNowRequest,
+        ) -> Result<TimeoutNowResponse, RpcError> {
+            Err(RpcError::Unreachable {
+                peer_ids: vec![peer_id.to_string()],
+            })
+        }
+
+        fn send_append_entries(&self, peer_id: &str, request: AppendEntriesRequest) {
+            self.sent.borrow_mut().push((peer_id.to_string(), request));
+        }
+
+        fn receive_append_entries_responses(&self) -> Vec<AppendEntriesResponse> {
+            self.responses.replace(Vec::new())
+        }
+    }
+
+    /// Delivers every RPC straight to the handlers of the other servers.
+    struct LoopbackRpc {
+        peers: Vec<Arc<Mutex<Server>>>,
+        responses: RefCell<Vec<AppendEntriesResponse>>,
+        /// Drops every AppendEntries while set.
+        paused: Cell<bool>,
+    }
+
+    impl LoopbackRpc {
+        fn new(peers: Vec<Arc<Mutex<Server>>>) -> Self {
+            LoopbackRpc {
+                peers: peers,
+                responses: RefCell::new(Vec::new()),
+                paused: Cell::new(false),
+            }
+        }
+
+        fn peer(&self, peer_id: &str) -> Option<&Arc<Mutex<Server>>> {
+            self.peers.iter().find(|p| p.lock().unwrap().id == peer_id)
+        }
+    }
+
+    impl RpcClient for LoopbackRpc {
+        fn request_vote(&self, request: VoteRequest) -> Result<Vec<VoteResponse>, RpcError> {
+            Ok(self
+                .peers
+                .iter()
+                .map(|peer| {
+                    handle_vote_request(
+                        Arc::clone(peer),
+                        VoteRequest {
+                            term: request.term,
+                            candidate_id: request.candidate_id.to_string(),
+                            last_log_index: request.last_log_index,
+                            last_log_term: request.last_log_term,
+                            leadership_transfer: request.leadership_transfer,
+                            election_priority: request.election_priority,
+                        },
+                    )
+                })
+                .collect())
+        }
+
+        fn request_pre_vote(
+            &self,
+            request: PreVoteRequest,
+        ) -> Result<Vec<PreVoteResponse>, RpcError> {
+            Ok(self
+                .peers
+                .iter()
+                .map(|peer| {
+                    handle_pre_vote_request(
+                        Arc::clone(peer),
+                        PreVoteRequest {
+                            term: request.term,
+                            candidate_id: request.candidate_id.to_string(),
+                            last_log_index: request.last_log_index,
+                            last_log_term: request.last_log_term,
+                        },
+                    )
+                })
+                .collect())
+        }
+
+        fn broadcast_log_entry(
+            &self,
+            _log_entry: LogEntry,
+        ) -> Result<Vec<HeartbeatResponse>, RpcError> {
+            Ok(Vec::new())
+        }
+
+        fn send_timeout_now(
+            &self,
+            peer_id: &str,
+            request: TimeoutNowRequest,
+        ) -> Result<TimeoutNowResponse, RpcError> {
+            match self.peer(peer_id) {
+                Some(peer) => Ok(handle_timeout_now(Arc::clone(peer), request)),
+                None => Err(RpcError::Unreachable {
+                    peer_ids: vec![peer_id.to_string()],
+                }),
+            }
+        }
+
+        fn send_append_entries(&self, peer_id: &str, request: AppendEntriesRequest) {
+            if self.paused.get() {
+                return;
+            }
+
+            if let Some(peer) = self.peer(peer_id) {
+                let response = handle_append_entries(Arc::clone(peer), request);
+                self.responses.borrow_mut().push(response);
+            }
+        }
+
+        fn receive_append_entries_responses(&self) -> Vec<AppendEntriesResponse> {
+            self.responses.replace(Vec::new())
         }
     }
 }
@@ -1,7 +1,7 @@
+use crate::raft::core::lock_server;
 use crate::raft::tcp_rpc::{TcpRpcClient, TcpRpcServer};
-use crate::raft::types::{Peer, Server, ServerConfig};
+use crate::raft::types::{NodeKind, Peer, Server, ServerConfig};
 use log::info;
-use rand::Rng;
 use std::net::{Ipv4Addr, SocketAddrV4};
 use std::sync::Arc;
 use std::sync::Mutex;
@@ -10,71 +10,91 @@ use std::time::Duration;
 
 pub fn start_demo() {
     let mut rpc_servers = Vec::new();
-    let mut rng = rand::thread_rng();
 
     let address_1 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3300);
-    let server_1 = Arc::new(Mutex::new(Server::new(
-        ServerConfig {
-            timeout: Duration::new(rng.gen_range(2..5), 0),
-        },
-        2,
-        address_1,
-        "server_1".to_string(),
-    )));
     let address_1_peers = vec![
         Peer {
             id: "server_2".to_string(),
             address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3301),
+            kind: NodeKind::Voter,
         },
         Peer {
             id: "server_3".to_string(),
             address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3302),
+            kind: NodeKind::Voter,
         },
     ];
+    let server_1 = Arc::new(Mutex::new(
+        Server::new(
+            ServerConfig {
+                election_timeout_min: Duration::new(2, 0),
+                election_timeout_max: Duration::new(5, 0),
+                ..ServerConfig::default()
+            },
+            address_1_peers,
+            address_1,
+            "server_1".to_string(),
+        )
+        .unwrap(),
+    ));
 
     rpc_servers.push(TcpRpcServer::new(Arc::clone(&server_1), address_1));
 
     let address_2 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3301);
-    let server_2 = Arc::new(Mutex::new(Server::new(
-        ServerConfig {
-            timeout: Duration::new(rng.gen_range(3..6), 0),
-        },
-        2,
-        address_2,
-        "server_2".to_string(),
-    )));
     let address_2_peers = vec![
         Peer {
             id: "server_1".to_string(),
             address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3300),
+            kind: NodeKind::Voter,
         },
         Peer {
             id: "server_3".to_string(),
             address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3302),
+            kind: NodeKind::Voter,
         },
     ];
+    let server_2 = Arc::new(Mutex::new(
+        Server::new(
+            ServerConfig {
+                election_timeout_min: Duration::new(2, 0),
+                election_timeout_max: Duration::new(5, 0),
+                ..ServerConfig::default()
+            },
+            address_2_peers,
+            address_2,
+            "server_2".to_string(),
+        )
+        .unwrap(),
+    ));
 
     rpc_servers.push(TcpRpcServer::new(Arc::clone(&server_2), address_2));
 
     let address_3 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3302);
-    let server_3 = Arc::new(Mutex::new(Server::new(
-        ServerConfig {
-            timeout: Duration::new(rng.gen_range(4..8), 0),
-        },
-        2,
-        address_3,
-        "server_3".to_string(),
-    )));
     let address_3_peers = vec![
         Peer {
             id: "server_1".to_string(),
             address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3300),
+            kind: NodeKind::Voter,
         },
         Peer {
-            id: "server_3".to_string(),
+            id: "server_2".to_string(),
             address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3301),
+            kind: NodeKind::Voter,
         },
     ];
+    let server_3 = Arc::new(Mutex::new(
+        Server::new(
+            ServerConfig {
+                election_timeout_min: Duration::new(2, 0),
+                election_timeout_max: Duration::new(5, 0),
+                ..ServerConfig::default()
+            },
+            address_3_peers,
+            address_3,
+            "server_3".to_string(),
+        )
+        .unwrap(),
+    ));
 
     rpc_servers.push(TcpRpcServer::new(Arc::clone(&server_3), address_3));
 
@@ -89,47 +109,77 @@ pub fn start_demo() {
     let mut raft_servers_threads = Vec::new();
 
     raft_servers_threads.push(thread::spawn(move || {
-        let client = TcpRpcClient::new(&address_1_peers);
+        let peer_set = Arc::clone(lock_server(&server_1).peers());
+        let client = TcpRpcClient::new(&[])
+            .with_local_server(&Peer {
+                id: "server_1".to_string(),
+                address: address_1,
+                kind: NodeKind::Voter,
+            })
+            .with_peer_set(peer_set);
 
         {
-            let tmp_server = server_1.lock().unwrap();
+            let tmp_server = lock_server(&server_1);
             info!(
-                "The server {}, has a timeout of {} seconds.",
+                "The server {}, has a timeout of {} to {} seconds.",
                 tmp_server.id,
-                tmp_server.config.timeout.as_secs()
+                tmp_server.config.election_timeout_min.as_secs(),
+                tmp_server.config.election_timeout_max.as_secs()
             )
         }
 
-        crate::raft::core::start_server(Arc::clone(&server_1), client);
+        crate::raft::core::start_server(Arc::clone(&server_1), client)
+            .unwrap()
+            .join();
     }));
 
     raft_servers_threads.push(thread::spawn(move || {
-        let client = TcpRpcClient::new(&address_2_peers);
+        let peer_set = Arc::clone(lock_server(&server_2).peers());
+        let client = TcpRpcClient::new(&[])
+            .with_local_server(&Peer {
+                id: "server_2".to_string(),
+                address: address_2,
+                kind: NodeKind::Voter,
+            })
+            .with_peer_set(peer_set);
 
         {
-            let tmp_server = server_2.lock().unwrap();
+            let tmp_server = lock_server(&server_2);
             info!(
-                "The server {}, has a timeout of {} seconds.",
+                "The server {}, has a timeout of {} to {} seconds.",
                 tmp_server.id,
-                tmp_server.config.timeout.as_secs()
+                tmp_server.config.election_timeout_min.as_secs(),
+                tmp_server.config.election_timeout_max.as_secs()
             )
         }
-        crate::raft::core::start_server(Arc::clone(&server_2), client);
+        crate::raft::core::start_server(Arc::clone(&server_2), client)
+            .unwrap()
+            .join();
     }));
 
     raft_servers_threads.push(thread::spawn(move || {
-        let client = TcpRpcClient::new(&address_3_peers);
+        let peer_set = Arc::clone(lock_server(&server_3).peers());
+        let client = TcpRpcClient::new(&[])
+            .with_local_server(&Peer {
+                id: "server_3".to_string(),
+                address: address_3,
+                kind: NodeKind::Voter,
+            })
+            .with_peer_set(peer_set);
 
         {
-            let tmp_server = server_3.lock().unwrap();
+            let tmp_server = lock_server(&server_3);
             info!(
-                "The server {}, has a timeout of {} seconds.",
+                "The server {}, has a timeout of {} to {} seconds.",
                 tmp_server.id,
-                tmp_server.config.timeout.as_secs()
+                tmp_server.config.election_timeout_min.as_secs(),
+                tmp_server.config.election_timeout_max.as_secs()
             )
         }
 
-        crate::raft::core::start_server(Arc::clone(&server_3), client);
+        crate::raft::core::start_server(Arc::clone(&server_3), client)
+            .unwrap()
+            .join();
     }));
 
     for st in server_threads {
@@ -0,0 +1,165 @@
+use crate::raft::snapshot::SnapshotError;
+use crate::raft::state_machine::ApplyError;
+use crate::raft::types::{ConfigError, Peer, ProposeError, RpcError, WaitError};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// What the public entry points fail with, for users who would rather
+/// match on one type than on the error of every operation. Those errors
+/// convert into it, so `?` works across them.
+#[derive(Debug)]
+pub enum RaftError {
+    /// Only the leader takes proposals. `leader` is the one this server
+    /// knows of, if any, to try instead.
+    NotLeader {
+        leader: Option<Peer>,
+    },
+    /// The leader refused the proposal for now, see `ProposeError`.
+    Rejected(ProposeError),
+    Io(io::Error),
+    Timeout,
+    Serialization(String),
+    /// None of the peers answered, so no quorum could be formed.
+    NoQuorum,
+    ApplyFailed {
+        index: u64,
+        error: ApplyError,
+    },
+    Snapshot(SnapshotError),
+    Cancelled,
+    /// The entry at `index` was applied, but what it returned is not
+    /// known here: it was not proposed on this server, or its output was
+    /// no longer kept, see `ServerConfig::max_retained_outputs`.
+    OutputUnavailable {
+        index: u64,
+    },
+    /// The server was set up wrong, see `ServerConfig::validate` and
+    /// `Server::check_peers`.
+    BadConfig(ConfigError),
+}
+
+impl fmt::Display for RaftError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RaftError::NotLeader {
+                leader: Some(leader),
+            } => {
+                write!(f, "not the leader, {} is", leader.id)
+            }
+            RaftError::NotLeader { leader: None } => write!(f, "not the leader"),
+            RaftError::Rejected(e) => write!(f, "proposal rejected: {:?}", e),
+            RaftError::Io(e) => write!(f, "{}", e),
+            RaftError::Timeout => write!(f, "timed out"),
+            RaftError::Serialization(e) => write!(f, "serialization failed: {}", e),
+            RaftError::NoQuorum => write!(f, "no quorum"),
+            RaftError::ApplyFailed { index, error } => {
+                write!(f, "entry {} failed to apply: {}", index, error.0)
+            }
+            RaftError::Snapshot(e) => write!(f, "snapshot failed: {:?}", e),
+            RaftError::Cancelled => write!(f, "cancelled"),
+            RaftError::OutputUnavailable { index } => {
+                write!(f, "the output of entry {} is not known", index)
+            }
+            RaftError::BadConfig(e) => write!(f, "bad configuration: {:?}", e),
+        }
+    }
+}
+
+impl Error for RaftError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RaftError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RaftError {
+    fn from(e: io::Error) -> Self {
+        RaftError::Io(e)
+    }
+}
+
+impl From<ConfigError> for RaftError {
+    fn from(e: ConfigError) -> Self {
+        RaftError::BadConfig(e)
+    }
+}
+
+impl From<bincode::Error> for RaftError {
+    fn from(e: bincode::Error) -> Self {
+        RaftError::Serialization(e.to_string())
+    }
+}
+
+/// Without the server at hand, the leader is unknown: `core::propose_command`
+/// fills it in.
+impl From<ProposeError> for RaftError {
+    fn from(e: ProposeError) -> Self {
+        match e {
+            ProposeError::NotLeader => RaftError::NotLeader { leader: None },
+            e => RaftError::Rejected(e),
+        }
+    }
+}
+
+impl From<WaitError> for RaftError {
+    fn from(e: WaitError) -> Self {
+        match e {
+            WaitError::Timeout { .. } => RaftError::Timeout,
+            WaitError::ApplyFailed { index, error } => RaftError::ApplyFailed {
+                index: index,
+                error: error,
+            },
+            WaitError::Cancelled => RaftError::Cancelled,
+        }
+    }
+}
+
+impl From<SnapshotError> for RaftError {
+    fn from(e: SnapshotError) -> Self {
+        match e {
+            SnapshotError::Cancelled => RaftError::Cancelled,
+            e => RaftError::Snapshot(e),
+        }
+    }
+}
+
+impl From<RpcError> for RaftError {
+    fn from(e: RpcError) -> Self {
+        match e {
+            RpcError::Unreachable { .. } => RaftError::NoQuorum,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raft_error_from_operation_errors() {
+        assert!(matches!(
+            RaftError::from(WaitError::Timeout { last_applied: 3 }),
+            RaftError::Timeout
+        ));
+        assert!(matches!(
+            RaftError::from(ProposeError::EntryTooLarge { size: 2, max: 1 }),
+            RaftError::Rejected(ProposeError::EntryTooLarge { size: 2, max: 1 })
+        ));
+        assert!(matches!(
+            RaftError::from(RpcError::Unreachable {
+                peer_ids: vec!["server_2".to_string()]
+            }),
+            RaftError::NoQuorum
+        ));
+
+        let error = RaftError::from(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(error.source().is_some());
+        assert_eq!(
+            RaftError::NotLeader { leader: None }.to_string(),
+            "not the leader"
+        );
+    }
+}
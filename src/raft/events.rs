@@ -0,0 +1,68 @@
+use crate::raft::state_machine::ApplyError;
+use crate::raft::types::{Limit, Term};
+use std::fmt;
+use std::sync::Arc;
+
+/// A change in a server's role or progress, reported to the observer set
+/// in `ServerConfig`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RaftEvent {
+    BecameCandidate {
+        term: Term,
+    },
+    BecameLeader {
+        term: Term,
+    },
+    /// A leader or candidate stepped down, or a follower moved on to a
+    /// new term. `leader_id` is set when the new leader is known.
+    BecameFollower {
+        term: Term,
+        leader_id: Option<String>,
+    },
+    VoteGranted {
+        term: Term,
+        candidate_id: String,
+    },
+    VoteDenied {
+        term: Term,
+        candidate_id: String,
+    },
+    CommitAdvanced {
+        term: Term,
+        commit_index: u64,
+    },
+    /// Applying stopped at `index`, see `Server::apply_committed`.
+    ApplyFailed {
+        index: u64,
+        error: ApplyError,
+    },
+    /// `current` crossed the soft threshold of `limit`, see
+    /// `Server::check_soft_limits`.
+    ApproachingLimit {
+        which: Limit,
+        current: u64,
+        limit: u64,
+    },
+}
+
+/// Called with every event of a server. Events are queued while the
+/// server is locked and delivered once it has been released, so the
+/// observer may lock the server itself.
+#[derive(Clone)]
+pub struct Observer(Arc<dyn Fn(RaftEvent) + Send + Sync>);
+
+impl Observer {
+    pub fn new(observer: impl Fn(RaftEvent) + Send + Sync + 'static) -> Self {
+        Observer(Arc::new(observer))
+    }
+
+    pub fn notify(&self, event: RaftEvent) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Observer")
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::mpsc::channel;
+use std::thread;
+
+/// One request to one peer, `None` when it got no answer.
+pub type Call<T> = Box<dyn FnOnce() -> Option<T> + Send>;
+
+/// Makes every call on its own thread, and collects the answers in the
+/// order they arrive until `decided` says that those so far are enough.
+/// The calls still running are not waited for: they finish on their own
+/// thread, and their answers are dropped.
+pub fn gather<T: Send + 'static>(calls: Vec<Call<T>>, decided: &dyn Fn(&[T]) -> bool) -> Vec<T> {
+    let (sender, receiver) = channel();
+
+    for call in calls {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            // the receiver is gone once the caller has decided
+            let _ = sender.send(call());
+        });
+    }
+    drop(sender);
+
+    let mut answers = Vec::new();
+    for answer in receiver.iter().flatten() {
+        answers.push(answer);
+        if decided(&answers) {
+            break;
+        }
+    }
+
+    answers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn answer_after(answer: u32, delay: Duration) -> Call<u32> {
+        Box::new(move || {
+            thread::sleep(delay);
+            Some(answer)
+        })
+    }
+
+    #[test]
+    fn fanout_gathers_every_answer_unless_decided() {
+        let calls = vec![
+            answer_after(1, Duration::ZERO),
+            Box::new(|| None) as Call<u32>,
+            answer_after(3, Duration::from_millis(20)),
+        ];
+
+        let mut answers = gather(calls, &|_| false);
+        answers.sort();
+        assert_eq!(answers, vec![1, 3]);
+    }
+
+    #[test]
+    fn fanout_does_not_wait_once_decided() {
+        let calls = vec![
+            answer_after(1, Duration::ZERO),
+            answer_after(2, Duration::from_secs(5)),
+            answer_after(3, Duration::ZERO),
+        ];
+
+        let started = Instant::now();
+        let mut answers = gather(calls, &|answers| answers.len() == 2);
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        answers.sort();
+        assert_eq!(answers, vec![1, 3]);
+    }
+}
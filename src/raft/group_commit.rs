@@ -0,0 +1,482 @@
+use crate::raft::types::LogEntry;
+use log::info;
+use std::io;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Where the log is made durable. `write` may buffer, only what was
+/// written before a successful `sync` is guaranteed to survive a crash.
+pub trait LogWriter: Send + 'static {
+    fn write(&mut self, entries: &[LogEntry]) -> io::Result<()>;
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// When the log is synced, trading durability for throughput.
+///
+/// A server must not forget an entry it acknowledged: the leader counts
+/// it towards a majority, and once a majority lost an entry that was
+/// committed, it may be committed again with a different command. Only
+/// `Always` rules that out. With the others, a power loss or a crash of
+/// the machine, not merely of the process, may lose entries that were
+/// already acknowledged, so they only suit data that can be lost, or
+/// servers in different failure domains that rarely go down together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Every batch is synced before it is acknowledged.
+    Always,
+    /// Batches are acknowledged as soon as they are written, and whatever
+    /// was written since is synced at most this many milliseconds later:
+    /// a crash loses up to that long of acknowledged entries.
+    EveryMillis(u64),
+    /// The log is never synced: the operating system writes it back when
+    /// it sees fit, and a crash may lose any of it.
+    Never,
+}
+
+impl SyncPolicy {
+    /// How long written entries may wait for a sync, None if forever.
+    pub fn max_unsynced(self) -> Option<Duration> {
+        match self {
+            SyncPolicy::Always => Some(Duration::ZERO),
+            SyncPolicy::EveryMillis(millis) => Some(Duration::from_millis(millis)),
+            SyncPolicy::Never => None,
+        }
+    }
+}
+
+/// Group commit: appends arriving within `window` of each other, up to
+/// `max_entries`, are written together and, following `sync_policy`,
+/// made durable with a single sync. Whatever queued up while a batch was
+/// being written goes in the next one, so appends share a sync even
+/// without a window. Under `SyncPolicy::Always` nobody hears back about an
+/// entry before that sync succeeded. The entries of one `submit` always go
+/// in the same batch, however many they are.
+///
+/// After a failed write or sync, what the writer holds is unknown, so
+/// the batch fails and so does every later append.
+#[derive(Debug)]
+pub struct GroupCommit {
+    /// None once stopped.
+    queue: Mutex<Option<Queue>>,
+    acks: Acks,
+    writer_thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Queue {
+    sender: Sender<Vec<LogEntry>>,
+    /// The index of the next entry submitted.
+    next_index: u64,
+}
+
+/// How far a `GroupCommit` has written the log, and synced it as the sync
+/// policy says. A clone can be waited on without holding on to the log.
+#[derive(Clone, Debug)]
+pub struct Acks(Arc<(Mutex<Acked>, Condvar)>);
+
+#[derive(Debug)]
+struct Acked {
+    index: u64,
+    /// Why the writer stopped, if it did: nothing more is acknowledged.
+    error: Option<(io::ErrorKind, String)>,
+}
+
+impl Acks {
+    fn new(index: u64) -> Self {
+        Acks(Arc::new((
+            Mutex::new(Acked {
+                index: index,
+                error: None,
+            }),
+            Condvar::new(),
+        )))
+    }
+
+    /// The index of the last entry acknowledged.
+    pub fn index(&self) -> u64 {
+        self.0 .0.lock().unwrap().index
+    }
+
+    /// Why nothing more will be acknowledged, if that is so.
+    pub fn error(&self) -> Option<io::Error> {
+        let acked = self.0 .0.lock().unwrap();
+        acked
+            .error
+            .as_ref()
+            .map(|(kind, message)| io::Error::new(*kind, message.clone()))
+    }
+
+    /// Blocks until the entry at `index` is acknowledged, and fails if it
+    /// never will be.
+    pub fn wait(&self, index: u64) -> io::Result<()> {
+        let (acked, changed) = &*self.0;
+        let mut acked = acked.lock().unwrap();
+
+        loop {
+            if acked.index >= index {
+                return Ok(());
+            }
+            if let Some((kind, message)) = &acked.error {
+                return Err(io::Error::new(*kind, message.clone()));
+            }
+            acked = changed.wait(acked).unwrap();
+        }
+    }
+
+    fn set_index(&self, index: u64) {
+        let (acked, changed) = &*self.0;
+        acked.lock().unwrap().index = index;
+        changed.notify_all();
+    }
+
+    /// Keeps the first error, the one the writer stopped on.
+    fn stop(&self, e: &io::Error) {
+        let (acked, changed) = &*self.0;
+        acked
+            .lock()
+            .unwrap()
+            .error
+            .get_or_insert_with(|| (e.kind(), e.to_string()));
+        changed.notify_all();
+    }
+}
+
+impl GroupCommit {
+    /// Starts the thread writing to `writer`. The first entry appended
+    /// gets `next_index`.
+    pub fn new(
+        writer: impl LogWriter,
+        next_index: u64,
+        window: Duration,
+        max_entries: usize,
+        sync_policy: SyncPolicy,
+    ) -> Self {
+        let (sender, receiver) = channel();
+        let acks = Acks::new(next_index - 1);
+
+        let writer_thread = {
+            let acks = acks.clone();
+            thread::spawn(move || {
+                write_batches(
+                    writer,
+                    receiver,
+                    acks,
+                    window,
+                    max_entries.max(1),
+                    sync_policy,
+                )
+            })
+        };
+
+        GroupCommit {
+            queue: Mutex::new(Some(Queue {
+                sender: sender,
+                next_index: next_index,
+            })),
+            acks: acks,
+            writer_thread: Some(writer_thread),
+        }
+    }
+
+    /// Blocks until the entry is written, and synced if the sync policy
+    /// says so, and returns its index.
+    pub fn append(&self, entry: LogEntry) -> io::Result<u64> {
+        let index = self.submit(vec![entry])?;
+        self.acks.wait(index)?;
+
+        Ok(index)
+    }
+
+    /// Hands the entries over to be written, one after the other, and
+    /// returns the index of the last one right away: `acks` tells when it
+    /// is written. Entries get their indexes in the order they are
+    /// submitted.
+    pub fn submit(&self, entries: Vec<LogEntry>) -> io::Result<u64> {
+        if let Some(e) = self.acks.error() {
+            return Err(e);
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        let queue = queue.as_mut().ok_or_else(closed)?;
+        let last_index = queue.next_index + entries.len() as u64 - 1;
+        queue.sender.send(entries).map_err(|_| closed())?;
+        queue.next_index = last_index + 1;
+
+        Ok(last_index)
+    }
+
+    pub fn acks(&self) -> Acks {
+        self.acks.clone()
+    }
+
+    /// The entries from `index` on were removed from the writer: the next
+    /// one submitted gets `index`. Only once everything submitted was
+    /// acknowledged.
+    pub fn truncate_from(&self, index: u64) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(queue) = queue.as_mut() {
+            queue.next_index = index;
+        }
+        self.acks.set_index(index - 1);
+    }
+}
+
+impl Drop for GroupCommit {
+    /// Waits for the appends already submitted to be written, and for a
+    /// last sync unless the policy is `SyncPolicy::Never`.
+    fn drop(&mut self) {
+        self.queue.lock().unwrap().take();
+
+        if let Some(writer_thread) = self.writer_thread.take() {
+            let _ = writer_thread.join();
+        }
+        self.acks.stop(&closed());
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the log writer has stopped")
+}
+
+fn write_batches(
+    mut writer: impl LogWriter,
+    receiver: Receiver<Vec<LogEntry>>,
+    acks: Acks,
+    window: Duration,
+    max_entries: usize,
+    sync_policy: SyncPolicy,
+) {
+    let max_unsynced = sync_policy.max_unsynced();
+    let mut last_sync = Instant::now();
+    let mut unsynced = false;
+
+    loop {
+        // Entries written but not synced yet are synced on a timer, if no
+        // batch comes along first.
+        let mut entries = match max_unsynced.filter(|_| unsynced) {
+            Some(max_unsynced) => {
+                let timeout = (last_sync + max_unsynced).saturating_duration_since(Instant::now());
+                match receiver.recv_timeout(timeout) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        if let Err(e) = writer.sync() {
+                            info!("Could not sync the log, no longer writing it: {}", e);
+                            acks.stop(&e);
+                            return;
+                        }
+                        if e == RecvTimeoutError::Disconnected {
+                            return;
+                        }
+                        last_sync = Instant::now();
+                        unsynced = false;
+                        continue;
+                    }
+                }
+            }
+            None => match receiver.recv() {
+                Ok(entries) => entries,
+                Err(_) => return,
+            },
+        };
+        let deadline = Instant::now() + window;
+
+        while entries.len() < max_entries {
+            let more = match receiver.try_recv() {
+                Ok(more) => more,
+                Err(_) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+
+                    match receiver.recv_timeout(deadline - now) {
+                        Ok(more) => more,
+                        Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                            break
+                        }
+                    }
+                }
+            };
+            entries.extend(more);
+        }
+
+        let now = Instant::now();
+        let sync = max_unsynced.is_some_and(|max_unsynced| now >= last_sync + max_unsynced);
+        let result = writer
+            .write(&entries)
+            .and_then(|_| if sync { writer.sync() } else { Ok(()) });
+
+        match result {
+            Ok(()) => {
+                if sync {
+                    last_sync = now;
+                }
+                unsynced = !sync;
+                acks.set_index(acks.index() + entries.len() as u64);
+            }
+            Err(e) => {
+                info!(
+                    "Could not make {} entries durable, no longer writing the log: {}",
+                    entries.len(),
+                    e
+                );
+                acks.stop(&e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::types::Term;
+
+    /// Keeps the log in memory, and counts the syncs.
+    #[derive(Clone, Default)]
+    struct FakeWriter {
+        log: Arc<Mutex<FakeLog>>,
+    }
+
+    #[derive(Default)]
+    struct FakeLog {
+        written: Vec<LogEntry>,
+        durable: usize,
+        syncs: usize,
+        fail: bool,
+    }
+
+    impl LogWriter for FakeWriter {
+        fn write(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+            self.log.lock().unwrap().written.extend_from_slice(entries);
+            Ok(())
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            let mut log = self.log.lock().unwrap();
+            if log.fail {
+                return Err(io::Error::other("disk full"));
+            }
+
+            log.syncs += 1;
+            log.durable = log.written.len();
+            Ok(())
+        }
+    }
+
+    fn entry(data: u8) -> LogEntry {
+        LogEntry::Command {
+            term: Term(1),
+            data: vec![data],
+        }
+    }
+
+    #[test]
+    fn group_commit_batches_syncs() {
+        let writer = FakeWriter::default();
+        let log = Arc::clone(&writer.log);
+        let group_commit = GroupCommit::new(
+            writer,
+            1,
+            Duration::from_millis(50),
+            1000,
+            SyncPolicy::Always,
+        );
+
+        thread::scope(|s| {
+            for data in 0..16 {
+                let group_commit = &group_commit;
+                let log = &log;
+                s.spawn(move || {
+                    let index = group_commit.append(entry(data)).unwrap();
+
+                    // durable before being acknowledged, at its index
+                    let log = log.lock().unwrap();
+                    assert!(log.durable >= index as usize);
+                    assert_eq!(log.written[index as usize - 1], entry(data));
+                });
+            }
+        });
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.written.len(), 16);
+        assert!(log.syncs < 16, "{} syncs", log.syncs);
+    }
+
+    #[test]
+    fn group_commit_limits_batches() {
+        let writer = FakeWriter::default();
+        let log = Arc::clone(&writer.log);
+        let group_commit =
+            GroupCommit::new(writer, 10, Duration::from_secs(60), 4, SyncPolicy::Always);
+
+        let started = Instant::now();
+        let mut indexes: Vec<u64> = thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|data| {
+                    let group_commit = &group_commit;
+                    s.spawn(move || group_commit.append(entry(data)).unwrap())
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        // full batches do not wait for the window to close
+        assert!(started.elapsed() < Duration::from_secs(10));
+        indexes.sort_unstable();
+        assert_eq!(indexes, (10..18).collect::<Vec<u64>>());
+        assert_eq!(log.lock().unwrap().syncs, 2);
+    }
+
+    #[test]
+    fn group_commit_failed_sync_acknowledges_nothing() {
+        let writer = FakeWriter::default();
+        let log = Arc::clone(&writer.log);
+        log.lock().unwrap().fail = true;
+        let group_commit =
+            GroupCommit::new(writer, 1, Duration::from_millis(1), 16, SyncPolicy::Always);
+
+        assert!(group_commit.append(entry(1)).is_err());
+        assert_eq!(log.lock().unwrap().durable, 0);
+
+        // even once the disk would work again
+        log.lock().unwrap().fail = false;
+        assert!(group_commit.append(entry(2)).is_err());
+    }
+
+    #[test]
+    fn group_commit_syncs_on_a_timer() {
+        // acknowledged unsynced, then synced once on shutdown
+        let writer = FakeWriter::default();
+        let log = Arc::clone(&writer.log);
+        let group_commit = GroupCommit::new(
+            writer,
+            1,
+            Duration::ZERO,
+            16,
+            SyncPolicy::EveryMillis(60_000),
+        );
+        for data in 1..=5 {
+            group_commit.append(entry(data)).unwrap();
+        }
+        assert_eq!(log.lock().unwrap().syncs, 0);
+        drop(group_commit);
+        assert_eq!(log.lock().unwrap().syncs, 1);
+        assert_eq!(log.lock().unwrap().durable, 5);
+
+        // synced once the interval is up, without another append
+        let writer = FakeWriter::default();
+        let log = Arc::clone(&writer.log);
+        let group_commit =
+            GroupCommit::new(writer, 1, Duration::ZERO, 16, SyncPolicy::EveryMillis(10));
+        group_commit.append(entry(1)).unwrap();
+        let started = Instant::now();
+        while log.lock().unwrap().durable < 1 {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
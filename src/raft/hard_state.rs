@@ -0,0 +1,75 @@
+use crate::raft::types::{Peer, Term};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+
+const HARD_STATE_FILE: &str = "hard_state.bin";
+
+/// What a server must not forget across a restart: a server that forgot
+/// its vote could grant another one in the same term.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct HardState {
+    pub term: Term,
+    pub voted_for: Option<Peer>,
+}
+
+/// Reads the hard state persisted in `data_dir`, if any.
+pub fn load(data_dir: &Path) -> io::Result<Option<HardState>> {
+    let bytes = match fs::read(data_dir.join(HARD_STATE_FILE)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    bincode::deserialize(&bytes)
+        .map(Some)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Replaces the hard state in `data_dir` atomically, and only returns once
+/// it is on disk.
+pub fn persist(data_dir: &Path, hard_state: &HardState) -> io::Result<()> {
+    let bytes =
+        bincode::serialize(hard_state).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+    fs::create_dir_all(data_dir)?;
+    let tmp = data_dir.join(format!("{}.tmp", HARD_STATE_FILE));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, data_dir.join(HARD_STATE_FILE))?;
+
+    // the rename itself must survive a crash too
+    File::open(data_dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::types::NodeKind;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn hard_state_round_trips() {
+        let data_dir =
+            std::env::temp_dir().join(format!("rsraft-hard-state-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+
+        assert_eq!(load(&data_dir).unwrap(), None);
+
+        let hard_state = HardState {
+            term: Term(3),
+            voted_for: Some(Peer {
+                id: "server_2".to_string(),
+                address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9091),
+                kind: NodeKind::Voter,
+            }),
+        };
+        persist(&data_dir, &hard_state).unwrap();
+        assert_eq!(load(&data_dir).unwrap(), Some(hard_state));
+
+        persist(&data_dir, &HardState::default()).unwrap();
+        assert_eq!(load(&data_dir).unwrap(), Some(HardState::default()));
+    }
+}
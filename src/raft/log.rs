@@ -0,0 +1,507 @@
+use crate::raft::group_commit::{Acks, GroupCommit, LogWriter, SyncPolicy};
+use crate::raft::log_storage::{LogStorage, MemLogStorage};
+use crate::raft::storage::Storage;
+use crate::raft::types::{LogEntry, Membership, Term};
+use log::info;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// The most entries written with a single sync, unless told otherwise.
+const MAX_BATCH_ENTRIES: usize = 1024;
+
+/// The replicated log, indexed from 1.
+///
+/// The entries are kept in a `LogStorage`, a `MemLogStorage` unless
+/// another one is given. What every entry needs to be looked at without
+/// reading it back, its payload size, stays in memory here, and so do
+/// configuration entries, which are few and looked at all the time.
+///
+/// Appends go through a `GroupCommit`. `append` returns once the entries
+/// are written and synced following the `SyncPolicy`; `submit` returns
+/// right away, and the entries are read from memory until they are
+/// written. Once a write or a sync failed, what was not acknowledged is
+/// dropped and every later append fails: the log can still be read, but
+/// no longer grows.
+#[derive(Debug)]
+pub struct Log {
+    storage: Arc<Mutex<Box<dyn LogStorage>>>,
+    group_commit: GroupCommit,
+    /// The entries from `first_unwritten` on, which may not be in storage
+    /// yet.
+    unwritten: VecDeque<LogEntry>,
+    first_unwritten: u64,
+    /// Of every entry, by index - 1.
+    payload_sizes: Vec<usize>,
+    configurations: BTreeMap<u64, LogEntry>,
+}
+
+impl Log {
+    /// An empty log in memory, see `MemLogStorage`. There is nothing to
+    /// sync.
+    pub fn new(max_cached: usize, spill: Option<Arc<dyn Storage>>) -> Self {
+        Log::with_storage(
+            Box::new(MemLogStorage::new(max_cached, spill)),
+            SyncPolicy::Never,
+            Duration::ZERO,
+            MAX_BATCH_ENTRIES,
+        )
+    }
+
+    /// The log held in `storage`, read through once. Appends are written
+    /// in batches, see `GroupCommit`, and synced following `sync_policy`.
+    pub fn with_storage(
+        storage: Box<dyn LogStorage>,
+        sync_policy: SyncPolicy,
+        window: Duration,
+        max_batch_entries: usize,
+    ) -> Self {
+        let next_index = storage.last_index() + 1;
+        let storage = Arc::new(Mutex::new(storage));
+        let group_commit = GroupCommit::new(
+            StorageWriter(Arc::clone(&storage)),
+            next_index,
+            window,
+            max_batch_entries,
+            sync_policy,
+        );
+        let mut log = Log {
+            storage: storage,
+            group_commit: group_commit,
+            unwritten: VecDeque::new(),
+            first_unwritten: next_index,
+            payload_sizes: Vec::new(),
+            configurations: BTreeMap::new(),
+        };
+
+        for index in 1..next_index {
+            let entry = log
+                .storage()
+                .entry(index)
+                .and_then(|entry| entry.ok_or_else(|| ErrorKind::NotFound.into()))
+                .unwrap_or_else(|e| panic!("Could not read entry {} of the log: {}", index, e));
+            log.index(index, &entry);
+        }
+
+        log
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.first_unwritten - 1 + self.unwritten.len() as u64
+    }
+
+    /// The last index written and synced following the sync policy. A
+    /// leader only counts its own log that far towards a majority.
+    pub fn durable_index(&self) -> u64 {
+        self.group_commit.acks().index().min(self.last_index())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last_index() == 0
+    }
+
+    /// How many entries are held in memory.
+    pub fn cached(&self) -> usize {
+        self.storage().cached() + self.unwritten.len()
+    }
+
+    /// The term of the entry at `index`, where index 0 is the empty prefix
+    /// of the log and always has term 0.
+    pub fn term_at(&self, index: u64) -> Option<Term> {
+        match self.unwritten_at(index) {
+            Some(entry) => Some(entry.term()),
+            None if index >= self.first_unwritten => None,
+            None => self.storage().term_at(index),
+        }
+    }
+
+    pub fn payload_size_at(&self, index: u64) -> Option<usize> {
+        match index {
+            0 => None,
+            i => self.payload_sizes.get(i as usize - 1).copied(),
+        }
+    }
+
+    /// The payload bytes of the entries after `index`.
+    pub fn payload_size_after(&self, index: u64) -> usize {
+        self.payload_sizes[(index as usize).min(self.payload_sizes.len())..]
+            .iter()
+            .sum()
+    }
+
+    /// The entry at `index`. None past the end of the log, or if it cannot
+    /// be read back.
+    pub fn entry_at(&self, index: u64) -> Option<LogEntry> {
+        if index >= self.first_unwritten {
+            return self.unwritten_at(index).cloned();
+        }
+
+        match self.storage().entry(index) {
+            Ok(entry) => entry,
+            Err(e) => {
+                info!("Could not read entry {} back from storage: {}", index, e);
+                None
+            }
+        }
+    }
+
+    /// The entries from `from` to `to` included, stopping at the end of the
+    /// log or at the first entry that cannot be read back.
+    pub fn entries(&self, from: u64, to: u64) -> Vec<LogEntry> {
+        let from = from.max(1);
+        let to = to.min(self.last_index());
+        let written = to.min(self.first_unwritten - 1);
+
+        let mut entries = if from <= written {
+            let read = self.storage().entries(from, written);
+            match read {
+                Ok(entries) => entries,
+                Err(_) => (from..=written)
+                    .map_while(|index| self.entry_at(index))
+                    .collect(),
+            }
+        } else {
+            Vec::new()
+        };
+
+        // stopped at an entry that cannot be read back
+        if from + (entries.len() as u64) <= written {
+            return entries;
+        }
+
+        entries.extend(
+            (from.max(self.first_unwritten)..=to)
+                .filter_map(|index| self.unwritten_at(index).cloned()),
+        );
+        entries
+    }
+
+    /// Appends the entry once it is durable, and returns its index.
+    pub fn append(self: &mut Self, entry: LogEntry) -> io::Result<u64> {
+        self.append_all(vec![entry])
+    }
+
+    /// Appends the entries with a single sync, and returns the index of
+    /// the last one. Either all of them are appended, or none is.
+    pub fn append_all(self: &mut Self, entries: Vec<LogEntry>) -> io::Result<u64> {
+        let index = self.submit(entries)?;
+        self.wait(index)?;
+
+        Ok(index)
+    }
+
+    /// Appends the entries without waiting for them to be written, and
+    /// returns the index of the last one. See `wait` and `acks`.
+    pub fn submit(self: &mut Self, entries: Vec<LogEntry>) -> io::Result<u64> {
+        self.settle();
+        if entries.is_empty() {
+            return Ok(self.last_index());
+        }
+
+        let last_index = self.group_commit.submit(entries.clone())?;
+        for entry in entries {
+            self.index(self.last_index() + 1, &entry);
+            self.unwritten.push_back(entry);
+        }
+        debug_assert_eq!(last_index, self.last_index());
+
+        Ok(last_index)
+    }
+
+    /// Blocks until the entry at `index` is durable. Fails if it never
+    /// will be, and then it is no longer in the log.
+    pub fn wait(self: &mut Self, index: u64) -> io::Result<()> {
+        let result = self.group_commit.acks().wait(index);
+        self.settle();
+
+        result
+    }
+
+    /// What tells when entries are durable, to wait on without the log.
+    pub fn acks(&self) -> Acks {
+        self.group_commit.acks()
+    }
+
+    /// Removes the entry at `index` and every one after it.
+    pub fn truncate_from(self: &mut Self, index: u64) -> io::Result<()> {
+        let index = index.max(1);
+        if index > self.last_index() {
+            return Ok(());
+        }
+
+        // Nothing may still be on its way to storage.
+        self.wait(self.last_index())?;
+        self.storage().truncate_from(index)?;
+        self.group_commit.truncate_from(index);
+        self.first_unwritten = index;
+        self.payload_sizes.truncate(index as usize - 1);
+        self.configurations.split_off(&index);
+
+        Ok(())
+    }
+
+    /// The latest configuration in the log, together with its index.
+    pub fn membership(&self) -> Option<(u64, &Membership)> {
+        self.configurations
+            .iter()
+            .next_back()
+            .and_then(|(index, entry)| Some((*index, entry.membership()?)))
+    }
+
+    /// The configuration entries from `from` to `to` included, with their
+    /// index, oldest first.
+    pub fn configurations(&self, from: u64, to: u64) -> impl Iterator<Item = (u64, &LogEntry)> {
+        self.configurations
+            .range(from.max(1)..)
+            .map(|(index, entry)| (*index, entry))
+            .take_while(move |(index, _)| *index <= to)
+    }
+
+    /// The configuration in place right before `index`.
+    pub fn membership_before(&self, index: u64) -> Option<&Membership> {
+        self.configurations
+            .range(..index)
+            .next_back()
+            .and_then(|(_, entry)| entry.membership())
+    }
+
+    fn storage(&self) -> MutexGuard<'_, Box<dyn LogStorage>> {
+        self.storage.lock().unwrap()
+    }
+
+    fn unwritten_at(&self, index: u64) -> Option<&LogEntry> {
+        match index.checked_sub(self.first_unwritten) {
+            Some(i) => self.unwritten.get(i as usize),
+            None => None,
+        }
+    }
+
+    /// Forgets the unwritten entries that were written since. Once the
+    /// writer stopped, the others never will be, and they go.
+    fn settle(self: &mut Self) {
+        let acks = self.group_commit.acks();
+        let acked = acks.index();
+        while self.first_unwritten <= acked && self.unwritten.pop_front().is_some() {
+            self.first_unwritten += 1;
+        }
+
+        if self.unwritten.is_empty() || acks.error().is_none() {
+            return;
+        }
+
+        let index = self.first_unwritten;
+        self.unwritten.clear();
+        self.payload_sizes.truncate(index as usize - 1);
+        self.configurations.split_off(&index);
+        // Whatever reached the storage was not acknowledged.
+        if let Err(e) = self.storage().truncate_from(index) {
+            info!("Could not truncate the log at {}: {}", index, e);
+        }
+    }
+
+    /// Keeps what is looked at without reading the entry back.
+    fn index(self: &mut Self, index: u64, entry: &LogEntry) {
+        self.payload_sizes.push(entry.payload_size());
+        if entry.membership().is_some() {
+            self.configurations.insert(index, entry.clone());
+        }
+    }
+}
+
+/// Writes the log to its storage, for the `GroupCommit`.
+struct StorageWriter(Arc<Mutex<Box<dyn LogStorage>>>);
+
+impl LogWriter for StorageWriter {
+    fn write(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        self.0.lock().unwrap().append(entries)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::storage::FileStorage;
+    use crate::raft::types::{NodeKind, Peer};
+    use std::env;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::path::PathBuf;
+    use std::process;
+
+    fn command(term: Term, n: u8) -> LogEntry {
+        LogEntry::Command {
+            term: term,
+            data: vec![n; n as usize],
+        }
+    }
+
+    fn configuration(term: Term, voters: &[&str]) -> LogEntry {
+        LogEntry::Configuration {
+            term: term,
+            membership: Membership {
+                voters: voters
+                    .iter()
+                    .map(|id| Peer {
+                        id: id.to_string(),
+                        address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090),
+                        kind: NodeKind::Voter,
+                    })
+                    .collect(),
+                learners: Vec::new(),
+            },
+        }
+    }
+
+    /// Writes to memory, but cannot sync.
+    #[derive(Debug)]
+    struct UnsyncableStorage(MemLogStorage);
+
+    impl LogStorage for UnsyncableStorage {
+        fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+            self.0.append(entries)
+        }
+
+        fn entry(&self, index: u64) -> io::Result<Option<LogEntry>> {
+            self.0.entry(index)
+        }
+
+        fn entries(&self, lo: u64, hi: u64) -> io::Result<Vec<LogEntry>> {
+            self.0.entries(lo, hi)
+        }
+
+        fn last_index(&self) -> u64 {
+            self.0.last_index()
+        }
+
+        fn term_at(&self, index: u64) -> Option<Term> {
+            self.0.term_at(index)
+        }
+
+        fn truncate_from(&mut self, index: u64) -> io::Result<()> {
+            self.0.truncate_from(index)
+        }
+
+        fn cached(&self) -> usize {
+            self.0.cached()
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+    }
+
+    fn data_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rsraft-log-{}-{}", name, process::id()))
+    }
+
+    #[test]
+    fn log_reads_evicted_entries_back_from_disk() {
+        let dir = data_dir("evict");
+        let mut log = Log::new(3, Some(Arc::new(FileStorage::new(dir.clone()))));
+
+        for n in 1..=10 {
+            assert_eq!(
+                log.append(command(Term(n as u64 / 4 + 1), n)).unwrap(),
+                n as u64
+            );
+        }
+        assert_eq!(log.cached(), 3);
+        assert_eq!(log.last_index(), 10);
+
+        // a cache miss
+        assert_eq!(log.entry_at(2), Some(command(Term(1), 2)));
+        assert_eq!(log.term_at(9), Some(Term(3)));
+        assert_eq!(log.payload_size_at(5), Some(5));
+        assert_eq!(log.payload_size_after(7), 8 + 9 + 10);
+        assert_eq!(
+            log.entries(6, 9),
+            (6..=9)
+                .map(|n| command(Term(n as u64 / 4 + 1), n))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(log.entry_at(11), None);
+
+        // truncating into the spilled part, then appending again
+        log.truncate_from(5).unwrap();
+        assert_eq!(log.last_index(), 4);
+        assert_eq!(log.cached(), 0);
+        assert_eq!(log.entry_at(5), None);
+        log.append(command(Term(7), 50)).unwrap();
+        assert_eq!(log.entry_at(4), Some(command(Term(2), 4)));
+        assert_eq!(log.entry_at(5), Some(command(Term(7), 50)));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_stays_in_memory_without_storage() {
+        let mut log = Log::new(2, None);
+
+        for n in 1..=5 {
+            log.append(command(Term(1), n)).unwrap();
+        }
+
+        assert_eq!(log.cached(), 5);
+        assert_eq!(log.entry_at(1), Some(command(Term(1), 1)));
+
+        log.truncate_from(3).unwrap();
+        assert_eq!(
+            log.entries(1, u64::MAX),
+            vec![command(Term(1), 1), command(Term(1), 2)]
+        );
+    }
+
+    #[test]
+    fn log_keeps_configurations_at_hand() {
+        let dir = data_dir("configurations");
+        let mut log = Log::new(1, Some(Arc::new(FileStorage::new(dir.clone()))));
+
+        log.append(configuration(Term(1), &["a"])).unwrap();
+        log.append(command(Term(1), 1)).unwrap();
+        log.append(configuration(Term(1), &["a", "b"])).unwrap();
+        log.append(command(Term(2), 2)).unwrap();
+
+        let (index, membership) = log.membership().unwrap();
+        assert_eq!(index, 3);
+        assert_eq!(membership.voters.len(), 2);
+        assert_eq!(log.membership_before(3).unwrap().voters.len(), 1);
+        assert_eq!(
+            log.configurations(2, 4).map(|(i, _)| i).collect::<Vec<_>>(),
+            vec![3]
+        );
+
+        log.truncate_from(3).unwrap();
+        assert_eq!(log.membership().unwrap().0, 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_append_fails_when_the_storage_cannot_sync() {
+        let storage = UnsyncableStorage(MemLogStorage::new(usize::MAX, None));
+        let mut log = Log::with_storage(Box::new(storage), SyncPolicy::Always, Duration::ZERO, 16);
+
+        assert!(log.append(command(Term(1), 1)).is_err());
+        // written, but not durable, so not in the log
+        assert_eq!(log.last_index(), 0);
+        assert_eq!(log.payload_size_at(1), None);
+
+        assert!(log
+            .append_all(vec![command(Term(1), 2), command(Term(1), 3)])
+            .is_err());
+        assert_eq!(log.last_index(), 0);
+
+        // nothing to sync under `SyncPolicy::Never`
+        let storage = UnsyncableStorage(MemLogStorage::new(usize::MAX, None));
+        let mut log = Log::with_storage(Box::new(storage), SyncPolicy::Never, Duration::ZERO, 16);
+        assert_eq!(
+            log.append_all(vec![command(Term(1), 1), command(Term(1), 2)])
+                .unwrap(),
+            2
+        );
+    }
+}
@@ -0,0 +1,687 @@
+use crate::raft::storage::Storage;
+use crate::raft::types::{LogEntry, Term};
+use log::info;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const LOG_FILE: &str = "log.bin";
+
+/// Where the entries of the log are kept, indexed from 1, see `Log`. Every
+/// backend must pass `testing::log_storage_conformance`.
+pub trait LogStorage: fmt::Debug + Send {
+    /// Appends `entries` after the last one.
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()>;
+    /// None past the end of the log.
+    fn entry(&self, index: u64) -> io::Result<Option<LogEntry>>;
+    /// The entries from `lo` to `hi` included, stopping at the end of the
+    /// log.
+    fn entries(&self, lo: u64, hi: u64) -> io::Result<Vec<LogEntry>>;
+    fn last_index(&self) -> u64;
+    /// The term of the entry at `index`, where index 0 is the empty prefix
+    /// of the log and always has term 0.
+    fn term_at(&self, index: u64) -> Option<Term>;
+    /// Removes the entry at `index` and every one after it.
+    fn truncate_from(&mut self, index: u64) -> io::Result<()>;
+    /// How many entries are held in memory.
+    fn cached(&self) -> usize;
+    /// Makes what was written so far survive a crash. `Log` calls it
+    /// following its `SyncPolicy`, backends never sync on their own.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the last `max_cached` entries in memory. Older ones are spilled
+/// to storage and read back on demand, for instance when a lagging
+/// follower needs them; the term of every entry stays in memory.
+///
+/// Without storage, or when spilling fails, entries simply stay in
+/// memory.
+#[derive(Debug)]
+pub struct MemLogStorage {
+    max_cached: usize,
+    /// The entries from `first_cached` on.
+    cached: VecDeque<LogEntry>,
+    first_cached: u64,
+    /// Of every entry, by index - 1.
+    terms: Vec<Term>,
+    /// Holds the entries before `first_cached`.
+    spill: Option<Arc<dyn Storage>>,
+}
+
+impl MemLogStorage {
+    /// An empty log, spilling to `spill` if there is one.
+    pub fn new(max_cached: usize, spill: Option<Arc<dyn Storage>>) -> Self {
+        MemLogStorage {
+            max_cached: max_cached,
+            cached: VecDeque::new(),
+            first_cached: 1,
+            terms: Vec::new(),
+            spill: spill,
+        }
+    }
+
+    /// Spills the oldest entries until at most `max_cached` are left in
+    /// memory, or spilling fails.
+    fn evict(self: &mut Self) {
+        let spill = match &self.spill {
+            Some(spill) => Arc::clone(spill),
+            None => return,
+        };
+
+        while self.cached.len() > self.max_cached {
+            let result = match self.cached.front() {
+                Some(entry) => spill.append_entry(self.first_cached, entry),
+                None => return,
+            };
+
+            if let Err(e) = result {
+                info!(
+                    "Could not spill entry {}, keeping it in memory: {}",
+                    self.first_cached, e
+                );
+                return;
+            }
+
+            self.cached.pop_front();
+            self.first_cached += 1;
+        }
+    }
+}
+
+impl LogStorage for MemLogStorage {
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        for entry in entries {
+            self.terms.push(entry.term());
+            self.cached.push_back(entry.clone());
+        }
+        self.evict();
+
+        Ok(())
+    }
+
+    fn entry(&self, index: u64) -> io::Result<Option<LogEntry>> {
+        if index == 0 || index > self.last_index() {
+            return Ok(None);
+        }
+
+        if index >= self.first_cached {
+            return Ok(self
+                .cached
+                .get((index - self.first_cached) as usize)
+                .cloned());
+        }
+
+        match &self.spill {
+            Some(spill) => Ok(spill.read_entries(index, index)?.pop()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn entries(&self, lo: u64, hi: u64) -> io::Result<Vec<LogEntry>> {
+        (lo.max(1)..=hi.min(self.last_index()))
+            .map(|index| self.entry(index)?.ok_or_else(|| ErrorKind::NotFound.into()))
+            .collect()
+    }
+
+    fn last_index(&self) -> u64 {
+        self.terms.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> Option<Term> {
+        match index {
+            0 => Some(Term(0)),
+            i => self.terms.get(i as usize - 1).copied(),
+        }
+    }
+
+    fn truncate_from(&mut self, index: u64) -> io::Result<()> {
+        let index = index.max(1);
+        if index > self.last_index() {
+            return Ok(());
+        }
+
+        self.terms.truncate(index as usize - 1);
+
+        if index >= self.first_cached {
+            self.cached.truncate((index - self.first_cached) as usize);
+        } else {
+            // What is spilled from `index` on is replaced as entries are
+            // spilled again.
+            self.cached.clear();
+            self.first_cached = index;
+        }
+
+        Ok(())
+    }
+
+    fn cached(&self) -> usize {
+        self.cached.len()
+    }
+}
+
+/// Keeps the log in a file in a data directory, so that it survives a
+/// restart. Every entry is a record: its length, the CRC-32 of that
+/// length, and the CRC-32 of the length and the entry, all three as
+/// little-endian u32s, then the entry serialized with bincode. Appends go
+/// at the end of the file, and truncating cuts it short.
+///
+/// Writes are only synced by `sync`: appends and truncations reach the
+/// file, but may be lost in a crash until then.
+///
+/// Opening the file reads it through once, to find where every entry
+/// starts. A record cut short at the end, by a crash in the middle of an
+/// append, is dropped. A whole record that does not match its checksum,
+/// there or when it is read later, fails with `CorruptEntry`.
+#[derive(Debug)]
+pub struct FileLogStorage {
+    file: Mutex<File>,
+    /// Where the entry at index i starts, at `offsets[i - 1]`.
+    offsets: Vec<u64>,
+    /// Of every entry, by index - 1.
+    terms: Vec<Term>,
+    len: u64,
+}
+
+impl FileLogStorage {
+    /// Opens the log in `data_dir`, creating it if there is none yet.
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(LOG_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut offsets = Vec::new();
+        let mut terms = Vec::new();
+        let mut len = 0;
+        while let Some((entry, size)) =
+            read_record(&bytes[len..], offsets.len() as u64 + 1, len as u64)?
+        {
+            offsets.push(len as u64);
+            terms.push(entry.term());
+            len += size;
+        }
+
+        if len < bytes.len() {
+            info!(
+                "Dropping the last {} bytes of {}, an entry cut short",
+                bytes.len() - len,
+                path.display()
+            );
+            file.set_len(len as u64)?;
+            file.sync_data()?;
+        }
+
+        Ok(FileLogStorage {
+            file: Mutex::new(file),
+            offsets: offsets,
+            terms: terms,
+            len: len as u64,
+        })
+    }
+
+    /// The bytes from `start` to `end` of the file.
+    fn read(&self, start: u64, end: u64) -> io::Result<Vec<u8>> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(start))?;
+        let mut bytes = vec![0; (end - start) as usize];
+        file.read_exact(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    /// Where the entry at `index` ends.
+    fn end_of(&self, index: u64) -> u64 {
+        self.offsets
+            .get(index as usize)
+            .copied()
+            .unwrap_or(self.len)
+    }
+}
+
+/// A record of the log file does not match its checksum, or does not hold
+/// an entry. It comes wrapped in an `io::Error` of kind `InvalidData`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptEntry {
+    pub index: u64,
+    /// Where the record starts in the file.
+    pub offset: u64,
+}
+
+impl fmt::Display for CorruptEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "entry {} at offset {} is corrupt",
+            self.index, self.offset
+        )
+    }
+}
+
+impl Error for CorruptEntry {}
+
+/// The length and checksums in front of every record.
+const HEADER_SIZE: usize = 12;
+
+/// The entry at the start of `bytes` and the size of its record, None if
+/// the record is not all there. The record holds the entry at `index` and
+/// starts at `offset` in the file, which is what a `CorruptEntry` reports.
+///
+/// The length has a checksum of its own: a damaged one could otherwise
+/// make the record look longer than what follows, like one cut short.
+fn read_record(bytes: &[u8], index: u64, offset: u64) -> io::Result<Option<(LogEntry, usize)>> {
+    if bytes.len() < HEADER_SIZE {
+        return Ok(None);
+    }
+
+    let corrupt = || {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            CorruptEntry {
+                index: index,
+                offset: offset,
+            },
+        )
+    };
+    let size_checksum = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    if crc32(&bytes[..4], &[]) != size_checksum {
+        return Err(corrupt());
+    }
+
+    let size = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    if bytes.len() < HEADER_SIZE + size {
+        return Ok(None);
+    }
+
+    let checksum = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+    let payload = &bytes[HEADER_SIZE..HEADER_SIZE + size];
+    if crc32(&bytes[..4], payload) != checksum {
+        return Err(corrupt());
+    }
+
+    let entry = bincode::deserialize(payload).map_err(|_| corrupt())?;
+    Ok(Some((entry, HEADER_SIZE + size)))
+}
+
+/// CRC-32 as in zlib, of `header` followed by `payload`.
+fn crc32(header: &[u8], payload: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in header.iter().chain(payload) {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl LogStorage for FileLogStorage {
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        let mut offsets = Vec::new();
+        for entry in entries {
+            let record =
+                bincode::serialize(entry).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            let size = (record.len() as u32).to_le_bytes();
+            offsets.push(self.len + bytes.len() as u64);
+            bytes.extend_from_slice(&size);
+            bytes.extend_from_slice(&crc32(&size, &[]).to_le_bytes());
+            bytes.extend_from_slice(&crc32(&size, &record).to_le_bytes());
+            bytes.extend_from_slice(&record);
+        }
+
+        let file = self.file.get_mut().unwrap();
+        file.seek(SeekFrom::Start(self.len))?;
+        file.write_all(&bytes)?;
+
+        self.offsets.extend(offsets);
+        self.terms.extend(entries.iter().map(|entry| entry.term()));
+        self.len += bytes.len() as u64;
+
+        Ok(())
+    }
+
+    fn entry(&self, index: u64) -> io::Result<Option<LogEntry>> {
+        if index == 0 || index > self.last_index() {
+            return Ok(None);
+        }
+
+        let offset = self.offsets[index as usize - 1];
+        let bytes = self.read(offset, self.end_of(index))?;
+        Ok(read_record(&bytes, index, offset)?.map(|(entry, _)| entry))
+    }
+
+    fn entries(&self, lo: u64, hi: u64) -> io::Result<Vec<LogEntry>> {
+        let lo = lo.max(1);
+        let hi = hi.min(self.last_index());
+        if lo > hi {
+            return Ok(Vec::new());
+        }
+
+        let offset = self.offsets[lo as usize - 1];
+        let bytes = self.read(offset, self.end_of(hi))?;
+        let mut entries = Vec::new();
+        let mut start = 0;
+        while let Some((entry, size)) = read_record(
+            &bytes[start..],
+            lo + entries.len() as u64,
+            offset + start as u64,
+        )? {
+            entries.push(entry);
+            start += size;
+        }
+
+        Ok(entries)
+    }
+
+    fn last_index(&self) -> u64 {
+        self.offsets.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> Option<Term> {
+        match index {
+            0 => Some(Term(0)),
+            i => self.terms.get(i as usize - 1).copied(),
+        }
+    }
+
+    fn truncate_from(&mut self, index: u64) -> io::Result<()> {
+        let index = index.max(1);
+        if index > self.last_index() {
+            return Ok(());
+        }
+
+        let len = self.offsets[index as usize - 1];
+        self.file.get_mut().unwrap().set_len(len)?;
+
+        self.offsets.truncate(index as usize - 1);
+        self.terms.truncate(index as usize - 1);
+        self.len = len;
+
+        Ok(())
+    }
+
+    fn cached(&self) -> usize {
+        0
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.get_mut().unwrap().sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::group_commit::SyncPolicy;
+    use crate::raft::log::Log;
+    use crate::raft::retry::JitterRng;
+    use crate::raft::storage::{FileStorage, MemStorage};
+    use crate::raft::testing;
+    use crate::raft::types::{ConfigError, Server, ServerConfig};
+    use std::env;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::Duration;
+
+    fn data_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rsraft-log-storage-{}-{}", name, process::id()))
+    }
+
+    fn command(term: u64, n: u64) -> LogEntry {
+        LogEntry::Command {
+            term: Term(term),
+            data: n.to_le_bytes()[..(n % 9) as usize].to_vec(),
+        }
+    }
+
+    #[test]
+    fn log_storage_mem_conformance() {
+        testing::log_storage_conformance(|| MemLogStorage::new(usize::MAX, None));
+        testing::log_storage_conformance(|| {
+            MemLogStorage::new(2, Some(Arc::new(MemStorage::default())))
+        });
+
+        let dir = data_dir("mem");
+        testing::log_storage_conformance(|| {
+            let _ = std::fs::remove_dir_all(&dir);
+            MemLogStorage::new(2, Some(Arc::new(FileStorage::new(dir.clone()))))
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_storage_file_conformance() {
+        let dir = data_dir("file");
+        testing::log_storage_conformance(|| {
+            let _ = std::fs::remove_dir_all(&dir);
+            FileLogStorage::open(&dir).unwrap()
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_storage_file_reopens() {
+        let dir = data_dir("reopen");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let entries: Vec<LogEntry> = (0..10_000).map(|n| command(n / 100 + 1, n)).collect();
+        let mut storage = FileLogStorage::open(&dir).unwrap();
+        for batch in entries.chunks(64) {
+            storage.append(batch).unwrap();
+        }
+        drop(storage);
+
+        let storage = FileLogStorage::open(&dir).unwrap();
+        assert_eq!(storage.last_index(), 10_000);
+        let mut rng = JitterRng::new(7);
+        for _ in 0..500 {
+            let index = rng.next_u64() % 10_000 + 1;
+            let entry = &entries[index as usize - 1];
+            assert_eq!(storage.entry(index).unwrap().as_ref(), Some(entry));
+            assert_eq!(storage.term_at(index), Some(entry.term()));
+        }
+        assert_eq!(
+            storage.entries(9_990, u64::MAX).unwrap(),
+            entries[9_989..].to_vec()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_storage_file_drops_an_entry_cut_short() {
+        let dir = data_dir("cut-short");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut storage = FileLogStorage::open(&dir).unwrap();
+        storage.append(&[command(1, 1), command(1, 2)]).unwrap();
+        drop(storage);
+
+        // the crash left half of a third entry behind
+        let path = dir.join(LOG_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[20, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let mut storage = FileLogStorage::open(&dir).unwrap();
+        assert_eq!(storage.last_index(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        storage.append(&[command(2, 3)]).unwrap();
+        drop(storage);
+
+        let storage = FileLogStorage::open(&dir).unwrap();
+        assert_eq!(
+            storage.entries(1, u64::MAX).unwrap(),
+            vec![command(1, 1), command(1, 2), command(2, 3)]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_storage_crc32_check_value() {
+        assert_eq!(crc32(b"1234", b"56789"), 0xcbf4_3926);
+    }
+
+    fn corrupt_entry(e: io::Error) -> Option<CorruptEntry> {
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        e.get_ref()?.downcast_ref::<CorruptEntry>().cloned()
+    }
+
+    #[test]
+    fn log_storage_file_finds_a_flipped_byte() {
+        let dir = data_dir("flipped");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let entries: Vec<LogEntry> = (0..100).map(|n| command(1, n)).collect();
+        let mut storage = FileLogStorage::open(&dir).unwrap();
+        storage.append(&entries).unwrap();
+
+        // the middle of the file, in whichever record holds it
+        let path = dir.join(LOG_FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0x10;
+        fs::write(&path, &bytes).unwrap();
+        let index = storage
+            .offsets
+            .partition_point(|&offset| offset <= middle as u64) as u64;
+        let expected = CorruptEntry {
+            index: index,
+            offset: storage.offsets[index as usize - 1],
+        };
+
+        let e = storage.entry(index).unwrap_err();
+        assert_eq!(corrupt_entry(e), Some(expected.clone()));
+        let e = storage.entries(1, u64::MAX).unwrap_err();
+        assert_eq!(corrupt_entry(e), Some(expected.clone()));
+        assert_eq!(
+            storage.entry(index - 1).unwrap(),
+            Some(entries[index as usize - 2].clone())
+        );
+        drop(storage);
+
+        let e = FileLogStorage::open(&dir).unwrap_err();
+        assert_eq!(corrupt_entry(e), Some(expected));
+        // nothing was dropped to get past it
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+
+        // nor does a server start on it
+        let config = ServerConfig {
+            data_dir: Some(dir.clone()),
+            ..ServerConfig::default()
+        };
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+        assert!(matches!(
+            Server::new(config, Vec::new(), address, "server_1".to_string()),
+            Err(ConfigError::LogUnreadable { .. })
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_storage_file_finds_a_damaged_length() {
+        let dir = data_dir("length");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let entries: Vec<LogEntry> = (0..10).map(|n| command(1, n)).collect();
+        let mut storage = FileLogStorage::open(&dir).unwrap();
+        storage.append(&entries).unwrap();
+        let offset = storage.offsets[4];
+        drop(storage);
+
+        // the length of the fifth record now runs past the end of the file
+        let path = dir.join(LOG_FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[offset as usize + 3] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+
+        let e = FileLogStorage::open(&dir).unwrap_err();
+        assert_eq!(
+            corrupt_entry(e),
+            Some(CorruptEntry {
+                index: 5,
+                offset: offset,
+            })
+        );
+        // rather than being dropped as if cut short
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_storage_file_log_survives_a_restart() {
+        let dir = data_dir("restart");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // in its data directory, unless told otherwise
+        let start = || {
+            let config = ServerConfig {
+                data_dir: Some(dir.clone()),
+                ..ServerConfig::default()
+            };
+            let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+            let mut server =
+                Server::new(config, Vec::new(), address, "server_1".to_string()).unwrap();
+            server.bootstrap(Vec::new()).unwrap();
+            server
+        };
+
+        let mut server = start();
+        server.log.append(command(1, 1)).unwrap();
+        server.log.append(command(1, 2)).unwrap();
+        server.log.truncate_from(3).unwrap();
+        server.log.append(command(2, 3)).unwrap();
+        let entries = server.log.entries(1, u64::MAX);
+        drop(server);
+
+        // not bootstrapped twice
+        let server = start();
+        assert_eq!(server.log.entries(1, u64::MAX), entries);
+        assert_eq!(server.log.membership().unwrap().0, 1);
+        assert_eq!(server.log.payload_size_at(3), Some(3));
+
+        let log = Log::with_storage(
+            Box::new(FileLogStorage::open(&dir).unwrap()),
+            SyncPolicy::Always,
+            Duration::ZERO,
+            16,
+        );
+        assert_eq!(log.last_index(), 3);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,624 @@
+use crate::raft::core;
+use crate::raft::fanout::{self, Call};
+use crate::raft::retry::JitterRng;
+use crate::raft::tcp_rpc::MAX_FRAME_BYTES;
+use crate::raft::types::{
+    AppendEntriesRequest, AppendEntriesResponse, HeartbeatResponse, LogEntry, PreVoteRequest,
+    PreVoteResponse, RpcClient, RpcError, Server, TimeoutNowRequest, TimeoutNowResponse,
+    VoteRequest, VoteResponse,
+};
+use log::info;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// In-process transport: requests are handed straight to the handlers in
+/// `core` of the servers registered on a `MemoryNetwork`, without any
+/// sockets or serialization.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    endpoints: Arc<Mutex<HashMap<String, Endpoint>>>,
+    partitions: PartitionController,
+    faults: Arc<Mutex<Option<InjectedFaults>>>,
+    /// AppendEntries requests larger than this, encoded, are not sent. It
+    /// is `MAX_FRAME_BYTES` if not set, as over TCP.
+    max_message_bytes: Arc<Mutex<Option<usize>>>,
+}
+
+/// Random loss and delay of the messages on a `MemoryNetwork`, requests
+/// and responses alike, for fuzzing. Like partitions, they only hold back
+/// clients that know whose they are. Every link draws from a generator of
+/// its own seeded from `seed`, so a run is replayed by the same seed,
+/// whatever order the links are used in.
+#[derive(Debug, Clone)]
+pub struct Faults {
+    pub seed: u64,
+    /// The chance of a message being lost, from 0 to 1.
+    pub loss: f64,
+    /// Messages are held back by a delay drawn evenly from zero to this,
+    /// to the millisecond. A response that would come after the caller's
+    /// `rpc_timeout` is lost.
+    pub max_delay: Duration,
+}
+
+struct InjectedFaults {
+    faults: Faults,
+    links: HashMap<(String, String), JitterRng>,
+}
+
+/// Decides which links of a `MemoryNetwork` drop their messages, to
+/// emulate partitions and asymmetric links. Only clients that know whose
+/// they are, from `MemoryNetwork::client_of`, are held back by it.
+#[derive(Clone, Default)]
+pub struct PartitionController {
+    links: Arc<Mutex<Links>>,
+}
+
+#[derive(Default)]
+struct Links {
+    /// Servers only reach those in the same group. Those not listed are
+    /// all in group 0.
+    groups: HashMap<String, usize>,
+    /// One way links that are down, from the first server to the second.
+    cut: HashSet<(String, String)>,
+}
+
+#[derive(Clone)]
+enum Endpoint {
+    Serving(Arc<Mutex<Server>>),
+    /// Reachable, but never answers.
+    Unresponsive,
+}
+
+pub struct MemoryRpcClient {
+    network: MemoryNetwork,
+    /// The server sending the requests, if known.
+    id: Option<String>,
+    peer_ids: Vec<String>,
+    rpc_timeout: Duration,
+    append_entries_responses: Mutex<Vec<AppendEntriesResponse>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        MemoryNetwork::default()
+    }
+
+    pub fn serve(&self, server: Arc<Mutex<Server>>) {
+        let id = core::lock_server(&server).id.to_string();
+        self.endpoints
+            .lock()
+            .unwrap()
+            .insert(id, Endpoint::Serving(server));
+    }
+
+    pub fn serve_unresponsive(&self, peer_id: &str) {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .insert(peer_id.to_string(), Endpoint::Unresponsive);
+    }
+
+    pub fn stop(&self, peer_id: &str) {
+        self.endpoints.lock().unwrap().remove(peer_id);
+    }
+
+    pub fn client(&self, peer_ids: Vec<String>, rpc_timeout: Duration) -> MemoryRpcClient {
+        self.new_client(None, peer_ids, rpc_timeout)
+    }
+
+    /// A client for the server `id`, whose messages are dropped on the
+    /// links that the `PartitionController` cut.
+    pub fn client_of(
+        &self,
+        id: &str,
+        peer_ids: Vec<String>,
+        rpc_timeout: Duration,
+    ) -> MemoryRpcClient {
+        self.new_client(Some(id.to_string()), peer_ids, rpc_timeout)
+    }
+
+    pub fn partitions(&self) -> PartitionController {
+        self.partitions.clone()
+    }
+
+    /// Injects `faults` from now on, or stops with `None`.
+    pub fn set_faults(&self, faults: Option<Faults>) {
+        *self.faults.lock().unwrap() = faults.map(|faults| InjectedFaults {
+            faults: faults,
+            links: HashMap::new(),
+        });
+    }
+
+    /// Leaves unsent, from now on, the AppendEntries requests larger than
+    /// `max_bytes` once encoded, at most `MAX_FRAME_BYTES`.
+    pub fn set_max_message_bytes(&self, max_bytes: usize) {
+        *self.max_message_bytes.lock().unwrap() = Some(max_bytes.min(MAX_FRAME_BYTES));
+    }
+
+    fn new_client(
+        &self,
+        id: Option<String>,
+        peer_ids: Vec<String>,
+        rpc_timeout: Duration,
+    ) -> MemoryRpcClient {
+        MemoryRpcClient {
+            network: self.clone(),
+            id: id,
+            peer_ids: peer_ids,
+            rpc_timeout: rpc_timeout,
+            append_entries_responses: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn max_message_bytes(&self) -> usize {
+        self.max_message_bytes
+            .lock()
+            .unwrap()
+            .unwrap_or(MAX_FRAME_BYTES)
+    }
+
+    fn endpoint(&self, peer_id: &str) -> Option<Endpoint> {
+        self.endpoints.lock().unwrap().get(peer_id).cloned()
+    }
+
+    /// How long a message from `from` to `to` takes, or `None` if it is
+    /// lost: on a cut link, or to the injected faults.
+    fn transit(&self, from: &str, to: &str) -> Option<Duration> {
+        if self.partitions.is_cut(from, to) {
+            info!("the link from {} to {} is down", from, to);
+            return None;
+        }
+
+        let mut injected = self.faults.lock().unwrap();
+        let delay = match injected.as_mut() {
+            Some(injected) => injected.draw(from, to),
+            None => Some(Duration::ZERO),
+        };
+        if delay.is_none() {
+            info!("a message from {} to {} was lost", from, to);
+        }
+        delay
+    }
+
+    /// Runs `handle` against the peer's server. An unresponsive peer costs
+    /// the caller a full `rpc_timeout`, just like it would over TCP. A
+    /// message lost on the way is lost right away, as if the peer were
+    /// unreachable, though the peer still handles a request whose response
+    /// is the one lost.
+    fn call<T>(
+        &self,
+        from: Option<&str>,
+        peer_id: &str,
+        rpc_timeout: Duration,
+        handle: impl FnOnce(Arc<Mutex<Server>>) -> T,
+    ) -> Option<T> {
+        let request_delay = match from {
+            Some(from) => self.transit(from, peer_id)?,
+            None => Duration::ZERO,
+        };
+        if request_delay >= rpc_timeout {
+            thread::sleep(rpc_timeout);
+            info!("{} did not answer within {:?}", peer_id, rpc_timeout);
+            return None;
+        }
+
+        match self.endpoint(peer_id) {
+            Some(Endpoint::Serving(server)) => {
+                thread::sleep(request_delay);
+                let response = handle(server);
+
+                let response_delay = match from {
+                    Some(from) => self.transit(peer_id, from)?,
+                    None => Duration::ZERO,
+                };
+                if request_delay + response_delay >= rpc_timeout {
+                    thread::sleep(rpc_timeout - request_delay);
+                    info!("{} did not answer within {:?}", peer_id, rpc_timeout);
+                    return None;
+                }
+                thread::sleep(response_delay);
+                Some(response)
+            }
+            Some(Endpoint::Unresponsive) => {
+                thread::sleep(rpc_timeout);
+                info!("{} did not answer within {:?}", peer_id, rpc_timeout);
+                None
+            }
+            None => {
+                info!("{} is unreachable", peer_id);
+                None
+            }
+        }
+    }
+}
+
+impl PartitionController {
+    /// Splits the network in two: the servers in `ids` only reach each
+    /// other, and the rest only reach each other. It replaces any earlier
+    /// partition, but links cut one way stay down.
+    pub fn partition(&self, ids: &[&str]) {
+        let mut links = self.links.lock().unwrap();
+        links.groups = ids.iter().map(|id| (id.to_string(), 1)).collect();
+    }
+
+    /// Cuts the server off from every other one, on top of any partition
+    /// already in place.
+    pub fn isolate(&self, id: &str) {
+        let mut links = self.links.lock().unwrap();
+        let group = links.groups.values().max().copied().unwrap_or(0) + 1;
+        links.groups.insert(id.to_string(), group);
+    }
+
+    /// Drops the messages from `from` to `to`, requests and responses
+    /// alike, and only those: `to` still reaches `from`.
+    pub fn cut(&self, from: &str, to: &str) {
+        let mut links = self.links.lock().unwrap();
+        links.cut.insert((from.to_string(), to.to_string()));
+    }
+
+    /// Brings every link back up.
+    pub fn heal(&self) {
+        let mut links = self.links.lock().unwrap();
+        links.groups.clear();
+        links.cut.clear();
+    }
+
+    pub fn is_cut(&self, from: &str, to: &str) -> bool {
+        let links = self.links.lock().unwrap();
+        let group = |id: &str| links.groups.get(id).copied().unwrap_or(0);
+        group(from) != group(to) || links.cut.contains(&(from.to_string(), to.to_string()))
+    }
+}
+
+impl InjectedFaults {
+    /// The delay of the next message on the link, or `None` if it is lost.
+    fn draw(self: &mut Self, from: &str, to: &str) -> Option<Duration> {
+        let seed = self.faults.seed;
+        let rng = self
+            .links
+            .entry((from.to_string(), to.to_string()))
+            .or_insert_with(|| {
+                let mut hasher = DefaultHasher::new();
+                (from, to).hash(&mut hasher);
+                JitterRng::new(seed ^ hasher.finish())
+            });
+
+        let lost = (rng.next_u64() as f64) < self.faults.loss * u64::MAX as f64;
+        let millis = self.faults.max_delay.as_millis() as u64;
+        let delay = Duration::from_millis(rng.next_u64() % (millis + 1));
+        (!lost).then_some(delay)
+    }
+}
+
+impl MemoryRpcClient {
+    fn call<T>(&self, peer_id: &str, handle: impl FnOnce(Arc<Mutex<Server>>) -> T) -> Option<T> {
+        self.network
+            .call(self.id.as_deref(), peer_id, self.rpc_timeout, handle)
+    }
+
+    /// Asks every peer on its own thread, so that a slow one holds the
+    /// caller up only until the answers so far are `decided`.
+    fn call_until<T: Send + 'static>(
+        &self,
+        handle: impl Fn(Arc<Mutex<Server>>) -> T + Clone + Send + 'static,
+        decided: &dyn Fn(&[T]) -> bool,
+    ) -> Result<Vec<T>, RpcError> {
+        let calls = self
+            .peer_ids
+            .iter()
+            .map(|peer_id| {
+                let network = self.network.clone();
+                let id = self.id.clone();
+                let peer_id = peer_id.to_string();
+                let rpc_timeout = self.rpc_timeout;
+                let handle = handle.clone();
+
+                Box::new(move || network.call(id.as_deref(), &peer_id, rpc_timeout, handle))
+                    as Call<T>
+            })
+            .collect();
+
+        RpcError::unless_answered(&self.peer_ids, fanout::gather(calls, decided))
+    }
+}
+
+impl RpcClient for MemoryRpcClient {
+    fn request_vote(&self, request: VoteRequest) -> Result<Vec<VoteResponse>, RpcError> {
+        let responses = self
+            .peer_ids
+            .iter()
+            .filter_map(|peer_id| {
+                self.call(peer_id, |server| {
+                    core::handle_vote_request(
+                        server,
+                        VoteRequest {
+                            term: request.term,
+                            candidate_id: request.candidate_id.to_string(),
+                            last_log_index: request.last_log_index,
+                            last_log_term: request.last_log_term,
+                            leadership_transfer: request.leadership_transfer,
+                            election_priority: request.election_priority,
+                        },
+                    )
+                })
+            })
+            .collect();
+
+        RpcError::unless_answered(&self.peer_ids, responses)
+    }
+
+    fn request_vote_until(
+        &self,
+        request: VoteRequest,
+        decided: &dyn Fn(&[VoteResponse]) -> bool,
+    ) -> Result<Vec<VoteResponse>, RpcError> {
+        self.call_until(
+            move |server| core::handle_vote_request(server, request.clone()),
+            decided,
+        )
+    }
+
+    fn request_pre_vote(&self, request: PreVoteRequest) -> Result<Vec<PreVoteResponse>, RpcError> {
+        let responses = self
+            .peer_ids
+            .iter()
+            .filter_map(|peer_id| {
+                self.call(peer_id, |server| {
+                    core::handle_pre_vote_request(
+                        server,
+                        PreVoteRequest {
+                            term: request.term,
+                            candidate_id: request.candidate_id.to_string(),
+                            last_log_index: request.last_log_index,
+                            last_log_term: request.last_log_term,
+                        },
+                    )
+                })
+            })
+            .collect();
+
+        RpcError::unless_answered(&self.peer_ids, responses)
+    }
+
+    fn request_pre_vote_until(
+        &self,
+        request: PreVoteRequest,
+        decided: &dyn Fn(&[PreVoteResponse]) -> bool,
+    ) -> Result<Vec<PreVoteResponse>, RpcError> {
+        self.call_until(
+            move |server| core::handle_pre_vote_request(server, request.clone()),
+            decided,
+        )
+    }
+
+    fn broadcast_log_entry(&self, log_entry: LogEntry) -> Result<Vec<HeartbeatResponse>, RpcError> {
+        let responses = self
+            .peer_ids
+            .iter()
+            .filter_map(|peer_id| {
+                self.call(peer_id, |server| {
+                    core::handle_log_entry(server, log_entry.clone())
+                })
+            })
+            .collect();
+
+        RpcError::unless_answered(&self.peer_ids, responses)
+    }
+
+    fn send_timeout_now(
+        &self,
+        peer_id: &str,
+        request: TimeoutNowRequest,
+    ) -> Result<TimeoutNowResponse, RpcError> {
+        self.call(peer_id, |server| core::handle_timeout_now(server, request))
+            .ok_or_else(|| RpcError::Unreachable {
+                peer_ids: vec![peer_id.to_string()],
+            })
+    }
+
+    fn send_append_entries(&self, peer_id: &str, request: AppendEntriesRequest) {
+        // What TCP would send: the request, bincode encoded.
+        let size = bincode::serialized_size(&request).unwrap_or(u64::MAX);
+        if size > self.network.max_message_bytes() as u64 {
+            info!(
+                "AppendEntries to {} not sent: {} bytes is too large",
+                peer_id, size
+            );
+            return;
+        }
+
+        if let Some(response) = self.call(peer_id, |server| {
+            core::handle_append_entries(server, request)
+        }) {
+            self.append_entries_responses.lock().unwrap().push(response);
+        }
+    }
+
+    fn receive_append_entries_responses(&self) -> Vec<AppendEntriesResponse> {
+        self.append_entries_responses
+            .lock()
+            .unwrap()
+            .drain(..)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::testing::{self, Transport};
+    use crate::raft::types::Term;
+    use std::time::Instant;
+
+    impl Transport for MemoryNetwork {
+        type Client = MemoryRpcClient;
+
+        fn serve(&mut self, server: Arc<Mutex<Server>>) {
+            MemoryNetwork::serve(self, server);
+        }
+
+        fn serve_unresponsive(&mut self, peer_id: &str) {
+            MemoryNetwork::serve_unresponsive(self, peer_id);
+        }
+
+        fn stop(&mut self, peer_id: &str) {
+            MemoryNetwork::stop(self, peer_id);
+        }
+
+        fn client(&mut self, peer_ids: &[&str], rpc_timeout: Duration) -> MemoryRpcClient {
+            MemoryNetwork::client(
+                self,
+                peer_ids.iter().map(|id| id.to_string()).collect(),
+                rpc_timeout,
+            )
+        }
+
+        fn limit_message_bytes(&mut self, max_bytes: usize) {
+            self.set_max_message_bytes(max_bytes);
+        }
+    }
+
+    #[test]
+    fn memory_rpc_transport_conformance() {
+        testing::transport_conformance(MemoryNetwork::new);
+    }
+
+    #[test]
+    fn memory_rpc_partition_controller() {
+        let partitions = PartitionController::default();
+        partitions.partition(&["server_1", "server_2"]);
+        assert!(!partitions.is_cut("server_1", "server_2"));
+        assert!(!partitions.is_cut("server_3", "server_4"));
+        assert!(partitions.is_cut("server_1", "server_3"));
+        assert!(partitions.is_cut("server_4", "server_2"));
+
+        partitions.isolate("server_4");
+        assert!(partitions.is_cut("server_3", "server_4"));
+        assert!(partitions.is_cut("server_4", "server_1"));
+        assert!(!partitions.is_cut("server_1", "server_2"));
+
+        partitions.heal();
+        partitions.cut("server_1", "server_2");
+        assert!(partitions.is_cut("server_1", "server_2"));
+        assert!(!partitions.is_cut("server_2", "server_1"));
+        assert!(!partitions.is_cut("server_1", "server_3"));
+
+        partitions.heal();
+        assert!(!partitions.is_cut("server_1", "server_2"));
+    }
+
+    #[test]
+    fn memory_rpc_faults_are_replayed_from_their_seed() {
+        let network = MemoryNetwork::new();
+        network.serve(Arc::new(Mutex::new(testing::build_server("server_2"))));
+        let client = network.client_of(
+            "server_1",
+            vec!["server_2".to_string()],
+            Duration::from_secs(1),
+        );
+        let answered = |seed: u64| {
+            network.set_faults(Some(Faults {
+                seed: seed,
+                loss: 0.3,
+                max_delay: Duration::ZERO,
+            }));
+            (0..100)
+                .map(|_| {
+                    client
+                        .send_timeout_now("server_2", timeout_now_request())
+                        .is_ok()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let run = answered(7);
+        assert_eq!(answered(7), run);
+        assert_ne!(answered(8), run);
+        // each of the request and its response is lost 30% of the time
+        let lost = run.iter().filter(|&&ok| !ok).count();
+        assert!((35..=65).contains(&lost), "{} lost", lost);
+
+        network.set_faults(None);
+        assert!(client
+            .send_timeout_now("server_2", timeout_now_request())
+            .is_ok());
+    }
+
+    #[test]
+    fn memory_rpc_delay_past_the_timeout_loses_the_response() {
+        let network = MemoryNetwork::new();
+        network.serve(Arc::new(Mutex::new(testing::build_server("server_2"))));
+        let client = network.client_of(
+            "server_1",
+            vec!["server_2".to_string()],
+            Duration::from_millis(20),
+        );
+        network.set_faults(Some(Faults {
+            seed: 0,
+            loss: 0.0,
+            max_delay: Duration::from_millis(1000),
+        }));
+
+        let started = Instant::now();
+        let answered = (0..10)
+            .filter(|_| {
+                client
+                    .send_timeout_now("server_2", timeout_now_request())
+                    .is_ok()
+            })
+            .count();
+
+        // nearly all take longer than the timeout, which caps the wait
+        assert!(answered < 5, "{} answered", answered);
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    fn timeout_now_request() -> TimeoutNowRequest {
+        TimeoutNowRequest {
+            term: Term(0),
+            leader_id: "server_1".to_string(),
+        }
+    }
+
+    #[test]
+    fn memory_rpc_drops_messages_on_a_cut_link() {
+        let network = MemoryNetwork::new();
+        let server_1 = Arc::new(Mutex::new(testing::build_server("server_1")));
+        let server_2 = Arc::new(Mutex::new(testing::build_server("server_2")));
+        network.serve(Arc::clone(&server_1));
+        network.serve(Arc::clone(&server_2));
+        let timeout = Duration::from_secs(1);
+        let client_1 = network.client_of("server_1", vec!["server_2".to_string()], timeout);
+        let client_2 = network.client_of("server_2", vec!["server_1".to_string()], timeout);
+        let vote_request = |candidate_id: &str| VoteRequest {
+            term: Term(1),
+            candidate_id: candidate_id.to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 1,
+        };
+
+        network.partitions().cut("server_1", "server_2");
+
+        // the request is lost on its way
+        assert!(client_1.request_vote(vote_request("server_1")).is_err());
+        assert_eq!(server_2.lock().unwrap().term, Term(0));
+
+        // this one gets there, but its response is lost
+        assert!(client_2.request_vote(vote_request("server_2")).is_err());
+        assert_eq!(server_1.lock().unwrap().term, Term(1));
+
+        // a client that does not say whose it is goes through
+        let client = network.client(vec!["server_2".to_string()], timeout);
+        assert!(client.request_vote(vote_request("server_3")).is_ok());
+
+        network.partitions().heal();
+        assert!(client_1.request_vote(vote_request("server_1")).is_ok());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::raft::build_info::BuildInfo;
+use crate::raft::clock::Timestamp;
+use crate::raft::types::{ApplyLagPolicy, Limit, State, Term};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const COUNTERS_FILE: &str = "counters.bin";
+
+/// Monotonic counters that survive restarts when the server has a data
+/// directory. Only counters are kept here: gauges and latencies describe
+/// the running process and always start afresh.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Counters {
+    pub elections_started_total: u64,
+    pub elections_won_total: u64,
+    pub heartbeats_sent_total: u64,
+    pub heartbeats_received_total: u64,
+    pub process_restarts_total: u64,
+}
+
+/// What `Server::metrics` returns: the server's state at `captured_at`,
+/// and its counters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RaftMetrics {
+    pub captured_at: Timestamp,
+    pub id: String,
+    /// Since the server was created.
+    pub uptime: Duration,
+    pub term: Term,
+    pub state: State,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub log_length: u64,
+    pub leader_id: Option<String>,
+    /// How many voters can fail with the rest still forming a quorum.
+    pub fault_tolerance: usize,
+    /// Committed entries this server has not applied yet, and the policy
+    /// in force if that, or the gap of a follower, is too large.
+    pub apply_gap: u64,
+    pub apply_lag_mode: Option<ApplyLagPolicy>,
+    /// The limits past their soft threshold, see `Server::check_soft_limits`.
+    pub approaching_limits: Vec<Limit>,
+    pub counters: Counters,
+    pub build: BuildInfo,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub counters: Counters,
+    /// The values read back from the data directory on start, if any, so
+    /// that they can be told apart from what this process counted.
+    pub restored: Option<Counters>,
+    last_flush: Option<Instant>,
+}
+
+impl Metrics {
+    /// Loads the counters persisted in `data_dir`. A directory without any
+    /// counters yet starts from zero; otherwise this start counts as a
+    /// restart.
+    pub fn restore(data_dir: &Path) -> io::Result<Metrics> {
+        let bytes = match fs::read(data_dir.join(COUNTERS_FILE)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Metrics::default()),
+            Err(e) => return Err(e),
+        };
+
+        let restored: Counters =
+            bincode::deserialize(&bytes).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+        let mut counters = restored.clone();
+        counters.process_restarts_total += 1;
+
+        Ok(Metrics {
+            counters: counters,
+            restored: Some(restored),
+            last_flush: None,
+        })
+    }
+
+    /// Writes the counters to `data_dir`, replacing the previous ones
+    /// atomically so that a crash mid-write never loses them.
+    pub fn persist(self: &mut Self, data_dir: &Path) -> io::Result<()> {
+        let bytes = bincode::serialize(&self.counters)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+        fs::create_dir_all(data_dir)?;
+        let tmp = data_dir.join(format!("{}.tmp", COUNTERS_FILE));
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, data_dir.join(COUNTERS_FILE))?;
+
+        self.last_flush = Some(Instant::now());
+        Ok(())
+    }
+
+    pub fn flush_due(&self, interval: Duration) -> bool {
+        match self.last_flush {
+            Some(t) => t.elapsed() >= interval,
+            None => true,
+        }
+    }
+
+    /// One `name value` line per counter. Counters continuing from a
+    /// previous process get a `name_restored` line with the value they
+    /// were restored at. The build this process runs comes last.
+    pub fn render(&self) -> String {
+        let restored = self.restored.as_ref();
+        let lines = vec![
+            (
+                "elections_started_total",
+                self.counters.elections_started_total,
+                restored.map(|c| c.elections_started_total),
+            ),
+            (
+                "elections_won_total",
+                self.counters.elections_won_total,
+                restored.map(|c| c.elections_won_total),
+            ),
+            (
+                "heartbeats_sent_total",
+                self.counters.heartbeats_sent_total,
+                restored.map(|c| c.heartbeats_sent_total),
+            ),
+            (
+                "heartbeats_received_total",
+                self.counters.heartbeats_received_total,
+                restored.map(|c| c.heartbeats_received_total),
+            ),
+            (
+                "process_restarts_total",
+                self.counters.process_restarts_total,
+                restored.map(|c| c.process_restarts_total),
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, value, restored) in lines {
+            output.push_str(&format!("{} {}\n", name, value));
+            if let Some(restored) = restored {
+                output.push_str(&format!("{}_restored {}\n", name, restored));
+            }
+        }
+        output.push_str(&BuildInfo::current().render());
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn metrics_fresh_data_dir_starts_from_zero() {
+        let data_dir = data_dir("fresh");
+
+        let metrics = Metrics::restore(&data_dir).unwrap();
+
+        assert_eq!(metrics.counters, Counters::default());
+        assert!(metrics.restored.is_none());
+        assert!(!metrics.render().contains("_restored"));
+    }
+
+    #[test]
+    fn metrics_render_marks_restored_values() {
+        let data_dir = data_dir("render");
+
+        let mut metrics = Metrics::restore(&data_dir).unwrap();
+        metrics.counters.elections_started_total = 2;
+        metrics.persist(&data_dir).unwrap();
+
+        let mut metrics = Metrics::restore(&data_dir).unwrap();
+        metrics.counters.elections_started_total += 1;
+
+        let output = metrics.render();
+        assert!(output.contains("elections_started_total 3\n"));
+        assert!(output.contains("elections_started_total_restored 2\n"));
+        assert!(output.contains("process_restarts_total 1\n"));
+        assert!(output.ends_with(&BuildInfo::current().render()));
+    }
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rsraft-metrics-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+}
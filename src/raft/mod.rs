@@ -1,4 +1,28 @@
+pub mod build_info;
+pub mod cancel;
+pub mod clock;
+pub mod codec;
 pub mod core;
+pub mod counter;
 pub mod demo;
+pub mod error;
+pub mod events;
+pub mod fanout;
+pub mod group_commit;
+pub mod hard_state;
+pub mod log;
+pub mod log_storage;
+#[cfg(test)]
+pub mod memory_rpc;
+pub mod metrics;
+pub mod quorum;
+pub mod replication;
+pub mod retry;
+pub mod snapshot;
+pub mod state_machine;
+pub mod status;
+pub mod storage;
 pub mod tcp_rpc;
+#[cfg(test)]
+pub mod testing;
 pub mod types;
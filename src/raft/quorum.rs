@@ -0,0 +1,327 @@
+use crate::raft::types::{Membership, Term};
+use std::collections::HashSet;
+
+/// Smallest number of voters that forms a majority of `voters`.
+pub fn majority(voters: usize) -> usize {
+    voters / 2 + 1
+}
+
+pub fn quorum_size(membership: &Membership) -> usize {
+    majority(membership.voters.len())
+}
+
+/// How many voters can fail while the rest still form a quorum.
+pub fn fault_tolerance(membership: &Membership) -> usize {
+    membership.voters.len() - quorum_size(membership)
+}
+
+/// Whether the acknowledging servers form a quorum. Learners may be part
+/// of `acked`, but they never count.
+pub fn is_quorum(membership: &Membership, acked: &HashSet<String>) -> bool {
+    let acked_voters = membership
+        .voters
+        .iter()
+        .filter(|p| acked.contains(&p.id))
+        .count();
+
+    acked_voters >= quorum_size(membership)
+}
+
+/// The votes of one election, as they arrive: seeded with the candidate's
+/// own, then each voter's answer, counted once however many times it comes
+/// back. Only answers in the election's term count, and only from the
+/// voters of the configuration when it is known.
+#[derive(Debug, Clone)]
+pub struct VoteTally {
+    term: Term,
+    voter_count: usize,
+    voters: Option<HashSet<String>>,
+    granted: HashSet<String>,
+    refused: HashSet<String>,
+    higher_term: Option<Term>,
+}
+
+impl VoteTally {
+    /// The tally of `candidate_id` standing in `term` among `voter_count`
+    /// voters, whoever they are.
+    pub fn new(candidate_id: &str, term: Term, voter_count: usize) -> Self {
+        VoteTally {
+            term: term,
+            voter_count: voter_count,
+            voters: None,
+            granted: HashSet::from([candidate_id.to_string()]),
+            refused: HashSet::new(),
+            higher_term: None,
+        }
+    }
+
+    /// The tally among the voters of `membership`.
+    pub fn of(candidate_id: &str, term: Term, membership: &Membership) -> Self {
+        let mut tally = VoteTally::new(candidate_id, term, membership.voters.len());
+        tally.voters = Some(membership.voters.iter().map(|p| p.id.to_string()).collect());
+        tally
+    }
+
+    pub fn term(&self) -> Term {
+        self.term
+    }
+
+    /// A voter may refuse first, and grant its vote once asked again; a
+    /// vote granted in a term is never taken back.
+    pub fn record(self: &mut Self, voter_id: &str, term: Term, vote_granted: bool) {
+        if term > self.term {
+            self.higher_term = self.higher_term.max(Some(term));
+            return;
+        }
+
+        // An earlier term is a straggler answering a past election.
+        let known = self.voters.as_ref().is_none_or(|v| v.contains(voter_id));
+        if term < self.term || !known {
+            return;
+        }
+
+        if vote_granted {
+            self.refused.remove(voter_id);
+            self.granted.insert(voter_id.to_string());
+        } else if !self.granted.contains(voter_id) {
+            self.refused.insert(voter_id.to_string());
+        }
+    }
+
+    /// The voters that granted their vote, in order of their ids.
+    pub fn granted_from(&self) -> Vec<String> {
+        let mut granted: Vec<String> = self.granted.iter().cloned().collect();
+        granted.sort();
+        granted
+    }
+
+    pub fn granted(&self) -> usize {
+        self.granted.len()
+    }
+
+    pub fn has_quorum(&self) -> bool {
+        self.granted.len() >= majority(self.voter_count)
+    }
+
+    /// So many voters refused that no majority is left to grant.
+    pub fn is_lost(&self) -> bool {
+        self.refused.len() + majority(self.voter_count) > self.voter_count
+    }
+
+    /// The latest term a voter answered from, if later than the election's:
+    /// the election is over, someone else was elected or stands.
+    pub fn higher_term(&self) -> Option<Term> {
+        self.higher_term
+    }
+
+    /// Whether the voters yet to answer no longer matter.
+    pub fn is_decided(&self) -> bool {
+        self.higher_term.is_some() || self.has_quorum() || self.is_lost()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::types::{NodeKind, Peer};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn quorum_sizes() {
+        let expected = vec![
+            (1, 1, 0),
+            (2, 2, 0),
+            (3, 2, 1),
+            (4, 3, 1),
+            (5, 3, 2),
+            (7, 4, 3),
+        ];
+
+        for (voters, quorum, tolerance) in expected {
+            let membership = build_membership(voters, 1);
+
+            assert_eq!(quorum_size(&membership), quorum);
+            assert_eq!(fault_tolerance(&membership), tolerance);
+        }
+    }
+
+    #[test]
+    fn quorum_ignores_learners() {
+        let membership = build_membership(3, 2);
+
+        let acked = vec!["voter_0", "learner_0", "learner_1"]
+            .into_iter()
+            .map(|id| id.to_string())
+            .collect();
+        assert!(!is_quorum(&membership, &acked));
+
+        let acked = vec!["voter_0", "voter_2"]
+            .into_iter()
+            .map(|id| id.to_string())
+            .collect();
+        assert!(is_quorum(&membership, &acked));
+    }
+
+    #[test]
+    fn any_two_quorums_intersect() {
+        for voters in 1..=7 {
+            for witnesses in 0..=(voters - 1).min(2) {
+                for learners in 0..=2 {
+                    let mut membership = build_membership(voters, learners);
+                    // Witnesses vote like any other voter, see `NodeKind`.
+                    for witness in membership.voters.iter_mut().rev().take(witnesses) {
+                        witness.kind = NodeKind::Witness;
+                    }
+                    let ids: Vec<String> = membership
+                        .voters
+                        .iter()
+                        .chain(membership.learners.iter())
+                        .map(|p| p.id.to_string())
+                        .collect();
+
+                    // Every subset of the members that forms a quorum.
+                    let quorums: Vec<HashSet<String>> = (0..1u32 << ids.len())
+                        .map(|mask| {
+                            ids.iter()
+                                .enumerate()
+                                .filter(|(i, _)| mask & (1 << i) != 0)
+                                .map(|(_, id)| id.to_string())
+                                .collect()
+                        })
+                        .filter(|acked| is_quorum(&membership, acked))
+                        .collect();
+
+                    assert!(!quorums.is_empty());
+
+                    for a in quorums.iter() {
+                        for b in quorums.iter() {
+                            let shared_voter = membership
+                                .voters
+                                .iter()
+                                .any(|p| a.contains(&p.id) && b.contains(&p.id));
+
+                            assert!(
+                                shared_voter,
+                                "{:?} and {:?} do not intersect with {} voters, {} witnesses",
+                                a, b, voters, witnesses
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn vote_tally_starts_with_the_candidate_s_own_vote() {
+        let tally = VoteTally::new("voter_0", Term(1), 1);
+        assert_eq!(tally.granted(), 1);
+        assert!(tally.has_quorum());
+
+        let tally = VoteTally::new("voter_0", Term(1), 3);
+        assert!(!tally.has_quorum());
+        assert!(!tally.is_decided());
+    }
+
+    #[test]
+    fn vote_tally_counts_each_voter_once() {
+        let mut tally = VoteTally::of("voter_0", Term(1), &build_membership(5, 0));
+
+        // a retried request answered three times by the same voter, and
+        // the candidate's own vote coming back
+        for voter_id in ["voter_1", "voter_1", "voter_1", "voter_0"] {
+            tally.record(voter_id, Term(1), true);
+        }
+        assert_eq!(tally.granted(), 2);
+        assert!(!tally.has_quorum());
+
+        tally.record("voter_2", Term(1), true);
+        assert!(tally.has_quorum());
+
+        // a refusal does not take a granted vote back
+        tally.record("voter_2", Term(1), false);
+        assert!(tally.has_quorum());
+    }
+
+    #[test]
+    fn vote_tally_needs_a_strict_majority() {
+        // (voters, fewest grants that win, the candidate's own included)
+        let expected = vec![(1, 1), (2, 2), (3, 2), (4, 3), (5, 3), (6, 4), (7, 4)];
+
+        for (voters, needed) in expected {
+            let membership = build_membership(voters, 0);
+            let tally = |grants: usize| {
+                let mut tally = VoteTally::of("voter_0", Term(1), &membership);
+                for i in 1..grants {
+                    tally.record(&format!("voter_{}", i), Term(1), true);
+                }
+                tally
+            };
+
+            assert!(tally(needed).has_quorum(), "{} of {}", needed, voters);
+            // alone, the candidate's own vote is never missing
+            if needed > 1 {
+                assert!(
+                    !tally(needed - 1).has_quorum(),
+                    "{} of {}",
+                    needed - 1,
+                    voters
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn vote_tally_ignores_unknown_voters_and_other_terms() {
+        let mut tally = VoteTally::of("voter_0", Term(2), &build_membership(3, 1));
+
+        tally.record("learner_0", Term(2), true);
+        tally.record("stranger", Term(2), true);
+        tally.record("voter_1", Term(1), true);
+        assert_eq!(tally.granted(), 1);
+        assert!(!tally.is_decided());
+
+        // without the configuration, every voter is taken at its word
+        let mut tally = VoteTally::new("voter_0", Term(2), 3);
+        tally.record("stranger", Term(2), true);
+        assert!(tally.has_quorum());
+    }
+
+    #[test]
+    fn vote_tally_is_decided_by_refusals_or_a_later_term() {
+        let mut tally = VoteTally::of("voter_0", Term(2), &build_membership(5, 0));
+        tally.record("voter_1", Term(2), false);
+        tally.record("voter_2", Term(2), false);
+        assert!(!tally.is_decided());
+
+        // a refusal can still turn into a grant
+        tally.record("voter_2", Term(2), true);
+        tally.record("voter_3", Term(2), false);
+        assert!(!tally.is_decided());
+
+        tally.record("voter_4", Term(2), false);
+        assert!(tally.is_lost());
+        assert!(!tally.has_quorum());
+
+        let mut tally = VoteTally::of("voter_0", Term(2), &build_membership(5, 0));
+        tally.record("voter_1", Term(4), false);
+        tally.record("voter_2", Term(3), false);
+        assert!(tally.is_decided());
+        assert_eq!(tally.higher_term(), Some(Term(4)));
+    }
+
+    fn build_membership(voters: usize, learners: usize) -> Membership {
+        let peer = |id: String| Peer {
+            id: id,
+            address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090),
+            kind: NodeKind::Voter,
+        };
+
+        Membership {
+            voters: (0..voters).map(|i| peer(format!("voter_{}", i))).collect(),
+            learners: (0..learners)
+                .map(|i| peer(format!("learner_{}", i)))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use crate::raft::retry::RetryPolicy;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// What the leader knows about the log of a single follower.
+///
+/// AppendEntries are pipelined: the leader keeps sending the next batch
+/// without waiting for the previous one to be acknowledged, as long as
+/// fewer than `max_inflight` requests are outstanding. Requests are
+/// tracked by the last index they cover, and responses are matched by
+/// their `match_index`, so they may arrive in any order.
+///
+/// The entries and bytes in flight are tracked as well, so that a follower
+/// catching up on a long log is fed at the pace it acknowledges rather
+/// than flooded with everything at once. While a follower's limits are
+/// reached its replication is paused; other followers are not affected.
+///
+/// Requests may be lost, for instance while the follower is unreachable.
+/// When the oldest one has gone unacknowledged for too long, everything
+/// in flight is given up on and replication restarts after `match_index`.
+/// The wait doubles with every consecutive retry, and resets once the
+/// follower acknowledges something.
+#[derive(Debug)]
+pub struct Progress {
+    pub next_index: u64,
+    pub match_index: u64,
+    /// The commit index last sent to the follower. A follower holding
+    /// every entry still needs to hear about entries committed since.
+    pub commit_index_sent: u64,
+    /// How far the follower has applied, as it last reported.
+    pub last_applied: u64,
+    /// When the follower last answered this leader in its term, to a
+    /// heartbeat or an AppendEntries; see `core::check_quorum`.
+    pub last_contact: Option<Instant>,
+    /// The follower's election priority, as it last reported.
+    pub election_priority: u8,
+    inflight: VecDeque<Inflight>,
+    paused: bool,
+    /// Bytes of catch-up budget this follower is owed but has not used yet.
+    pub catch_up_deficit: usize,
+    catch_up_bytes: u64,
+    catch_up_since: Option<Instant>,
+    retries: u32,
+}
+
+/// The bytes per second a leader may spend on entries for followers that
+/// are far behind, so that catching them up does not starve the live
+/// commit path. Every round, what was earned since the previous one is
+/// split evenly between the lagging followers (deficit round-robin).
+#[derive(Debug, Default)]
+pub struct CatchUpBudget {
+    last_refill: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Inflight {
+    last_index: u64,
+    entries: usize,
+    bytes: usize,
+    sent_at: Instant,
+}
+
+impl Progress {
+    pub fn new(next_index: u64) -> Self {
+        Progress {
+            next_index: next_index,
+            match_index: 0,
+            commit_index_sent: 0,
+            last_applied: 0,
+            last_contact: None,
+            election_priority: 0,
+            inflight: VecDeque::new(),
+            paused: false,
+            catch_up_deficit: 0,
+            catch_up_bytes: 0,
+            catch_up_since: None,
+            retries: 0,
+        }
+    }
+
+    pub fn is_catching_up(&self) -> bool {
+        self.catch_up_since.is_some()
+    }
+
+    /// Records `bytes` sent out of the catch-up budget.
+    pub fn caught_up_with(self: &mut Self, bytes: usize, now: Instant) {
+        self.catch_up_since.get_or_insert(now);
+        self.catch_up_deficit -= bytes.min(self.catch_up_deficit);
+        self.catch_up_bytes += bytes as u64;
+    }
+
+    pub fn stopped_catching_up(self: &mut Self) {
+        self.catch_up_deficit = 0;
+        self.catch_up_bytes = 0;
+        self.catch_up_since = None;
+    }
+
+    /// Bytes per second sent to this follower since it started catching
+    /// up.
+    pub fn catch_up_rate(&self, now: Instant) -> f64 {
+        match self.catch_up_since {
+            Some(since) if now > since => self.catch_up_bytes as f64 / (now - since).as_secs_f64(),
+            _ => 0.0,
+        }
+    }
+
+    pub fn inflight(&self) -> usize {
+        self.inflight.len()
+    }
+
+    pub fn inflight_entries(&self) -> usize {
+        self.inflight.iter().map(|i| i.entries).sum()
+    }
+
+    pub fn inflight_bytes(&self) -> usize {
+        self.inflight.iter().map(|i| i.bytes).sum()
+    }
+
+    pub fn can_send(&self, max_inflight: usize) -> bool {
+        self.inflight.len() < max_inflight
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Returns whether the state changed.
+    pub fn set_paused(&mut self, paused: bool) -> bool {
+        let changed = self.paused != paused;
+        self.paused = paused;
+        changed
+    }
+
+    /// Records a request covering every index up to `last_index`. An
+    /// empty request (a probe) covers nothing past its `prev_log_index`.
+    pub fn sent(&mut self, last_index: u64, entries: usize, bytes: usize, now: Instant) {
+        self.inflight.push_back(Inflight {
+            last_index: last_index,
+            entries: entries,
+            bytes: bytes,
+            sent_at: now,
+        });
+        self.next_index = self.next_index.max(last_index + 1);
+    }
+
+    pub fn acknowledged(&mut self, match_index: u64) {
+        self.match_index = self.match_index.max(match_index);
+        self.retries = 0;
+
+        while let Some(inflight) = self.inflight.front() {
+            if inflight.last_index > self.match_index {
+                break;
+            }
+            self.inflight.pop_front();
+        }
+    }
+
+    /// Whether the oldest request in flight has waited longer than
+    /// `timeout`, doubled for every retry so far and capped at
+    /// `max_timeout`.
+    pub fn retry_due(&self, now: Instant, timeout: Duration, max_timeout: Duration) -> bool {
+        let oldest = match self.inflight.front() {
+            Some(inflight) => inflight.sent_at,
+            None => return false,
+        };
+
+        let timeout = RetryPolicy::exponential(timeout, max_timeout)
+            .base_delay(self.retries.saturating_add(1));
+
+        now >= oldest + timeout
+    }
+
+    /// Gives up on everything in flight, to send it again. The commit
+    /// index that went with it may have been lost as well.
+    pub fn retry(&mut self) {
+        self.inflight.clear();
+        self.commit_index_sent = 0;
+        self.next_index = self.match_index + 1;
+        self.retries = self.retries.saturating_add(1);
+    }
+
+    /// Everything still in flight was built on top of the rejected
+    /// request, so it would be rejected as well. The pipeline restarts
+    /// from `next_index`.
+    pub fn rejected(&mut self, next_index: u64) {
+        self.inflight.clear();
+        self.next_index = next_index.max(self.match_index + 1);
+    }
+}
+
+impl CatchUpBudget {
+    /// The bytes earned since the previous refill.
+    pub fn refill(self: &mut Self, now: Instant, bytes_per_second: usize) -> usize {
+        let earned = match self.last_refill {
+            Some(last) if now > last => (now - last).as_secs_f64() * bytes_per_second as f64,
+            _ => 0.0,
+        };
+
+        self.last_refill = Some(now);
+        earned as usize
+    }
+
+    /// Pretends the previous refill happened `ago`, to test with a given
+    /// budget.
+    #[cfg(test)]
+    pub fn rewind(self: &mut Self, now: Instant, ago: std::time::Duration) {
+        self.last_refill = Some(now - ago);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_pipeline_window() {
+        let mut progress = Progress::new(1);
+
+        assert!(progress.can_send(2));
+        progress.sent(3, 3, 0, Instant::now());
+        assert_eq!(progress.next_index, 4);
+
+        assert!(progress.can_send(2));
+        progress.sent(6, 3, 0, Instant::now());
+        assert_eq!(progress.next_index, 7);
+
+        // the window is full until something is acknowledged
+        assert!(!progress.can_send(2));
+
+        progress.acknowledged(3);
+        assert_eq!(progress.inflight(), 1);
+        assert!(progress.can_send(2));
+    }
+
+    #[test]
+    fn progress_inflight_entries_and_bytes() {
+        let mut progress = Progress::new(1);
+        progress.sent(2, 2, 100, Instant::now());
+        progress.sent(5, 3, 50, Instant::now());
+
+        assert_eq!(progress.inflight_entries(), 5);
+        assert_eq!(progress.inflight_bytes(), 150);
+
+        progress.acknowledged(2);
+        assert_eq!(progress.inflight_entries(), 3);
+        assert_eq!(progress.inflight_bytes(), 50);
+
+        assert!(progress.set_paused(true));
+        assert!(!progress.set_paused(true));
+        assert!(progress.is_paused());
+    }
+
+    #[test]
+    fn progress_out_of_order_acknowledgements() {
+        let mut progress = Progress::new(1);
+        progress.sent(2, 2, 0, Instant::now());
+        progress.sent(4, 2, 0, Instant::now());
+        progress.sent(6, 2, 0, Instant::now());
+
+        // the response for the last request arrives first, and covers
+        // everything before it.
+        progress.acknowledged(6);
+        assert_eq!(progress.match_index, 6);
+        assert_eq!(progress.inflight(), 0);
+
+        // a late response must not move match_index backwards
+        progress.acknowledged(2);
+        assert_eq!(progress.match_index, 6);
+        assert_eq!(progress.next_index, 7);
+    }
+
+    #[test]
+    fn progress_retry_backs_off() {
+        let timeout = Duration::from_millis(100);
+        let max_timeout = Duration::from_millis(300);
+        let mut progress = Progress::new(1);
+        assert!(!progress.retry_due(Instant::now(), timeout, max_timeout));
+
+        progress.acknowledged(2);
+        let sent_at = Instant::now();
+        progress.sent(4, 2, 0, sent_at);
+        progress.sent(6, 2, 0, sent_at);
+
+        assert!(!progress.retry_due(sent_at, timeout, max_timeout));
+        assert!(progress.retry_due(sent_at + timeout, timeout, max_timeout));
+
+        progress.commit_index_sent = 2;
+        progress.retry();
+        assert_eq!(progress.inflight(), 0);
+        assert_eq!(progress.next_index, 3);
+        assert_eq!(progress.commit_index_sent, 0);
+
+        // every retry waits twice as long, up to the limit
+        let sent_at = Instant::now();
+        progress.sent(6, 4, 0, sent_at);
+        assert!(!progress.retry_due(sent_at + timeout, timeout, max_timeout));
+        assert!(progress.retry_due(sent_at + timeout * 2, timeout, max_timeout));
+
+        progress.retry();
+        progress.retry();
+        let sent_at = Instant::now();
+        progress.sent(6, 4, 0, sent_at);
+        assert!(progress.retry_due(sent_at + max_timeout, timeout, max_timeout));
+
+        // an acknowledgement resets the backoff
+        progress.acknowledged(4);
+        assert!(!progress.retry_due(sent_at + timeout / 2, timeout, max_timeout));
+        assert!(progress.retry_due(sent_at + timeout, timeout, max_timeout));
+    }
+
+    #[test]
+    fn progress_rejection_resets_pipeline() {
+        let mut progress = Progress::new(10);
+        progress.acknowledged(3);
+        progress.sent(12, 2, 0, Instant::now());
+        progress.sent(14, 2, 0, Instant::now());
+
+        progress.rejected(6);
+        assert_eq!(progress.inflight(), 0);
+        assert_eq!(progress.next_index, 6);
+
+        // never go back past what the follower already acknowledged
+        progress.rejected(2);
+        assert_eq!(progress.next_index, 4);
+    }
+}
@@ -0,0 +1,267 @@
+use rand::Rng;
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+/// How much of a delay is left to chance, so that peers that failed
+/// together do not all retry at the same moment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Jitter {
+    /// Exactly the computed delay.
+    None,
+    /// Anywhere between nothing and the computed delay.
+    Full,
+    /// Between half and all of the computed delay.
+    Equal,
+}
+
+/// When to try something again after it failed: the n-th retry waits
+/// `initial * multiplier^(n-1)`, capped at `max_delay` and then jittered,
+/// until either `max_attempts` retries were made or `max_elapsed` would be
+/// exceeded. Without limits, retries go on forever.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub initial: Duration,
+    pub multiplier: u32,
+    pub max_delay: Duration,
+    pub jitter: Jitter,
+    pub max_attempts: Option<u32>,
+    pub max_elapsed: Option<Duration>,
+}
+
+/// The delays of one run of retries, see `RetryPolicy::delays_seeded`.
+pub struct Delays {
+    policy: RetryPolicy,
+    rng: JitterRng,
+    attempt: u32,
+    elapsed: Duration,
+}
+
+/// A small seeded generator, so that a run of jittered delays can be
+/// replayed exactly in tests.
+#[derive(Debug, Clone)]
+pub struct JitterRng(u64);
+
+impl RetryPolicy {
+    /// Doubles from `initial` up to `max_delay`, with equal jitter and no
+    /// limits.
+    pub fn exponential(initial: Duration, max_delay: Duration) -> Self {
+        RetryPolicy {
+            initial: initial,
+            multiplier: 2,
+            max_delay: max_delay,
+            jitter: Jitter::Equal,
+            max_attempts: None,
+            max_elapsed: None,
+        }
+    }
+
+    pub fn with_multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    pub fn with_max_elapsed(mut self, max_elapsed: Duration) -> Self {
+        self.max_elapsed = Some(max_elapsed);
+        self
+    }
+
+    /// The delay before retry number `attempt` (from 1), without jitter.
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        if attempt == 0 {
+            return Duration::new(0, 0);
+        }
+
+        let factor = self.multiplier.checked_pow(attempt - 1).unwrap_or(u32::MAX);
+        self.initial
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// The delay before retry number `attempt`, jittered with `rng`.
+    pub fn delay(&self, attempt: u32, rng: &mut JitterRng) -> Duration {
+        let base = self.base_delay(attempt);
+
+        match self.jitter {
+            Jitter::None => base,
+            Jitter::Full => rng.up_to(base),
+            Jitter::Equal => base / 2 + rng.up_to(base - base / 2),
+        }
+    }
+
+    /// Whether retry number `attempt` is still allowed by `max_attempts`.
+    pub fn allows(&self, attempt: u32) -> bool {
+        match self.max_attempts {
+            Some(max) => attempt <= max,
+            None => true,
+        }
+    }
+
+    /// Whether a failure is worth retrying: one of a peer that is down,
+    /// restarting or slow, rather than of a request that will never work.
+    pub fn should_retry(&self, error: &io::Error) -> bool {
+        matches!(
+            error.kind(),
+            ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+                | ErrorKind::WouldBlock
+                | ErrorKind::Interrupted
+                | ErrorKind::UnexpectedEof
+        )
+    }
+
+    /// The delays between the retries of one operation, always the same
+    /// ones for the same `seed`.
+    pub fn delays_seeded(&self, seed: u64) -> Delays {
+        Delays {
+            policy: self.clone(),
+            rng: JitterRng::new(seed),
+            attempt: 0,
+            elapsed: Duration::new(0, 0),
+        }
+    }
+}
+
+impl Iterator for Delays {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let attempt = self.attempt.checked_add(1)?;
+        if !self.policy.allows(attempt) {
+            return None;
+        }
+
+        let delay = self.policy.delay(attempt, &mut self.rng);
+        let elapsed = self.elapsed.checked_add(delay)?;
+        match self.policy.max_elapsed {
+            Some(max) if elapsed > max => return None,
+            _ => (),
+        }
+
+        self.attempt = attempt;
+        self.elapsed = elapsed;
+        Some(delay)
+    }
+}
+
+impl JitterRng {
+    pub fn new(seed: u64) -> Self {
+        JitterRng(seed)
+    }
+
+    pub fn from_entropy() -> Self {
+        JitterRng(rand::thread_rng().gen_range(0..u64::MAX))
+    }
+
+    /// splitmix64.
+    pub fn next_u64(self: &mut Self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform between zero and `max`, both included, to the millisecond.
+    fn up_to(self: &mut Self, max: Duration) -> Duration {
+        let millis = max.as_millis() as u64;
+        Duration::from_millis(self.next_u64() % (millis + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(delays: impl Iterator<Item = Duration>) -> Vec<u64> {
+        delays.map(|d| d.as_millis() as u64).collect()
+    }
+
+    #[test]
+    fn retry_policy_grows_to_the_cap() {
+        let policy = RetryPolicy::exponential(Duration::from_millis(100), Duration::from_secs(1))
+            .with_jitter(Jitter::None)
+            .with_max_attempts(6);
+
+        assert_eq!(
+            millis(policy.delays_seeded(0)),
+            vec![100, 200, 400, 800, 1000, 1000]
+        );
+        assert_eq!(
+            millis(policy.with_multiplier(3).delays_seeded(0)),
+            vec![100, 300, 900, 1000, 1000, 1000]
+        );
+    }
+
+    #[test]
+    fn retry_policy_jitter_is_replayed_from_its_seed() {
+        let policy = RetryPolicy::exponential(Duration::from_millis(100), Duration::from_secs(1))
+            .with_max_attempts(8);
+
+        for jitter in [Jitter::Full, Jitter::Equal] {
+            let policy = policy.clone().with_jitter(jitter);
+
+            assert_eq!(
+                millis(policy.delays_seeded(7)),
+                millis(policy.delays_seeded(7))
+            );
+            assert_ne!(
+                millis(policy.delays_seeded(7)),
+                millis(policy.delays_seeded(8))
+            );
+        }
+    }
+
+    #[test]
+    fn retry_policy_never_exceeds_the_cap_nor_the_elapsed_limit() {
+        let max_delay = Duration::from_millis(700);
+        let max_elapsed = Duration::from_secs(3);
+
+        for seed in 0..200 {
+            for jitter in [Jitter::None, Jitter::Full, Jitter::Equal] {
+                let policy = RetryPolicy::exponential(Duration::from_millis(10 + seed), max_delay)
+                    .with_multiplier(1 + (seed % 4) as u32)
+                    .with_jitter(jitter)
+                    .with_max_elapsed(max_elapsed);
+
+                let delays: Vec<Duration> = policy.delays_seeded(seed).take(1000).collect();
+                assert!(!delays.is_empty());
+                assert!(delays.len() < 1000, "the elapsed limit stops the retries");
+                assert!(delays.iter().sum::<Duration>() <= max_elapsed);
+
+                for (n, delay) in delays.iter().enumerate() {
+                    let base = policy.base_delay(n as u32 + 1);
+                    assert!(base <= max_delay);
+                    assert!(*delay <= base, "{:?} > {:?}", delay, base);
+                    if jitter == Jitter::Equal {
+                        assert!(*delay >= base / 2);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn retry_policy_only_retries_transient_errors() {
+        let policy = RetryPolicy::exponential(Duration::from_millis(1), Duration::from_millis(1));
+
+        assert!(policy.should_retry(&io::Error::from(ErrorKind::ConnectionRefused)));
+        assert!(policy.should_retry(&io::Error::from(ErrorKind::TimedOut)));
+        assert!(!policy.should_retry(&io::Error::from(ErrorKind::InvalidData)));
+        assert!(!policy.should_retry(&io::Error::from(ErrorKind::PermissionDenied)));
+    }
+}
@@ -0,0 +1,151 @@
+use crate::raft::clock::Timestamp;
+use crate::raft::state_machine::Sessions;
+use crate::raft::types::Term;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::Duration;
+
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+
+/// Describes a snapshot once it has been written.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotMetadata {
+    pub last_included_index: u64,
+    pub last_included_term: Term,
+    /// Bytes written to the data directory.
+    pub size: u64,
+    /// When the state machine was captured.
+    pub taken_at: Timestamp,
+    /// From the moment the state machine was captured until the snapshot
+    /// was on disk.
+    pub duration: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    InProgress,
+    /// Nothing was applied since the last snapshot, which already covers
+    /// `last_applied`.
+    NothingNewApplied {
+        last_applied: u64,
+    },
+    /// The server has no storage to write it to.
+    NoDataDir,
+    Failed(String),
+    Cancelled,
+}
+
+/// The state machine as of `last_included_index`, as it is written to
+/// storage.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub last_included_index: u64,
+    pub last_included_term: Term,
+    pub data: Vec<u8>,
+    pub sessions: Sessions,
+}
+
+/// Keeps track of the snapshots a server takes, so that only one is taken
+/// at a time and none is taken twice of the same state.
+#[derive(Debug, Default)]
+pub struct Snapshots {
+    in_progress: bool,
+    pub last: Option<SnapshotMetadata>,
+}
+
+impl Snapshots {
+    pub fn last_included_index(&self) -> u64 {
+        self.last.as_ref().map_or(0, |s| s.last_included_index)
+    }
+
+    pub fn is_in_progress(&self) -> bool {
+        self.in_progress
+    }
+
+    /// Claims the right to take a snapshot of the state as of
+    /// `last_applied`. Every successful call must be followed by
+    /// `finished`.
+    pub fn begin(self: &mut Self, last_applied: u64) -> Result<(), SnapshotError> {
+        if self.in_progress {
+            return Err(SnapshotError::InProgress);
+        }
+
+        if self.last.is_some() && last_applied <= self.last_included_index() {
+            return Err(SnapshotError::NothingNewApplied {
+                last_applied: last_applied,
+            });
+        }
+
+        self.in_progress = true;
+        Ok(())
+    }
+
+    pub fn finished(self: &mut Self, result: &Result<SnapshotMetadata, SnapshotError>) {
+        self.in_progress = false;
+        if let Ok(metadata) = result {
+            self.last = Some(metadata.clone());
+        }
+    }
+}
+
+/// Writes the snapshot to `data_dir`, replacing the previous one
+/// atomically. Returns the number of bytes written.
+pub fn write(data_dir: &Path, snapshot: &Snapshot) -> io::Result<u64> {
+    let bytes =
+        bincode::serialize(snapshot).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+    fs::create_dir_all(data_dir)?;
+    let tmp = data_dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+    fs::write(&tmp, &bytes)?;
+    fs::rename(&tmp, data_dir.join(SNAPSHOT_FILE))?;
+
+    Ok(bytes.len() as u64)
+}
+
+/// Reads the snapshot written to `data_dir`, if any.
+#[cfg(test)]
+pub fn read(data_dir: &Path) -> io::Result<Option<Snapshot>> {
+    let bytes = match fs::read(data_dir.join(SNAPSHOT_FILE)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    bincode::deserialize(&bytes)
+        .map(Some)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_one_at_a_time() {
+        let mut snapshots = Snapshots::default();
+
+        // the very first snapshot may cover nothing
+        assert_eq!(snapshots.begin(0), Ok(()));
+        assert_eq!(snapshots.begin(0), Err(SnapshotError::InProgress));
+
+        snapshots.finished(&Ok(SnapshotMetadata {
+            last_included_index: 5,
+            last_included_term: Term(1),
+            size: 10,
+            taken_at: Timestamp::now(),
+            duration: Duration::new(0, 0),
+        }));
+        assert_eq!(
+            snapshots.begin(5),
+            Err(SnapshotError::NothingNewApplied { last_applied: 5 })
+        );
+
+        // a failed snapshot does not count
+        assert_eq!(snapshots.begin(6), Ok(()));
+        snapshots.finished(&Err(SnapshotError::Failed("disk full".to_string())));
+        assert_eq!(snapshots.last_included_index(), 5);
+        assert_eq!(snapshots.begin(6), Ok(()));
+    }
+}
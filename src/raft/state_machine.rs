@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+/// The application the log is replicated for. Every server applies the
+/// same committed commands in the same order.
+pub trait StateMachine: Send + fmt::Debug {
+    /// A command that cannot be applied leaves the state machine in a
+    /// state the other servers do not share, so the server applies nothing
+    /// after it.
+    fn apply(&mut self, command: &[u8]) -> Result<Vec<u8>, ApplyError>;
+
+    /// Everything applied so far, serialized.
+    fn snapshot(&self) -> Vec<u8>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApplyError(pub String);
+
+/// Applies the command, turning a panic of the state machine into an
+/// error.
+pub fn apply_guarded(
+    state_machine: &mut dyn StateMachine,
+    command: &[u8],
+) -> Result<Vec<u8>, ApplyError> {
+    panic::catch_unwind(AssertUnwindSafe(|| state_machine.apply(command))).unwrap_or_else(
+        |payload| {
+            let message = match payload.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => payload
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .unwrap_or_default(),
+            };
+
+            Err(ApplyError(format!(
+                "the state machine panicked: {}",
+                message
+            )))
+        },
+    )
+}
+
+/// The latest request applied for a client, and what it returned.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientSession {
+    pub sequence: u64,
+    pub response: Vec<u8>,
+}
+
+/// Client sessions make retried proposals safe: a client numbers its
+/// requests, and a request whose sequence number was already applied is
+/// skipped. The table is only ever changed by applying committed entries,
+/// so every server ends up with the same one. It is written to snapshots
+/// along with the state machine, ordered by client so that equal tables
+/// serialize the same.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Sessions {
+    clients: BTreeMap<String, ClientSession>,
+}
+
+impl Sessions {
+    /// Applies the request through `apply` unless it is a duplicate.
+    /// Returns whether it was applied. A request that failed is not
+    /// recorded.
+    pub fn apply(
+        self: &mut Self,
+        client_id: &str,
+        sequence: u64,
+        apply: impl FnOnce() -> Result<Vec<u8>, ApplyError>,
+    ) -> Result<bool, ApplyError> {
+        match self.clients.get(client_id) {
+            Some(session) if sequence <= session.sequence => Ok(false),
+            _ => {
+                let session = ClientSession {
+                    sequence: sequence,
+                    response: apply()?,
+                };
+                self.clients.insert(client_id.to_string(), session);
+                Ok(true)
+            }
+        }
+    }
+
+    /// The response to a client's request, as long as it is the latest
+    /// one applied for that client.
+    pub fn response(&self, client_id: &str, sequence: u64) -> Option<&[u8]> {
+        match self.clients.get(client_id) {
+            Some(session) if session.sequence == sequence => Some(&session.response),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::testing::snapshot_differential;
+
+    /// Adds to a number once per client request, whatever the retries.
+    #[derive(Serialize, Deserialize, Debug, Default)]
+    struct SessionSum {
+        sessions: Sessions,
+        sum: i64,
+    }
+
+    impl StateMachine for SessionSum {
+        fn apply(&mut self, command: &[u8]) -> Result<Vec<u8>, ApplyError> {
+            let (client_id, sequence, amount): (String, u64, i64) =
+                bincode::deserialize(command).unwrap();
+            let sum = &mut self.sum;
+            self.sessions.apply(&client_id, sequence, || {
+                *sum += amount;
+                Ok(bincode::serialize(sum).unwrap())
+            })?;
+
+            Ok(Vec::new())
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            bincode::serialize(self).unwrap()
+        }
+    }
+
+    #[test]
+    fn sessions_skip_duplicates() {
+        let mut sessions = Sessions::default();
+        let mut applied = 0;
+
+        assert_eq!(
+            sessions.apply("client_1", 1, || {
+                applied += 1;
+                Ok(vec![1])
+            }),
+            Ok(true)
+        );
+        assert_eq!(sessions.apply("client_1", 1, || unreachable!()), Ok(false));
+        assert_eq!(sessions.response("client_1", 1), Some(&[1][..]));
+
+        // requests older than the latest are not applied either
+        assert_eq!(sessions.apply("client_1", 3, || Ok(vec![3])), Ok(true));
+        assert_eq!(sessions.apply("client_1", 2, || unreachable!()), Ok(false));
+        assert_eq!(sessions.response("client_1", 1), None);
+
+        // every client has its own sequence
+        assert_eq!(sessions.apply("client_2", 1, || Ok(vec![1])), Ok(true));
+        assert_eq!(applied, 1);
+
+        // a failed request can be retried
+        let error = ApplyError("disk full".to_string());
+        assert_eq!(
+            sessions.apply("client_3", 1, || Err(error.clone())),
+            Err(error)
+        );
+        assert_eq!(sessions.response("client_3", 1), None);
+        assert_eq!(sessions.apply("client_3", 1, || Ok(vec![1])), Ok(true));
+    }
+
+    #[test]
+    fn sessions_restored_from_a_snapshot_match_a_full_replay() {
+        for seed in 0..20 {
+            snapshot_differential(
+                seed,
+                300,
+                |random| {
+                    // few clients and sequences, so that many are retries
+                    let client_id = format!("client_{}", random % 3);
+                    let sequence = (random >> 8) % 40;
+                    let amount = (random >> 16) as i64 % 100;
+                    bincode::serialize(&(client_id, sequence, amount)).unwrap()
+                },
+                SessionSum::default,
+                |snapshot| bincode::deserialize(snapshot).unwrap(),
+            );
+        }
+    }
+}
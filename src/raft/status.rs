@@ -0,0 +1,296 @@
+use crate::raft::build_info::BuildInfo;
+use crate::raft::core::lock_server;
+use crate::raft::error::RaftError;
+use crate::raft::metrics::RaftMetrics;
+use crate::raft::types::{Server, State, Term};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a monitoring client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a node tells monitoring about itself: the `status` RPC, and the
+/// `StatusEndpoint` for tools that do not speak the Raft wire protocol.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeStatus {
+    pub id: String,
+    pub role: State,
+    pub term: Term,
+    pub leader_id: Option<String>,
+    pub commit_index: u64,
+    pub fault_tolerance: usize,
+    pub uptime: Duration,
+    pub build: BuildInfo,
+}
+
+impl NodeStatus {
+    /// Read off a metrics snapshot, which is taken under the server's lock
+    /// in one go.
+    pub fn of(metrics: &RaftMetrics) -> Self {
+        NodeStatus {
+            id: metrics.id.to_string(),
+            role: metrics.state,
+            term: metrics.term,
+            leader_id: metrics.leader_id.clone(),
+            commit_index: metrics.commit_index,
+            fault_tolerance: metrics.fault_tolerance,
+            uptime: metrics.uptime,
+            build: metrics.build.clone(),
+        }
+    }
+
+    pub fn current(server: &Mutex<Server>) -> Self {
+        NodeStatus::of(&lock_server(server).metrics())
+    }
+
+    /// One JSON object, on one line.
+    pub fn to_json(&self) -> String {
+        let role = match self.role {
+            State::FOLLOWER => "follower",
+            State::CANDIDATE => "candidate",
+            State::LEADER => "leader",
+        };
+        let leader_id = match &self.leader_id {
+            Some(id) => json_string(id),
+            None => "null".to_string(),
+        };
+        let features: Vec<String> = self.build.features.iter().map(|f| json_string(f)).collect();
+        let build = format!(
+            "{{\"version\":{},\"git_hash\":{},\"features\":[{}]}}",
+            json_string(&self.build.version),
+            json_string(&self.build.git_hash),
+            features.join(",")
+        );
+
+        format!(
+            "{{\"id\":{},\"role\":\"{}\",\"term\":{},\"leader_id\":{},\"commit_index\":{},\"fault_tolerance\":{},\"uptime_ms\":{},\"build\":{}}}",
+            json_string(&self.id),
+            role,
+            self.term,
+            leader_id,
+            self.commit_index,
+            self.fault_tolerance,
+            self.uptime.as_millis(),
+            build
+        )
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Answers every HTTP request on its port with the node's status as JSON,
+/// whatever the path, so that `curl <address>` is all it takes. The one
+/// exception is `/metrics`, answered with `Metrics::render`. Enabled by
+/// `ServerConfig::status_address`.
+pub struct StatusEndpoint {
+    address: SocketAddrV4,
+    stopped: Arc<AtomicBool>,
+    accept_loop: JoinHandle<()>,
+}
+
+impl StatusEndpoint {
+    pub fn spawn(server: Arc<Mutex<Server>>, address: SocketAddrV4) -> Result<Self, RaftError> {
+        let listener = TcpListener::bind(address)?;
+        let address = match listener.local_addr()? {
+            SocketAddr::V4(address) => address,
+            SocketAddr::V6(_) => address,
+        };
+        info!("Serving the status at: {}", address);
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let accept_loop = {
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || serve(listener, &server, &stopped))
+        };
+
+        Ok(StatusEndpoint {
+            address: address,
+            stopped: stopped,
+            accept_loop: accept_loop,
+        })
+    }
+
+    /// Where it listens, the actual port if it was asked for port 0.
+    pub fn address(&self) -> SocketAddrV4 {
+        self.address
+    }
+
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the accept loop so that it sees the flag.
+        let _ = TcpStream::connect(self.address);
+        self.accept_loop.join().unwrap();
+    }
+}
+
+/// One request at a time: each takes a single quick look at the server.
+fn serve(listener: TcpListener, server: &Mutex<Server>, stopped: &AtomicBool) {
+    for stream in listener.incoming() {
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let result = stream.and_then(|stream| answer(stream, server));
+        if let Err(e) = result {
+            info!("Could not answer a status request: {}", e);
+        }
+    }
+}
+
+fn answer(mut stream: TcpStream, server: &Mutex<Server>) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // Only the path matters, but the request must have been sent whole.
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut line = request_line.clone();
+    while !line.trim_end().is_empty() {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+    }
+
+    let (content_type, body) = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => ("text/plain", lock_server(server).metrics.render()),
+        _ => (
+            "application/json",
+            NodeStatus::current(server).to_json() + "\n",
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::types::ServerConfig;
+    use std::io::Read;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn status_endpoint_answers_with_json() {
+        let mut server = Server::new(
+            ServerConfig::default(),
+            Vec::new(),
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090),
+            "server_1".to_string(),
+        )
+        .unwrap();
+        server.term = Term(3);
+        server.state = State::CANDIDATE;
+        server.become_leader();
+        let server = Arc::new(Mutex::new(server));
+
+        let endpoint = StatusEndpoint::spawn(
+            Arc::clone(&server),
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+        )
+        .unwrap();
+
+        let (head, body) = get(endpoint.address(), "/status");
+        assert!(head.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(head.contains("Content-Type: application/json"));
+
+        let status: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status["id"], "server_1");
+        assert_eq!(status["role"], "leader");
+        assert_eq!(status["term"], 3);
+        assert_eq!(status["leader_id"], "server_1");
+        assert_eq!(status["commit_index"], 0);
+        assert_eq!(status["fault_tolerance"], 0);
+        assert!(status["uptime_ms"].is_u64());
+        assert_eq!(status["build"]["version"], env!("CARGO_PKG_VERSION"));
+
+        endpoint.stop();
+    }
+
+    #[test]
+    fn status_endpoint_serves_the_metrics() {
+        let mut server = Server::new(
+            ServerConfig::default(),
+            Vec::new(),
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090),
+            "server_1".to_string(),
+        )
+        .unwrap();
+        server.metrics.counters.elections_won_total = 2;
+        let server = Arc::new(Mutex::new(server));
+
+        let endpoint = StatusEndpoint::spawn(
+            Arc::clone(&server),
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+        )
+        .unwrap();
+
+        let (head, body) = get(endpoint.address(), "/metrics");
+        assert!(head.contains("Content-Type: text/plain"));
+        assert!(body.contains("elections_won_total 2\n"));
+        assert_eq!(body, server.lock().unwrap().metrics.render());
+
+        endpoint.stop();
+    }
+
+    /// The head and the body of the response to `GET path`.
+    fn get(address: SocketAddrV4, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    }
+
+    #[test]
+    fn status_json_escapes_strings() {
+        let status = NodeStatus {
+            id: "a \"b\"\\c\n".to_string(),
+            role: State::FOLLOWER,
+            term: Term(1),
+            leader_id: None,
+            commit_index: 2,
+            fault_tolerance: 1,
+            uptime: Duration::from_millis(1500),
+            build: BuildInfo {
+                version: "0.1.0".to_string(),
+                git_hash: "unknown".to_string(),
+                features: vec!["json".to_string()],
+            },
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&status.to_json()).unwrap();
+        assert_eq!(json["id"], "a \"b\"\\c\n");
+        assert_eq!(json["role"], "follower");
+        assert!(json["leader_id"].is_null());
+        assert_eq!(json["uptime_ms"], 1500);
+        assert_eq!(json["build"]["features"][0], "json");
+    }
+}
@@ -0,0 +1,386 @@
+use crate::raft::hard_state::{self, HardState};
+use crate::raft::snapshot::{self, Snapshot};
+use crate::raft::types::LogEntry;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+#[cfg(test)]
+use std::sync::Arc;
+use std::sync::Mutex;
+
+const SPILL_FILE: &str = "log_spill.bin";
+
+/// Where a server keeps its hard state and snapshots, and the log entries
+/// it has no room for in memory, see `ServerConfig::storage`.
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Stores `entry` at `index`, in place of the entries stored from
+    /// `index` on. Entries are stored from index 1, without gaps.
+    fn append_entry(&self, index: u64, entry: &LogEntry) -> io::Result<()>;
+    /// The stored entries from `from` to `to` included. Fails if any of
+    /// them is not stored.
+    fn read_entries(&self, from: u64, to: u64) -> io::Result<Vec<LogEntry>>;
+    /// Only returns once `hard_state` is durable.
+    fn save_hard_state(&self, hard_state: &HardState) -> io::Result<()>;
+    fn load_hard_state(&self) -> io::Result<Option<HardState>>;
+    /// Replaces the previous snapshot atomically, and returns its size in
+    /// bytes.
+    fn save_snapshot(&self, snapshot: &Snapshot) -> io::Result<u64>;
+}
+
+/// Keeps everything in files in a data directory. Log entries stored by a
+/// previous run are discarded.
+#[derive(Debug)]
+pub struct FileStorage {
+    data_dir: PathBuf,
+    entries: Mutex<EntryFile>,
+}
+
+/// The entries back to back in a file, where the entry at index i starts
+/// at `offsets[i - 1]`.
+#[derive(Debug, Default)]
+struct EntryFile {
+    file: Option<File>,
+    offsets: Vec<u64>,
+    len: u64,
+}
+
+/// Keeps everything in memory, for tests. Clones share the same contents,
+/// so a server built again with a clone finds what the previous one
+/// stored, as if it restarted.
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+pub struct MemStorage {
+    contents: Arc<Mutex<MemContents>>,
+}
+
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct MemContents {
+    entries: Vec<LogEntry>,
+    hard_state: Option<HardState>,
+    snapshot: Option<Snapshot>,
+}
+
+impl FileStorage {
+    pub fn new(data_dir: PathBuf) -> Self {
+        FileStorage {
+            data_dir: data_dir,
+            entries: Mutex::new(EntryFile::default()),
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn append_entry(&self, index: u64, entry: &LogEntry) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if index == 0 || index > entries.offsets.len() as u64 + 1 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "cannot store entry {} after {}",
+                    index,
+                    entries.offsets.len()
+                ),
+            ));
+        }
+
+        let bytes =
+            bincode::serialize(entry).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+        if entries.file.is_none() {
+            std::fs::create_dir_all(&self.data_dir)?;
+            entries.file = Some(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(self.data_dir.join(SPILL_FILE))?,
+            );
+        }
+
+        let i = index as usize - 1;
+        if let Some(&offset) = entries.offsets.get(i) {
+            entries.offsets.truncate(i);
+            entries.len = offset;
+        }
+
+        let start = entries.len;
+        let file = entries.file.as_mut().unwrap();
+        file.set_len(start)?;
+        file.seek(SeekFrom::Start(start))?;
+        file.write_all(&bytes)?;
+
+        entries.offsets.push(start);
+        entries.len += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn read_entries(&self, from: u64, to: u64) -> io::Result<Vec<LogEntry>> {
+        let mut entries = self.entries.lock().unwrap();
+        if from == 0 || to > entries.offsets.len() as u64 {
+            return Err(ErrorKind::NotFound.into());
+        }
+        if from > to {
+            return Ok(Vec::new());
+        }
+
+        let start = entries.offsets[from as usize - 1];
+        let end = entries
+            .offsets
+            .get(to as usize)
+            .copied()
+            .unwrap_or(entries.len);
+        let offsets = entries.offsets[from as usize - 1..to as usize].to_vec();
+
+        let file = entries.file.as_mut().ok_or(ErrorKind::NotFound)?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut bytes = vec![0; (end - start) as usize];
+        file.read_exact(&mut bytes)?;
+
+        offsets
+            .iter()
+            .map(|offset| {
+                bincode::deserialize(&bytes[(offset - start) as usize..])
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+            })
+            .collect()
+    }
+
+    fn save_hard_state(&self, hard_state: &HardState) -> io::Result<()> {
+        hard_state::persist(&self.data_dir, hard_state)
+    }
+
+    fn load_hard_state(&self) -> io::Result<Option<HardState>> {
+        hard_state::load(&self.data_dir)
+    }
+
+    fn save_snapshot(&self, snapshot: &Snapshot) -> io::Result<u64> {
+        snapshot::write(&self.data_dir, snapshot)
+    }
+}
+
+#[cfg(test)]
+impl Storage for MemStorage {
+    fn append_entry(&self, index: u64, entry: &LogEntry) -> io::Result<()> {
+        let mut contents = self.contents.lock().unwrap();
+        if index == 0 || index > contents.entries.len() as u64 + 1 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "cannot store entry {} after {}",
+                    index,
+                    contents.entries.len()
+                ),
+            ));
+        }
+
+        contents.entries.truncate(index as usize - 1);
+        contents.entries.push(entry.clone());
+        Ok(())
+    }
+
+    fn read_entries(&self, from: u64, to: u64) -> io::Result<Vec<LogEntry>> {
+        let contents = self.contents.lock().unwrap();
+        if from == 0 || to > contents.entries.len() as u64 {
+            return Err(ErrorKind::NotFound.into());
+        }
+
+        Ok(contents
+            .entries
+            .get(from as usize - 1..to as usize)
+            .unwrap_or_default()
+            .to_vec())
+    }
+
+    fn save_hard_state(&self, hard_state: &HardState) -> io::Result<()> {
+        self.contents.lock().unwrap().hard_state = Some(hard_state.clone());
+        Ok(())
+    }
+
+    fn load_hard_state(&self) -> io::Result<Option<HardState>> {
+        Ok(self.contents.lock().unwrap().hard_state.clone())
+    }
+
+    /// The size is what the snapshot would take in a file.
+    fn save_snapshot(&self, snapshot: &Snapshot) -> io::Result<u64> {
+        let size = bincode::serialized_size(snapshot)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        self.contents.lock().unwrap().snapshot = Some(snapshot.clone());
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::clock::Timestamp;
+    use crate::raft::core::{self, propose_command};
+    use crate::raft::counter::{Counter, CounterCommand};
+    use crate::raft::snapshot::SnapshotMetadata;
+    use crate::raft::state_machine::Sessions;
+    use crate::raft::testing::TestCluster;
+    use crate::raft::types::{NodeKind, Peer, Term};
+    use std::env;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::process;
+    use std::time::Duration;
+
+    fn command(term: u64, n: u8) -> LogEntry {
+        LogEntry::Command {
+            term: Term(term),
+            data: vec![n],
+        }
+    }
+
+    fn data_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rsraft-storage-{}-{}", name, process::id()))
+    }
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            last_included_index: 3,
+            last_included_term: Term(2),
+            data: vec![1, 2, 3],
+            sessions: Sessions::default(),
+        }
+    }
+
+    fn round_trips(storage: &dyn Storage) {
+        assert!(storage.read_entries(1, 1).is_err());
+        for n in 1..=5 {
+            storage.append_entry(n, &command(1, n as u8)).unwrap();
+        }
+        assert_eq!(
+            storage.read_entries(2, 4).unwrap(),
+            vec![command(1, 2), command(1, 3), command(1, 4)]
+        );
+
+        // replacing from the middle drops what came after
+        storage.append_entry(3, &command(2, 30)).unwrap();
+        assert_eq!(storage.read_entries(3, 3).unwrap(), vec![command(2, 30)]);
+        assert!(storage.read_entries(4, 4).is_err());
+        assert!(storage.append_entry(5, &command(2, 50)).is_err());
+
+        assert_eq!(storage.load_hard_state().unwrap(), None);
+        let hard_state = HardState {
+            term: Term(3),
+            voted_for: Some(Peer {
+                id: "server_2".to_string(),
+                address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9092),
+                kind: NodeKind::Voter,
+            }),
+        };
+        storage.save_hard_state(&hard_state).unwrap();
+        assert_eq!(storage.load_hard_state().unwrap(), Some(hard_state));
+
+        let size = storage.save_snapshot(&snapshot()).unwrap();
+        assert_eq!(size, bincode::serialized_size(&snapshot()).unwrap());
+    }
+
+    #[test]
+    fn storage_file_round_trips() {
+        let dir = data_dir("round-trips");
+        let _ = std::fs::remove_dir_all(&dir);
+        round_trips(&FileStorage::new(dir.clone()));
+        assert_eq!(snapshot::read(&dir).unwrap(), Some(snapshot()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn storage_mem_round_trips() {
+        let storage = MemStorage::default();
+        round_trips(&storage);
+        assert_eq!(storage.contents.lock().unwrap().snapshot, Some(snapshot()));
+    }
+
+    /// What can be seen of a cluster run against storage.
+    #[derive(Debug, PartialEq)]
+    struct Outcome {
+        leader: String,
+        term: Term,
+        logs: Vec<Vec<LogEntry>>,
+        counters: Vec<i64>,
+        snapshot: SnapshotMetadata,
+        /// Only the terms: whether a follower's vote reached it before the
+        /// leader's first heartbeat depends on timing.
+        hard_state_terms: Vec<Option<Term>>,
+    }
+
+    /// Elects a leader, replicates more entries than fit in memory, and
+    /// takes a snapshot.
+    fn run_cluster(storage: impl Fn(&str) -> Arc<dyn Storage>) -> Outcome {
+        let mut storages = Vec::new();
+        let mut counters = Vec::new();
+        let cluster = TestCluster::with_config(
+            &[NodeKind::Voter; 3],
+            |_| {
+                let counter = Counter::default();
+                counters.push(counter.clone());
+                Box::new(counter)
+            },
+            |id, config| {
+                let storage = storage(id);
+                storages.push(Arc::clone(&storage));
+                config.storage = Some(storage);
+                config.max_cached_log_entries = 2;
+            },
+        );
+
+        cluster.tick_until(100, |c| c.leader().is_some());
+        let leader = cluster.leader().unwrap();
+        let mut index = 0;
+        for _ in 0..10 {
+            index = propose_command(&leader, CounterCommand::Incr.encode()).unwrap();
+        }
+        cluster.tick_until(100, |c| {
+            c.servers()
+                .iter()
+                .all(|s| s.lock().unwrap().last_applied() >= index)
+        });
+        let mut snapshot = core::take_snapshot(&leader).unwrap();
+        // not up to the storage
+        snapshot.taken_at = Timestamp::from_unix_millis(0);
+        snapshot.duration = Duration::ZERO;
+
+        let (leader, term) = {
+            let tmp_server = leader.lock().unwrap();
+            (tmp_server.id.to_string(), tmp_server.term)
+        };
+        Outcome {
+            leader: leader,
+            term: term,
+            logs: cluster
+                .servers()
+                .iter()
+                .map(|s| s.lock().unwrap().log.entries(1, u64::MAX))
+                .collect(),
+            counters: counters.iter().map(|c| c.value()).collect(),
+            snapshot: snapshot,
+            hard_state_terms: storages
+                .iter()
+                .map(|s| {
+                    s.load_hard_state()
+                        .unwrap()
+                        .map(|hard_state| hard_state.term)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn storage_backends_behave_alike() {
+        let dir = data_dir("backends");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let on_files = run_cluster(|id| Arc::new(FileStorage::new(dir.join(id))));
+        let in_memory = run_cluster(|_| Arc::new(MemStorage::default()));
+
+        assert_eq!(on_files.logs[0].len(), 12);
+        assert_eq!(on_files.counters, vec![10; 3]);
+        assert_eq!(on_files, in_memory);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
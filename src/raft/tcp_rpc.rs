@@ -1,174 +1,2795 @@
-use crate::raft::types::{LogEntry, Peer, RpcClient, Server, VoteRequest, VoteResponse};
-use log::info;
+use crate::raft::codec::{BincodeCodec, Codec};
+use crate::raft::error::RaftError;
+use crate::raft::fanout::{self, Call};
+use crate::raft::retry::{JitterRng, RetryPolicy};
+use crate::raft::snapshot::{SnapshotError, SnapshotMetadata};
+use crate::raft::status::NodeStatus;
+use crate::raft::types::{
+    AppendEntriesRequest, AppendEntriesResponse, HeartbeatResponse, LogEntry, MembershipRecord,
+    Peer, PeerSet, PreVoteRequest, PreVoteResponse, RpcClient, RpcError, Server, Term,
+    TimeoutNowRequest, TimeoutNowResponse, VoteRequest, VoteResponse,
+};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::SocketAddrV4;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::net::{Shutdown, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::RwLock;
 use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_millis(500);
+const DEFAULT_BACKOFF_INITIAL: Duration = Duration::from_millis(50);
+const DEFAULT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+/// Larger frames are refused rather than buffered: the length of a frame
+/// comes from the peer.
+pub const MAX_FRAME_BYTES: usize = 256 * 1024 * 1024;
 
 #[derive(Serialize, Deserialize, Debug)]
-enum RpcMessage {
-    VoteRequest { term: u64, candidate_id: String },
-    VoteResponse { term: u64, vote_granted: bool },
-    Heartbeat { term: u64, peer_id: String },
-    HeartbeatResponse { term: u64, peer_id: String },
+pub enum RpcMessage {
+    VoteRequest {
+        term: Term,
+        candidate_id: String,
+        last_log_index: u64,
+        last_log_term: Term,
+        leadership_transfer: bool,
+        election_priority: u8,
+    },
+    VoteResponse {
+        term: Term,
+        vote_granted: bool,
+        voter_id: String,
+    },
+    PreVoteRequest {
+        term: Term,
+        candidate_id: String,
+        last_log_index: u64,
+        last_log_term: Term,
+    },
+    PreVoteResponse {
+        term: Term,
+        vote_granted: bool,
+        voter_id: String,
+    },
+    TimeoutNow {
+        term: Term,
+        leader_id: String,
+    },
+    TimeoutNowResponse {
+        term: Term,
+        peer_id: String,
+    },
+    Heartbeat {
+        term: Term,
+        peer_id: String,
+    },
+    HeartbeatResponse {
+        term: Term,
+        peer_id: String,
+        success: bool,
+    },
+    AppendEntries {
+        term: Term,
+        leader_id: String,
+        prev_log_index: u64,
+        prev_log_term: Term,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    AppendEntriesResponse {
+        term: Term,
+        peer_id: String,
+        success: bool,
+        match_index: u64,
+        conflict_term: Option<Term>,
+        conflict_index: u64,
+        last_applied: u64,
+        election_priority: u8,
+    },
+    /// Answers a request that the server has no handler for.
+    UnsupportedMessage {
+        message_type: MessageType,
+    },
+    /// Admin request: take a snapshot now.
+    SnapshotRequest,
+    SnapshotResponse {
+        result: Result<SnapshotMetadata, SnapshotError>,
+    },
+    /// Admin request: the configurations appended between two indexes.
+    MembershipHistoryRequest {
+        from_index: u64,
+        to_index: u64,
+    },
+    MembershipHistoryResponse {
+        records: Vec<MembershipRecord>,
+    },
+    /// Admin request: what the node tells monitoring about itself.
+    StatusRequest,
+    StatusResponse {
+        status: NodeStatus,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageType {
+    VoteRequest,
+    VoteResponse,
+    Heartbeat,
+    HeartbeatResponse,
+    AppendEntries,
+    AppendEntriesResponse,
+    UnsupportedMessage,
+    SnapshotRequest,
+    SnapshotResponse,
+    MembershipHistoryRequest,
+    MembershipHistoryResponse,
+    PreVoteRequest,
+    PreVoteResponse,
+    TimeoutNow,
+    TimeoutNowResponse,
+    StatusRequest,
+    StatusResponse,
+}
+
+type Handler = Box<dyn Fn(RpcMessage) -> RpcMessage + Send + Sync>;
+
+/// Routes every request a `TcpRpcServer` receives to the handler
+/// registered for its type. Handlers capture whatever they need, and can
+/// be exercised without any socket through `dispatch`.
+pub struct Dispatcher {
+    handlers: HashMap<MessageType, Handler>,
+}
+
+/// Where a peer can be reached, looked up by its id on every connect, so
+/// that its addresses may change while a client is running.
+pub trait PeerResolver: Send + Sync {
+    /// The addresses to try, in order of preference.
+    fn resolve(&self, peer_id: &str) -> Vec<SocketAddrV4>;
 }
 
+/// The resolver of a fixed set of members: their configured addresses,
+/// which can be replaced in place.
+#[derive(Default)]
+pub struct PeerAddresses {
+    addresses: Mutex<HashMap<String, Vec<SocketAddrV4>>>,
+}
+
+/// Votes and heartbeats are request/response on `servers`. AppendEntries
+/// are pipelined on a second connection per peer, whose responses are
+/// read by a background thread and queued on `append_entries_responses`.
+///
+/// Connections are opened on first use, reused while they work and
+/// dropped after any error. A peer's addresses come from `resolver` and
+/// are tried in turn, starting with the one that worked last. Every
+/// connect, read and write gives up after `rpc_timeout`, so a dead peer
+/// cannot stall the caller for longer than that per address. A peer that
+/// failed is not tried again until its backoff has elapsed; the backoff
+/// doubles with every failure up to a cap, and resets once the peer
+/// answers.
+///
+/// The peers can be changed while the client is in use, see `set_peers`:
+/// every call goes to the peers of the moment. A client made
+/// `with_peer_set` changes them along with its server.
 pub struct TcpRpcClient {
-    servers: HashMap<String, TcpStream>,
+    peers: RwLock<Peers>,
+    /// The resolver of a client made from `Peer`s, which learns the
+    /// addresses of the peers added later.
+    addresses: Option<Arc<PeerAddresses>>,
+    /// The server using the client, never contacted even if it is listed
+    /// among the peers, see `with_local_server`.
+    local: Option<Peer>,
+    /// The peers of the server using the client, and the version of them
+    /// the client last caught up with.
+    peer_set: Option<Arc<PeerSet>>,
+    followed_version: AtomicU64,
+    retry_policy: RetryPolicy,
+    dialer: Arc<Dialer>,
+    codec: Arc<dyn Codec>,
+    /// AppendEntries requests encoded larger than this are not sent.
+    max_frame_bytes: usize,
+    append_entries_sender: Mutex<Sender<AppendEntriesResponse>>,
+    append_entries_responses: Mutex<Receiver<AppendEntriesResponse>>,
+}
+
+/// The peers a `TcpRpcClient` talks to, in order, and its two connections
+/// to each of them.
+#[derive(Default)]
+struct Peers {
+    ids: Vec<String>,
+    servers: HashMap<String, Arc<Mutex<Connection>>>,
+    replication: HashMap<String, Arc<Mutex<Connection>>>,
+}
+
+/// Opens connections to the peers. It is shared with the threads asking
+/// for votes, which may still be waiting on a slow peer after the election
+/// is decided.
+struct Dialer {
+    resolver: Arc<dyn PeerResolver>,
+    last_connected: Mutex<HashMap<String, SocketAddrV4>>,
+    rpc_timeout: Duration,
+}
+
+struct Connection<S = TcpStream> {
+    stream: Option<S>,
+    backoff: Backoff,
+}
+
+/// When a peer may be tried again: after the n-th consecutive failure, the
+/// n-th delay of `policy`, or its longest one straight away for a failure
+/// it does not consider worth retrying soon. Its limits are not applied, a
+/// peer is never given up on.
+#[derive(Debug)]
+struct Backoff {
+    policy: RetryPolicy,
+    rng: JitterRng,
+    failures: u32,
+    retry_at: Option<Instant>,
 }
 
 pub struct TcpRpcServer {
-    server: Arc<Mutex<Server>>,
+    dispatcher: Arc<Dispatcher>,
+    codec: Arc<dyn Codec>,
+    address: SocketAddrV4,
+}
+
+/// A `TcpRpcServer` serving requests in the background.
+pub struct TcpRpcServerHandle {
     address: SocketAddrV4,
+    stopped: Arc<AtomicBool>,
+    connections: Arc<Mutex<HashMap<u64, TcpStream>>>,
+    accept_loop: JoinHandle<()>,
 }
 
 impl RpcClient for TcpRpcClient {
-    fn request_vote(&self, request: VoteRequest) -> Vec<VoteResponse> {
-        let rpc_message = RpcMessage::VoteRequest {
+    fn request_vote(&self, request: VoteRequest) -> Result<Vec<VoteResponse>, RpcError> {
+        self.request_vote_until(request, &|_| false)
+    }
+
+    fn request_vote_until(
+        &self,
+        request: VoteRequest,
+        decided: &dyn Fn(&[VoteResponse]) -> bool,
+    ) -> Result<Vec<VoteResponse>, RpcError> {
+        self.follow_peer_set();
+        self.call_until(
+            vote_request_message(&request),
+            vote_response,
+            "vote",
+            decided,
+        )
+    }
+
+    fn request_pre_vote(&self, request: PreVoteRequest) -> Result<Vec<PreVoteResponse>, RpcError> {
+        self.request_pre_vote_until(request, &|_| false)
+    }
+
+    fn request_pre_vote_until(
+        &self,
+        request: PreVoteRequest,
+        decided: &dyn Fn(&[PreVoteResponse]) -> bool,
+    ) -> Result<Vec<PreVoteResponse>, RpcError> {
+        self.follow_peer_set();
+        let rpc_message = RpcMessage::PreVoteRequest {
             term: request.term,
             candidate_id: request.candidate_id,
+            last_log_index: request.last_log_index,
+            last_log_term: request.last_log_term,
         };
 
-        let request_vote_bin = bincode::serialize(&rpc_message).unwrap();
-        let mut rpc_responses: Vec<RpcMessage> = Vec::new();
+        self.call_until(rpc_message, pre_vote_response, "pre-vote", decided)
+    }
 
-        for mut stream in self.servers.values() {
-            stream.write(&request_vote_bin).unwrap();
+    fn broadcast_log_entry(&self, log_entry: LogEntry) -> Result<Vec<HeartbeatResponse>, RpcError> {
+        self.follow_peer_set();
+        let peer_ids = self.peer_ids();
+        let mut responses = Vec::new();
 
-            let mut buffer = [0; 256];
-            stream.read(&mut buffer).unwrap();
-            rpc_responses.push(bincode::deserialize(&buffer).unwrap());
-        }
+        if let LogEntry::Heartbeat { term, peer_id } = log_entry {
+            let rpc_message = RpcMessage::Heartbeat {
+                term: term,
+                peer_id: peer_id,
+            };
 
-        let mut response = Vec::new();
-        for rpc_resp in rpc_responses {
-            if let RpcMessage::VoteResponse { term, vote_granted } = rpc_resp {
-                response.push(VoteResponse {
-                    term: term,
-                    vote_granted: vote_granted,
-                });
+            for peer_id in peer_ids.iter() {
+                match self.call(peer_id, &rpc_message) {
+                    Ok(RpcMessage::HeartbeatResponse {
+                        term,
+                        peer_id,
+                        success,
+                    }) => responses.push(HeartbeatResponse {
+                        term: term,
+                        peer_id: peer_id,
+                        success: success,
+                    }),
+                    Ok(other) => info!("Heartbeat to {} failed: {:?}", peer_id, other),
+                    Err(e) if !backing_off(&e) => info!("Heartbeat to {} failed: {}", peer_id, e),
+                    Err(_) => {}
+                }
             }
         }
 
-        response
+        RpcError::unless_answered(&peer_ids, responses)
     }
 
-    fn broadcast_log_entry(&self, log_entry: LogEntry) {
-        if let LogEntry::Heartbeat { term, peer_id } = log_entry {
-            let rpc_message = RpcMessage::Heartbeat {
+    fn send_timeout_now(
+        &self,
+        peer_id: &str,
+        request: TimeoutNowRequest,
+    ) -> Result<TimeoutNowResponse, RpcError> {
+        self.follow_peer_set();
+        let rpc_message = RpcMessage::TimeoutNow {
+            term: request.term,
+            leader_id: request.leader_id,
+        };
+        let unreachable = || RpcError::Unreachable {
+            peer_ids: vec![peer_id.to_string()],
+        };
+
+        match self.call(peer_id, &rpc_message) {
+            Ok(RpcMessage::TimeoutNowResponse { term, peer_id }) => Ok(TimeoutNowResponse {
                 term: term,
                 peer_id: peer_id,
-            };
+            }),
+            Ok(other) => {
+                info!("TimeoutNow to {} failed: {:?}", peer_id, other);
+                Err(unreachable())
+            }
+            Err(e) => {
+                info!("TimeoutNow to {} failed: {}", peer_id, e);
+                Err(unreachable())
+            }
+        }
+    }
+
+    fn send_append_entries(&self, peer_id: &str, request: AppendEntriesRequest) {
+        self.follow_peer_set();
+        let rpc_message = RpcMessage::AppendEntries {
+            term: request.term,
+            leader_id: request.leader_id,
+            prev_log_index: request.prev_log_index,
+            prev_log_term: request.prev_log_term,
+            entries: request.entries,
+            leader_commit: request.leader_commit,
+        };
 
-            let broadcast_bin = bincode::serialize(&rpc_message).unwrap();
+        let connection = match self.peers.read().unwrap().replication.get(peer_id) {
+            Some(connection) => Arc::clone(connection),
+            None => return,
+        };
+        let mut connection = connection.lock().unwrap();
+        if connection.backoff.check(Instant::now()).is_err() {
+            return;
+        }
 
-            for mut stream in self.servers.values() {
-                stream.write(&broadcast_bin).unwrap();
+        let codec = self.codec.as_ref();
+        let max_bytes = self.max_frame_bytes;
+        let result = match connection.stream.as_mut() {
+            Some(stream) => write_frame_within(stream, codec, &rpc_message, max_bytes),
+            None => self.connect_replication(peer_id).and_then(|mut stream| {
+                write_frame_within(&mut stream, codec, &rpc_message, max_bytes)?;
+                connection.stream = Some(stream);
+                Ok(())
+            }),
+        };
 
-                let mut buffer = [0; 256];
-                stream.read(&mut buffer).unwrap();
+        match result {
+            Ok(()) => connection.backoff.succeeded(),
+            // Nothing was written, the connection is still good.
+            Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                info!("AppendEntries to {} not sent: {}", peer_id, e)
+            }
+            Err(e) => {
+                let delay = connection.backoff.failed(Instant::now(), &e);
+                info!(
+                    "AppendEntries to {} failed, retrying in {:?}: {}",
+                    peer_id, delay, e
+                );
+                connection.stream = None;
             }
         }
     }
+
+    fn receive_append_entries_responses(&self) -> Vec<AppendEntriesResponse> {
+        self.append_entries_responses
+            .lock()
+            .unwrap()
+            .try_iter()
+            .collect()
+    }
 }
 
 impl TcpRpcClient {
-    pub fn new(peers: &Vec<Peer>) -> Self {
-        let mut servers = HashMap::new();
+    pub fn new(peers: &[Peer]) -> Self {
+        TcpRpcClient::with_timeout(peers, DEFAULT_RPC_TIMEOUT)
+    }
+
+    pub fn with_timeout(peers: &[Peer], rpc_timeout: Duration) -> Self {
+        let peer_ids = peers.iter().map(|p| p.id.to_string()).collect();
+        let addresses = Arc::new(PeerAddresses::new(peers));
+
+        let mut client = TcpRpcClient::with_resolver(peer_ids, addresses.clone(), rpc_timeout);
+        client.addresses = Some(addresses);
+        client
+    }
+
+    /// A client of the peers `peer_ids`, reached wherever `resolver` says
+    /// they are.
+    pub fn with_resolver(
+        peer_ids: Vec<String>,
+        resolver: Arc<dyn PeerResolver>,
+        rpc_timeout: Duration,
+    ) -> Self {
+        let (sender, receiver) = channel();
+        let retry_policy = RetryPolicy::exponential(DEFAULT_BACKOFF_INITIAL, DEFAULT_BACKOFF_MAX);
+
+        let mut peers = Peers::default();
+        for peer_id in peer_ids {
+            peers.add(&peer_id, &retry_policy);
+        }
+
+        TcpRpcClient {
+            peers: RwLock::new(peers),
+            addresses: None,
+            local: None,
+            peer_set: None,
+            followed_version: AtomicU64::new(0),
+            retry_policy: retry_policy,
+            dialer: Arc::new(Dialer {
+                resolver: resolver,
+                last_connected: Mutex::new(HashMap::new()),
+                rpc_timeout: rpc_timeout,
+            }),
+            codec: Arc::new(BincodeCodec),
+            max_frame_bytes: MAX_FRAME_BYTES,
+            append_entries_sender: Mutex::new(sender),
+            append_entries_responses: Mutex::new(receiver),
+        }
+    }
+
+    /// Talks to the peers with `codec`, which their servers must use too.
+    pub fn with_codec(mut self, codec: Arc<dyn Codec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Sets how long to wait before retrying a peer after its first
+    /// failure, and the most to ever wait.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        let policy = RetryPolicy::exponential(initial, max);
+        {
+            let peers = self.peers.read().unwrap();
+            for connection in peers.servers.values().chain(peers.replication.values()) {
+                connection.lock().unwrap().backoff = Backoff::new(policy.clone());
+            }
+        }
+
+        self.retry_policy = policy;
+        self
+    }
+
+    /// The peers contacted, in order.
+    pub fn peer_ids(&self) -> Vec<String> {
+        self.peers.read().unwrap().ids.clone()
+    }
+
+    /// Leaves out the peer that is `local` itself, by id or by address: a
+    /// misconfigured peer list naming it would have it vote for itself
+    /// twice. Peers added later are checked too.
+    pub fn with_local_server(mut self, local: &Peer) -> Self {
+        for peer_id in self.peer_ids() {
+            let addresses = self.dialer.resolver.resolve(&peer_id);
+            if is_local(local, &peer_id, &addresses) {
+                warn!(
+                    "Peer {} at {:?} is the local server {} at {}, leaving it out",
+                    peer_id, addresses, local.id, local.address
+                );
+                self.remove_peer(&peer_id);
+            }
+        }
+
+        self.local = Some(local.clone());
+        self
+    }
+
+    /// Contacts the peers of `peer_set` from now on, and follows their
+    /// changes: every call first catches up with them, see
+    /// `Server::peers`.
+    pub fn with_peer_set(mut self, peer_set: Arc<PeerSet>) -> Self {
+        self.followed_version = AtomicU64::new(peer_set.version());
+        self.set_peers(&peer_set.peers());
+        self.peer_set = Some(peer_set);
+        self
+    }
+
+    /// Takes the peers of the peer set again, if they changed since.
+    fn follow_peer_set(&self) {
+        if let Some(peer_set) = &self.peer_set {
+            let version = peer_set.version();
+            if self.followed_version.swap(version, Ordering::SeqCst) != version {
+                self.set_peers(&peer_set.peers());
+            }
+        }
+    }
+
+    /// Contacts `peer` too from now on, at its address unless the client
+    /// was made `with_resolver`, whose resolver must know it instead.
+    pub fn add_peer(&self, peer: &Peer) {
+        if let Some(local) = self
+            .local
+            .as_ref()
+            .filter(|local| is_local(local, &peer.id, &[peer.address]))
+        {
+            warn!(
+                "Peer {} at {} is the local server {} at {}, leaving it out",
+                peer.id, peer.address, local.id, local.address
+            );
+            return;
+        }
+
+        if let Some(addresses) = &self.addresses {
+            addresses.update(&peer.id, vec![peer.address]);
+        }
+
+        self.peers
+            .write()
+            .unwrap()
+            .add(&peer.id, &self.retry_policy);
+    }
+
+    /// Stops contacting the peer, and closes the connections to it.
+    pub fn remove_peer(&self, peer_id: &str) {
+        let replication = self.peers.write().unwrap().remove(peer_id);
+
+        // Not under the lock: an AppendEntries may be connecting. Shutting
+        // the stream down also stops the thread reading its responses.
+        if let Some(connection) = replication {
+            if let Some(stream) = connection.lock().unwrap().stream.take() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// Contacts exactly `peers` from now on, for instance once a new
+    /// configuration is committed. The connections to the peers that stay
+    /// are kept.
+    pub fn set_peers(&self, peers: &[Peer]) {
+        let removed: Vec<String> = self
+            .peer_ids()
+            .into_iter()
+            .filter(|id| !peers.iter().any(|p| p.id == *id))
+            .collect();
 
-        for peer in peers.iter() {
-            let stream = TcpStream::connect(&peer.address).unwrap();
-            servers.insert(peer.id.to_string(), stream);
+        for peer_id in removed {
+            self.remove_peer(&peer_id);
         }
+        for peer in peers {
+            self.add_peer(peer);
+        }
+    }
+
+    /// Asks every peer for its vote. A peer that cannot be reached, or
+    /// does not answer within `rpc_timeout`, results in an error.
+    pub fn request_vote_from_each(
+        &self,
+        request: &VoteRequest,
+    ) -> Vec<(String, io::Result<VoteResponse>)> {
+        self.call_each(&vote_request_message(request))
+            .into_iter()
+            .map(|(peer_id, result)| (peer_id, result.and_then(vote_response)))
+            .collect()
+    }
+
+    /// Sends the message to every peer, each on its own thread, and
+    /// returns the answers as soon as those so far are `decided`. The peers
+    /// yet to answer are left to time out on their own.
+    fn call_until<T: Send + 'static>(
+        &self,
+        message: RpcMessage,
+        parse: fn(RpcMessage) -> io::Result<T>,
+        what: &'static str,
+        decided: &dyn Fn(&[T]) -> bool,
+    ) -> Result<Vec<T>, RpcError> {
+        let message = Arc::new(message);
+        let peers = self.peers.read().unwrap();
+
+        let calls = peers
+            .ids
+            .iter()
+            .map(|peer_id| {
+                let connection = Arc::clone(&peers.servers[peer_id]);
+                let dialer = Arc::clone(&self.dialer);
+                let codec = Arc::clone(&self.codec);
+                let message = Arc::clone(&message);
+                let peer_id = peer_id.to_string();
+
+                Box::new(move || {
+                    let result = connection
+                        .lock()
+                        .unwrap()
+                        .call(codec.as_ref(), &message, || dialer.connect(&peer_id))
+                        .and_then(parse);
+                    match result {
+                        Ok(answer) => Some(answer),
+                        Err(e) if backing_off(&e) => None,
+                        Err(e) => {
+                            info!("No {} from {}: {}", what, peer_id, e);
+                            None
+                        }
+                    }
+                }) as Call<T>
+            })
+            .collect();
+
+        let peer_ids = peers.ids.clone();
+        drop(peers);
+
+        RpcError::unless_answered(&peer_ids, fanout::gather(calls, decided))
+    }
+
+    /// Sends the message to every peer and returns their answers.
+    fn call_each(&self, message: &RpcMessage) -> Vec<(String, io::Result<RpcMessage>)> {
+        // Each peer is asked on its own thread, so an election waits for
+        // the slowest peer (at most one rpc_timeout) rather than for all of
+        // them in turn.
+        let peer_ids = self.peer_ids();
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = peer_ids
+                .iter()
+                .map(|peer_id| {
+                    scope.spawn(move || (peer_id.to_string(), self.call(peer_id, message)))
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
 
-        TcpRpcClient { servers: servers }
+    fn call(&self, peer_id: &str, message: &RpcMessage) -> io::Result<RpcMessage> {
+        let connection = match self.peers.read().unwrap().servers.get(peer_id) {
+            Some(connection) => Arc::clone(connection),
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} is not a peer", peer_id),
+                ))
+            }
+        };
+
+        let mut connection = connection.lock().unwrap();
+        connection.call(self.codec.as_ref(), message, || {
+            self.dialer.connect(peer_id)
+        })
+    }
+
+    /// Replication connections only time out on writes, their responses
+    /// are awaited by a reader thread for as long as the connection lives.
+    fn connect_replication(&self, peer_id: &str) -> io::Result<TcpStream> {
+        let stream = self.dialer.connect(peer_id)?;
+        stream.set_read_timeout(None)?;
+
+        let reader = stream.try_clone()?;
+        let sender = self.append_entries_sender.lock().unwrap().clone();
+        let codec = Arc::clone(&self.codec);
+        thread::spawn(move || read_append_entries_responses(reader, codec.as_ref(), sender));
+
+        Ok(stream)
     }
 }
 
-impl TcpRpcServer {
-    pub fn new(server: Arc<Mutex<Server>>, address: SocketAddrV4) -> Self {
-        TcpRpcServer {
-            server: server,
-            address: address,
+fn is_local(local: &Peer, peer_id: &str, addresses: &[SocketAddrV4]) -> bool {
+    peer_id == local.id || addresses.contains(&local.address)
+}
+
+impl Peers {
+    /// Does nothing if the peer is known already.
+    fn add(&mut self, peer_id: &str, retry_policy: &RetryPolicy) {
+        if self.ids.iter().any(|id| id == peer_id) {
+            return;
         }
+
+        let connection = || {
+            Arc::new(Mutex::new(Connection {
+                stream: None,
+                backoff: Backoff::new(retry_policy.clone()),
+            }))
+        };
+        self.ids.push(peer_id.to_string());
+        self.servers.insert(peer_id.to_string(), connection());
+        self.replication.insert(peer_id.to_string(), connection());
     }
 
-    pub fn start_server(&self) {
-        info!("Starting server at: {}...", self.address);
-        let listener = TcpListener::bind(self.address).unwrap();
+    /// A call to the peer still running keeps its connection until it is
+    /// done. Returns the replication connection, for the caller to shut
+    /// down.
+    fn remove(&mut self, peer_id: &str) -> Option<Arc<Mutex<Connection>>> {
+        self.ids.retain(|id| id != peer_id);
+        self.servers.remove(peer_id);
+        self.replication.remove(peer_id)
+    }
+}
 
-        for stream in listener.incoming() {
-            let server_clone = Arc::clone(&self.server);
+impl Dialer {
+    /// Connects to the first of the peer's addresses that accepts, trying
+    /// the one that worked last time first.
+    fn connect(&self, peer_id: &str) -> io::Result<TcpStream> {
+        let mut addresses = self.resolver.resolve(peer_id);
+        let last_connected = self.last_connected.lock().unwrap().get(peer_id).cloned();
+        if let Some(i) = addresses.iter().position(|a| Some(*a) == last_connected) {
+            let address = addresses.remove(i);
+            addresses.insert(0, address);
+        }
 
-            match stream {
+        let mut error = io::Error::new(
+            ErrorKind::NotFound,
+            format!("no known address for {}", peer_id),
+        );
+        for address in addresses {
+            match self.connect_to(address) {
                 Ok(stream) => {
-                    thread::spawn(move || handle_connection(server_clone, stream));
+                    self.last_connected
+                        .lock()
+                        .unwrap()
+                        .insert(peer_id.to_string(), address);
+                    return Ok(stream);
                 }
                 Err(e) => {
-                    info!("Error while listening to client: {}", e);
+                    info!("Could not connect to {} at {}: {}", peer_id, address, e);
+                    error = e;
                 }
             }
         }
+
+        Err(error)
+    }
+
+    fn connect_to(&self, address: SocketAddrV4) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect_timeout(&SocketAddr::V4(address), self.rpc_timeout)?;
+        stream.set_read_timeout(Some(self.rpc_timeout))?;
+        stream.set_write_timeout(Some(self.rpc_timeout))?;
+
+        Ok(stream)
     }
 }
 
-fn handle_connection(server: Arc<Mutex<Server>>, mut stream: TcpStream) {
-    loop {
-        let mut buffer = [0; 256];
-        stream.read(&mut buffer).unwrap();
+impl PeerAddresses {
+    pub fn new(peers: &[Peer]) -> Self {
+        let addresses = peers
+            .iter()
+            .map(|p| (p.id.to_string(), vec![p.address]))
+            .collect();
 
-        let deserialized: RpcMessage = bincode::deserialize(&buffer).unwrap();
+        PeerAddresses {
+            addresses: Mutex::new(addresses),
+        }
+    }
 
-        let response = match deserialized {
-            RpcMessage::Heartbeat { term, peer_id } => {
-                handle_log_entry(Arc::clone(&server), term, peer_id)
+    /// Replaces the peer's addresses, for the next connection to it.
+    pub fn update(&self, peer_id: &str, addresses: Vec<SocketAddrV4>) {
+        self.addresses
+            .lock()
+            .unwrap()
+            .insert(peer_id.to_string(), addresses);
+    }
+}
+
+impl PeerResolver for PeerAddresses {
+    fn resolve(&self, peer_id: &str) -> Vec<SocketAddrV4> {
+        self.addresses
+            .lock()
+            .unwrap()
+            .get(peer_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl<S: Read + Write> Connection<S> {
+    /// Sends a request and reads its response, on the open connection if
+    /// there is one or on a new one from `connect`.
+    ///
+    /// A pooled connection the peer has closed in the meantime, typically
+    /// because it restarted, is replaced once without backing off. The
+    /// request may then be sent twice, which is why only idempotent
+    /// requests (votes and heartbeats) go through here.
+    fn call(
+        self: &mut Self,
+        codec: &dyn Codec,
+        message: &RpcMessage,
+        connect: impl Fn() -> io::Result<S>,
+    ) -> io::Result<RpcMessage> {
+        self.backoff.check(Instant::now())?;
+
+        let result = match self.stream.take() {
+            Some(mut stream) => match exchange(&mut stream, codec, message) {
+                Ok(response) => Ok((stream, response)),
+                Err(e) if closed_by_peer(&e) => {
+                    info!("Pooled connection closed by the peer, reconnecting: {}", e);
+                    connect_and_exchange(&connect, codec, message)
+                }
+                Err(e) => Err(e),
+            },
+            None => connect_and_exchange(&connect, codec, message),
+        };
+
+        match result {
+            Ok((stream, response)) => {
+                self.stream = Some(stream);
+                self.backoff.succeeded();
+                Ok(response)
             }
-            RpcMessage::VoteRequest { term, candidate_id } => {
-                handle_vote_request(Arc::clone(&server), term, candidate_id)
+            Err(e) => {
+                self.backoff.failed(Instant::now(), &e);
+                Err(e)
             }
-            _ => Vec::new(), // Response messages;
+        }
+    }
+}
+
+fn connect_and_exchange<S: Read + Write>(
+    connect: impl Fn() -> io::Result<S>,
+    codec: &dyn Codec,
+    message: &RpcMessage,
+) -> io::Result<(S, RpcMessage)> {
+    let mut stream = connect()?;
+    let response = exchange(&mut stream, codec, message)?;
+    Ok((stream, response))
+}
+
+fn exchange(
+    stream: &mut (impl Read + Write),
+    codec: &dyn Codec,
+    message: &RpcMessage,
+) -> io::Result<RpcMessage> {
+    write_frame(stream, codec, message)?;
+    read_frame(stream, codec)
+}
+
+/// Errors of a connection the peer closed, as opposed to one that is slow
+/// or broken for other reasons.
+fn closed_by_peer(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+    )
+}
+
+impl Backoff {
+    fn new(policy: RetryPolicy) -> Self {
+        Backoff {
+            policy: policy,
+            rng: JitterRng::from_entropy(),
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    /// Fails with `BackingOff` while the peer should not be tried yet.
+    fn check(&self, now: Instant) -> io::Result<()> {
+        match self.retry_at {
+            Some(retry_at) if now < retry_at => Err(io::Error::other(BackingOff(retry_at - now))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The delay before the next attempt, without jitter.
+    fn delay(&self) -> Duration {
+        self.policy.base_delay(self.failures)
+    }
+
+    fn failed(self: &mut Self, now: Instant, error: &io::Error) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+
+        let delay = if self.policy.should_retry(error) {
+            self.policy.delay(self.failures, &mut self.rng)
+        } else {
+            self.policy.max_delay
         };
 
-        stream.write(&response).unwrap();
-        stream.flush().unwrap();
+        self.retry_at = Some(now + delay);
+        delay
+    }
+
+    fn succeeded(self: &mut Self) {
+        self.failures = 0;
+        self.retry_at = None;
     }
 }
 
-fn handle_log_entry(server: Arc<Mutex<Server>>, term: u64, peer_id: String) -> Vec<u8> {
-    let term = crate::raft::core::handle_log_entry(
-        server,
-        LogEntry::Heartbeat {
-            term: term,
-            peer_id: peer_id.to_string(),
-        },
-    );
+#[derive(Debug)]
+struct BackingOff(Duration);
+
+impl fmt::Display for BackingOff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "backing off for another {:?}", self.0)
+    }
+}
+
+impl Error for BackingOff {}
+
+/// Peers backing off were already reported when they failed.
+fn backing_off(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<BackingOff>())
+}
+
+/// Asks the server at `address` to take a snapshot now, and waits up to
+/// `timeout` for it to be written. The server refusing to take one is a
+/// `RaftError::Snapshot`, not reaching it a `RaftError::Io`.
+pub fn request_snapshot(
+    address: SocketAddrV4,
+    timeout: Duration,
+) -> Result<SnapshotMetadata, RaftError> {
+    match admin_call(address, &RpcMessage::SnapshotRequest, timeout)? {
+        RpcMessage::SnapshotResponse { result } => Ok(result?),
+        other => Err(unexpected_message(other).into()),
+    }
+}
 
-    let response = RpcMessage::HeartbeatResponse {
-        term: term,
-        peer_id: peer_id,
+/// The configurations the server at `address` has in its log between
+/// `from_index` and `to_index` included.
+pub fn request_membership_history(
+    address: SocketAddrV4,
+    from_index: u64,
+    to_index: u64,
+    timeout: Duration,
+) -> Result<Vec<MembershipRecord>, RaftError> {
+    let request = RpcMessage::MembershipHistoryRequest {
+        from_index: from_index,
+        to_index: to_index,
     };
 
-    bincode::serialize(&response).unwrap()
+    match admin_call(address, &request, timeout)? {
+        RpcMessage::MembershipHistoryResponse { records } => Ok(records),
+        other => Err(unexpected_message(other).into()),
+    }
 }
 
-fn handle_vote_request(server: Arc<Mutex<Server>>, term: u64, candidate_id: String) -> Vec<u8> {
-    let response = crate::raft::core::handle_vote_request(
-        server,
-        VoteRequest {
+/// The status of the server at `address`, as its metrics have it.
+pub fn request_status(address: SocketAddrV4, timeout: Duration) -> Result<NodeStatus, RaftError> {
+    match admin_call(address, &RpcMessage::StatusRequest, timeout)? {
+        RpcMessage::StatusResponse { status } => Ok(status),
+        other => Err(unexpected_message(other).into()),
+    }
+}
+
+/// A one-off request on a connection of its own, for admin tools.
+fn admin_call(
+    address: SocketAddrV4,
+    message: &RpcMessage,
+    timeout: Duration,
+) -> io::Result<RpcMessage> {
+    let mut stream = TcpStream::connect_timeout(&SocketAddr::V4(address), timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    exchange(&mut stream, &BincodeCodec, message)
+}
+
+/// Writes the message as one frame: its encoded length, as 4 bytes big
+/// endian, then the encoded message.
+fn write_frame(stream: &mut impl Write, codec: &dyn Codec, message: &RpcMessage) -> io::Result<()> {
+    write_frame_within(stream, codec, message, MAX_FRAME_BYTES)
+}
+
+/// Like `write_frame`, but refuses a body of more than `max_bytes` with
+/// `InvalidInput`, before writing anything.
+fn write_frame_within(
+    stream: &mut impl Write,
+    codec: &dyn Codec,
+    message: &RpcMessage,
+    max_bytes: usize,
+) -> io::Result<()> {
+    let body = codec.encode(message)?;
+    if body.len() > max_bytes {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("message of {} bytes is too large to send", body.len()),
+        ));
+    }
+
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend(body);
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+/// Reads exactly one frame, however the bytes of the stream were split
+/// into reads.
+fn read_frame(stream: &mut impl Read, codec: &dyn Codec) -> io::Result<RpcMessage> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_BYTES {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", length),
+        ));
+    }
+
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body)?;
+    codec.decode(&body)
+}
+
+fn vote_request_message(request: &VoteRequest) -> RpcMessage {
+    RpcMessage::VoteRequest {
+        term: request.term,
+        candidate_id: request.candidate_id.to_string(),
+        last_log_index: request.last_log_index,
+        last_log_term: request.last_log_term,
+        leadership_transfer: request.leadership_transfer,
+        election_priority: request.election_priority,
+    }
+}
+
+fn vote_response(message: RpcMessage) -> io::Result<VoteResponse> {
+    match message {
+        RpcMessage::VoteResponse {
+            term,
+            vote_granted,
+            voter_id,
+        } => Ok(VoteResponse {
             term: term,
-            candidate_id: candidate_id,
-        },
-    );
+            vote_granted: vote_granted,
+            voter_id: voter_id,
+        }),
+        other => Err(unexpected_message(other)),
+    }
+}
 
-    let response = RpcMessage::VoteResponse {
-        term: response.term,
-        vote_granted: response.vote_granted,
-    };
+fn pre_vote_response(message: RpcMessage) -> io::Result<PreVoteResponse> {
+    match message {
+        RpcMessage::PreVoteResponse {
+            term,
+            vote_granted,
+            voter_id,
+        } => Ok(PreVoteResponse {
+            term: term,
+            vote_granted: vote_granted,
+            voter_id: voter_id,
+        }),
+        other => Err(unexpected_message(other)),
+    }
+}
+
+fn unexpected_message(message: RpcMessage) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("unexpected response: {:?}", message),
+    )
+}
+
+fn read_append_entries_responses(
+    stream: TcpStream,
+    codec: &dyn Codec,
+    sender: Sender<AppendEntriesResponse>,
+) {
+    let mut reader = BufReader::new(stream);
+
+    while let Ok(message) = read_frame(&mut reader, codec) {
+        if let RpcMessage::AppendEntriesResponse {
+            term,
+            peer_id,
+            success,
+            match_index,
+            conflict_term,
+            conflict_index,
+            last_applied,
+            election_priority,
+        } = message
+        {
+            let response = AppendEntriesResponse {
+                term: term,
+                peer_id: peer_id,
+                success: success,
+                match_index: match_index,
+                conflict_term: conflict_term,
+                conflict_index: conflict_index,
+                last_applied: last_applied,
+                election_priority: election_priority,
+            };
+
+            if sender.send(response).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl RpcMessage {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            RpcMessage::VoteRequest { .. } => MessageType::VoteRequest,
+            RpcMessage::VoteResponse { .. } => MessageType::VoteResponse,
+            RpcMessage::Heartbeat { .. } => MessageType::Heartbeat,
+            RpcMessage::HeartbeatResponse { .. } => MessageType::HeartbeatResponse,
+            RpcMessage::AppendEntries { .. } => MessageType::AppendEntries,
+            RpcMessage::AppendEntriesResponse { .. } => MessageType::AppendEntriesResponse,
+            RpcMessage::UnsupportedMessage { .. } => MessageType::UnsupportedMessage,
+            RpcMessage::SnapshotRequest => MessageType::SnapshotRequest,
+            RpcMessage::SnapshotResponse { .. } => MessageType::SnapshotResponse,
+            RpcMessage::MembershipHistoryRequest { .. } => MessageType::MembershipHistoryRequest,
+            RpcMessage::MembershipHistoryResponse { .. } => MessageType::MembershipHistoryResponse,
+            RpcMessage::PreVoteRequest { .. } => MessageType::PreVoteRequest,
+            RpcMessage::PreVoteResponse { .. } => MessageType::PreVoteResponse,
+            RpcMessage::TimeoutNow { .. } => MessageType::TimeoutNow,
+            RpcMessage::TimeoutNowResponse { .. } => MessageType::TimeoutNowResponse,
+            RpcMessage::StatusRequest => MessageType::StatusRequest,
+            RpcMessage::StatusResponse { .. } => MessageType::StatusResponse,
+        }
+    }
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Dispatcher {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// The handlers of the Raft protocol itself, and of admin requests,
+    /// served by `server`.
+    pub fn for_server(server: Arc<Mutex<Server>>) -> Self {
+        let mut dispatcher = Dispatcher::new();
+
+        let snapshot_server = Arc::clone(&server);
+        dispatcher.register(MessageType::SnapshotRequest, move |_| {
+            RpcMessage::SnapshotResponse {
+                result: crate::raft::core::take_snapshot(&snapshot_server),
+            }
+        });
+
+        let history_server = Arc::clone(&server);
+        dispatcher.register(
+            MessageType::MembershipHistoryRequest,
+            move |message| match message {
+                RpcMessage::MembershipHistoryRequest {
+                    from_index,
+                    to_index,
+                } => RpcMessage::MembershipHistoryResponse {
+                    records: crate::raft::core::lock_server(&history_server)
+                        .membership_history(from_index, to_index)
+                        .collect(),
+                },
+                other => unsupported(&other),
+            },
+        );
+
+        let status_server = Arc::clone(&server);
+        dispatcher.register(MessageType::StatusRequest, move |_| {
+            RpcMessage::StatusResponse {
+                status: NodeStatus::current(&status_server),
+            }
+        });
+
+        let heartbeat_server = Arc::clone(&server);
+        dispatcher.register(MessageType::Heartbeat, move |message| match message {
+            RpcMessage::Heartbeat { term, peer_id } => {
+                handle_log_entry(Arc::clone(&heartbeat_server), term, peer_id)
+            }
+            other => unsupported(&other),
+        });
+
+        let vote_server = Arc::clone(&server);
+        dispatcher.register(MessageType::VoteRequest, move |message| match message {
+            RpcMessage::VoteRequest {
+                term,
+                candidate_id,
+                last_log_index,
+                last_log_term,
+                leadership_transfer,
+                election_priority,
+            } => handle_vote_request(
+                Arc::clone(&vote_server),
+                VoteRequest {
+                    term: term,
+                    candidate_id: candidate_id,
+                    last_log_index: last_log_index,
+                    last_log_term: last_log_term,
+                    leadership_transfer: leadership_transfer,
+                    election_priority: election_priority,
+                },
+            ),
+            other => unsupported(&other),
+        });
+
+        let pre_vote_server = Arc::clone(&server);
+        dispatcher.register(MessageType::PreVoteRequest, move |message| match message {
+            RpcMessage::PreVoteRequest {
+                term,
+                candidate_id,
+                last_log_index,
+                last_log_term,
+            } => handle_pre_vote_request(
+                Arc::clone(&pre_vote_server),
+                PreVoteRequest {
+                    term: term,
+                    candidate_id: candidate_id,
+                    last_log_index: last_log_index,
+                    last_log_term: last_log_term,
+                },
+            ),
+            other => unsupported(&other),
+        });
+
+        let timeout_now_server = Arc::clone(&server);
+        dispatcher.register(MessageType::TimeoutNow, move |message| match message {
+            RpcMessage::TimeoutNow { term, leader_id } => handle_timeout_now(
+                Arc::clone(&timeout_now_server),
+                TimeoutNowRequest {
+                    term: term,
+                    leader_id: leader_id,
+                },
+            ),
+            other => unsupported(&other),
+        });
+
+        dispatcher.register(MessageType::AppendEntries, move |message| match message {
+            RpcMessage::AppendEntries {
+                term,
+                leader_id,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => handle_append_entries(
+                Arc::clone(&server),
+                AppendEntriesRequest {
+                    term: term,
+                    leader_id: leader_id,
+                    prev_log_index: prev_log_index,
+                    prev_log_term: prev_log_term,
+                    entries: entries,
+                    leader_commit: leader_commit,
+                },
+            ),
+            other => unsupported(&other),
+        });
 
-    bincode::serialize(&response).unwrap()
+        dispatcher
+    }
+
+    /// Registers the handler for a type of message, replacing any previous
+    /// one.
+    pub fn register(
+        self: &mut Self,
+        message_type: MessageType,
+        handler: impl Fn(RpcMessage) -> RpcMessage + Send + Sync + 'static,
+    ) {
+        self.handlers.insert(message_type, Box::new(handler));
+    }
+
+    pub fn dispatch(&self, message: RpcMessage) -> RpcMessage {
+        match self.handlers.get(&message.message_type()) {
+            Some(handler) => handler(message),
+            None => unsupported(&message),
+        }
+    }
+}
+
+fn unsupported(message: &RpcMessage) -> RpcMessage {
+    info!("No handler for {:?} messages", message.message_type());
+
+    RpcMessage::UnsupportedMessage {
+        message_type: message.message_type(),
+    }
+}
+
+impl TcpRpcServer {
+    pub fn new(server: Arc<Mutex<Server>>, address: SocketAddrV4) -> Self {
+        TcpRpcServer::with_dispatcher(Dispatcher::for_server(server), address)
+    }
+
+    /// A server whose clients talk `codec` rather than bincode.
+    pub fn new_with_codec(
+        server: Arc<Mutex<Server>>,
+        address: SocketAddrV4,
+        codec: Arc<dyn Codec>,
+    ) -> Self {
+        TcpRpcServer {
+            codec: codec,
+            ..TcpRpcServer::new(server, address)
+        }
+    }
+
+    pub fn with_dispatcher(dispatcher: Dispatcher, address: SocketAddrV4) -> Self {
+        TcpRpcServer {
+            dispatcher: Arc::new(dispatcher),
+            codec: Arc::new(BincodeCodec),
+            address: address,
+        }
+    }
+
+    /// Serves requests for as long as the process runs.
+    pub fn start_server(&self) {
+        self.spawn().unwrap().join();
+    }
+
+    /// Serves requests on a background thread until the returned handle is
+    /// stopped.
+    pub fn spawn(&self) -> Result<TcpRpcServerHandle, RaftError> {
+        info!("Starting server at: {}...", self.address);
+        let listener = TcpListener::bind(self.address)?;
+        // Bound to port 0, the listener got a free port of its own.
+        let address = match listener.local_addr()? {
+            SocketAddr::V4(address) => address,
+            SocketAddr::V6(_) => self.address,
+        };
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let connections = Arc::new(Mutex::new(HashMap::new()));
+
+        let accept_loop = {
+            let dispatcher = Arc::clone(&self.dispatcher);
+            let stopped = Arc::clone(&stopped);
+            let connections = Arc::clone(&connections);
+
+            let codec = Arc::clone(&self.codec);
+
+            thread::spawn(move || {
+                accept_connections(listener, dispatcher, codec, &stopped, connections)
+            })
+        };
+
+        Ok(TcpRpcServerHandle {
+            address: address,
+            stopped: stopped,
+            connections: connections,
+            accept_loop: accept_loop,
+        })
+    }
+}
+
+impl TcpRpcServerHandle {
+    /// The address the server listens on.
+    pub fn address(&self) -> SocketAddrV4 {
+        self.address
+    }
+
+    /// Stops accepting connections, closes the open ones and releases the
+    /// port.
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the accept loop so that it sees the flag.
+        let _ = TcpStream::connect(self.address);
+        self.accept_loop.join().unwrap();
+
+        for stream in self.connections.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        info!("Stopped server at: {}", self.address);
+    }
+
+    pub fn join(self) {
+        self.accept_loop.join().unwrap();
+    }
+}
+
+fn accept_connections(
+    listener: TcpListener,
+    dispatcher: Arc<Dispatcher>,
+    codec: Arc<dyn Codec>,
+    stopped: &AtomicBool,
+    connections: Arc<Mutex<HashMap<u64, TcpStream>>>,
+) {
+    for (id, stream) in (0u64..).zip(listener.incoming()) {
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                info!("Error while listening to client: {}", e);
+                continue;
+            }
+        };
+
+        // Kept so that stopping can close the connection; the handler
+        // forgets it when it is done.
+        if let Ok(clone) = stream.try_clone() {
+            connections.lock().unwrap().insert(id, clone);
+        }
+
+        let dispatcher = Arc::clone(&dispatcher);
+        let codec = Arc::clone(&codec);
+        let connections = Arc::clone(&connections);
+        thread::spawn(move || {
+            handle_connection(&dispatcher, codec.as_ref(), stream);
+            connections.lock().unwrap().remove(&id);
+        });
+    }
+}
+
+fn handle_connection(dispatcher: &Dispatcher, codec: &dyn Codec, stream: TcpStream) {
+    match stream.try_clone() {
+        Ok(reader) => serve_connection(dispatcher, codec, BufReader::new(reader), stream),
+        Err(e) => info!("Could not serve {:?}: {}", stream.peer_addr(), e),
+    }
+}
+
+/// Answers the requests read from `reader` on `writer` until the
+/// connection is closed or fails. Requests may be pipelined, so exactly
+/// one message is read at a time instead of whatever is available.
+fn serve_connection(
+    dispatcher: &Dispatcher,
+    codec: &dyn Codec,
+    mut reader: impl Read,
+    mut writer: impl Write,
+) {
+    while let Ok(request) = read_frame(&mut reader, codec) {
+        let response = dispatcher.dispatch(request);
+
+        if let Err(e) = write_frame(&mut writer, codec, &response) {
+            info!("Could not answer a request: {}", e);
+            break;
+        }
+    }
+}
+
+fn handle_log_entry(server: Arc<Mutex<Server>>, term: Term, peer_id: String) -> RpcMessage {
+    let response = crate::raft::core::handle_log_entry(
+        server,
+        LogEntry::Heartbeat {
+            term: term,
+            peer_id: peer_id,
+        },
+    );
+
+    // answers with its own id, so that a leader knows who is ahead of it
+    RpcMessage::HeartbeatResponse {
+        term: response.term,
+        peer_id: response.peer_id,
+        success: response.success,
+    }
+}
+
+fn handle_vote_request(server: Arc<Mutex<Server>>, request: VoteRequest) -> RpcMessage {
+    let response = crate::raft::core::handle_vote_request(server, request);
+
+    RpcMessage::VoteResponse {
+        term: response.term,
+        vote_granted: response.vote_granted,
+        voter_id: response.voter_id,
+    }
+}
+
+fn handle_pre_vote_request(server: Arc<Mutex<Server>>, request: PreVoteRequest) -> RpcMessage {
+    let response = crate::raft::core::handle_pre_vote_request(server, request);
+
+    RpcMessage::PreVoteResponse {
+        term: response.term,
+        vote_granted: response.vote_granted,
+        voter_id: response.voter_id,
+    }
+}
+
+fn handle_timeout_now(server: Arc<Mutex<Server>>, request: TimeoutNowRequest) -> RpcMessage {
+    let response = crate::raft::core::handle_timeout_now(server, request);
+
+    RpcMessage::TimeoutNowResponse {
+        term: response.term,
+        peer_id: response.peer_id,
+    }
+}
+
+fn handle_append_entries(server: Arc<Mutex<Server>>, request: AppendEntriesRequest) -> RpcMessage {
+    let response = crate::raft::core::handle_append_entries(server, request);
+
+    RpcMessage::AppendEntriesResponse {
+        term: response.term,
+        peer_id: response.peer_id,
+        success: response.success,
+        match_index: response.match_index,
+        conflict_term: response.conflict_term,
+        conflict_index: response.conflict_index,
+        last_applied: response.last_applied,
+        election_priority: response.election_priority,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::build_info::BuildInfo;
+    #[cfg(feature = "json")]
+    use crate::raft::codec::JsonCodec;
+    use crate::raft::testing::{self, Transport};
+    use crate::raft::types::{NodeKind, ServerConfig, State};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::net::Ipv4Addr;
+
+    /// Serves each peer on its own port with a `TcpRpcServer`, which can
+    /// be torn down and brought back on the same port.
+    struct TcpTransport {
+        codec: Arc<dyn Codec>,
+        addresses: HashMap<String, SocketAddrV4>,
+        running: HashMap<String, Running>,
+        max_frame_bytes: usize,
+    }
+
+    enum Running {
+        Serving(TcpRpcServerHandle),
+        Unresponsive(TcpListener),
+    }
+
+    impl Default for TcpTransport {
+        fn default() -> Self {
+            TcpTransport::with_codec(Arc::new(BincodeCodec))
+        }
+    }
+
+    impl TcpTransport {
+        fn with_codec(codec: Arc<dyn Codec>) -> Self {
+            TcpTransport {
+                codec: codec,
+                addresses: HashMap::new(),
+                running: HashMap::new(),
+                max_frame_bytes: MAX_FRAME_BYTES,
+            }
+        }
+
+        fn address(&mut self, peer_id: &str) -> SocketAddrV4 {
+            *self
+                .addresses
+                .entry(peer_id.to_string())
+                .or_insert_with(|| {
+                    let listener =
+                        TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+                    local_v4(listener.local_addr().unwrap())
+                })
+        }
+    }
+
+    impl Transport for TcpTransport {
+        type Client = TcpRpcClient;
+
+        fn serve(&mut self, server: Arc<Mutex<Server>>) {
+            let peer_id = server.lock().unwrap().id.to_string();
+            self.stop(&peer_id);
+
+            let address = self.address(&peer_id);
+            let rpc_server = TcpRpcServer::new_with_codec(server, address, Arc::clone(&self.codec));
+            let handle = rpc_server.spawn().unwrap();
+
+            self.running.insert(peer_id, Running::Serving(handle));
+        }
+
+        fn serve_unresponsive(&mut self, peer_id: &str) {
+            self.stop(peer_id);
+
+            let listener = TcpListener::bind(self.address(peer_id)).unwrap();
+            self.running
+                .insert(peer_id.to_string(), Running::Unresponsive(listener));
+        }
+
+        fn stop(&mut self, peer_id: &str) {
+            match self.running.remove(peer_id) {
+                Some(Running::Serving(handle)) => handle.stop(),
+                // Closed, connecting to it is refused from now on.
+                Some(Running::Unresponsive(listener)) => drop(listener),
+                None => (),
+            }
+        }
+
+        fn client(&mut self, peer_ids: &[&str], rpc_timeout: Duration) -> TcpRpcClient {
+            let peers: Vec<Peer> = peer_ids
+                .iter()
+                .map(|id| Peer {
+                    id: id.to_string(),
+                    address: self.address(id),
+                    kind: NodeKind::Voter,
+                })
+                .collect();
+
+            let mut client =
+                TcpRpcClient::with_timeout(&peers, rpc_timeout).with_codec(Arc::clone(&self.codec));
+            client.max_frame_bytes = self.max_frame_bytes;
+            client
+        }
+
+        fn limit_message_bytes(&mut self, max_bytes: usize) {
+            self.max_frame_bytes = max_bytes;
+        }
+    }
+
+    #[test]
+    fn tcp_rpc_transport_conformance() {
+        testing::transport_conformance(TcpTransport::default);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn tcp_rpc_transport_conformance_over_json() {
+        testing::transport_conformance(|| TcpTransport::with_codec(Arc::new(JsonCodec)));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn tcp_rpc_json_is_readable_on_the_wire() {
+        let server = Server::new(
+            ServerConfig::default(),
+            Vec::new(),
+            any_port(),
+            "server_2".to_string(),
+        )
+        .unwrap();
+        let rpc_handle = TcpRpcServer::new_with_codec(
+            Arc::new(Mutex::new(server)),
+            any_port(),
+            Arc::new(JsonCodec),
+        )
+        .spawn()
+        .unwrap();
+        let address = rpc_handle.address();
+
+        // plain JSON behind the length prefix
+        let request: &[u8] = b"{\"VoteRequest\":{\"term\":1,\"candidate_id\":\"server_1\",\"last_log_index\":0,\"last_log_term\":0,\"leadership_transfer\":false,\"election_priority\":0}}";
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(&(request.len() as u32).to_be_bytes())
+            .unwrap();
+        stream.write_all(request).unwrap();
+
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).unwrap();
+        let mut response = vec![0; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(
+            response,
+            b"{\"VoteResponse\":{\"term\":1,\"vote_granted\":true,\"voter_id\":\"server_2\"}}"
+        );
+
+        rpc_handle.stop();
+    }
+
+    #[test]
+    fn tcp_rpc_server_stop_releases_port() {
+        let server = Arc::new(Mutex::new(
+            Server::new(
+                ServerConfig::default(),
+                Vec::new(),
+                any_port(),
+                "server_2".to_string(),
+            )
+            .unwrap(),
+        ));
+
+        let rpc_server = TcpRpcServer::new(Arc::clone(&server), any_port());
+        let rpc_handle = rpc_server.spawn().unwrap();
+        let address = rpc_handle.address();
+        let node =
+            crate::raft::core::start_server(Arc::clone(&server), TcpRpcClient::new(&[])).unwrap();
+
+        // a client still connected does not keep the server alive
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        // both return once their threads have exited
+        node.shutdown();
+        rpc_handle.stop();
+
+        assert!(read_frame(&mut stream, &BincodeCodec).is_err());
+        assert!(TcpListener::bind(address).is_ok());
+    }
+
+    #[test]
+    fn tcp_rpc_request_snapshot() {
+        let data_dir =
+            std::env::temp_dir().join(format!("rsraft-tcp-snapshot-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        let rpc_handle = spawn_rpc_server(
+            "server_2",
+            ServerConfig {
+                data_dir: Some(data_dir),
+                ..ServerConfig::default()
+            },
+        );
+        let address = rpc_handle.address();
+        let timeout = Duration::from_secs(5);
+
+        let metadata = request_snapshot(address, timeout).unwrap();
+        assert_eq!(metadata.last_included_index, 0);
+        assert!(matches!(
+            request_snapshot(address, timeout),
+            Err(RaftError::Snapshot(SnapshotError::NothingNewApplied {
+                last_applied: 0
+            }))
+        ));
+
+        rpc_handle.stop();
+    }
+
+    #[test]
+    fn tcp_rpc_admin_call_to_nobody_is_an_io_error() {
+        // a port that was free a moment ago
+        let address = match TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+        {
+            SocketAddr::V4(address) => address,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+
+        match request_membership_history(address, 1, 2, Duration::from_secs(1)) {
+            Err(RaftError::Io(e)) => assert_eq!(e.kind(), ErrorKind::ConnectionRefused),
+            other => panic!("expected an I/O error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tcp_rpc_request_membership_history() {
+        let mut tmp_server = Server::new(
+            ServerConfig::default(),
+            Vec::new(),
+            any_port(),
+            "server_1".to_string(),
+        )
+        .unwrap();
+        // never contacted
+        let server_2 = TcpListener::bind(any_port()).unwrap();
+        tmp_server
+            .bootstrap(vec![Peer {
+                id: "server_2".to_string(),
+                address: local_v4(server_2.local_addr().unwrap()),
+                kind: NodeKind::Voter,
+            }])
+            .unwrap();
+        tmp_server.state = State::LEADER;
+        tmp_server.commit_index = 1;
+        tmp_server.remove_server("server_2").unwrap();
+
+        let rpc_handle = TcpRpcServer::new(Arc::new(Mutex::new(tmp_server)), any_port())
+            .spawn()
+            .unwrap();
+        let address = rpc_handle.address();
+
+        let records = request_membership_history(address, 1, 2, Duration::from_secs(5)).unwrap();
+        let changes: Vec<&str> = records.iter().map(|r| r.change.as_str()).collect();
+        assert_eq!(
+            changes,
+            vec!["bootstrapped with server_1, server_2", "removed server_2"]
+        );
+
+        rpc_handle.stop();
+    }
+
+    #[test]
+    fn tcp_rpc_request_vote() {
+        let address = start_rpc_server();
+
+        let client = TcpRpcClient::new(&[Peer {
+            id: "server_2".to_string(),
+            address: address,
+            kind: NodeKind::Voter,
+        }]);
+
+        let responses = client
+            .request_vote(VoteRequest {
+                term: Term(1),
+                candidate_id: "server_1".to_string(),
+                last_log_index: 0,
+                last_log_term: Term(0),
+                leadership_transfer: false,
+                election_priority: 0,
+            })
+            .unwrap();
+
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].vote_granted);
+        assert_eq!(responses[0].voter_id, "server_2");
+    }
+
+    #[test]
+    fn tcp_rpc_dispatcher_routes_every_message() {
+        let server = Arc::new(Mutex::new(
+            Server::new(
+                ServerConfig::default(),
+                Vec::new(),
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090),
+                "server_2".to_string(),
+            )
+            .unwrap(),
+        ));
+        let dispatcher = Dispatcher::for_server(Arc::clone(&server));
+
+        let response = dispatcher.dispatch(RpcMessage::VoteRequest {
+            term: Term(1),
+            candidate_id: "server_1".to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        });
+        assert!(matches!(
+            response,
+            RpcMessage::VoteResponse {
+                term: Term(1),
+                vote_granted: true,
+                ..
+            }
+        ));
+
+        let response = dispatcher.dispatch(RpcMessage::PreVoteRequest {
+            term: Term(2),
+            candidate_id: "server_3".to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+        });
+        assert!(matches!(
+            response,
+            RpcMessage::PreVoteResponse {
+                term: Term(1),
+                vote_granted: true,
+                ..
+            }
+        ));
+
+        let response = dispatcher.dispatch(RpcMessage::Heartbeat {
+            term: Term(2),
+            peer_id: "server_1".to_string(),
+        });
+        assert!(matches!(
+            response,
+            RpcMessage::HeartbeatResponse {
+                term: Term(2),
+                success: true,
+                ..
+            }
+        ));
+
+        let response = dispatcher.dispatch(RpcMessage::AppendEntries {
+            term: Term(2),
+            leader_id: "server_1".to_string(),
+            prev_log_index: 0,
+            prev_log_term: Term(0),
+            entries: vec![LogEntry::Command {
+                term: Term(2),
+                data: vec![1],
+            }],
+            leader_commit: 0,
+        });
+        assert!(matches!(
+            response,
+            RpcMessage::AppendEntriesResponse {
+                success: true,
+                match_index: 1,
+                ..
+            }
+        ));
+
+        let response = dispatcher.dispatch(RpcMessage::TimeoutNow {
+            term: Term(2),
+            leader_id: "server_1".to_string(),
+        });
+        assert!(matches!(
+            response,
+            RpcMessage::TimeoutNowResponse { term: Term(2), .. }
+        ));
+        assert!(server.lock().unwrap().timeout_now);
+
+        match dispatcher.dispatch(RpcMessage::StatusRequest) {
+            RpcMessage::StatusResponse { status } => {
+                assert_eq!(status.term, Term(2));
+                assert_eq!(status.leader_id, Some("server_1".to_string()));
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        // responses are not requests, no handler knows them
+        let unknown = vec![
+            RpcMessage::VoteResponse {
+                term: Term(1),
+                vote_granted: true,
+                voter_id: "server_2".to_string(),
+            },
+            RpcMessage::PreVoteResponse {
+                term: Term(1),
+                vote_granted: true,
+                voter_id: "server_2".to_string(),
+            },
+            RpcMessage::TimeoutNowResponse {
+                term: Term(1),
+                peer_id: "server_2".to_string(),
+            },
+            RpcMessage::HeartbeatResponse {
+                term: Term(1),
+                peer_id: "server_1".to_string(),
+                success: true,
+            },
+            RpcMessage::AppendEntriesResponse {
+                term: Term(1),
+                peer_id: "server_1".to_string(),
+                success: true,
+                match_index: 0,
+                conflict_term: None,
+                conflict_index: 0,
+                last_applied: 0,
+                election_priority: 0,
+            },
+            RpcMessage::StatusResponse {
+                status: NodeStatus {
+                    id: "server_2".to_string(),
+                    role: State::FOLLOWER,
+                    term: Term(1),
+                    leader_id: None,
+                    commit_index: 0,
+                    fault_tolerance: 0,
+                    uptime: Duration::ZERO,
+                    build: BuildInfo::current(),
+                },
+            },
+            RpcMessage::UnsupportedMessage {
+                message_type: MessageType::Heartbeat,
+            },
+        ];
+
+        for message in unknown {
+            let message_type = message.message_type();
+
+            match dispatcher.dispatch(message) {
+                RpcMessage::UnsupportedMessage { message_type: t } => assert_eq!(t, message_type),
+                other => panic!("unexpected response {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn tcp_rpc_frames_sent_back_to_back_stay_apart() {
+        let (mut sender, mut receiver) = socket_pair();
+        let heartbeat = |term: Term| RpcMessage::Heartbeat {
+            term: term,
+            peer_id: "server_1".to_string(),
+        };
+
+        // both frames in a single write
+        let mut bytes = frame(&heartbeat(Term(1)));
+        bytes.extend(frame(&heartbeat(Term(2))));
+        sender.write_all(&bytes).unwrap();
+
+        for term in 1..=2 {
+            assert!(matches!(
+                read_frame(&mut receiver, &BincodeCodec).unwrap(),
+                RpcMessage::Heartbeat { term: t, .. } if t == Term(term)
+            ));
+        }
+    }
+
+    #[test]
+    fn tcp_rpc_frame_larger_than_the_socket_buffer() {
+        let (mut sender, mut receiver) = socket_pair();
+        let data: Vec<u8> = (0..8 * 1024 * 1024).map(|i| i as u8).collect();
+        let message = |data: Vec<u8>| RpcMessage::AppendEntries {
+            term: Term(1),
+            leader_id: "server_1".to_string(),
+            prev_log_index: 0,
+            prev_log_term: Term(0),
+            entries: vec![LogEntry::Command {
+                term: Term(1),
+                data: data,
+            }],
+            leader_commit: 0,
+        };
+
+        // the write blocks until the other end has read most of it
+        let writer = {
+            let message = message(data.clone());
+            thread::spawn(move || write_frame(&mut sender, &BincodeCodec, &message))
+        };
+
+        match read_frame(&mut receiver, &BincodeCodec).unwrap() {
+            RpcMessage::AppendEntries { entries, .. } => {
+                assert_eq!(
+                    entries,
+                    vec![LogEntry::Command {
+                        term: Term(1),
+                        data: data
+                    }]
+                )
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        writer.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn tcp_rpc_oversized_frame_is_refused() {
+        let mut bytes = ((MAX_FRAME_BYTES + 1) as u32).to_be_bytes().to_vec();
+        bytes.extend(vec![0; 16]);
+
+        let e = read_frame(&mut &bytes[..], &BincodeCodec).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn tcp_rpc_unsupported_message_keeps_connection() {
+        let address = start_rpc_server();
+
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        write_frame(
+            &mut stream,
+            &BincodeCodec,
+            &RpcMessage::VoteResponse {
+                term: Term(1),
+                vote_granted: true,
+                voter_id: "server_2".to_string(),
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            read_frame(&mut stream, &BincodeCodec).unwrap(),
+            RpcMessage::UnsupportedMessage {
+                message_type: MessageType::VoteResponse
+            }
+        ));
+
+        write_frame(
+            &mut stream,
+            &BincodeCodec,
+            &RpcMessage::VoteRequest {
+                term: Term(1),
+                candidate_id: "server_1".to_string(),
+                last_log_index: 0,
+                last_log_term: Term(0),
+                leadership_transfer: false,
+                election_priority: 0,
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            read_frame(&mut stream, &BincodeCodec).unwrap(),
+            RpcMessage::VoteResponse { .. }
+        ));
+    }
+
+    #[test]
+    fn tcp_rpc_backoff_grows_and_resets() {
+        let initial = Duration::from_millis(100);
+        let max = Duration::from_millis(1000);
+        let mut backoff = Backoff::new(RetryPolicy::exponential(initial, max));
+        let now = Instant::now();
+        let refused = io::Error::from(ErrorKind::ConnectionRefused);
+
+        assert!(backoff.check(now).is_ok());
+
+        let mut expected = vec![100, 200, 400, 800, 1000, 1000].into_iter();
+        for _ in 0..6 {
+            let delay = backoff.failed(now, &refused);
+            let full = Duration::from_millis(expected.next().unwrap());
+
+            assert_eq!(backoff.delay(), full);
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+            assert!(backoff.check(now).is_err());
+            assert!(backoff.check(now + delay).is_ok());
+        }
+
+        backoff.succeeded();
+        assert!(backoff.check(now).is_ok());
+        backoff.failed(now, &refused);
+        assert_eq!(backoff.delay(), initial);
+    }
+
+    #[test]
+    fn tcp_rpc_backoff_waits_longest_for_hopeless_failures() {
+        let max = Duration::from_millis(1000);
+        let mut backoff = Backoff::new(RetryPolicy::exponential(Duration::from_millis(100), max));
+        let now = Instant::now();
+
+        // a peer answering in another codec will not do better soon
+        let garbled = io::Error::from(ErrorKind::InvalidData);
+        assert_eq!(backoff.failed(now, &garbled), max);
+        assert!(backoff.check(now + max / 2).is_err());
+        assert!(backoff.check(now + max).is_ok());
+
+        backoff.succeeded();
+        let refused = io::Error::from(ErrorKind::ConnectionRefused);
+        assert!(backoff.failed(now, &refused) <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn tcp_rpc_backs_off_unreachable_peer() {
+        let address = local_v4(
+            TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+                .unwrap()
+                .local_addr()
+                .unwrap(),
+        );
+        let peers = vec![Peer {
+            id: "server_2".to_string(),
+            address: address,
+            kind: NodeKind::Voter,
+        }];
+
+        let client = TcpRpcClient::with_timeout(&peers, Duration::from_millis(200))
+            .with_backoff(Duration::from_millis(200), Duration::from_secs(1));
+        let request = VoteRequest {
+            term: Term(1),
+            candidate_id: "server_1".to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+
+        // refused, then not even tried while backing off
+        let results = client.request_vote_from_each(&request);
+        assert!(!backing_off(results[0].1.as_ref().err().unwrap()));
+        let results = client.request_vote_from_each(&request);
+        assert!(backing_off(results[0].1.as_ref().err().unwrap()));
+
+        // once the peer is back and the backoff elapsed, it answers and is
+        // tried right away from then on
+        let server = Server::new(
+            ServerConfig::default(),
+            Vec::new(),
+            address,
+            "server_2".to_string(),
+        )
+        .unwrap();
+        TcpRpcServer::new(Arc::new(Mutex::new(server)), address)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(client.request_vote(request).unwrap().len(), 1);
+        assert!(client.peers.read().unwrap().servers["server_2"]
+            .lock()
+            .unwrap()
+            .backoff
+            .check(Instant::now())
+            .is_ok());
+    }
+
+    #[test]
+    fn tcp_rpc_unreachable_peers_time_out() {
+        // Accepts connections in the kernel backlog, but never answers.
+        let silent = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        // Nothing listens here anymore, connecting is refused.
+        let refused = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let peers = vec![
+            Peer {
+                id: "silent".to_string(),
+                address: local_v4(silent.local_addr().unwrap()),
+                kind: NodeKind::Voter,
+            },
+            Peer {
+                id: "refused".to_string(),
+                address: local_v4(refused),
+                kind: NodeKind::Voter,
+            },
+        ];
+
+        let client = TcpRpcClient::with_timeout(&peers, Duration::from_millis(200));
+        let request = VoteRequest {
+            term: Term(1),
+            candidate_id: "server_1".to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+
+        let started = Instant::now();
+        let results = client.request_vote_from_each(&request);
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(_, r)| r.is_err()));
+
+        // Through the RpcClient trait no peer answered at all.
+        assert!(client.request_vote(request).is_err());
+    }
+
+    #[test]
+    fn tcp_rpc_fails_over_to_the_next_address() {
+        let dead = local_v4(
+            TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+                .unwrap()
+                .local_addr()
+                .unwrap(),
+        );
+        let address = start_rpc_server();
+
+        let resolver = Arc::new(PeerAddresses::default());
+        resolver.update("server_2", vec![dead, address]);
+        let client = TcpRpcClient::with_resolver(
+            vec!["server_2".to_string()],
+            resolver,
+            Duration::from_millis(200),
+        );
+
+        let votes = client
+            .request_vote(VoteRequest {
+                term: Term(1),
+                candidate_id: "server_1".to_string(),
+                last_log_index: 0,
+                last_log_term: Term(0),
+                leadership_transfer: false,
+                election_priority: 0,
+            })
+            .unwrap();
+
+        assert_eq!(votes.len(), 1);
+        assert_eq!(
+            client.dialer.last_connected.lock().unwrap().get("server_2"),
+            Some(&address)
+        );
+    }
+
+    #[test]
+    fn tcp_rpc_follows_address_updates() {
+        let server = Arc::new(Mutex::new(
+            Server::new(
+                ServerConfig::default(),
+                Vec::new(),
+                any_port(),
+                "server_2".into(),
+            )
+            .unwrap(),
+        ));
+        let old_handle = TcpRpcServer::new(Arc::clone(&server), any_port())
+            .spawn()
+            .unwrap();
+        let old_address = old_handle.address();
+
+        let resolver = Arc::new(PeerAddresses::new(&[Peer {
+            id: "server_2".to_string(),
+            address: old_address,
+            kind: NodeKind::Voter,
+        }]));
+        let client = TcpRpcClient::with_resolver(
+            vec!["server_2".to_string()],
+            Arc::clone(&resolver) as Arc<dyn PeerResolver>,
+            Duration::from_millis(200),
+        );
+        let vote_request = |term: Term| VoteRequest {
+            term: term,
+            candidate_id: "server_1".to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        };
+        assert_eq!(client.request_vote(vote_request(Term(1))).unwrap().len(), 1);
+
+        // The peer moves: the same client reaches it at its new address.
+        old_handle.stop();
+        let new_handle = TcpRpcServer::new(server, any_port()).spawn().unwrap();
+        let new_address = new_handle.address();
+        resolver.update("server_2", vec![new_address]);
+
+        assert_eq!(client.request_vote(vote_request(Term(2))).unwrap().len(), 1);
+        assert_eq!(
+            client.dialer.last_connected.lock().unwrap().get("server_2"),
+            Some(&new_address)
+        );
+
+        new_handle.stop();
+    }
+
+    #[test]
+    fn tcp_rpc_follows_peer_updates() {
+        let handles: Vec<TcpRpcServerHandle> = ["server_2", "server_3"]
+            .iter()
+            .map(|id| spawn_rpc_server(id, ServerConfig::default()))
+            .collect();
+        let peer = |id: &str, handle: &TcpRpcServerHandle| Peer {
+            id: id.to_string(),
+            address: handle.address(),
+            kind: NodeKind::Voter,
+        };
+        let (server_2, server_3) = (peer("server_2", &handles[0]), peer("server_3", &handles[1]));
+
+        let client =
+            TcpRpcClient::with_timeout(std::slice::from_ref(&server_2), Duration::from_secs(1));
+        let answered = |term: Term| -> Vec<String> {
+            let heartbeat = LogEntry::Heartbeat {
+                term: term,
+                peer_id: "server_1".to_string(),
+            };
+            let mut peer_ids: Vec<String> = client
+                .broadcast_log_entry(heartbeat)
+                .unwrap()
+                .into_iter()
+                .map(|r| r.peer_id)
+                .collect();
+            peer_ids.sort();
+            peer_ids
+        };
+        assert_eq!(answered(Term(1)), vec!["server_2"]);
+
+        client.set_peers(&[server_3]);
+        assert_eq!(client.peer_ids(), vec!["server_3"]);
+        assert_eq!(answered(Term(2)), vec!["server_3"]);
+
+        client.add_peer(&server_2);
+        assert_eq!(answered(Term(3)), vec!["server_2", "server_3"]);
+
+        client.remove_peer("server_3");
+        assert_eq!(answered(Term(4)), vec!["server_2"]);
+
+        for handle in handles {
+            handle.stop();
+        }
+    }
+
+    #[test]
+    fn tcp_rpc_client_follows_its_peer_set() {
+        let peer = |id: &str, port: u16| Peer {
+            id: id.to_string(),
+            address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+            kind: NodeKind::Voter,
+        };
+        let peer_set = Arc::new(PeerSet::new(vec![peer("server_2", 1)]));
+        let client = TcpRpcClient::new(&[]).with_peer_set(Arc::clone(&peer_set));
+        assert_eq!(client.peer_ids(), vec!["server_2".to_string()]);
+
+        peer_set.set(vec![peer("server_2", 1), peer("server_3", 2)]);
+        client.follow_peer_set();
+        assert_eq!(
+            client.peer_ids(),
+            vec!["server_2".to_string(), "server_3".to_string()]
+        );
+
+        peer_set.set(vec![peer("server_3", 3)]);
+        client.follow_peer_set();
+        assert_eq!(client.peer_ids(), vec!["server_3".to_string()]);
+        assert_eq!(
+            client.dialer.resolver.resolve("server_3"),
+            vec![SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3)]
+        );
+    }
+
+    #[test]
+    fn tcp_rpc_local_server_is_left_out_of_the_peers() {
+        let handles: Vec<TcpRpcServerHandle> = ["server_1", "server_2"]
+            .iter()
+            .map(|id| spawn_rpc_server(id, ServerConfig::default()))
+            .collect();
+        let peer = |id: &str, handle: &TcpRpcServerHandle| Peer {
+            id: id.to_string(),
+            address: handle.address(),
+            kind: NodeKind::Voter,
+        };
+        let local = peer("server_1", &handles[0]);
+
+        // listed by its id, and under another id at its address
+        let peers = vec![
+            local.clone(),
+            peer("server_2", &handles[1]),
+            peer("server_3", &handles[0]),
+        ];
+        let client =
+            TcpRpcClient::with_timeout(&peers, Duration::from_secs(1)).with_local_server(&local);
+        assert_eq!(client.peer_ids(), vec!["server_2"]);
+
+        let heartbeat = LogEntry::Heartbeat {
+            term: Term(1),
+            peer_id: "server_1".to_string(),
+        };
+        let responses = client.broadcast_log_entry(heartbeat).unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].peer_id, "server_2");
+
+        // nor added back later
+        client.add_peer(&local);
+        assert_eq!(client.peer_ids(), vec!["server_2"]);
+
+        for handle in handles {
+            handle.stop();
+        }
+    }
+
+    #[test]
+    fn tcp_rpc_request_vote_in_parallel() {
+        let rpc_timeout = Duration::from_millis(200);
+
+        let address = start_rpc_server();
+
+        // Slow peers: connections are accepted by the kernel, but nobody
+        // ever answers, so each of them costs a full rpc_timeout.
+        let silent: Vec<TcpListener> = (0..4)
+            .map(|_| TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap())
+            .collect();
+
+        let mut peers: Vec<Peer> = silent
+            .iter()
+            .enumerate()
+            .map(|(i, listener)| Peer {
+                id: format!("silent_{}", i),
+                address: local_v4(listener.local_addr().unwrap()),
+                kind: NodeKind::Voter,
+            })
+            .collect();
+        peers.push(Peer {
+            id: "server_2".to_string(),
+            address: address,
+            kind: NodeKind::Voter,
+        });
+
+        let client = TcpRpcClient::with_timeout(&peers, rpc_timeout);
+
+        let started = Instant::now();
+        let votes = client
+            .request_vote(VoteRequest {
+                term: Term(1),
+                candidate_id: "server_1".to_string(),
+                last_log_index: 0,
+                last_log_term: Term(0),
+                leadership_transfer: false,
+                election_priority: 0,
+            })
+            .unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(votes.len(), 1);
+        assert!(votes[0].vote_granted);
+        // one slow peer's worth of waiting, not four
+        assert!(elapsed >= rpc_timeout, "took {:?}", elapsed);
+        assert!(elapsed < rpc_timeout * 2, "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn tcp_rpc_request_vote_until_decided() {
+        let rpc_timeout = Duration::from_secs(5);
+
+        let address = start_rpc_server();
+
+        let silent = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let peers = vec![
+            Peer {
+                id: "silent".to_string(),
+                address: local_v4(silent.local_addr().unwrap()),
+                kind: NodeKind::Voter,
+            },
+            Peer {
+                id: "server_2".to_string(),
+                address: address,
+                kind: NodeKind::Voter,
+            },
+        ];
+        let client = TcpRpcClient::with_timeout(&peers, rpc_timeout);
+
+        let started = Instant::now();
+        let votes = client
+            .request_vote_until(
+                VoteRequest {
+                    term: Term(1),
+                    candidate_id: "server_1".to_string(),
+                    last_log_index: 0,
+                    last_log_term: Term(0),
+                    leadership_transfer: false,
+                    election_priority: 0,
+                },
+                &|votes| votes.iter().any(|v| v.vote_granted),
+            )
+            .unwrap();
+
+        assert_eq!(votes.len(), 1);
+        assert_eq!(votes[0].voter_id, "server_2");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Serves "server_2" on a free port, returning its address.
+    fn start_rpc_server() -> SocketAddrV4 {
+        spawn_rpc_server("server_2", ServerConfig::default()).address()
+    }
+
+    fn spawn_rpc_server(id: &str, config: ServerConfig) -> TcpRpcServerHandle {
+        let address = any_port();
+        let server = Server::new(config, Vec::new(), address, id.to_string()).unwrap();
+
+        TcpRpcServer::new(Arc::new(Mutex::new(server)), address)
+            .spawn()
+            .unwrap()
+    }
+
+    fn any_port() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)
+    }
+
+    /// Both ends of a loopback connection.
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (receiver, _) = listener.accept().unwrap();
+
+        (sender, receiver)
+    }
+
+    fn local_v4(address: SocketAddr) -> SocketAddrV4 {
+        match address {
+            SocketAddr::V4(address) => address,
+            SocketAddr::V6(_) => panic!("expected an IPv4 address"),
+        }
+    }
+
+    /// What a `ScriptedStream` does on the next read.
+    enum Step {
+        Bytes(Vec<u8>),
+        Delay(Duration),
+        Reset,
+    }
+
+    /// A stream that plays a script: reads return the scripted chunks one
+    /// at a time, a reset fails the read, and once the script is over the
+    /// stream is at its end. Writes are kept, and fail with a reset past
+    /// `write_limit` bytes.
+    #[derive(Default)]
+    struct ScriptedStream {
+        script: VecDeque<Step>,
+        written: Vec<u8>,
+        write_limit: Option<usize>,
+    }
+
+    impl ScriptedStream {
+        /// Delivers `message` in reads of `chunk_size` bytes.
+        fn message(self, message: &RpcMessage, chunk_size: usize) -> Self {
+            self.bytes(&frame(message), chunk_size)
+        }
+
+        fn bytes(mut self, bytes: &[u8], chunk_size: usize) -> Self {
+            for chunk in bytes.chunks(chunk_size) {
+                self.script.push_back(Step::Bytes(chunk.to_vec()));
+            }
+            self
+        }
+
+        fn then(mut self, step: Step) -> Self {
+            self.script.push_back(step);
+            self
+        }
+
+        fn fail_writes_after(mut self, bytes: usize) -> Self {
+            self.write_limit = Some(bytes);
+            self
+        }
+    }
+
+    impl Read for ScriptedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                match self.script.pop_front() {
+                    Some(Step::Bytes(mut bytes)) => {
+                        let n = bytes.len().min(buf.len());
+                        buf[..n].copy_from_slice(&bytes[..n]);
+                        if n < bytes.len() {
+                            self.script.push_front(Step::Bytes(bytes.split_off(n)));
+                        }
+                        return Ok(n);
+                    }
+                    Some(Step::Delay(delay)) => thread::sleep(delay),
+                    Some(Step::Reset) => return Err(ErrorKind::ConnectionReset.into()),
+                    None => return Ok(0),
+                }
+            }
+        }
+    }
+
+    impl Write for ScriptedStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let room = self
+                .write_limit
+                .map_or(buf.len(), |limit| limit.saturating_sub(self.written.len()));
+            if room == 0 {
+                return Err(ErrorKind::ConnectionReset.into());
+            }
+
+            let n = buf.len().min(room);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The bytes of the message on the wire.
+    fn frame(message: &RpcMessage) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &BincodeCodec, message).unwrap();
+        frame
+    }
+
+    fn vote_request() -> RpcMessage {
+        RpcMessage::VoteRequest {
+            term: Term(1),
+            candidate_id: "server_1".to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+            election_priority: 0,
+        }
+    }
+
+    fn vote_response() -> RpcMessage {
+        RpcMessage::VoteResponse {
+            term: Term(1),
+            vote_granted: true,
+            voter_id: "server_2".to_string(),
+        }
+    }
+
+    /// A connection whose `connect` hands out `streams` in turn, and fails
+    /// once they are used up.
+    fn scripted_call(
+        connection: &mut Connection<ScriptedStream>,
+        streams: &RefCell<VecDeque<ScriptedStream>>,
+    ) -> io::Result<RpcMessage> {
+        connection.call(&BincodeCodec, &vote_request(), || {
+            streams
+                .borrow_mut()
+                .pop_front()
+                .ok_or_else(|| io::Error::from(ErrorKind::ConnectionRefused))
+        })
+    }
+
+    fn scripted_connection() -> Connection<ScriptedStream> {
+        Connection {
+            stream: None,
+            backoff: Backoff::new(RetryPolicy::exponential(
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+            )),
+        }
+    }
+
+    #[test]
+    fn tcp_rpc_scripted_request_interrupted() {
+        let streams = RefCell::new(VecDeque::from(vec![
+            ScriptedStream::default().fail_writes_after(3)
+        ]));
+        let mut connection = scripted_connection();
+
+        let e = scripted_call(&mut connection, &streams).unwrap_err();
+
+        assert_eq!(e.kind(), ErrorKind::ConnectionReset);
+        assert!(connection.stream.is_none());
+        assert!(connection.backoff.check(Instant::now()).is_err());
+    }
+
+    #[test]
+    fn tcp_rpc_scripted_response_split_across_reads() {
+        let stream = ScriptedStream::default()
+            .bytes(&frame(&vote_response())[..2], 1)
+            .then(Step::Delay(Duration::from_millis(10)))
+            .bytes(&frame(&vote_response())[2..], 1);
+        let streams = RefCell::new(VecDeque::from(vec![stream]));
+        let mut connection = scripted_connection();
+
+        assert!(matches!(
+            scripted_call(&mut connection, &streams),
+            Ok(RpcMessage::VoteResponse {
+                term: Term(1),
+                vote_granted: true,
+                ..
+            })
+        ));
+
+        // the request went out whole, and the connection is kept
+        let written = &connection.stream.as_ref().unwrap().written;
+        assert_eq!(*written, frame(&vote_request()));
+    }
+
+    #[test]
+    fn tcp_rpc_scripted_response_truncated_by_reset() {
+        let response = frame(&vote_response());
+        let stream = ScriptedStream::default()
+            .bytes(&response[..response.len() / 2], 1)
+            .then(Step::Reset);
+        let streams = RefCell::new(VecDeque::from(vec![stream]));
+        let mut connection = scripted_connection();
+
+        assert!(scripted_call(&mut connection, &streams).is_err());
+        assert!(connection.stream.is_none());
+    }
+
+    #[test]
+    fn tcp_rpc_scripted_response_lost() {
+        // The request is written, and the peer goes away without a word. A
+        // fresh connection is not retried: the peer is really down.
+        let streams = RefCell::new(VecDeque::from(vec![
+            ScriptedStream::default().then(Step::Reset),
+            ScriptedStream::default().message(&vote_response(), 64),
+        ]));
+        let mut connection = scripted_connection();
+
+        assert!(scripted_call(&mut connection, &streams).is_err());
+        assert_eq!(streams.borrow().len(), 1);
+        assert!(connection.backoff.check(Instant::now()).is_err());
+    }
+
+    #[test]
+    fn tcp_rpc_scripted_peer_restarted_between_requests() {
+        // The pooled connection answers once, then the peer restarts and
+        // the connection is at its end.
+        let streams = RefCell::new(VecDeque::from(vec![
+            ScriptedStream::default().message(&vote_response(), 64),
+            ScriptedStream::default().message(&vote_response(), 64),
+        ]));
+        let mut connection = scripted_connection();
+
+        assert!(scripted_call(&mut connection, &streams).is_ok());
+        assert!(scripted_call(&mut connection, &streams).is_ok());
+
+        // reconnected without backing off
+        assert!(streams.borrow().is_empty());
+        assert!(connection.backoff.check(Instant::now()).is_ok());
+    }
+
+    fn scripted_dispatcher() -> Dispatcher {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(MessageType::VoteRequest, |_| vote_response());
+        dispatcher
+    }
+
+    #[test]
+    fn tcp_rpc_scripted_server_requests_split_across_reads() {
+        let reader = ScriptedStream::default()
+            .message(&vote_request(), 1)
+            .message(&vote_request(), 3);
+        let mut writer = ScriptedStream::default();
+
+        serve_connection(&scripted_dispatcher(), &BincodeCodec, reader, &mut writer);
+
+        let mut expected = frame(&vote_response());
+        expected.extend(frame(&vote_response()));
+        assert_eq!(writer.written, expected);
+    }
+
+    #[test]
+    fn tcp_rpc_scripted_server_request_truncated() {
+        let request = frame(&vote_request());
+
+        for end in [Step::Reset, Step::Delay(Duration::new(0, 0))] {
+            let reader = ScriptedStream::default()
+                .message(&vote_request(), 64)
+                .bytes(&request[..request.len() - 1], 1)
+                .then(end);
+            let mut writer = ScriptedStream::default();
+
+            serve_connection(&scripted_dispatcher(), &BincodeCodec, reader, &mut writer);
+
+            // the whole request is answered, the partial one is not
+            assert_eq!(writer.written, frame(&vote_response()));
+        }
+    }
+
+    #[test]
+    fn tcp_rpc_scripted_server_response_lost() {
+        let reader = ScriptedStream::default()
+            .message(&vote_request(), 64)
+            .message(&vote_request(), 64);
+        let mut writer = ScriptedStream::default().fail_writes_after(2);
+
+        // gives up on the connection instead of reading on
+        serve_connection(&scripted_dispatcher(), &BincodeCodec, reader, &mut writer);
+
+        assert_eq!(writer.written.len(), 2);
+    }
 }
@@ -0,0 +1,870 @@
+use crate::raft::clock::ManualClock;
+use crate::raft::core::{self, ServerHandle};
+use crate::raft::log_storage::LogStorage;
+use crate::raft::memory_rpc::{Faults, MemoryNetwork, MemoryRpcClient, PartitionController};
+use crate::raft::retry::JitterRng;
+use crate::raft::state_machine::StateMachine;
+use crate::raft::types::{
+    AppendEntriesRequest, HeartbeatResponse, LogEntry, NodeKind, Peer, PreVoteRequest, RpcClient,
+    RpcError, Server, ServerConfig, State, Term, TimeoutNowRequest, TimeoutNowResponse,
+    VoteRequest,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A transport under test: something that can make servers reachable
+/// under their id, take them down again, and build clients to reach them.
+pub trait Transport {
+    type Client: RpcClient + Send + Sync + 'static;
+
+    fn serve(&mut self, server: Arc<Mutex<Server>>);
+
+    /// Makes the peer reachable, but it never answers any request.
+    fn serve_unresponsive(&mut self, peer_id: &str);
+
+    /// Takes the peer down, as if its process died.
+    fn stop(&mut self, peer_id: &str);
+
+    fn client(&mut self, peer_ids: &[&str], rpc_timeout: Duration) -> Self::Client;
+
+    /// Leaves unsent the AppendEntries requests larger than `max_bytes`
+    /// once encoded, from the clients built from now on.
+    fn limit_message_bytes(&mut self, max_bytes: usize);
+}
+
+const RPC_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Checks that a transport behaves like every other one. Each check gets a
+/// fresh transport from `make`.
+pub fn transport_conformance<T: Transport>(make: impl Fn() -> T) {
+    vote_round_trip(make());
+    pre_vote_round_trip(make());
+    timeout_now_round_trip(make());
+    heartbeat_round_trip(make());
+    append_entries_round_trip(make());
+    oversized_entries(make());
+    concurrent_requests(make());
+    peer_down(make());
+    slow_peer(make());
+    reconnect_after_restart(make());
+}
+
+/// Checks that a log storage behaves like every other one. Each check gets
+/// a fresh, empty storage from `make`.
+pub fn log_storage_conformance<S: LogStorage>(make: impl Fn() -> S) {
+    log_storage_starts_empty(make());
+    log_storage_reads_back_appends(make());
+    log_storage_truncates(make());
+}
+
+fn entry(term: u64, n: u8) -> LogEntry {
+    LogEntry::Command {
+        term: Term(term),
+        data: vec![n; n as usize],
+    }
+}
+
+fn log_storage_starts_empty(storage: impl LogStorage) {
+    assert_eq!(storage.last_index(), 0);
+    assert_eq!(storage.term_at(0), Some(Term(0)));
+    assert_eq!(storage.term_at(1), None);
+    assert_eq!(storage.entry(0).unwrap(), None);
+    assert_eq!(storage.entry(1).unwrap(), None);
+    assert_eq!(storage.entries(1, u64::MAX).unwrap(), Vec::new());
+}
+
+fn log_storage_reads_back_appends(mut storage: impl LogStorage) {
+    let entries: Vec<LogEntry> = (1..=10).map(|n| entry(n as u64 / 4 + 1, n)).collect();
+    storage.append(&entries[..3]).unwrap();
+    for entry in &entries[3..] {
+        storage.append(std::slice::from_ref(entry)).unwrap();
+    }
+    // syncing changes nothing that can be read back
+    storage.sync().unwrap();
+
+    assert_eq!(storage.last_index(), 10);
+    for (i, entry) in entries.iter().enumerate() {
+        let index = i as u64 + 1;
+        assert_eq!(storage.entry(index).unwrap().as_ref(), Some(entry));
+        assert_eq!(storage.term_at(index), Some(entry.term()));
+    }
+    assert_eq!(storage.entry(11).unwrap(), None);
+    assert_eq!(storage.entries(4, 6).unwrap(), entries[3..6].to_vec());
+    assert_eq!(storage.entries(0, 3).unwrap(), entries[..3].to_vec());
+    assert_eq!(storage.entries(8, u64::MAX).unwrap(), entries[7..].to_vec());
+    assert_eq!(storage.entries(6, 5).unwrap(), Vec::new());
+}
+
+fn log_storage_truncates(mut storage: impl LogStorage) {
+    let entries: Vec<LogEntry> = (1..=10).map(|n| entry(1, n)).collect();
+    storage.append(&entries).unwrap();
+
+    // past the end, nothing to remove
+    storage.truncate_from(11).unwrap();
+    assert_eq!(storage.last_index(), 10);
+
+    storage.truncate_from(8).unwrap();
+    assert_eq!(storage.last_index(), 7);
+    assert_eq!(storage.entry(8).unwrap(), None);
+    assert_eq!(storage.term_at(8), None);
+
+    // deep into the log, then appending in place of what was removed
+    storage.truncate_from(3).unwrap();
+    assert_eq!(storage.last_index(), 2);
+    storage.append(&[entry(2, 30), entry(2, 40)]).unwrap();
+    assert_eq!(
+        storage.entries(1, u64::MAX).unwrap(),
+        vec![
+            entries[0].clone(),
+            entries[1].clone(),
+            entry(2, 30),
+            entry(2, 40)
+        ]
+    );
+    assert_eq!(storage.term_at(3), Some(Term(2)));
+
+    storage.truncate_from(1).unwrap();
+    assert_eq!(storage.last_index(), 0);
+    assert_eq!(storage.entry(1).unwrap(), None);
+}
+
+/// A cluster of servers running in this process on a `MemoryNetwork`, each
+/// with its own background task, for tests that exercise everything
+/// together.
+pub struct Cluster {
+    network: MemoryNetwork,
+    servers: Vec<Arc<Mutex<Server>>>,
+    /// Behind a lock so that a server can be killed while clients use the
+    /// cluster.
+    handles: Mutex<HashMap<String, ServerHandle>>,
+}
+
+impl Cluster {
+    /// Starts `size` servers, `server_1` to `server_<size>`, with state
+    /// machines from `state_machine`. Their election timeouts are spread
+    /// apart so that the first election usually has a single candidate.
+    pub fn start(
+        size: usize,
+        mut state_machine: impl FnMut(&str) -> Box<dyn StateMachine>,
+    ) -> Cluster {
+        let peers: Vec<Peer> = (1..=size)
+            .map(|i| Peer {
+                id: format!("server_{}", i),
+                address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090 + i as u16),
+                kind: NodeKind::Voter,
+            })
+            .collect();
+
+        let network = MemoryNetwork::new();
+        let mut servers = Vec::new();
+        let mut handles = HashMap::new();
+
+        for (i, peer) in peers.iter().enumerate() {
+            let others: Vec<Peer> = peers.iter().filter(|p| p.id != peer.id).cloned().collect();
+            let mut server = Server::new(
+                cluster_config(),
+                others.clone(),
+                peer.address,
+                peer.id.to_string(),
+            )
+            .unwrap();
+            server.bootstrap(others.clone()).unwrap();
+            server.state_machine = Some(state_machine(&peer.id));
+
+            let server = Arc::new(Mutex::new(server));
+            network.serve(Arc::clone(&server));
+            servers.push(server);
+
+            let client = network.client(others.into_iter().map(|p| p.id).collect(), RPC_TIMEOUT);
+            handles.insert(
+                peer.id.to_string(),
+                core::start_server(Arc::clone(&servers[i]), client).unwrap(),
+            );
+        }
+
+        Cluster {
+            network: network,
+            servers: servers,
+            handles: Mutex::new(handles),
+        }
+    }
+
+    /// Starts one more server, `server_<n>` after the last one, that joins
+    /// the cluster as a learner: it is added to the configuration by the
+    /// leader, which promotes it once it has caught up.
+    pub fn join(self: &mut Self, state_machine: Box<dyn StateMachine>) -> Arc<Mutex<Server>> {
+        let i = self.servers.len() + 1;
+        let peer = Peer {
+            id: format!("server_{}", i),
+            address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090 + i as u16),
+            kind: NodeKind::Voter,
+        };
+        let others: Vec<Peer> = self
+            .servers
+            .iter()
+            .map(|s| {
+                let tmp_server = s.lock().unwrap();
+                Peer {
+                    id: tmp_server.id.to_string(),
+                    address: tmp_server.address,
+                    kind: tmp_server.config.kind,
+                }
+            })
+            .collect();
+        let peer_ids: Vec<String> = others.iter().map(|p| p.id.to_string()).collect();
+
+        let mut server =
+            Server::new(cluster_config(), others, peer.address, peer.id.to_string()).unwrap();
+        server.join_as_learner();
+        server.state_machine = Some(state_machine);
+
+        let server = Arc::new(Mutex::new(server));
+        self.network.serve(Arc::clone(&server));
+        self.servers.push(Arc::clone(&server));
+        let client = self.network.client(peer_ids, RPC_TIMEOUT);
+        self.handles.lock().unwrap().insert(
+            peer.id.to_string(),
+            core::start_server(Arc::clone(&server), client).unwrap(),
+        );
+
+        // The previous configuration may not be committed yet.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while let Err(e) = self.leader().lock().unwrap().add_server(peer.clone()) {
+            assert!(
+                Instant::now() < deadline,
+                "could not add {}: {:?}",
+                peer.id,
+                e
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        server
+    }
+
+    /// The servers still running.
+    pub fn servers(&self) -> Vec<Arc<Mutex<Server>>> {
+        let handles = self.handles.lock().unwrap();
+        self.servers
+            .iter()
+            .filter(|s| handles.contains_key(&s.lock().unwrap().id))
+            .cloned()
+            .collect()
+    }
+
+    /// Waits for a running server to lead, and returns the one with the
+    /// highest term if several think they do.
+    pub fn leader(&self) -> Arc<Mutex<Server>> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        loop {
+            let leader = self
+                .servers()
+                .into_iter()
+                .filter(|s| s.lock().unwrap().state == State::LEADER)
+                .max_by_key(|s| s.lock().unwrap().term);
+
+            match leader {
+                Some(leader) => return leader,
+                None if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                None => panic!("no leader was elected"),
+            }
+        }
+    }
+
+    /// Takes the server down, as if its process died.
+    pub fn kill(&self, id: &str) {
+        self.network.stop(id);
+        let handle = self.handles.lock().unwrap().remove(id);
+        if let Some(handle) = handle {
+            handle.shutdown();
+        }
+    }
+
+    /// Drops every request to the server until it is reconnected. The
+    /// server itself keeps running.
+    pub fn disconnect(&self, id: &str) {
+        self.network.stop(id);
+    }
+
+    pub fn reconnect(&self, id: &str) {
+        let server = self.server(id);
+        self.network.serve(server);
+    }
+
+    pub fn server(&self, id: &str) -> Arc<Mutex<Server>> {
+        self.servers
+            .iter()
+            .find(|s| s.lock().unwrap().id == id)
+            .cloned()
+            .unwrap()
+    }
+
+    /// Proposes until the command has been applied on a leader, retrying
+    /// like a client would when the leader changes or pushes back. The
+    /// session makes sure it is applied only once. Returns the response.
+    pub fn propose_in_session(&self, client_id: &str, sequence: u64, data: Vec<u8>) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+
+        while Instant::now() < deadline {
+            let leader = self.leader();
+            let proposed =
+                leader
+                    .lock()
+                    .unwrap()
+                    .propose_in_session(client_id, sequence, data.clone());
+
+            let index = match proposed {
+                Ok(index) => index,
+                Err(_) => {
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+            };
+
+            if core::wait_for_applied(&leader, index, Duration::from_secs(1)).is_ok() {
+                // The entry at `index` may not be ours if the leader lost
+                // its leadership in the meantime.
+                let tmp_server = leader.lock().unwrap();
+                if let Some(response) = tmp_server.sessions.response(client_id, sequence) {
+                    return response.to_vec();
+                }
+            }
+        }
+
+        panic!("{} could not apply request {}", client_id, sequence);
+    }
+
+    pub fn shutdown(self) {
+        for (_, handle) in self.handles.into_inner().unwrap() {
+            handle.shutdown();
+        }
+    }
+}
+
+/// How far the clock of a `TestCluster` moves per tick.
+pub const TICK: Duration = Duration::from_millis(10);
+
+/// A cluster of servers that only move when the test steps them: each
+/// `tick` moves their shared `ManualClock` on and runs one round of every
+/// server's background task, in order. There are no background threads,
+/// and election timeouts are fixed and spread apart rather than random,
+/// so a scenario plays out the same way on every run.
+pub struct TestCluster {
+    clock: ManualClock,
+    servers: Vec<Arc<Mutex<Server>>>,
+    network: MemoryNetwork,
+    clients: Vec<MemoryRpcClient>,
+    shutdown: AtomicBool,
+}
+
+impl TestCluster {
+    /// Builds `size` servers, `server_1` to `server_<size>`, with state
+    /// machines from `state_machine`. `server_1` times out first, after
+    /// 300ms, and every next one 100ms later.
+    pub fn new(
+        size: usize,
+        state_machine: impl FnMut(&str) -> Box<dyn StateMachine>,
+    ) -> TestCluster {
+        TestCluster::with_kinds(&vec![NodeKind::Voter; size], state_machine)
+    }
+
+    /// Like `new`, but `server_<i>` is of kind `kinds[i - 1]`.
+    pub fn with_kinds(
+        kinds: &[NodeKind],
+        state_machine: impl FnMut(&str) -> Box<dyn StateMachine>,
+    ) -> TestCluster {
+        TestCluster::with_config(kinds, state_machine, |_, _| {})
+    }
+
+    /// Like `with_kinds`, letting `configure` change the configuration of
+    /// every server before it is built.
+    pub fn with_config(
+        kinds: &[NodeKind],
+        mut state_machine: impl FnMut(&str) -> Box<dyn StateMachine>,
+        mut configure: impl FnMut(&str, &mut ServerConfig),
+    ) -> TestCluster {
+        let size = kinds.len();
+        let clock = ManualClock::new();
+        let peers: Vec<Peer> = (1..=size)
+            .map(|i| Peer {
+                id: format!("server_{}", i),
+                address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090 + i as u16),
+                kind: kinds[i - 1],
+            })
+            .collect();
+
+        let network = MemoryNetwork::new();
+        let mut servers = Vec::new();
+        let mut clients = Vec::new();
+
+        for (i, peer) in peers.iter().enumerate() {
+            let election_timeout = Duration::from_millis(300 + 100 * i as u64);
+            let mut config = ServerConfig {
+                election_timeout_min: election_timeout,
+                election_timeout_max: election_timeout,
+                heartbeat_interval: Duration::from_millis(50),
+                clock: Arc::new(clock.clone()),
+                kind: peer.kind,
+                ..ServerConfig::default()
+            };
+            configure(&peer.id, &mut config);
+            let others: Vec<Peer> = peers.iter().filter(|p| p.id != peer.id).cloned().collect();
+            let mut server =
+                Server::new(config, others.clone(), peer.address, peer.id.to_string()).unwrap();
+            server.bootstrap(others.clone()).unwrap();
+            server.state_machine = Some(state_machine(&peer.id));
+            server.start();
+            let server = Arc::new(Mutex::new(server));
+            network.serve(Arc::clone(&server));
+            servers.push(server);
+
+            clients.push(network.client_of(
+                &peer.id,
+                others.into_iter().map(|p| p.id).collect(),
+                RPC_TIMEOUT,
+            ));
+        }
+
+        TestCluster {
+            clock: clock,
+            servers: servers,
+            network: network,
+            clients: clients,
+            shutdown: AtomicBool::new(false),
+        }
+    }
+
+    /// Moves the clock on by `TICK`, and runs a round of every server.
+    pub fn tick(&self) {
+        self.clock.advance(TICK);
+
+        for (server, client) in self.servers.iter().zip(self.clients.iter()) {
+            core::run_round(server, client, &self.shutdown);
+        }
+    }
+
+    /// Ticks until `done`, and panics after `max_ticks`. Returns how many
+    /// ticks it took.
+    pub fn tick_until(&self, max_ticks: usize, done: impl Fn(&TestCluster) -> bool) -> usize {
+        for ticks in 0..max_ticks {
+            if done(self) {
+                return ticks;
+            }
+            self.tick();
+        }
+
+        panic!("still not done after {} ticks", max_ticks);
+    }
+
+    /// Splits the cluster in two: the servers in `ids` only reach each
+    /// other, and the rest only reach each other.
+    pub fn partition(&self, ids: &[&str]) {
+        self.partitions().partition(ids);
+    }
+
+    /// Lets every server reach every other one again.
+    pub fn heal(&self) {
+        self.partitions().heal();
+    }
+
+    /// Cuts links finer than `partition` does: one server at a time, or
+    /// one way only.
+    pub fn partitions(&self) -> PartitionController {
+        self.network.partitions()
+    }
+
+    /// Loses and delays messages at random from now on, or stops with
+    /// `None`.
+    pub fn set_faults(&self, faults: Option<Faults>) {
+        self.network.set_faults(faults);
+    }
+
+    /// The server leading in the highest term, if any.
+    pub fn leader(&self) -> Option<Arc<Mutex<Server>>> {
+        self.servers
+            .iter()
+            .filter(|s| s.lock().unwrap().state == State::LEADER)
+            .max_by_key(|s| s.lock().unwrap().term)
+            .cloned()
+    }
+
+    pub fn servers(&self) -> &[Arc<Mutex<Server>>] {
+        &self.servers
+    }
+
+    pub fn server(&self, id: &str) -> Arc<Mutex<Server>> {
+        self.servers
+            .iter()
+            .find(|s| s.lock().unwrap().id == id)
+            .cloned()
+            .unwrap()
+    }
+}
+
+fn cluster_config() -> ServerConfig {
+    ServerConfig {
+        election_timeout_min: Duration::from_millis(300),
+        election_timeout_max: Duration::from_millis(600),
+        heartbeat_interval: Duration::from_millis(50),
+        ..ServerConfig::default()
+    }
+}
+
+/// Checks that a state machine restored from a snapshot and then given the
+/// rest of the log ends up where one that replayed the whole log does.
+///
+/// A reference state machine applies `length` commands, built by `command`
+/// from random numbers drawn from `seed`, and is snapshotted at a few cut
+/// points on the way. Each snapshot is restored with `restore` and the
+/// commands after its cut point are applied; the result must snapshot to
+/// the same bytes as a fresh state machine from `new` that applied every
+/// command. A mismatch panics with both states and the seed, to replay it.
+pub fn snapshot_differential<S: StateMachine>(
+    seed: u64,
+    length: usize,
+    mut command: impl FnMut(u64) -> Vec<u8>,
+    new: impl Fn() -> S,
+    restore: impl Fn(&[u8]) -> S,
+) {
+    let mut rng = JitterRng::new(seed);
+    let workload: Vec<Vec<u8>> = (0..length).map(|_| command(rng.next_u64())).collect();
+
+    let mut cuts = vec![0, length / 4, length / 2, length * 3 / 4, length];
+    cuts.push(rng.next_u64() as usize % (length + 1));
+    cuts.sort_unstable();
+    cuts.dedup();
+
+    let apply = |state_machine: &mut S, commands: &[Vec<u8>]| {
+        for (i, command) in commands.iter().enumerate() {
+            if let Err(e) = state_machine.apply(command) {
+                panic!("seed {}: command {} failed: {:?}", seed, i, e);
+            }
+        }
+    };
+
+    let mut reference = new();
+    let mut snapshots = Vec::new();
+    let mut applied = 0;
+    for &cut in &cuts {
+        apply(&mut reference, &workload[applied..cut]);
+        applied = cut;
+        snapshots.push((cut, reference.snapshot()));
+    }
+
+    let mut replayed = new();
+    apply(&mut replayed, &workload);
+    assert_same_state(seed, "the reference", &reference, &replayed);
+
+    for (cut, snapshot) in snapshots {
+        let mut restored = restore(&snapshot);
+        apply(&mut restored, &workload[cut..]);
+        assert_same_state(seed, &format!("restored at {}", cut), &restored, &replayed);
+    }
+}
+
+fn assert_same_state<S: StateMachine>(seed: u64, name: &str, state: &S, replayed: &S) {
+    let checksum = |snapshot: &[u8]| {
+        let mut hasher = DefaultHasher::new();
+        snapshot.hash(&mut hasher);
+        hasher.finish()
+    };
+    let (snapshot, expected) = (state.snapshot(), replayed.snapshot());
+
+    if snapshot != expected {
+        panic!(
+            "seed {}: {} is {:?} (checksum {:016x}), the full replay {:?} (checksum {:016x})",
+            seed,
+            name,
+            state,
+            checksum(&snapshot),
+            replayed,
+            checksum(&expected)
+        );
+    }
+}
+
+pub fn build_server(id: &str) -> Server {
+    let config = ServerConfig {
+        election_timeout_min: Duration::new(1, 0),
+        election_timeout_max: Duration::new(1, 0),
+        heartbeat_interval: Duration::from_millis(200),
+        ..ServerConfig::default()
+    };
+
+    let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+
+    Server::new(config, Vec::new(), address, id.to_string()).unwrap()
+}
+
+fn vote_request(term: Term) -> VoteRequest {
+    VoteRequest {
+        term: term,
+        candidate_id: "server_1".to_string(),
+        last_log_index: 0,
+        last_log_term: Term(0),
+        leadership_transfer: false,
+        election_priority: 0,
+    }
+}
+
+fn vote_round_trip(mut transport: impl Transport) {
+    let server = Arc::new(Mutex::new(build_server("server_2")));
+    transport.serve(Arc::clone(&server));
+    let client = transport.client(&["server_2"], RPC_TIMEOUT);
+
+    let responses = client.request_vote(vote_request(Term(1))).unwrap();
+
+    assert_eq!(responses.len(), 1);
+    assert!(responses[0].vote_granted);
+    assert_eq!(
+        server.lock().unwrap().voted_for.as_ref().unwrap().id,
+        "server_1"
+    );
+}
+
+fn pre_vote_round_trip(mut transport: impl Transport) {
+    let server = Arc::new(Mutex::new(build_server("server_2")));
+    transport.serve(Arc::clone(&server));
+    let client = transport.client(&["server_2"], RPC_TIMEOUT);
+
+    let responses = client
+        .request_pre_vote(PreVoteRequest {
+            term: Term(1),
+            candidate_id: "server_1".to_string(),
+            last_log_index: 0,
+            last_log_term: Term(0),
+        })
+        .unwrap();
+
+    assert_eq!(responses.len(), 1);
+    assert!(responses[0].vote_granted);
+    assert_eq!(responses[0].voter_id, "server_2");
+    let server = server.lock().unwrap();
+    assert_eq!(server.term, Term(0));
+    assert!(server.voted_for.is_none());
+}
+
+fn timeout_now_round_trip(mut transport: impl Transport) {
+    let server = Arc::new(Mutex::new(build_server("server_2")));
+    transport.serve(Arc::clone(&server));
+    let client = transport.client(&["server_2"], RPC_TIMEOUT);
+
+    let response = client
+        .send_timeout_now(
+            "server_2",
+            TimeoutNowRequest {
+                term: Term(0),
+                leader_id: "server_1".to_string(),
+            },
+        )
+        .unwrap();
+
+    assert_eq!(
+        response,
+        TimeoutNowResponse {
+            term: Term(0),
+            peer_id: "server_2".to_string(),
+        }
+    );
+    assert!(server.lock().unwrap().timeout_now);
+}
+
+fn heartbeat_round_trip(mut transport: impl Transport) {
+    let server = Arc::new(Mutex::new(build_server("server_2")));
+    transport.serve(Arc::clone(&server));
+    let client = transport.client(&["server_2"], RPC_TIMEOUT);
+
+    let responses = client
+        .broadcast_log_entry(LogEntry::Heartbeat {
+            term: Term(3),
+            peer_id: "server_1".to_string(),
+        })
+        .unwrap();
+
+    assert_eq!(
+        responses,
+        vec![HeartbeatResponse {
+            term: Term(3),
+            peer_id: "server_2".to_string(),
+            success: true,
+        }]
+    );
+    let server = server.lock().unwrap();
+    assert_eq!(server.term, Term(3));
+    assert_eq!(server.state, State::FOLLOWER);
+}
+
+fn append_entries_round_trip(mut transport: impl Transport) {
+    let server = Arc::new(Mutex::new(build_server("server_2")));
+    transport.serve(Arc::clone(&server));
+    let client = transport.client(&["server_2"], RPC_TIMEOUT);
+
+    let entries = vec![
+        LogEntry::Command {
+            term: Term(1),
+            data: vec![1, 2, 3],
+        },
+        LogEntry::Command {
+            term: Term(1),
+            data: Vec::new(),
+        },
+    ];
+
+    client.send_append_entries(
+        "server_2",
+        AppendEntriesRequest {
+            term: Term(1),
+            leader_id: "server_1".to_string(),
+            prev_log_index: 0,
+            prev_log_term: Term(0),
+            entries: entries.clone(),
+            leader_commit: 0,
+        },
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let mut responses = Vec::new();
+    while responses.is_empty() && Instant::now() < deadline {
+        responses = client.receive_append_entries_responses();
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(responses.len(), 1);
+    assert!(responses[0].success);
+    assert_eq!(responses[0].peer_id, "server_2");
+    assert_eq!(responses[0].match_index, 2);
+    assert_eq!(server.lock().unwrap().log.entries(1, u64::MAX), entries);
+}
+
+fn oversized_entries(mut transport: impl Transport) {
+    let server = Arc::new(Mutex::new(build_server("server_2")));
+    server.lock().unwrap().config.max_entry_bytes = 1024;
+    transport.serve(Arc::clone(&server));
+    transport.limit_message_bytes(64 * 1024);
+    let client = transport.client(&["server_2"], RPC_TIMEOUT);
+
+    let append = |size: usize| {
+        client.send_append_entries(
+            "server_2",
+            AppendEntriesRequest {
+                term: Term(1),
+                leader_id: "server_1".to_string(),
+                prev_log_index: 0,
+                prev_log_term: Term(0),
+                entries: vec![LogEntry::Command {
+                    term: Term(1),
+                    data: vec![7; size],
+                }],
+                leader_commit: 0,
+            },
+        );
+
+        thread::sleep(Duration::from_millis(100));
+        client.receive_append_entries_responses()
+    };
+
+    // too large to send: nothing arrives
+    assert!(append(1024 * 1024).is_empty());
+    // sent, but the entry is over the server's limit
+    let responses = append(2048);
+    assert_eq!(responses.len(), 1);
+    assert!(!responses[0].success);
+    assert_eq!(server.lock().unwrap().log.last_index(), 0);
+
+    // and the peer is still reached as usual
+    let responses = append(16);
+    assert_eq!(responses.len(), 1);
+    assert!(responses[0].success);
+    assert_eq!(server.lock().unwrap().log.last_index(), 1);
+}
+
+fn concurrent_requests(mut transport: impl Transport) {
+    let server = Arc::new(Mutex::new(build_server("server_2")));
+    transport.serve(Arc::clone(&server));
+    let client = Arc::new(transport.client(&["server_2"], RPC_TIMEOUT));
+
+    // Eight candidates of the same term race for a single vote.
+    let handles: Vec<_> = (1..=8)
+        .map(|candidate| {
+            let client = Arc::clone(&client);
+            let request = VoteRequest {
+                term: Term(1),
+                candidate_id: format!("candidate_{}", candidate),
+                last_log_index: 0,
+                last_log_term: Term(0),
+                leadership_transfer: false,
+                election_priority: 0,
+            };
+            thread::spawn(move || client.request_vote(request).unwrap())
+        })
+        .collect();
+
+    let responses: Vec<_> = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
+
+    // Every caller gets exactly its own answer, and only one vote is
+    // granted however the requests were interleaved.
+    assert!(responses.iter().all(|r| r.len() == 1));
+    assert_eq!(responses.iter().filter(|r| r[0].vote_granted).count(), 1);
+}
+
+fn peer_down(mut transport: impl Transport) {
+    transport.stop("server_2");
+    let client = transport.client(&["server_2"], RPC_TIMEOUT);
+
+    let started = Instant::now();
+    let responses = client.request_vote(vote_request(Term(1)));
+
+    match responses {
+        Err(RpcError::Unreachable { peer_ids }) => assert_eq!(peer_ids, vec!["server_2"]),
+        Ok(responses) => panic!("{} responses from a peer that is down", responses.len()),
+    }
+    assert!(started.elapsed() < RPC_TIMEOUT * 2);
+}
+
+fn slow_peer(mut transport: impl Transport) {
+    transport.serve_unresponsive("server_2");
+    let client = transport.client(&["server_2"], RPC_TIMEOUT);
+
+    let started = Instant::now();
+    let responses = client.request_vote(vote_request(Term(1)));
+    let elapsed = started.elapsed();
+
+    assert!(responses.is_err());
+    assert!(elapsed >= RPC_TIMEOUT / 2, "gave up after {:?}", elapsed);
+    assert!(elapsed < RPC_TIMEOUT * 3, "gave up after {:?}", elapsed);
+}
+
+fn reconnect_after_restart(mut transport: impl Transport) {
+    transport.serve(Arc::new(Mutex::new(build_server("server_2"))));
+    let client = transport.client(&["server_2"], RPC_TIMEOUT);
+
+    assert_eq!(client.request_vote(vote_request(Term(1))).unwrap().len(), 1);
+
+    transport.stop("server_2");
+    assert!(client.request_vote(vote_request(Term(2))).is_err());
+
+    // The client may hold off retrying a peer that just failed, but it
+    // must get through eventually.
+    transport.serve(Arc::new(Mutex::new(build_server("server_2"))));
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let mut responses = client.request_vote(vote_request(Term(3)));
+    while responses.is_err() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+        responses = client.request_vote(vote_request(Term(3)));
+    }
+
+    let responses = responses.unwrap();
+    assert_eq!(responses.len(), 1);
+    assert!(responses[0].vote_granted);
+}
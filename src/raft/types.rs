@@ -1,35 +1,507 @@
+use crate::raft::build_info::BuildInfo;
+use crate::raft::cancel::CancelToken;
+use crate::raft::clock::{Clock, SystemClock, Timestamp};
+use crate::raft::events::{Observer, RaftEvent};
+use crate::raft::group_commit::SyncPolicy;
+use crate::raft::hard_state::HardState;
+use crate::raft::log::Log;
+use crate::raft::log_storage::{FileLogStorage, LogStorage};
+use crate::raft::metrics::{Metrics, RaftMetrics};
+use crate::raft::quorum;
+use crate::raft::replication::{CatchUpBudget, Progress};
+use crate::raft::retry::{JitterRng, RetryPolicy};
+use crate::raft::snapshot::Snapshots;
+use crate::raft::state_machine::{self, ApplyError, Sessions, StateMachine};
+use crate::raft::storage::{FileStorage, Storage};
 use log::info;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io;
 use std::net::SocketAddrV4;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, RwLock};
 use std::time::{Duration, Instant};
 
-#[derive(Debug, PartialEq)]
+/// An election term. A server moves to the next one when it stands, and
+/// to a later one it hears of through `Server::adopt_term`; terms never go
+/// back. On the wire it is the bare number.
+#[derive(
+    Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(transparent)]
+pub struct Term(pub u64);
+
+impl Term {
+    /// The term after this one. Terms do not wrap around: running out of
+    /// them is a bug, not something to recover from.
+    pub fn increment(self) -> Term {
+        Term(self.0.checked_add(1).expect("the term overflowed"))
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum State {
     FOLLOWER,
     LEADER,
     CANDIDATE,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum LogEntry {
-    Heartbeat { term: u64, peer_id: String },
+    Heartbeat {
+        term: Term,
+        peer_id: String,
+    },
+    Configuration {
+        term: Term,
+        membership: Membership,
+    },
+    Command {
+        term: Term,
+        data: Vec<u8>,
+    },
+    /// A command proposed within a client session, applied at most once
+    /// for a given client and sequence number.
+    SessionCommand {
+        term: Term,
+        client_id: String,
+        sequence: u64,
+        data: Vec<u8>,
+    },
+    /// Appended by a leader as it takes over, see `Server::become_leader`.
+    /// It carries nothing to apply.
+    NoOp {
+        term: Term,
+        leader_id: String,
+    },
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Peer {
     pub id: String,
     pub address: SocketAddrV4,
+    pub kind: NodeKind,
+}
+
+/// What part a voter takes in the cluster.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    #[default]
+    Voter,
+    /// A cheap tie-breaker: it votes, and its acknowledgements count
+    /// towards the quorum, but it never leads. It keeps the term and index
+    /// of every entry, to tell whether a candidate's log is up to date,
+    /// and none of the commands.
+    Witness,
+}
+
+/// The set of servers taking part in the cluster. Voters count towards
+/// the quorum, learners only receive the log until they are caught up.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Membership {
+    pub voters: Vec<Peer>,
+    pub learners: Vec<Peer>,
+}
+
+/// The other servers of the cluster, as a `Server` knows them: first those
+/// it was made with, then those of the latest configuration in its log.
+/// It is shared with the client that reaches them, which follows its
+/// changes, see `TcpRpcClient::with_peer_set`.
+#[derive(Debug, Default)]
+pub struct PeerSet {
+    peers: RwLock<Vec<Peer>>,
+    /// Moves on with every change, for a sharer to tell it is behind.
+    version: AtomicU64,
+}
+
+/// A configuration entry of the log, and what it changed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MembershipRecord {
+    pub index: u64,
+    pub term: Term,
+    pub membership: Membership,
+    pub change: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    /// A leader must heartbeat more often than its followers time out,
+    /// or they stand for election while it is healthy.
+    HeartbeatNotBelowElectionTimeout {
+        heartbeat_interval: Duration,
+        election_timeout_min: Duration,
+    },
+    /// The election timeout is drawn from a range that must not be empty,
+    /// nor start at zero.
+    ElectionTimeoutRange {
+        election_timeout_min: Duration,
+        election_timeout_max: Duration,
+    },
+    /// Warnings must start below the hard limits, and clear below where
+    /// they start.
+    SoftLimitsOutOfOrder {
+        soft_limit_percent: u64,
+        soft_limit_clear_percent: u64,
+    },
+    /// The log in the data directory could not be opened, for instance
+    /// because an entry in it is corrupt.
+    LogUnreadable {
+        data_dir: PathBuf,
+        error: String,
+    },
+    /// The server is among its own peers, by id or by address: it would
+    /// count its own vote twice, and may win an election nobody else
+    /// knows of.
+    SelfAmongPeers {
+        peer_id: String,
+        address: SocketAddrV4,
+    },
+    DuplicatePeer {
+        peer_id: String,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MembershipError {
+    NotLeader,
+    ChangeInProgress,
+    AlreadyMember,
+    UnknownPeer,
+    NotCaughtUp,
+    LastVoter,
+    /// The new configuration could not be written to the log.
+    LogUnwritable {
+        error: String,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProposeError {
+    NotLeader,
+    EntryTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// Too much of the log is not committed yet, try again later.
+    Backpressure {
+        uncommitted: u64,
+        uncommitted_bytes: usize,
+    },
+    /// A state machine in the cluster is `gap` committed entries behind,
+    /// try again later.
+    ApplyLag {
+        gap: u64,
+    },
+    /// Leadership is being handed over to `target`, which should take
+    /// proposals shortly.
+    TransferringLeadership {
+        target: String,
+    },
+    /// The command could not be written to the log, and no later one
+    /// will be: the server needs to be restarted.
+    LogUnwritable {
+        error: String,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TransferError {
+    NotLeader,
+    /// The target is not one of the other voters, or is a witness.
+    UnknownPeer,
+    /// Another transfer is under way.
+    InProgress,
+}
+
+/// Why a request to the peers got no answer from any of them. The server
+/// carries on as if they had not answered.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcError {
+    /// None of the peers could be reached, or none answered in time.
+    Unreachable { peer_ids: Vec<String> },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WaitError {
+    /// The index was still not applied when the timeout expired, for
+    /// instance because the cluster lost its quorum.
+    Timeout { last_applied: u64 },
+    /// The state machine failed to apply the entry at `index`, so nothing
+    /// from there on will be applied.
+    ApplyFailed { index: u64, error: ApplyError },
+    /// The wait was cancelled, or the server shut down.
+    Cancelled,
+}
+
+/// What became of a command this server proposed as leader, kept for
+/// `core::wait_for_output`.
+#[derive(Debug, PartialEq)]
+pub enum Output {
+    /// Not applied yet. Proposed in `term`: an entry of another term at
+    /// its index means the leader lost the command along with its
+    /// leadership.
+    Pending {
+        term: Term,
+    },
+    /// What the state machine returned.
+    Applied(Vec<u8>),
+    Superseded,
+}
+
+/// Entries are always applied in log order; the priority only decides
+/// whether a proposal is admitted while the log is backed up. Control
+/// proposals (membership, session housekeeping, ...) are never held back
+/// by bulk ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+    Control,
+    Bulk,
+}
+
+/// What is done once a state machine has fallen more than `max_apply_lag`
+/// committed entries behind.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ApplyLagPolicy {
+    /// The lagging server applies everything committed in one go, however
+    /// long that keeps it from answering its peers.
+    CatchUp,
+    /// The leader refuses bulk proposals while any member lags behind.
+    Throttle,
+}
+
+/// A hard limit that proposals or applying run into, see
+/// `Server::approaching_limits`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Limit {
+    /// `max_uncommitted_entries`
+    UncommittedEntries,
+    /// `max_uncommitted_bytes`
+    UncommittedBytes,
+    /// `max_apply_lag`
+    ApplyGap,
+}
+
+/// What a leader knows about the replication to one of its followers.
+#[derive(Debug, PartialEq)]
+pub struct PeerStats {
+    pub peer_id: String,
+    pub match_index: u64,
+    pub next_index: u64,
+    pub paused: bool,
+    pub catching_up: bool,
+    pub catch_up_bytes_per_second: f64,
+    /// The total budget shared by the followers catching up.
+    pub catch_up_budget: usize,
 }
 
 #[derive(Debug)]
 pub struct Leader {
     pub id: String,
-    pub term: u64,
+}
+
+/// How the last election a server stood in ended, see
+/// `Server::last_election`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElectionRecord {
+    pub term: Term,
+    /// When the votes were asked for, after the pre-vote.
+    pub started_at: Instant,
+    /// The voters that granted their vote, the candidate's own included,
+    /// in order of their ids.
+    pub votes_granted_from: Vec<String>,
+    pub outcome: ElectionResult,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ElectionResult {
+    Won,
+    /// Too few voters granted their vote. The candidate stands again.
+    Lost,
+    /// The election was given up before it was decided, for this reason.
+    Abandoned(AbandonReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AbandonReason {
+    /// The election timeout expired while the votes were collected.
+    TimedOut,
+    /// A voter answered from this later term.
+    HigherTerm(Term),
+    /// The candidate stepped down meanwhile: it heard from the leader of
+    /// the term, or from a candidate of a later one.
+    SteppedDown,
+}
+
+/// A leader handing over to `target`, see `Server::transfer_leadership`.
+#[derive(Debug, Clone)]
+pub struct LeadershipTransfer {
+    pub target: String,
+    /// The transfer is given up if the target has not won by then.
+    pub deadline: Instant,
+    /// Whether the target was told to stand, once it had every entry.
+    pub timeout_now_sent: bool,
 }
 
 #[derive(Debug)]
 pub struct ServerConfig {
-    pub timeout: Duration,
+    /// How long a follower goes without hearing from a leader before it
+    /// stands for election: a fresh random duration in this range, both
+    /// included, every time the timeout starts over, so that followers
+    /// rarely stand at the same time. Equal bounds give a fixed timeout.
+    pub election_timeout_min: Duration,
+    pub election_timeout_max: Duration,
+    /// After the n-th split vote in a row, a candidate waits the n-th delay
+    /// of this policy on top of a fresh election timeout before it stands
+    /// again, so that candidates that keep colliding stand less and less
+    /// often. Its limits are not applied.
+    pub split_vote_backoff: RetryPolicy,
+    /// How often a leader sends heartbeats, well within
+    /// `election_timeout_min`.
+    pub heartbeat_interval: Duration,
+    /// How many AppendEntries may be outstanding to a single follower
+    /// before the leader waits for an acknowledgement.
+    pub max_inflight_append_entries: usize,
+    /// The batch size: commands proposed since the last AppendEntries to a
+    /// follower go out together in the next one, up to this many.
+    pub max_entries_per_append: usize,
+    /// Flow control: the most entries, and bytes of entry payload, that
+    /// may be unacknowledged by a single follower.
+    pub max_inflight_entries: usize,
+    pub max_inflight_bytes: usize,
+    /// How long a leader waits for a follower to acknowledge an
+    /// AppendEntries before sending it again. The wait doubles with every
+    /// retry that goes unanswered, up to `max_append_entries_timeout`.
+    pub append_entries_timeout: Duration,
+    pub max_append_entries_timeout: Duration,
+    /// Largest command payload accepted in a single log entry.
+    pub max_entry_bytes: usize,
+    /// Followers whose next entry is more than `catch_up_horizon` entries
+    /// behind the end of the log share `catch_up_bytes_per_second`.
+    /// Followers that are up to date are never throttled.
+    pub catch_up_horizon: u64,
+    pub catch_up_bytes_per_second: usize,
+    /// Bulk proposals are refused while this many entries, or bytes of
+    /// entry payload, are uncommitted. They are accepted again as soon as
+    /// enough of the log is committed.
+    pub max_uncommitted_entries: usize,
+    pub max_uncommitted_bytes: usize,
+    /// Committed entries applied per round of the background task, so that
+    /// a slow state machine does not keep the server from its peers.
+    pub max_applied_per_round: usize,
+    pub max_apply_lag: u64,
+    pub apply_lag_policy: ApplyLagPolicy,
+    /// The limits above warn once they are `soft_limit_percent` full, and
+    /// stop warning once they are back below `soft_limit_clear_percent`.
+    pub soft_limit_percent: u64,
+    pub soft_limit_clear_percent: u64,
+    /// Where the server keeps what must survive a restart. Without one,
+    /// everything starts afresh.
+    pub data_dir: Option<PathBuf>,
+    /// Where the server keeps its hard state, snapshots and the log
+    /// entries it has no room for in memory. Unset, that is a
+    /// `FileStorage` in `data_dir`, if there is one.
+    pub storage: Option<Arc<dyn Storage>>,
+    /// When the log is synced to disk, in `data_dir` or in `log_storage`.
+    /// Anything but `Always` may lose acknowledged entries in a crash, see
+    /// `SyncPolicy`.
+    pub sync_policy: SyncPolicy,
+    /// Log appends arriving within this long of each other, up to
+    /// `group_commit_max_entries`, are written with a single sync, see
+    /// `GroupCommit`. Without a window, appends still share a sync with
+    /// whatever queued up while the previous one ran.
+    pub group_commit_window: Duration,
+    pub group_commit_max_entries: usize,
+    /// Where the log entries are kept. Unset, that is a `FileLogStorage`
+    /// in `data_dir` if there is one, for the log to survive a restart,
+    /// and otherwise memory, see `MemLogStorage`. `Server::new` takes it
+    /// out.
+    pub log_storage: Option<Box<dyn LogStorage>>,
+    /// Log entries kept in memory, the most recent ones, when the log is
+    /// in memory. Older entries are spilled to `storage` and read back
+    /// when needed; without storage the whole log stays in memory.
+    pub max_cached_log_entries: usize,
+    pub metrics_flush_interval: Duration,
+    /// How many applied entries a subscriber may leave unread.
+    pub applied_channel_capacity: usize,
+    /// Outputs of proposed commands kept for `core::wait_for_output`. The
+    /// oldest are dropped first, collected or not.
+    pub max_retained_outputs: usize,
+    /// A snapshot is taken automatically once this many entries were
+    /// applied since the last one, if the server has a data directory.
+    pub snapshot_threshold: u64,
+    /// Servers with a higher priority are preferred as leader: a voter
+    /// refuses a candidate it outranks unless the candidate's log is ahead
+    /// of its own, and a leader hands over to a caught-up follower that
+    /// outranks it. Equal priorities, the default, elect whoever stands
+    /// first.
+    pub election_priority: u8,
+    /// Whether this server may lead, or only votes; see `NodeKind`. Its
+    /// peers learn it from its `Peer` in the configuration.
+    pub kind: NodeKind,
+    /// Told about every change of role, vote and commit index.
+    pub observer: Option<Observer>,
+    /// Where to answer HTTP requests with the node's status as JSON, for
+    /// monitoring. Not served unless set, see `status::StatusEndpoint`.
+    pub status_address: Option<SocketAddrV4>,
+    /// What election timeouts, heartbeats and retries are timed by. Tests
+    /// put a `ManualClock` here.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            election_timeout_min: Duration::new(5, 0),
+            election_timeout_max: Duration::new(10, 0),
+            split_vote_backoff: RetryPolicy::exponential(
+                Duration::from_millis(100),
+                Duration::new(5, 0),
+            ),
+            heartbeat_interval: Duration::new(1, 0),
+            max_inflight_append_entries: 4,
+            max_entries_per_append: 64,
+            max_inflight_entries: 1024,
+            max_inflight_bytes: 4 * 1024 * 1024,
+            append_entries_timeout: Duration::from_millis(500),
+            max_append_entries_timeout: Duration::new(5, 0),
+            max_entry_bytes: 1024 * 1024,
+            max_uncommitted_entries: 4096,
+            max_uncommitted_bytes: 64 * 1024 * 1024,
+            max_applied_per_round: 1024,
+            max_apply_lag: 8192,
+            apply_lag_policy: ApplyLagPolicy::CatchUp,
+            soft_limit_percent: 80,
+            soft_limit_clear_percent: 60,
+            catch_up_horizon: 1024,
+            catch_up_bytes_per_second: 4 * 1024 * 1024,
+            data_dir: None,
+            storage: None,
+            sync_policy: SyncPolicy::Always,
+            group_commit_window: Duration::ZERO,
+            group_commit_max_entries: 1024,
+            log_storage: None,
+            max_cached_log_entries: 16 * 1024,
+            metrics_flush_interval: Duration::new(10, 0),
+            applied_channel_capacity: 1024,
+            max_retained_outputs: 1024,
+            snapshot_threshold: 10_000,
+            election_priority: 0,
+            kind: NodeKind::Voter,
+            observer: None,
+            status_address: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -37,55 +509,516 @@ pub struct Server {
     pub id: String,
     pub address: SocketAddrV4,
     pub state: State,
-    pub term: u64,
-    pub log_entries: Vec<LogEntry>,
+    pub term: Term,
+    pub log: Log,
     pub voted_for: Option<Peer>,
     pub next_timeout: Option<Instant>,
+    pub next_heartbeat: Option<Instant>,
     pub config: ServerConfig,
     pub current_leader: Option<Leader>,
-    pub number_of_peers: usize,
+    /// When a leader was last heard from. Until `election_timeout_min`
+    /// later, votes are denied to candidates that would depose it.
+    pub last_leader_contact: Option<Instant>,
+    pub transfer: Option<LeadershipTransfer>,
+    /// Set when the leader handed over to this server: it stands right
+    /// away, without a pre-vote, see `core::handle_timeout_now`.
+    pub timeout_now: bool,
+    last_election: Option<ElectionRecord>,
+    /// Split votes in a row since the server last followed or led, see
+    /// `ServerConfig::split_vote_backoff`.
+    split_votes: u32,
+    peers: Arc<PeerSet>,
+    pub commit_index: u64,
+    pub progress: HashMap<String, Progress>,
+    pub catch_up: CatchUpBudget,
+    pub metrics: Metrics,
+    started_at: Instant,
+    /// Set by `join_as_learner`: until a configuration reaches its log,
+    /// the server is not a voter.
+    joining: bool,
+    /// Only ever moves forward, one entry at a time, once the entry was
+    /// applied.
+    last_applied: u64,
+    /// The entry the state machine failed to apply, if any. Applying
+    /// stops there for good.
+    apply_failure: Option<(u64, ApplyError)>,
+    approaching_limits: BTreeSet<Limit>,
+    applied_subscribers: Vec<SyncSender<(u64, LogEntry)>>,
+    /// Of the commands proposed here, by index.
+    outputs: BTreeMap<u64, Output>,
+    pub state_machine: Option<Box<dyn StateMachine>>,
+    pub sessions: Sessions,
+    pub snapshots: Snapshots,
+    /// Waiting to be delivered to the observer, outside the lock.
+    events: Vec<RaftEvent>,
+    /// Wakes up the background task, to be used with the mutex guarding
+    /// this server.
+    pub wakeup: Arc<Condvar>,
+    /// Cancelled when the server shuts down, and with it every operation
+    /// still running on it.
+    pub cancel: CancelToken,
+    /// Cancelled when the server, standing or leading, steps down to
+    /// follower, and with it whatever it started since it stood. That
+    /// happens under the lock, so callbacks on it must not take it.
+    pub stepped_down: CancelToken,
+    /// Signalled whenever `last_applied` moves.
+    pub applied: Arc<Condvar>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VoteRequest {
-    pub term: u64,
+    pub term: Term,
     pub candidate_id: String,
+    /// Where the candidate's log ends, so that a voter with a more
+    /// up-to-date log can refuse it.
+    pub last_log_index: u64,
+    pub last_log_term: Term,
+    /// Set when the leader handed over to the candidate on purpose: voters
+    /// then grant it even while they still hear from that leader.
+    pub leadership_transfer: bool,
+    /// The candidate's `ServerConfig::election_priority`.
+    pub election_priority: u8,
+}
+
+/// Tells the target of a leadership transfer to stand for election now.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimeoutNowRequest {
+    pub term: Term,
+    pub leader_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TimeoutNowResponse {
+    pub term: Term,
+    pub peer_id: String,
 }
 
 pub struct VoteResponse {
-    pub term: u64,
+    pub term: Term,
+    pub vote_granted: bool,
+    pub voter_id: String,
+}
+
+/// Asks whether a peer would vote for the candidate in `term`, the one
+/// after the candidate's, before it stands. See `core::handle_pre_vote_request`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreVoteRequest {
+    pub term: Term,
+    pub candidate_id: String,
+    pub last_log_index: u64,
+    pub last_log_term: Term,
+}
+
+/// `term` is the voter's current term, which it did not change to answer.
+pub struct PreVoteResponse {
+    pub term: Term,
     pub vote_granted: bool,
+    pub voter_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AppendEntriesRequest {
+    pub term: Term,
+    pub leader_id: String,
+    pub prev_log_index: u64,
+    pub prev_log_term: Term,
+    pub entries: Vec<LogEntry>,
+    pub leader_commit: u64,
+}
+
+/// On success `match_index` is the last index covered by the request.
+///
+/// On a rejection the follower hints where its log diverges, so that the
+/// leader can skip a whole term per round trip instead of a single entry:
+/// `conflict_term` is the term of the follower's entry at `prev_log_index`
+/// and `conflict_index` the first index it holds for that term. When the
+/// follower's log is too short there is no conflicting term, and
+/// `conflict_index` is right past its last entry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AppendEntriesResponse {
+    pub term: Term,
+    pub peer_id: String,
+    pub success: bool,
+    pub match_index: u64,
+    pub conflict_term: Option<Term>,
+    pub conflict_index: u64,
+    /// How far the follower has applied its log.
+    pub last_applied: u64,
+    /// The follower's `ServerConfig::election_priority`, so that a leader
+    /// that ranks lower can hand over to it.
+    pub election_priority: u8,
+}
+
+/// A peer's answer to a heartbeat: its current term, which tells a stale
+/// leader that it has been superseded, in which case `success` is false.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeartbeatResponse {
+    pub term: Term,
+    pub peer_id: String,
+    pub success: bool,
 }
 
 pub trait RpcClient {
-    fn request_vote(&self, request: VoteRequest) -> Vec<VoteResponse>;
+    /// Asks every peer for its vote, and returns the answers of those
+    /// that could be reached; an error if there were peers but none of
+    /// them answered.
+    fn request_vote(&self, request: VoteRequest) -> Result<Vec<VoteResponse>, RpcError>;
+
+    /// Like `request_vote`, but returns as soon as `decided` says that the
+    /// answers so far settle the election, without waiting for the other
+    /// peers. A transport that cannot stop early waits for all of them.
+    fn request_vote_until(
+        &self,
+        request: VoteRequest,
+        decided: &dyn Fn(&[VoteResponse]) -> bool,
+    ) -> Result<Vec<VoteResponse>, RpcError> {
+        let _ = decided;
+        self.request_vote(request)
+    }
+
+    /// Asks every peer whether it would vote, like `request_vote`.
+    fn request_pre_vote(&self, request: PreVoteRequest) -> Result<Vec<PreVoteResponse>, RpcError>;
+
+    /// Like `request_vote_until`, for a pre-vote.
+    fn request_pre_vote_until(
+        &self,
+        request: PreVoteRequest,
+        decided: &dyn Fn(&[PreVoteResponse]) -> bool,
+    ) -> Result<Vec<PreVoteResponse>, RpcError> {
+        let _ = decided;
+        self.request_pre_vote(request)
+    }
+
+    /// Sends the entry to every peer, and returns the answers of those
+    /// that could be reached; an error if there were peers but none of
+    /// them answered.
+    fn broadcast_log_entry(&self, log_entry: LogEntry) -> Result<Vec<HeartbeatResponse>, RpcError>;
+
+    /// Tells the target of a leadership transfer to stand for election;
+    /// an error if it could not be reached.
+    fn send_timeout_now(
+        &self,
+        peer_id: &str,
+        request: TimeoutNowRequest,
+    ) -> Result<TimeoutNowResponse, RpcError>;
+
+    /// Sends the request without waiting for the follower to answer, the
+    /// response is later picked up by `receive_append_entries_responses`.
+    fn send_append_entries(&self, peer_id: &str, request: AppendEntriesRequest);
+
+    fn receive_append_entries_responses(&self) -> Vec<AppendEntriesResponse>;
+}
+
+impl RpcError {
+    /// The answers of `peer_ids`, an error if there were peers and none of
+    /// them answered.
+    pub fn unless_answered<T>(peer_ids: &[String], responses: Vec<T>) -> Result<Vec<T>, RpcError> {
+        if responses.is_empty() && !peer_ids.is_empty() {
+            Err(RpcError::Unreachable {
+                peer_ids: peer_ids.to_vec(),
+            })
+        } else {
+            Ok(responses)
+        }
+    }
+}
+
+impl LogEntry {
+    pub fn term(&self) -> Term {
+        match self {
+            LogEntry::Heartbeat { term, .. } => *term,
+            LogEntry::Configuration { term, .. } => *term,
+            LogEntry::Command { term, .. } => *term,
+            LogEntry::SessionCommand { term, .. } => *term,
+            LogEntry::NoOp { term, .. } => *term,
+        }
+    }
+
+    /// The membership a configuration entry carries.
+    pub fn membership(&self) -> Option<&Membership> {
+        match self {
+            LogEntry::Configuration { membership, .. } => Some(membership),
+            _ => None,
+        }
+    }
+
+    /// Size of the data carried by the entry, used to account for it in
+    /// flow control.
+    pub fn payload_size(&self) -> usize {
+        match self {
+            LogEntry::Command { data, .. } => data.len(),
+            LogEntry::SessionCommand { data, .. } => data.len(),
+            _ => 0,
+        }
+    }
+
+    /// The entry without its command, as a witness keeps it.
+    pub fn without_payload(self) -> LogEntry {
+        match self {
+            LogEntry::Command { term, .. } => LogEntry::Command {
+                term: term,
+                data: Vec::new(),
+            },
+            LogEntry::SessionCommand {
+                term,
+                client_id,
+                sequence,
+                ..
+            } => LogEntry::SessionCommand {
+                term: term,
+                client_id: client_id,
+                sequence: sequence,
+                data: Vec::new(),
+            },
+            entry => entry,
+        }
+    }
+}
+
+impl PeerSet {
+    pub fn new(peers: Vec<Peer>) -> Self {
+        PeerSet {
+            peers: RwLock::new(peers),
+            version: AtomicU64::new(0),
+        }
+    }
+
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers.read().unwrap().clone()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// Replaces the peers, unless they are the same already.
+    pub fn set(&self, peers: Vec<Peer>) {
+        let mut current = self.peers.write().unwrap();
+        if *current != peers {
+            *current = peers;
+            self.version.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Membership {
+    pub fn contains(&self, peer_id: &str) -> bool {
+        self.voters.iter().any(|p| p.id == peer_id) || self.learners.iter().any(|p| p.id == peer_id)
+    }
+
+    pub fn is_witness(&self, peer_id: &str) -> bool {
+        self.voters
+            .iter()
+            .any(|p| p.id == peer_id && p.kind == NodeKind::Witness)
+    }
+
+    /// Describes how this configuration differs from `previous`, for
+    /// people reading the history.
+    pub fn describe_change(&self, previous: Option<&Membership>) -> String {
+        let ids =
+            |peers: &[Peer]| -> Vec<String> { peers.iter().map(|p| p.id.to_string()).collect() };
+
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return format!("bootstrapped with {}", ids(&self.voters).join(", ")),
+        };
+
+        let is_voter =
+            |membership: &Membership, id: &str| membership.voters.iter().any(|p| p.id == id);
+        let mut changes = Vec::new();
+
+        for peer in self.voters.iter() {
+            if previous.learners.iter().any(|p| p.id == peer.id) {
+                changes.push(format!("promoted {}", peer.id));
+            } else if !is_voter(previous, &peer.id) {
+                changes.push(format!("added voter {}", peer.id));
+            }
+        }
+        for peer in self.learners.iter() {
+            if !previous.contains(&peer.id) {
+                changes.push(format!("added learner {}", peer.id));
+            }
+        }
+        for peer in previous.voters.iter().chain(previous.learners.iter()) {
+            if !self.contains(&peer.id) {
+                changes.push(format!("removed {}", peer.id));
+            }
+        }
+
+        if changes.is_empty() {
+            "unchanged".to_string()
+        } else {
+            changes.join(", ")
+        }
+    }
+}
+
+impl ServerConfig {
+    /// A random election timeout, see `election_timeout_min`.
+    pub fn election_timeout(&self) -> Duration {
+        let min = self.election_timeout_min.as_nanos() as u64;
+        let max = self.election_timeout_max.as_nanos() as u64;
+
+        Duration::from_nanos(rand::thread_rng().gen_range(min..max.max(min) + 1))
+    }
+
+    /// `storage`, or else a `FileStorage` in `data_dir` if there is one.
+    pub fn storage(&self) -> Option<Arc<dyn Storage>> {
+        match (&self.storage, &self.data_dir) {
+            (Some(storage), _) => Some(Arc::clone(storage)),
+            (None, Some(data_dir)) => Some(Arc::new(FileStorage::new(data_dir.clone()))),
+            (None, None) => None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.election_timeout_min.is_zero()
+            || self.election_timeout_min > self.election_timeout_max
+        {
+            return Err(ConfigError::ElectionTimeoutRange {
+                election_timeout_min: self.election_timeout_min,
+                election_timeout_max: self.election_timeout_max,
+            });
+        }
+
+        if self.heartbeat_interval >= self.election_timeout_min {
+            return Err(ConfigError::HeartbeatNotBelowElectionTimeout {
+                heartbeat_interval: self.heartbeat_interval,
+                election_timeout_min: self.election_timeout_min,
+            });
+        }
+
+        if self.soft_limit_percent >= 100
+            || self.soft_limit_clear_percent >= self.soft_limit_percent
+        {
+            return Err(ConfigError::SoftLimitsOutOfOrder {
+                soft_limit_percent: self.soft_limit_percent,
+                soft_limit_clear_percent: self.soft_limit_clear_percent,
+            });
+        }
 
-    fn broadcast_log_entry(&self, log_entry: LogEntry);
+        Ok(())
+    }
 }
 
 impl Server {
     pub fn new(
-        config: ServerConfig,
-        number_of_peers: usize,
+        mut config: ServerConfig,
+        peers: Vec<Peer>,
         address: SocketAddrV4,
         id: String,
-    ) -> Self {
-        Server {
+    ) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let started_at = config.clock.now();
+        let log = match (config.log_storage.take(), &config.data_dir) {
+            (Some(log_storage), _) => Log::with_storage(
+                log_storage,
+                config.sync_policy,
+                config.group_commit_window,
+                config.group_commit_max_entries,
+            ),
+            (None, Some(data_dir)) => match FileLogStorage::open(data_dir) {
+                Ok(log_storage) => Log::with_storage(
+                    Box::new(log_storage),
+                    config.sync_policy,
+                    config.group_commit_window,
+                    config.group_commit_max_entries,
+                ),
+                Err(e) => {
+                    return Err(ConfigError::LogUnreadable {
+                        data_dir: data_dir.clone(),
+                        error: e.to_string(),
+                    })
+                }
+            },
+            (None, None) => Log::new(config.max_cached_log_entries, config.storage()),
+        };
+
+        let server = Server {
             id: id,
             state: State::FOLLOWER,
-            term: 0,
-            log_entries: Vec::new(),
+            term: Term(0),
+            log: log,
             voted_for: None,
             next_timeout: None,
+            next_heartbeat: None,
             config: config,
             current_leader: None,
-            number_of_peers: number_of_peers,
+            last_leader_contact: None,
+            transfer: None,
+            timeout_now: false,
+            last_election: None,
+            split_votes: 0,
+            peers: Arc::new(PeerSet::new(peers)),
             address: address,
-        }
+            commit_index: 0,
+            progress: HashMap::new(),
+            catch_up: CatchUpBudget::default(),
+            metrics: Metrics::default(),
+            started_at: started_at,
+            joining: false,
+            last_applied: 0,
+            apply_failure: None,
+            approaching_limits: BTreeSet::new(),
+            applied_subscribers: Vec::new(),
+            outputs: BTreeMap::new(),
+            state_machine: None,
+            sessions: Sessions::default(),
+            snapshots: Snapshots::default(),
+            events: Vec::new(),
+            wakeup: Arc::new(Condvar::new()),
+            cancel: CancelToken::new(),
+            stepped_down: CancelToken::new(),
+            applied: Arc::new(Condvar::new()),
+        };
+        server.check_peers()?;
+
+        Ok(server)
     }
 
     pub fn refresh_timeout(self: &mut Self) {
-        self.next_timeout = Some(Instant::now() + self.config.timeout);
+        self.next_timeout = Some(self.now() + self.config.election_timeout());
+        self.notify();
+    }
+
+    /// Like `refresh_timeout`, for a candidate whose election split: the
+    /// timeout is backed off by `split_vote_backoff`.
+    pub fn refresh_timeout_after_split_vote(self: &mut Self) {
+        self.split_votes = self.split_votes.saturating_add(1);
+        let backoff = self
+            .config
+            .split_vote_backoff
+            .delay(self.split_votes, &mut JitterRng::from_entropy());
+
+        self.next_timeout = Some(self.now() + self.config.election_timeout() + backoff);
+        self.notify();
+    }
+
+    /// Lets the background task know that something changed, so that it
+    /// reconsiders what to do next without waiting for its deadline.
+    pub fn notify(&self) {
+        self.wakeup.notify_all();
+    }
+
+    /// Queues the event for the observer, if there is one.
+    pub fn emit(self: &mut Self, event: RaftEvent) {
+        if self.config.observer.is_some() {
+            self.events.push(event);
+        }
+    }
+
+    /// The observer and the events queued for it, which the caller
+    /// delivers once it has released the server.
+    pub fn take_events(self: &mut Self) -> Option<(Observer, Vec<RaftEvent>)> {
+        if self.events.is_empty() {
+            return None;
+        }
+
+        let events = self.events.drain(..).collect();
+        self.config
+            .observer
+            .clone()
+            .map(|observer| (observer, events))
     }
 
     pub fn become_leader(self: &mut Self) {
@@ -96,95 +1029,2047 @@ impl Server {
             );
             self.state = State::LEADER;
             self.next_timeout = None;
+            self.split_votes = 0;
+            self.transfer = None;
+            self.next_heartbeat = Some(self.now());
+            self.metrics.counters.elections_won_total += 1;
+            self.emit(RaftEvent::BecameLeader { term: self.term });
+
+            // Without a configuration in the log, the peers the server was
+            // started with are the cluster.
+            let next_index = self.last_log_index() + 1;
+            let membership = self.current_membership();
+            let peer_ids: Vec<String> = membership
+                .voters
+                .iter()
+                .chain(membership.learners.iter())
+                .filter(|p| p.id != self.id)
+                .map(|p| p.id.to_string())
+                .collect();
+
+            // Every follower gets a whole election timeout to answer.
+            let now = self.now();
+            self.progress = peer_ids
+                .into_iter()
+                .map(|id| {
+                    let mut progress = Progress::new(next_index);
+                    progress.last_contact = Some(now);
+                    (id, progress)
+                })
+                .collect();
+
+            // Entries of earlier terms only commit once one of its own
+            // term does, and the followers it replicates to tell who leads.
+            let written = self.log.append(LogEntry::NoOp {
+                term: self.term,
+                leader_id: self.id.to_string(),
+            });
+            if let Err(e) = written {
+                info!(
+                    "Server {} cannot write to its log, stepping down: {}",
+                    self.id, e
+                );
+                self.become_follower(self.term, None);
+                return;
+            }
+            self.notify();
+        }
+    }
+
+    /// Makes the server a follower in `term`, of `leader` if it is known.
+    /// A later term forgets the vote cast in the old one, and whatever only
+    /// a leader keeps is dropped. Announced if the server was not a
+    /// follower, or if it follows the leader of a later term.
+    pub fn become_follower(self: &mut Self, term: Term, leader: Option<Leader>) {
+        let new_term = self.adopt_term(term);
+        let was_follower = self.state == State::FOLLOWER;
+        let leader_is_known = leader.is_some();
+        if !was_follower {
+            self.stepped_down.cancel();
+        }
+
+        self.state = State::FOLLOWER;
+        self.current_leader = leader;
+        self.split_votes = 0;
+        self.next_heartbeat = None;
+        self.transfer = None;
+        self.progress.clear();
+        self.refresh_timeout();
+
+        if !was_follower || (new_term && leader_is_known) {
+            let leader_id = self.current_leader.as_ref().map(|l| l.id.to_string());
+            self.emit(RaftEvent::BecameFollower {
+                term: self.term,
+                leader_id: leader_id,
+            });
+        }
+    }
+
+    /// Makes the server a candidate in the next term, that voted for
+    /// itself. It must not ask for votes unless this returns `Ok`: the
+    /// term and the vote have to be durable first.
+    pub fn become_candidate(self: &mut Self) -> io::Result<()> {
+        if self.state == State::FOLLOWER {
+            self.stepped_down = CancelToken::any(&[&self.cancel]);
         }
+        self.term = self.term.increment();
+        self.state = State::CANDIDATE;
+        self.voted_for = Some(Peer {
+            id: self.id.to_string(),
+            address: self.address,
+            kind: NodeKind::Voter,
+        });
+        self.current_leader = None;
+        self.next_heartbeat = None;
+        self.transfer = None;
+        self.progress.clear();
+        self.refresh_timeout();
+        self.metrics.counters.elections_started_total += 1;
+        self.emit(RaftEvent::BecameCandidate { term: self.term });
+
+        self.persist_hard_state()
     }
 
     pub fn start(self: &mut Self) {
+        if let Some(data_dir) = &self.config.data_dir {
+            match Metrics::restore(data_dir) {
+                Ok(metrics) => self.metrics = metrics,
+                Err(e) => info!("Server {} could not restore its counters: {}", self.id, e),
+            }
+        }
+
+        // Starting without it could mean voting twice in a term, so an
+        // unreadable hard state is fatal.
+        if let Some(storage) = self.config.storage() {
+            if let Some(hard_state) = storage.load_hard_state().unwrap() {
+                self.term = hard_state.term;
+                self.voted_for = hard_state.voted_for;
+            }
+        }
+
+        self.sync_peers();
         self.refresh_timeout();
+
+        // Alone in its cluster, nobody else could win an election, so
+        // there is no point waiting for a timeout to stand.
+        if self.voter_count() == 1 && self.can_lead() {
+            self.next_timeout = Some(self.now());
+        }
+    }
+
+    /// Moves the server to `term` if it is later than its own, forgetting
+    /// the vote it cast in the old one. Returns whether the term moved.
+    fn adopt_term(self: &mut Self, term: Term) -> bool {
+        if term <= self.term {
+            return false;
+        }
+        self.term = term;
+        self.voted_for = None;
+        if let Err(e) = self.persist_hard_state() {
+            info!(
+                "Server {} could not persist its new term {}: {}",
+                self.id, term, e
+            );
+        }
+        true
+    }
+
+    /// Makes `term` and `voted_for` durable, if the server has storage.
+    /// Must succeed before a vote is granted or requested.
+    pub fn persist_hard_state(&self) -> io::Result<()> {
+        match self.config.storage() {
+            Some(storage) => storage.save_hard_state(&HardState {
+                term: self.term,
+                voted_for: self.voted_for.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Persists the counters if the server has a data directory and the
+    /// last flush is older than `metrics_flush_interval`.
+    pub fn flush_metrics_if_due(self: &mut Self) {
+        if self.metrics.flush_due(self.config.metrics_flush_interval) {
+            self.flush_metrics();
+        }
+    }
+
+    pub fn flush_metrics(self: &mut Self) {
+        if let Some(data_dir) = &self.config.data_dir {
+            if let Err(e) = self.metrics.persist(data_dir) {
+                info!("Server {} could not persist its counters: {}", self.id, e);
+            }
+        }
+    }
+
+    /// The time by `config.clock`.
+    pub fn now(&self) -> Instant {
+        self.config.clock.now()
     }
 
     pub fn has_timed_out(self: &mut Self) -> bool {
         match self.next_timeout {
-            Some(t) => Instant::now() > t,
+            Some(t) => self.now() > t,
             None => false,
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::net::Ipv4Addr;
-    use std::thread;
 
-    #[test]
-    fn server_become_leader() {
-        let mut server = build_server();
+    pub fn last_log_index(&self) -> u64 {
+        self.log.last_index()
+    }
 
-        server.become_leader();
+    /// The term of the entry at `index`, where index 0 is the empty
+    /// prefix of the log and always has term 0.
+    pub fn term_at(&self, index: u64) -> Option<Term> {
+        self.log.term_at(index)
+    }
 
-        assert_eq!(server.state, State::FOLLOWER);
+    /// Checks the peers of this server, all but itself. Mistakes there
+    /// otherwise go unnoticed. `Server::new` and `core::start_server` refuse
+    /// a server that fails it.
+    pub fn check_peers(&self) -> Result<(), ConfigError> {
+        let mut ids = HashSet::new();
 
-        // can only become leader if the current state
-        // is "CANDIDATE".
-        server.state = State::CANDIDATE;
-        server.become_leader();
+        for peer in self.peers.peers() {
+            if peer.id == self.id || peer.address == self.address {
+                return Err(ConfigError::SelfAmongPeers {
+                    peer_id: peer.id.to_string(),
+                    address: peer.address,
+                });
+            }
+            if !ids.insert(peer.id.to_string()) {
+                return Err(ConfigError::DuplicatePeer {
+                    peer_id: peer.id.to_string(),
+                });
+            }
+        }
 
-        assert_eq!(server.state, State::LEADER);
+        Ok(())
     }
 
-    #[test]
-    fn server_new() {
-        let server = build_server();
+    /// The other servers of the cluster, for the client reaching them to
+    /// share, see `PeerSet`.
+    pub fn peers(&self) -> &Arc<PeerSet> {
+        &self.peers
+    }
 
-        assert_eq!(server.state, State::FOLLOWER);
-        assert_eq!(server.term, 0);
-        assert_eq!(server.log_entries.len(), 0);
-        assert!(server.voted_for.is_none());
-        assert!(server.next_timeout.is_none());
-        assert!(server.current_leader.is_none());
+    /// Makes the peers those of the latest configuration in the log, if
+    /// there is one.
+    pub fn sync_peers(&self) {
+        if let Some((_, membership)) = self.membership() {
+            let peers = membership
+                .voters
+                .iter()
+                .chain(membership.learners.iter())
+                .filter(|p| p.id != self.id)
+                .cloned()
+                .collect();
+            self.peers.set(peers);
+        }
     }
 
-    #[test]
-    fn server_start() {
-        let mut server = build_server();
+    /// Writes the initial configuration (this server plus the given peers)
+    /// as the first entry of an empty log. Fails if it cannot be written.
+    pub fn bootstrap(self: &mut Self, peers: Vec<Peer>) -> io::Result<()> {
+        if !self.log.is_empty() {
+            return Ok(());
+        }
 
-        server.start();
+        let mut voters = vec![Peer {
+            id: self.id.to_string(),
+            address: self.address,
+            kind: self.config.kind,
+        }];
+        voters.extend(peers);
 
-        assert!(server.next_timeout.as_ref().unwrap() > &Instant::now());
+        self.log.append(LogEntry::Configuration {
+            term: self.term,
+            membership: Membership {
+                voters: voters,
+                learners: Vec::new(),
+            },
+        })?;
+        self.sync_peers();
+
+        Ok(())
     }
 
-    #[test]
-    fn server_has_timed_out() {
-        let mut server = build_server();
+    /// Makes a server with an empty log wait to be added to a running
+    /// cluster, see `add_server`: it takes no part in elections until the
+    /// leader sends it a configuration that makes it a voter. Without this,
+    /// a server that knows of no configuration stands like any voter.
+    pub fn join_as_learner(self: &mut Self) {
+        self.joining = self.log.is_empty();
+    }
 
-        server.start();
+    /// The latest configuration in the log, together with its index. A
+    /// configuration takes effect as soon as it is appended, committed
+    /// or not.
+    pub fn membership(&self) -> Option<(u64, &Membership)> {
+        self.log.membership()
+    }
 
-        assert!(!server.has_timed_out());
+    /// Every configuration appended at an index from `from_index` to
+    /// `to_index` included, oldest first.
+    pub fn membership_history(
+        &self,
+        from_index: u64,
+        to_index: u64,
+    ) -> impl Iterator<Item = MembershipRecord> + '_ {
+        // The configuration in place before the range, to describe the
+        // first change in it.
+        let previous = self.log.membership_before(from_index.max(1));
 
-        thread::sleep(Duration::new(1, 0));
+        self.log
+            .configurations(from_index, to_index)
+            .scan(previous, move |previous, (index, entry)| {
+                let record = entry.membership().map(|membership| {
+                    let record = MembershipRecord {
+                        index: index,
+                        term: entry.term(),
+                        membership: membership.clone(),
+                        change: membership.describe_change(*previous),
+                    };
+                    *previous = Some(membership);
+                    record
+                });
 
-        assert!(server.has_timed_out());
+                Some(record)
+            })
+            .flatten()
     }
 
-    #[test]
-    fn server_refresh_timeout() {
-        let mut server = build_server();
+    /// The servers votes and acknowledgements are counted against: those
+    /// of the latest configuration in the log, or while there is none,
+    /// this server and its peers, all voters.
+    pub fn current_membership(&self) -> Membership {
+        if let Some((_, membership)) = self.membership() {
+            return membership.clone();
+        }
 
-        server.refresh_timeout();
+        let mut voters = vec![Peer {
+            id: self.id.to_string(),
+            address: self.address,
+            kind: self.config.kind,
+        }];
+        voters.extend(self.peers.peers());
+        Membership {
+            voters: voters,
+            learners: Vec::new(),
+        }
+    }
 
-        assert!(server.next_timeout.as_ref().unwrap() > &Instant::now());
+    /// Number of servers that count towards the quorum, see
+    /// `current_membership`.
+    pub fn voter_count(&self) -> usize {
+        match self.membership() {
+            Some((_, membership)) => membership.voters.len(),
+            None => self.peers.peers().len() + 1,
+        }
+    }
+
+    /// Whether enough was applied since the last snapshot to take another
+    /// one automatically.
+    pub fn snapshot_due(&self) -> bool {
+        self.config.data_dir.is_some()
+            && !self.snapshots.is_in_progress()
+            && self.last_applied
+                >= self.snapshots.last_included_index() + self.config.snapshot_threshold
+    }
+
+    /// The leader this server last heard from, as a member of the
+    /// configuration, for a client to be sent to. None if unknown, or if
+    /// it is not in the configuration.
+    pub fn known_leader(&self) -> Option<Peer> {
+        let leader = self.current_leader.as_ref()?;
+        let (_, membership) = self.membership()?;
+
+        membership
+            .voters
+            .iter()
+            .chain(membership.learners.iter())
+            .find(|p| p.id == leader.id)
+            .cloned()
+    }
+
+    /// A snapshot of where the server stands and what it has counted, to
+    /// poll for dashboards.
+    pub fn metrics(&self) -> RaftMetrics {
+        let leader_id = match self.state {
+            State::LEADER => Some(self.id.to_string()),
+            _ => self.current_leader.as_ref().map(|l| l.id.to_string()),
+        };
+
+        RaftMetrics {
+            captured_at: Timestamp::now(),
+            id: self.id.to_string(),
+            uptime: self.now().saturating_duration_since(self.started_at),
+            apply_gap: self.apply_gap(),
+            apply_lag_mode: self.apply_lag_mode(),
+            approaching_limits: self.approaching_limits.iter().cloned().collect(),
+            term: self.term,
+            state: self.state,
+            commit_index: self.commit_index,
+            last_applied: self.last_applied,
+            log_length: self.log.last_index(),
+            leader_id: leader_id,
+            fault_tolerance: quorum::fault_tolerance(&self.current_membership()),
+            counters: self.metrics.counters.clone(),
+            build: BuildInfo::current(),
+        }
+    }
+
+    /// One entry per follower, sorted by id. Empty unless leading.
+    pub fn peer_stats(&self) -> Vec<PeerStats> {
+        let now = self.now();
+
+        let mut stats: Vec<PeerStats> = self
+            .progress
+            .iter()
+            .map(|(peer_id, progress)| PeerStats {
+                peer_id: peer_id.to_string(),
+                match_index: progress.match_index,
+                next_index: progress.next_index,
+                paused: progress.is_paused(),
+                catching_up: progress.is_catching_up(),
+                catch_up_bytes_per_second: progress.catch_up_rate(now),
+                catch_up_budget: self.config.catch_up_bytes_per_second,
+            })
+            .collect();
+
+        stats.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        stats
+    }
+
+    /// Appends a bulk command to the leader's log, returning its index.
+    pub fn propose(self: &mut Self, data: Vec<u8>) -> Result<u64, ProposeError> {
+        self.propose_with_priority(data, Priority::Bulk)
+    }
+
+    /// Like `propose`, but returns as soon as the command is handed to the
+    /// log writer: `log.acks()` tells when it is durable. Waiting for that
+    /// without the lock lets concurrent proposals share a sync, see
+    /// `core::propose_command`.
+    pub fn submit(self: &mut Self, data: Vec<u8>) -> Result<u64, ProposeError> {
+        let entry = LogEntry::Command {
+            term: self.term,
+            data: data,
+        };
+
+        self.append_command(entry, Priority::Bulk)
+    }
+
+    pub fn propose_with_priority(
+        self: &mut Self,
+        data: Vec<u8>,
+        priority: Priority,
+    ) -> Result<u64, ProposeError> {
+        let entry = LogEntry::Command {
+            term: self.term,
+            data: data,
+        };
+
+        let index = self.append_command(entry, priority)?;
+        self.wait_for_log(index)
+    }
+
+    /// Proposes a command within a client session. A client retrying
+    /// after a timeout proposes again with the same sequence number: the
+    /// command is applied only once, and `sessions` keeps its response.
+    pub fn propose_in_session(
+        self: &mut Self,
+        client_id: &str,
+        sequence: u64,
+        data: Vec<u8>,
+    ) -> Result<u64, ProposeError> {
+        let entry = LogEntry::SessionCommand {
+            term: self.term,
+            client_id: client_id.to_string(),
+            sequence: sequence,
+            data: data,
+        };
+
+        let index = self.append_command(entry, Priority::Bulk)?;
+        self.wait_for_log(index)
+    }
+
+    fn append_command(
+        self: &mut Self,
+        entry: LogEntry,
+        priority: Priority,
+    ) -> Result<u64, ProposeError> {
+        if self.state != State::LEADER {
+            return Err(ProposeError::NotLeader);
+        }
+
+        if let Some(transfer) = &self.transfer {
+            return Err(ProposeError::TransferringLeadership {
+                target: transfer.target.to_string(),
+            });
+        }
+
+        if entry.payload_size() > self.config.max_entry_bytes {
+            return Err(ProposeError::EntryTooLarge {
+                size: entry.payload_size(),
+                max: self.config.max_entry_bytes,
+            });
+        }
+
+        let (uncommitted, uncommitted_bytes) = self.uncommitted();
+        if priority == Priority::Bulk
+            && (uncommitted >= self.config.max_uncommitted_entries as u64
+                || uncommitted_bytes >= self.config.max_uncommitted_bytes)
+        {
+            return Err(ProposeError::Backpressure {
+                uncommitted: uncommitted,
+                uncommitted_bytes: uncommitted_bytes,
+            });
+        }
+
+        if priority == Priority::Bulk && self.apply_lag_mode() == Some(ApplyLagPolicy::Throttle) {
+            return Err(ProposeError::ApplyLag {
+                gap: self.slowest_apply_gap(),
+            });
+        }
+
+        let is_command = matches!(entry, LogEntry::Command { .. });
+        let index = self
+            .log
+            .submit(vec![entry])
+            .map_err(|e| ProposeError::LogUnwritable {
+                error: e.to_string(),
+            })?;
+        self.check_soft_limits();
+        self.notify();
+
+        if is_command {
+            self.outputs
+                .insert(index, Output::Pending { term: self.term });
+            while self.outputs.len() > self.config.max_retained_outputs {
+                self.outputs.pop_first();
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Blocks until the proposal at `index` is durable.
+    fn wait_for_log(self: &mut Self, index: u64) -> Result<u64, ProposeError> {
+        self.log
+            .wait(index)
+            .map_err(|e| ProposeError::LogUnwritable {
+                error: e.to_string(),
+            })?;
+
+        Ok(index)
+    }
+
+    /// How many entries, and bytes of entry payload, are not committed.
+    fn uncommitted(&self) -> (u64, usize) {
+        let entries = self.last_log_index() - self.commit_index;
+        let bytes = self.log.payload_size_after(self.commit_index);
+
+        (entries, bytes)
+    }
+
+    /// The limits this server is getting close to, so that operators hear
+    /// of them before proposals are refused.
+    pub fn approaching_limits(&self) -> &BTreeSet<Limit> {
+        &self.approaching_limits
+    }
+
+    /// Emits `ApproachingLimit` once when a limit gets `soft_limit_percent`
+    /// full, and forgets about it once it is back below
+    /// `soft_limit_clear_percent`, so that a value hovering around the
+    /// threshold does not warn over and over.
+    pub fn check_soft_limits(self: &mut Self) {
+        // only a leader refuses proposals
+        let (uncommitted, uncommitted_bytes) = match self.state {
+            State::LEADER => self.uncommitted(),
+            _ => (0, 0),
+        };
+        let limits = [
+            (
+                Limit::UncommittedEntries,
+                uncommitted,
+                self.config.max_uncommitted_entries as u64,
+            ),
+            (
+                Limit::UncommittedBytes,
+                uncommitted_bytes as u64,
+                self.config.max_uncommitted_bytes as u64,
+            ),
+            (
+                Limit::ApplyGap,
+                self.slowest_apply_gap(),
+                self.config.max_apply_lag,
+            ),
+        ];
+
+        for (which, current, limit) in limits.iter().cloned() {
+            let percent = current.saturating_mul(100);
+
+            if percent >= limit.saturating_mul(self.config.soft_limit_percent) {
+                if self.approaching_limits.insert(which) {
+                    info!(
+                        "Server {} is approaching its {:?} limit: {} of {}",
+                        self.id, which, current, limit
+                    );
+                    self.emit(RaftEvent::ApproachingLimit {
+                        which: which,
+                        current: current,
+                        limit: limit,
+                    });
+                }
+            } else if percent < limit.saturating_mul(self.config.soft_limit_clear_percent) {
+                self.approaching_limits.remove(&which);
+            }
+        }
+    }
+
+    /// Every command applied from now on is sent, with its index, in log
+    /// order. A subscriber that leaves `applied_channel_capacity` entries
+    /// unread is unsubscribed rather than allowed to hold back the server:
+    /// once it has drained its channel it sees the sender disconnected, and
+    /// never a gap.
+    pub fn subscribe_applied(self: &mut Self) -> Receiver<(u64, LogEntry)> {
+        let (sender, receiver) = sync_channel(self.config.applied_channel_capacity);
+        self.applied_subscribers.push(sender);
+        receiver
+    }
+
+    /// How the last election this server stood in ended, if it stood in
+    /// one since it started.
+    pub fn last_election(&self) -> Option<&ElectionRecord> {
+        self.last_election.as_ref()
+    }
+
+    pub fn record_election(self: &mut Self, record: ElectionRecord) {
+        self.last_election = Some(record);
+    }
+
+    /// The index of the last entry applied to the state machine.
+    pub fn last_applied(&self) -> u64 {
+        self.last_applied
+    }
+
+    pub fn apply_failure(&self) -> Option<&(u64, ApplyError)> {
+        self.apply_failure.as_ref()
+    }
+
+    /// Takes what is known of the command proposed here at `index`, if
+    /// it is still kept.
+    pub fn take_output(self: &mut Self, index: u64) -> Option<Output> {
+        self.outputs.remove(&index)
+    }
+
+    /// Whether `apply_committed` has more to do.
+    pub fn apply_pending(&self) -> bool {
+        self.apply_failure.is_none() && self.last_applied < self.commit_index
+    }
+
+    /// Committed entries not applied yet.
+    pub fn apply_gap(&self) -> u64 {
+        self.commit_index - self.last_applied
+    }
+
+    /// The largest apply gap this server knows of: its own, and on a
+    /// leader that of every follower over the committed entries it holds.
+    /// A follower that is behind on replication is not lagging on apply.
+    pub fn slowest_apply_gap(&self) -> u64 {
+        self.progress
+            .values()
+            .map(|p| {
+                p.match_index
+                    .min(self.commit_index)
+                    .saturating_sub(p.last_applied)
+            })
+            .fold(self.apply_gap(), u64::max)
+    }
+
+    /// The policy in force, if a state machine lags too far behind.
+    pub fn apply_lag_mode(&self) -> Option<ApplyLagPolicy> {
+        let gap = match self.config.apply_lag_policy {
+            ApplyLagPolicy::CatchUp => self.apply_gap(),
+            ApplyLagPolicy::Throttle => self.slowest_apply_gap(),
+        };
+
+        if gap > self.config.max_apply_lag {
+            Some(self.config.apply_lag_policy)
+        } else {
+            None
+        }
+    }
+
+    /// Applies the entries committed since the last call, at most
+    /// `max_applied_per_round` of them unless catching up. A failure to
+    /// apply, or a panic of the state machine, stops applying for good,
+    /// and is reported to the observer and to `wait_for_applied`.
+    pub fn apply_committed(self: &mut Self) {
+        if !self.apply_pending() {
+            return;
+        }
+        self.applied.notify_all();
+
+        let limit = match self.apply_lag_mode() {
+            Some(ApplyLagPolicy::CatchUp) => {
+                info!(
+                    "Server {} is {} entries behind, catching up",
+                    self.id,
+                    self.apply_gap()
+                );
+                self.apply_gap()
+            }
+            _ => self.config.max_applied_per_round as u64,
+        };
+        let apply_to = self.commit_index.min(self.last_applied + limit);
+
+        // It holds no commands to apply.
+        if self.config.kind == NodeKind::Witness {
+            self.last_applied = apply_to;
+            return;
+        }
+
+        while self.last_applied < apply_to {
+            let index = self.last_applied + 1;
+
+            let entry = match self.log.entry_at(index) {
+                Some(entry) => entry,
+                None => return,
+            };
+            let state_machine = &mut self.state_machine;
+            let mut apply = |data: &[u8]| match state_machine {
+                Some(state_machine) => state_machine::apply_guarded(state_machine.as_mut(), data),
+                None => Ok(Vec::new()),
+            };
+
+            let outputs = &mut self.outputs;
+            let applied = match &entry {
+                LogEntry::Command { term, data } => apply(data).map(|output| {
+                    // An entry of the same index and term is the one proposed.
+                    if outputs.get(&index) == Some(&Output::Pending { term: *term }) {
+                        outputs.insert(index, Output::Applied(output));
+                    }
+                    true
+                }),
+                LogEntry::SessionCommand {
+                    client_id,
+                    sequence,
+                    data,
+                    ..
+                } => self.sessions.apply(client_id, *sequence, || apply(data)),
+                _ => Ok(false),
+            };
+
+            let applied = match applied {
+                Ok(applied) => applied,
+                Err(error) => {
+                    info!(
+                        "Server {} could not apply entry {}, no longer applying: {:?}",
+                        self.id, index, error
+                    );
+                    self.apply_failure = Some((index, error.clone()));
+                    self.emit(RaftEvent::ApplyFailed {
+                        index: index,
+                        error: error,
+                    });
+                    return;
+                }
+            };
+
+            if let Some(Output::Pending { .. }) = self.outputs.get(&index) {
+                self.outputs.insert(index, Output::Superseded);
+            }
+            self.last_applied = index;
+
+            if applied {
+                let id = &self.id;
+
+                self.applied_subscribers.retain(|subscriber| {
+                    match subscriber.try_send((index, entry.clone())) {
+                        Ok(()) => true,
+                        Err(TrySendError::Full(_)) => {
+                            info!("Server {} dropping a slow subscriber at {}", id, index);
+                            false
+                        }
+                        Err(TrySendError::Disconnected(_)) => false,
+                    }
+                });
+            }
+        }
+    }
+
+    /// Adds a server to the cluster as a learner. Only one change may be in
+    /// flight at a time, so the previous configuration must be committed
+    /// before a new one is appended.
+    pub fn add_server(self: &mut Self, peer: Peer) -> Result<u64, MembershipError> {
+        let mut membership = self.membership_for_change()?;
+
+        if membership.contains(&peer.id) {
+            return Err(MembershipError::AlreadyMember);
+        }
+
+        info!(
+            "Server {} adding {} to the cluster as a learner.",
+            self.id, peer.id
+        );
+
+        let peer_id = peer.id.to_string();
+        membership.learners.push(peer);
+        let index = self.append_configuration(membership)?;
+
+        // The learner is sent the log from now on, and gets a whole
+        // election timeout to answer like any follower.
+        let mut progress = Progress::new(index);
+        progress.last_contact = Some(self.now());
+        self.progress.insert(peer_id, progress);
+
+        Ok(index)
+    }
+
+    /// Removes a voter or a learner from the cluster. The new configuration
+    /// is used for quorums as soon as it is appended. A leader removing
+    /// itself keeps leading until the change is committed, and then steps
+    /// down.
+    pub fn remove_server(self: &mut Self, peer_id: &str) -> Result<u64, MembershipError> {
+        let mut membership = self.membership_for_change()?;
+
+        if !membership.contains(peer_id) {
+            return Err(MembershipError::UnknownPeer);
+        }
+
+        membership.voters.retain(|p| p.id != peer_id);
+        membership.learners.retain(|p| p.id != peer_id);
+
+        if membership.voters.is_empty() {
+            return Err(MembershipError::LastVoter);
+        }
+
+        info!("Server {} removing {} from the cluster.", self.id, peer_id);
+
+        self.append_configuration(membership)
+    }
+
+    /// Hands leadership over to the voter `target_id`: once it has every
+    /// entry, it is told to stand right away, and wins unless it lost
+    /// touch with the others. Proposals are refused meanwhile, so that it
+    /// can catch up. The transfer is given up if the target has not won
+    /// within `election_timeout_max`.
+    pub fn transfer_leadership(self: &mut Self, target_id: &str) -> Result<(), TransferError> {
+        if self.state != State::LEADER {
+            return Err(TransferError::NotLeader);
+        }
+
+        let can_lead = self.membership().is_some_and(|(_, m)| {
+            m.voters.iter().any(|p| p.id == target_id) && !m.is_witness(target_id)
+        });
+        if target_id == self.id || !can_lead {
+            return Err(TransferError::UnknownPeer);
+        }
+
+        if self.transfer.is_some() {
+            return Err(TransferError::InProgress);
+        }
+
+        info!(
+            "Server {} handing leadership over to {}.",
+            self.id, target_id
+        );
+        self.transfer = Some(LeadershipTransfer {
+            target: target_id.to_string(),
+            deadline: self.now() + self.config.election_timeout_max,
+            timeout_now_sent: false,
+        });
+        self.notify();
+
+        Ok(())
+    }
+
+    /// Whether this server may stand for election: a voter that is no
+    /// witness.
+    pub fn can_lead(&self) -> bool {
+        self.is_voter() && self.config.kind == NodeKind::Voter
+    }
+
+    /// Whether this server takes part in elections. Learners and removed
+    /// servers never start one, nor do servers joining as learners.
+    pub fn is_voter(&self) -> bool {
+        match self.membership() {
+            Some((_, membership)) => membership.voters.iter().any(|p| p.id == self.id),
+            None => !self.joining,
+        }
+    }
+
+    /// Promotes a learner to voter once its log has caught up with the
+    /// leader's.
+    pub fn promote_learner(
+        self: &mut Self,
+        peer_id: &str,
+        match_index: u64,
+    ) -> Result<u64, MembershipError> {
+        let mut membership = self.membership_for_change()?;
+
+        let position = membership
+            .learners
+            .iter()
+            .position(|p| p.id == peer_id)
+            .ok_or(MembershipError::UnknownPeer)?;
+
+        if match_index < self.last_log_index() {
+            return Err(MembershipError::NotCaughtUp);
+        }
+
+        info!("Server {} promoting learner {} to voter.", self.id, peer_id);
+
+        let peer = membership.learners.remove(position);
+        membership.voters.push(peer);
+
+        self.append_configuration(membership)
+    }
+
+    fn membership_for_change(&self) -> Result<Membership, MembershipError> {
+        if self.state != State::LEADER {
+            return Err(MembershipError::NotLeader);
+        }
+
+        match self.membership() {
+            Some((index, _)) if index > self.commit_index => Err(MembershipError::ChangeInProgress),
+            Some((_, membership)) => Ok(membership.clone()),
+            None => Ok(self.current_membership()),
+        }
+    }
+
+    fn append_configuration(
+        self: &mut Self,
+        membership: Membership,
+    ) -> Result<u64, MembershipError> {
+        self.log
+            .append(LogEntry::Configuration {
+                term: self.term,
+                membership: membership,
+            })
+            .map_err(|e| MembershipError::LogUnwritable {
+                error: e.to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::clock::ManualClock;
+    use crate::raft::core;
+    use crate::raft::log_storage::MemLogStorage;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn server_rejects_heartbeats_slower_than_the_election_timeout() {
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+        let config = |heartbeat_interval: Duration| ServerConfig {
+            election_timeout_min: Duration::from_millis(300),
+            election_timeout_max: Duration::from_millis(600),
+            heartbeat_interval: heartbeat_interval,
+            ..ServerConfig::default()
+        };
+
+        assert!(Server::new(
+            config(Duration::from_millis(50)),
+            Vec::new(),
+            address,
+            "server_1".into()
+        )
+        .is_ok());
+
+        for heartbeat_interval in [Duration::from_millis(300), Duration::from_secs(1)] {
+            let error = Server::new(
+                config(heartbeat_interval),
+                Vec::new(),
+                address,
+                "server_1".into(),
+            )
+            .unwrap_err();
+            assert_eq!(
+                error,
+                ConfigError::HeartbeatNotBelowElectionTimeout {
+                    heartbeat_interval: heartbeat_interval,
+                    election_timeout_min: Duration::from_millis(300),
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn server_rejects_an_empty_election_timeout_range() {
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+        let config = |min: u64, max: u64| ServerConfig {
+            election_timeout_min: Duration::from_millis(min),
+            election_timeout_max: Duration::from_millis(max),
+            heartbeat_interval: Duration::from_millis(50),
+            ..ServerConfig::default()
+        };
+
+        assert!(Server::new(config(300, 600), Vec::new(), address, "server_1".into()).is_ok());
+        assert!(Server::new(config(300, 300), Vec::new(), address, "server_1".into()).is_ok());
+        for (min, max) in [(600, 300), (0, 300), (0, 0)] {
+            assert_eq!(
+                Server::new(config(min, max), Vec::new(), address, "server_1".into()).unwrap_err(),
+                ConfigError::ElectionTimeoutRange {
+                    election_timeout_min: Duration::from_millis(min),
+                    election_timeout_max: Duration::from_millis(max),
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn server_refresh_timeout_draws_from_the_range() {
+        let clock = ManualClock::new();
+        let mut server = build_server();
+        server.config.election_timeout_min = Duration::from_millis(150);
+        server.config.election_timeout_max = Duration::from_millis(300);
+        server.config.clock = Arc::new(clock.clone());
+
+        let timeouts: Vec<Duration> = (0..20)
+            .map(|_| {
+                server.refresh_timeout();
+                server.next_timeout.unwrap() - clock.now()
+            })
+            .collect();
+
+        for timeout in timeouts.iter() {
+            assert!(*timeout >= Duration::from_millis(150), "{:?}", timeout);
+            assert!(*timeout <= Duration::from_millis(300), "{:?}", timeout);
+        }
+        assert!(timeouts.iter().any(|t| *t != timeouts[0]));
+    }
+
+    #[test]
+    fn server_soft_limit_warns_once_until_it_clears() {
+        let mut server = build_server();
+        server.config.max_uncommitted_entries = 10;
+        server.config.observer = Some(Observer::new(|_| {}));
+        server.state = State::LEADER;
+        for _ in 0..10 {
+            server
+                .log
+                .append(LogEntry::Command {
+                    term: Term(0),
+                    data: Vec::new(),
+                })
+                .unwrap();
+        }
+
+        let warnings_at = |server: &mut Server, uncommitted: u64| {
+            server.commit_index = 10 - uncommitted;
+            server.check_soft_limits();
+            server.take_events().map_or(0, |(_, events)| events.len())
+        };
+
+        assert_eq!(warnings_at(&mut server, 7), 0);
+        assert_eq!(warnings_at(&mut server, 8), 1);
+        // between the two thresholds, the warning holds
+        assert_eq!(warnings_at(&mut server, 7), 0);
+        assert_eq!(warnings_at(&mut server, 9), 0);
+        assert_eq!(warnings_at(&mut server, 6), 0);
+        assert!(server
+            .approaching_limits()
+            .contains(&Limit::UncommittedEntries));
+
+        assert_eq!(warnings_at(&mut server, 5), 0);
+        assert!(server.approaching_limits().is_empty());
+        assert_eq!(warnings_at(&mut server, 8), 1);
+    }
+
+    #[test]
+    fn server_rejects_soft_limits_out_of_order() {
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+        let config = |soft_limit_percent: u64, soft_limit_clear_percent: u64| ServerConfig {
+            soft_limit_percent: soft_limit_percent,
+            soft_limit_clear_percent: soft_limit_clear_percent,
+            ..ServerConfig::default()
+        };
+
+        assert!(Server::new(config(90, 80), Vec::new(), address, "server_1".into()).is_ok());
+        for (soft, clear) in [(100, 80), (80, 80), (80, 90)] {
+            assert_eq!(
+                Server::new(config(soft, clear), Vec::new(), address, "server_1".into())
+                    .unwrap_err(),
+                ConfigError::SoftLimitsOutOfOrder {
+                    soft_limit_percent: soft,
+                    soft_limit_clear_percent: clear,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn server_become_leader() {
+        let mut server = build_server();
+
+        server.become_leader();
+
+        assert_eq!(server.state, State::FOLLOWER);
+
+        // can only become leader if the current state
+        // is "CANDIDATE".
+        server.state = State::CANDIDATE;
+        server.become_leader();
+
+        assert_eq!(server.state, State::LEADER);
+    }
+
+    #[test]
+    fn server_become_follower() {
+        let mut server = build_server();
+        server.config.observer = Some(Observer::new(|_| {}));
+        server
+            .bootstrap(vec![
+                build_peer("server_2", 9091),
+                build_peer("server_3", 9092),
+            ])
+            .unwrap();
+        server.term = Term(2);
+        server.state = State::CANDIDATE;
+        server.become_leader();
+        server.transfer_leadership("server_2").unwrap();
+        server.events.clear();
+
+        server.become_follower(
+            Term(3),
+            Some(Leader {
+                id: "server_3".to_string(),
+            }),
+        );
+
+        assert_eq!(server.state, State::FOLLOWER);
+        assert_eq!(server.term, Term(3));
+        assert!(server.voted_for.is_none());
+        assert_eq!(server.current_leader.as_ref().unwrap().id, "server_3");
+        assert!(server.next_timeout.is_some());
+        assert!(server.next_heartbeat.is_none());
+        assert!(server.transfer.is_none());
+        assert!(server.progress.is_empty());
+        assert_eq!(
+            server.events,
+            vec![RaftEvent::BecameFollower {
+                term: Term(3),
+                leader_id: Some("server_3".to_string()),
+            }]
+        );
+
+        // a follower staying in its term keeps its vote, and has nothing
+        // to announce
+        server.voted_for = Some(build_peer("server_2", 9091));
+        server.become_follower(Term(3), None);
+        assert!(server.voted_for.is_some());
+        assert!(server.current_leader.is_none());
+        assert_eq!(server.events.len(), 1);
+    }
+
+    #[test]
+    fn server_become_candidate() {
+        let mut server = build_server();
+        server.config.observer = Some(Observer::new(|_| {}));
+        server.term = Term(4);
+        server.voted_for = Some(build_peer("server_2", 9091));
+        server.current_leader = Some(Leader {
+            id: "server_2".to_string(),
+        });
+
+        server.become_candidate().unwrap();
+
+        assert_eq!(server.state, State::CANDIDATE);
+        assert_eq!(server.term, Term(5));
+        assert_eq!(server.voted_for.as_ref().unwrap().id, "server_1");
+        assert!(server.current_leader.is_none());
+        assert!(server.next_timeout.is_some());
+        assert!(server.next_heartbeat.is_none());
+        assert!(server.progress.is_empty());
+        assert_eq!(server.metrics.counters.elections_started_total, 1);
+        assert_eq!(
+            server.events,
+            vec![RaftEvent::BecameCandidate { term: Term(5) }]
+        );
+    }
+
+    #[test]
+    fn server_catches_up_when_applying_lags() {
+        let mut server = build_server();
+        server.config.max_applied_per_round = 2;
+        server.config.max_apply_lag = 5;
+        let applied = Arc::new(AtomicU64::new(0));
+        server.state_machine = Some(Box::new(Slow(Arc::clone(&applied))));
+        for _ in 0..20 {
+            server
+                .log
+                .append(LogEntry::Command {
+                    term: Term(1),
+                    data: vec![1],
+                })
+                .unwrap();
+        }
+
+        // a small gap is worked off a few entries per round
+        server.commit_index = 4;
+        server.apply_committed();
+        assert_eq!(server.last_applied, 2);
+        assert_eq!(server.metrics().apply_lag_mode, None);
+
+        // a large one all at once
+        server.commit_index = 12;
+        assert_eq!(server.metrics().apply_gap, 10);
+        assert_eq!(
+            server.metrics().apply_lag_mode,
+            Some(ApplyLagPolicy::CatchUp)
+        );
+        server.apply_committed();
+        assert_eq!(server.apply_gap(), 0);
+        assert_eq!(applied.load(Ordering::SeqCst), 12);
+
+        // and then back to normal
+        server.commit_index = 16;
+        server.apply_committed();
+        assert_eq!(server.last_applied, 14);
+        assert_eq!(server.metrics().apply_lag_mode, None);
+    }
+
+    #[test]
+    fn server_apply_stops_at_a_failed_entry() {
+        for panics in [false, true] {
+            let mut server = build_server();
+            server.config.observer = Some(Observer::new(|_| {}));
+            server.state_machine = Some(Box::new(FailsThird {
+                applied: 0,
+                panics: panics,
+            }));
+            for _ in 0..5 {
+                server
+                    .log
+                    .append(LogEntry::Command {
+                        term: Term(1),
+                        data: vec![1],
+                    })
+                    .unwrap();
+            }
+            server.commit_index = 5;
+
+            server.apply_committed();
+
+            assert_eq!(server.last_applied(), 2);
+            let (index, error) = server.apply_failure().cloned().unwrap();
+            assert_eq!(index, 3);
+            assert!(error.0.contains("corrupted"), "{:?}", error);
+
+            let (_, events) = server.take_events().unwrap();
+            assert!(events.contains(&RaftEvent::ApplyFailed {
+                index: 3,
+                error: error,
+            }));
+
+            // nothing is skipped, however often it is tried again
+            server.apply_committed();
+            assert_eq!(server.last_applied(), 2);
+
+            let server = Arc::new(Mutex::new(server));
+            assert!(matches!(
+                core::wait_for_applied(&server, 5, Duration::from_secs(1)),
+                Err(WaitError::ApplyFailed { index: 3, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn server_become_leader_tracks_progress() {
+        let mut server = build_server();
+        server
+            .bootstrap(vec![
+                build_peer("server_2", 9091),
+                build_peer("server_3", 9092),
+            ])
+            .unwrap();
+
+        server.state = State::CANDIDATE;
+        server.become_leader();
+
+        assert_eq!(server.progress.len(), 2);
+        assert_eq!(server.progress["server_2"].next_index, 2);
+        assert_eq!(server.progress["server_3"].match_index, 0);
+        assert!(!server.progress.contains_key("server_1"));
+    }
+
+    #[test]
+    fn server_new() {
+        let server = build_server();
+
+        assert_eq!(server.state, State::FOLLOWER);
+        assert_eq!(server.term, Term(0));
+        assert!(server.log.is_empty());
+        assert!(server.voted_for.is_none());
+        assert!(server.next_timeout.is_none());
+        assert!(server.current_leader.is_none());
+        assert!(server.progress.is_empty());
+    }
+
+    #[test]
+    fn server_start() {
+        let mut server = build_server();
+
+        server.start();
+
+        assert!(server.next_timeout.as_ref().unwrap() > &Instant::now());
+    }
+
+    #[test]
+    fn server_has_timed_out() {
+        let clock = ManualClock::new();
+        let mut server = build_server();
+        server.config.clock = Arc::new(clock.clone());
+
+        server.start();
+
+        assert!(!server.has_timed_out());
+
+        clock.advance(Duration::from_millis(1000));
+        assert!(!server.has_timed_out());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(server.has_timed_out());
+    }
+
+    #[test]
+    fn server_check_peers() {
+        let check = |peers: Vec<Peer>| {
+            let server = build_server();
+            server.peers().set(peers);
+            server.check_peers()
+        };
+
+        assert!(build_server().check_peers().is_ok());
+        assert_eq!(
+            check(vec![
+                build_peer("server_2", 9091),
+                build_peer("server_2", 9092)
+            ]),
+            Err(ConfigError::DuplicatePeer {
+                peer_id: "server_2".to_string()
+            })
+        );
+        assert_eq!(
+            check(vec![
+                build_peer("server_2", 9091),
+                build_peer("server_1", 9092)
+            ]),
+            Err(ConfigError::SelfAmongPeers {
+                peer_id: "server_1".to_string(),
+                address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9092),
+            })
+        );
+        assert_eq!(
+            check(vec![
+                build_peer("server_2", 9091),
+                build_peer("server_3", 9090)
+            ]),
+            Err(ConfigError::SelfAmongPeers {
+                peer_id: "server_3".to_string(),
+                address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090),
+            })
+        );
+    }
+
+    #[test]
+    fn server_new_refuses_bad_peers() {
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+        let new = |peers: Vec<Peer>| {
+            Server::new(
+                ServerConfig::default(),
+                peers,
+                address,
+                "server_1".to_string(),
+            )
+            .err()
+        };
+
+        assert_eq!(
+            new(vec![
+                build_peer("server_2", 9091),
+                build_peer("server_2", 9092)
+            ]),
+            Some(ConfigError::DuplicatePeer {
+                peer_id: "server_2".to_string()
+            })
+        );
+        assert_eq!(
+            new(vec![build_peer("server_1", 9091)]),
+            Some(ConfigError::SelfAmongPeers {
+                peer_id: "server_1".to_string(),
+                address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9091),
+            })
+        );
+    }
+
+    #[test]
+    fn server_peers_follow_the_configuration() {
+        let mut server = build_server();
+        let peers = Arc::clone(server.peers());
+        let version = peers.version();
+        assert_eq!(server.voter_count(), 3);
+
+        server
+            .bootstrap(vec![build_peer("server_2", 9091)])
+            .unwrap();
+        assert_eq!(peers.peers(), vec![build_peer("server_2", 9091)]);
+        assert_eq!(peers.version(), version + 1);
+        assert_eq!(server.voter_count(), 2);
+
+        // the same peers again are no change
+        server.sync_peers();
+        assert_eq!(peers.version(), version + 1);
+    }
+
+    #[test]
+    fn server_adopt_term() {
+        let mut server = build_server();
+        server.term = Term(3);
+        server.voted_for = Some(Peer {
+            id: "server_2".to_string(),
+            address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9091),
+            kind: NodeKind::Voter,
+        });
+
+        // not later, the vote of the term stands
+        assert!(!server.adopt_term(Term(2)));
+        assert!(!server.adopt_term(Term(3)));
+        assert_eq!(server.term, Term(3));
+        assert!(server.voted_for.is_some());
+
+        assert!(server.adopt_term(Term(5)));
+        assert_eq!(server.term, Term(5));
+        assert!(server.voted_for.is_none());
+    }
+
+    #[test]
+    fn term_is_a_bare_u64_on_the_wire() {
+        /// A `VoteRequest` as it was sent before terms had their own type.
+        #[derive(Serialize)]
+        struct PlainVoteRequest {
+            term: u64,
+            candidate_id: String,
+            last_log_index: u64,
+            last_log_term: u64,
+            leadership_transfer: bool,
+            election_priority: u8,
+        }
+        let request = VoteRequest {
+            term: Term(7),
+            candidate_id: "server_1".to_string(),
+            last_log_index: 12,
+            last_log_term: Term(6),
+            leadership_transfer: false,
+            election_priority: 1,
+        };
+        let plain = PlainVoteRequest {
+            term: 7,
+            candidate_id: "server_1".to_string(),
+            last_log_index: 12,
+            last_log_term: 6,
+            leadership_transfer: false,
+            election_priority: 1,
+        };
+
+        assert_eq!(
+            bincode::serialize(&Term(7)).unwrap(),
+            bincode::serialize(&7u64).unwrap()
+        );
+        assert_eq!(
+            bincode::serialize(&request).unwrap(),
+            bincode::serialize(&plain).unwrap()
+        );
+        assert_eq!(serde_json::to_string(&Term(7)).unwrap(), "7");
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            serde_json::to_string(&plain).unwrap()
+        );
+        let decoded: VoteRequest =
+            bincode::deserialize(&bincode::serialize(&plain).unwrap()).unwrap();
+        assert_eq!(decoded.term, Term(7));
+        assert_eq!(decoded.last_log_term, Term(6));
+    }
+
+    #[test]
+    fn term_increment_and_display() {
+        assert_eq!(Term(0).increment(), Term(1));
+        assert!(Term(2) < Term(10));
+        assert_eq!(Term(42).to_string(), "42");
+    }
+
+    #[test]
+    fn server_refresh_timeout() {
+        let mut server = build_server();
+
+        server.refresh_timeout();
+
+        assert!(server.next_timeout.as_ref().unwrap() > &Instant::now());
+    }
+
+    #[test]
+    fn server_add_server() {
+        let mut server = build_server();
+        server
+            .bootstrap(vec![
+                build_peer("server_2", 9091),
+                build_peer("server_3", 9092),
+            ])
+            .unwrap();
+
+        assert_eq!(server.voter_count(), 3);
+
+        // only the leader can change the membership
+        assert_eq!(
+            server.add_server(build_peer("server_4", 9093)),
+            Err(MembershipError::NotLeader)
+        );
+
+        server.state = State::LEADER;
+        server.commit_index = server.last_log_index();
+
+        assert_eq!(server.add_server(build_peer("server_4", 9093)), Ok(2));
+
+        // server_4 is a learner until it catches up, so the quorum
+        // does not change yet. It is sent the log all the same.
+        assert_eq!(server.voter_count(), 3);
+        assert_eq!(server.progress["server_4"].next_index, 2);
+
+        // a second change must wait for the first one to be committed
+        assert_eq!(
+            server.add_server(build_peer("server_5", 9094)),
+            Err(MembershipError::ChangeInProgress)
+        );
+
+        server.commit_index = server.last_log_index();
+
+        assert_eq!(
+            server.add_server(build_peer("server_4", 9093)),
+            Err(MembershipError::AlreadyMember)
+        );
+        assert_eq!(
+            server.promote_learner("server_4", 1),
+            Err(MembershipError::NotCaughtUp)
+        );
+        assert_eq!(server.promote_learner("server_4", 2), Ok(3));
+        assert_eq!(server.voter_count(), 4);
+
+        server.commit_index = server.last_log_index();
+        assert_eq!(server.add_server(build_peer("server_5", 9094)), Ok(4));
+
+        server.commit_index = server.last_log_index();
+        assert_eq!(
+            server.promote_learner("server_6", 4),
+            Err(MembershipError::UnknownPeer)
+        );
+        assert_eq!(server.promote_learner("server_5", 4), Ok(5));
+        assert_eq!(server.voter_count(), 5);
+
+        let (index, membership) = server.membership().unwrap();
+        assert_eq!(index, 5);
+        assert!(membership.learners.is_empty());
+    }
+
+    #[test]
+    fn server_add_server_without_a_configuration() {
+        // the peers it was started with stay voters, or the quorums of
+        // the new configuration need not overlap with theirs
+        let mut server = build_server();
+        server.state = State::LEADER;
+
+        assert_eq!(server.add_server(build_peer("server_4", 9093)), Ok(1));
+
+        let (_, membership) = server.membership().unwrap();
+        let voters: Vec<&str> = membership.voters.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(voters, vec!["server_1", "server_2", "server_3"]);
+        assert_eq!(membership.learners, vec![build_peer("server_4", 9093)]);
+    }
+
+    #[test]
+    fn server_remove_server() {
+        let mut server = build_server();
+        server
+            .bootstrap(vec![
+                build_peer("server_2", 9091),
+                build_peer("server_3", 9092),
+            ])
+            .unwrap();
+        server.state = State::LEADER;
+        server.commit_index = server.last_log_index();
+
+        assert_eq!(
+            server.remove_server("server_4"),
+            Err(MembershipError::UnknownPeer)
+        );
+        assert_eq!(server.remove_server("server_3"), Ok(2));
+
+        // the new configuration is used right away, even uncommitted
+        assert_eq!(server.voter_count(), 2);
+        assert_eq!(
+            server.remove_server("server_2"),
+            Err(MembershipError::ChangeInProgress)
+        );
+
+        server.commit_index = server.last_log_index();
+        assert_eq!(server.remove_server("server_1"), Ok(3));
+        assert_eq!(server.voter_count(), 1);
+        assert!(!server.is_voter());
+
+        server.commit_index = server.last_log_index();
+        assert_eq!(
+            server.remove_server("server_2"),
+            Err(MembershipError::LastVoter)
+        );
+    }
+
+    #[test]
+    fn server_transfer_leadership() {
+        let mut server = build_server();
+        server
+            .bootstrap(vec![
+                build_peer("server_2", 9091),
+                build_peer("server_3", 9092),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            server.transfer_leadership("server_2"),
+            Err(TransferError::NotLeader)
+        );
+
+        server.state = State::CANDIDATE;
+        server.become_leader();
+
+        assert_eq!(
+            server.transfer_leadership("server_4"),
+            Err(TransferError::UnknownPeer)
+        );
+        assert_eq!(
+            server.transfer_leadership("server_1"),
+            Err(TransferError::UnknownPeer)
+        );
+        assert_eq!(server.transfer_leadership("server_2"), Ok(()));
+        assert_eq!(
+            server.transfer_leadership("server_3"),
+            Err(TransferError::InProgress)
+        );
+        assert_eq!(
+            server.propose(vec![1]),
+            Err(ProposeError::TransferringLeadership {
+                target: "server_2".to_string()
+            })
+        );
+
+        // a later term's leadership starts without it
+        server.state = State::CANDIDATE;
+        server.become_leader();
+        assert!(server.transfer.is_none());
+        assert!(server.propose(vec![1]).is_ok());
+    }
+
+    #[test]
+    fn server_membership_history() {
+        let mut server = build_server();
+        server
+            .bootstrap(vec![build_peer("server_2", 9091)])
+            .unwrap();
+        server.state = State::LEADER;
+        server.term = Term(1);
+
+        let commit = |server: &mut Server| server.commit_index = server.last_log_index();
+        commit(&mut server);
+        server.add_server(build_peer("server_3", 9092)).unwrap();
+        commit(&mut server);
+        server.propose(vec![1]).unwrap();
+        server.promote_learner("server_3", 3).unwrap();
+        commit(&mut server);
+        server.term = Term(2);
+        server.remove_server("server_2").unwrap();
+
+        let history: Vec<(u64, Term, String)> = server
+            .membership_history(1, u64::MAX)
+            .map(|r| (r.index, r.term, r.change))
+            .collect();
+        assert_eq!(
+            history,
+            vec![
+                (
+                    1,
+                    Term(0),
+                    "bootstrapped with server_1, server_2".to_string()
+                ),
+                (2, Term(1), "added learner server_3".to_string()),
+                (4, Term(1), "promoted server_3".to_string()),
+                (5, Term(2), "removed server_2".to_string()),
+            ]
+        );
+
+        // a range starting after a change still describes the first one
+        // against the configuration before it
+        let history: Vec<MembershipRecord> = server.membership_history(3, 4).collect();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].change, "promoted server_3");
+        assert_eq!(history[0].membership.voters.len(), 3);
+
+        assert_eq!(server.membership_history(6, 10).count(), 0);
+        assert_eq!(server.membership_history(4, 2).count(), 0);
+    }
+
+    #[test]
+    fn server_propose() {
+        let mut server = build_server();
+        server.config.max_entry_bytes = 16;
+
+        assert_eq!(server.propose(vec![0; 16]), Err(ProposeError::NotLeader));
+
+        server.state = State::LEADER;
+        assert_eq!(server.propose(vec![0; 16]), Ok(1));
+        assert_eq!(
+            server.propose(vec![0; 17]),
+            Err(ProposeError::EntryTooLarge { size: 17, max: 16 })
+        );
+        assert_eq!(server.last_log_index(), 1);
+    }
+
+    #[test]
+    fn server_propose_waits_for_the_log_to_be_durable() {
+        let storage = SyncCountingStorage::default();
+        let syncs = Arc::clone(&storage.syncs);
+        let mut server = build_server_with_log(storage, SyncPolicy::Always);
+        server.state = State::LEADER;
+
+        assert_eq!(server.propose(vec![1]), Ok(1));
+        assert_eq!(syncs.lock().unwrap().durable, 1);
+
+        // nothing is acknowledged that did not make it to disk
+        syncs.lock().unwrap().fail = true;
+        assert!(matches!(
+            server.propose(vec![2]),
+            Err(ProposeError::LogUnwritable { .. })
+        ));
+        assert_eq!(server.last_log_index(), 1);
+
+        // nor does the server lead on a log it cannot write
+        server.state = State::CANDIDATE;
+        server.become_leader();
+        assert_eq!(server.state, State::FOLLOWER);
+        assert_eq!(server.last_log_index(), 1);
+    }
+
+    #[test]
+    fn server_sync_policy_always_syncs_every_append() {
+        let storage = SyncCountingStorage::default();
+        let syncs = Arc::clone(&storage.syncs);
+        let mut server = build_server_with_log(storage, SyncPolicy::Always);
+        server.state = State::LEADER;
+
+        for data in 1..=5 {
+            let index = server.propose(vec![data]).unwrap();
+            let syncs = syncs.lock().unwrap();
+            assert_eq!(syncs.count, index as usize);
+            assert_eq!(syncs.durable, index);
+        }
+    }
+
+    #[test]
+    fn server_concurrent_proposals_share_a_sync() {
+        let storage = SyncCountingStorage::default();
+        let syncs = Arc::clone(&storage.syncs);
+        let config = ServerConfig {
+            group_commit_window: Duration::from_millis(50),
+            log_storage: Some(Box::new(storage)),
+            ..ServerConfig::default()
+        };
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+        let mut server = Server::new(config, Vec::new(), address, "server_1".to_string()).unwrap();
+        server.state = State::LEADER;
+        let server = Arc::new(Mutex::new(server));
+
+        let mut indexes: Vec<u64> = thread::scope(|s| {
+            let handles: Vec<_> = (0..16)
+                .map(|data| {
+                    let server = &server;
+                    let syncs = &syncs;
+                    s.spawn(move || {
+                        let index = core::propose_command(server, vec![data]).unwrap();
+                        // durable before it is acknowledged
+                        assert!(syncs.lock().unwrap().durable >= index);
+                        index
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        indexes.sort_unstable();
+        assert_eq!(indexes, (1..=16).collect::<Vec<u64>>());
+        let count = syncs.lock().unwrap().count;
+        assert!(count < 16, "{} syncs", count);
+    }
+
+    #[test]
+    fn server_sync_policy_never_syncs() {
+        let storage = SyncCountingStorage::default();
+        let syncs = Arc::clone(&storage.syncs);
+        let mut server = build_server_with_log(storage, SyncPolicy::Never);
+        server.state = State::LEADER;
+
+        for data in 1..=5 {
+            server.propose(vec![data]).unwrap();
+        }
+        assert_eq!(server.last_log_index(), 5);
+        // not even on the way out
+        drop(server);
+
+        assert_eq!(syncs.lock().unwrap().count, 0);
+    }
+
+    #[test]
+    fn server_sync_policy_every_millis_syncs_later() {
+        let storage = SyncCountingStorage::default();
+        let syncs = Arc::clone(&storage.syncs);
+        let mut server = build_server_with_log(storage, SyncPolicy::EveryMillis(60_000));
+        server.state = State::LEADER;
+
+        for data in 1..=5 {
+            server.propose(vec![data]).unwrap();
+        }
+        assert_eq!(syncs.lock().unwrap().count, 0);
+        drop(server);
+
+        let syncs = syncs.lock().unwrap();
+        assert_eq!(syncs.count, 1);
+        assert_eq!(syncs.durable, 5);
+    }
+
+    #[test]
+    fn server_subscribe_applied() {
+        let mut server = build_server();
+        server.config.applied_channel_capacity = 4;
+        server.state = State::LEADER;
+
+        let first = server.subscribe_applied();
+        let second = server.subscribe_applied();
+
+        for i in 1..=3 {
+            server.propose(vec![i]).unwrap();
+        }
+        server.commit_index = 2;
+        server.apply_committed();
+        server.commit_index = 3;
+        server.apply_committed();
+
+        for subscriber in [&first, &second] {
+            let applied: Vec<(u64, LogEntry)> = subscriber.try_iter().collect();
+            let expected: Vec<(u64, LogEntry)> = (1..=3)
+                .map(|i| {
+                    (
+                        i as u64,
+                        LogEntry::Command {
+                            term: Term(0),
+                            data: vec![i],
+                        },
+                    )
+                })
+                .collect();
+
+            assert_eq!(applied, expected);
+        }
+
+        // "second" stops reading: it is dropped once its channel is full,
+        // while "first" keeps getting everything.
+        for i in 4..=8 {
+            server.propose(vec![i]).unwrap();
+        }
+        server.commit_index = 6;
+        server.apply_committed();
+        assert_eq!(first.try_iter().count(), 3);
+
+        server.commit_index = 8;
+        server.apply_committed();
+        assert_eq!(first.try_iter().count(), 2);
+
+        assert_eq!(second.try_iter().count(), 4);
+        assert!(second.recv().is_err());
+        assert_eq!(server.last_applied, 8);
+    }
+
+    #[test]
+    fn server_propose_in_session() {
+        let mut server = build_server();
+        let counter = Arc::new(AtomicU64::new(0));
+        server.state_machine = Some(Box::new(Counter(Arc::clone(&counter))));
+        server.state = State::LEADER;
+
+        // the client times out and retries the same request
+        assert_eq!(server.propose_in_session("client_1", 1, vec![1]), Ok(1));
+        assert_eq!(server.propose_in_session("client_1", 1, vec![1]), Ok(2));
+        assert_eq!(server.propose_in_session("client_1", 2, vec![1]), Ok(3));
+
+        server.commit_index = 3;
+        server.apply_committed();
+
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        assert_eq!(server.sessions.response("client_1", 2), Some(&[2][..]));
+    }
+
+    #[derive(Debug)]
+    struct Counter(Arc<AtomicU64>);
+
+    impl StateMachine for Counter {
+        fn apply(&mut self, _command: &[u8]) -> Result<Vec<u8>, ApplyError> {
+            Ok(vec![self.0.fetch_add(1, Ordering::SeqCst) as u8 + 1])
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            self.0.load(Ordering::SeqCst).to_be_bytes().to_vec()
+        }
+    }
+
+    /// Takes a millisecond per command.
+    #[derive(Debug)]
+    struct Slow(Arc<AtomicU64>);
+
+    impl StateMachine for Slow {
+        fn apply(&mut self, _command: &[u8]) -> Result<Vec<u8>, ApplyError> {
+            thread::sleep(Duration::from_millis(1));
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Vec::new())
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            Vec::new()
+        }
+    }
+
+    /// Fails to apply its third command, with an error or a panic.
+    #[derive(Debug)]
+    struct FailsThird {
+        applied: u64,
+        panics: bool,
+    }
+
+    impl StateMachine for FailsThird {
+        fn apply(&mut self, _command: &[u8]) -> Result<Vec<u8>, ApplyError> {
+            if self.applied == 2 {
+                if self.panics {
+                    panic!("corrupted");
+                }
+                return Err(ApplyError("corrupted".to_string()));
+            }
+
+            self.applied += 1;
+            Ok(Vec::new())
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            Vec::new()
+        }
+    }
+
+    /// Keeps the log in memory, and counts the syncs.
+    #[derive(Debug)]
+    struct SyncCountingStorage {
+        entries: MemLogStorage,
+        syncs: Arc<Mutex<Syncs>>,
+    }
+
+    #[derive(Debug, Default)]
+    struct Syncs {
+        count: usize,
+        /// The last index synced.
+        durable: u64,
+        fail: bool,
+    }
+
+    impl Default for SyncCountingStorage {
+        fn default() -> Self {
+            SyncCountingStorage {
+                entries: MemLogStorage::new(usize::MAX, None),
+                syncs: Arc::default(),
+            }
+        }
+    }
+
+    impl LogStorage for SyncCountingStorage {
+        fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+            self.entries.append(entries)
+        }
+
+        fn entry(&self, index: u64) -> io::Result<Option<LogEntry>> {
+            self.entries.entry(index)
+        }
+
+        fn entries(&self, lo: u64, hi: u64) -> io::Result<Vec<LogEntry>> {
+            self.entries.entries(lo, hi)
+        }
+
+        fn last_index(&self) -> u64 {
+            self.entries.last_index()
+        }
+
+        fn term_at(&self, index: u64) -> Option<Term> {
+            self.entries.term_at(index)
+        }
+
+        fn truncate_from(&mut self, index: u64) -> io::Result<()> {
+            self.entries.truncate_from(index)
+        }
+
+        fn cached(&self) -> usize {
+            self.entries.cached()
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            let mut syncs = self.syncs.lock().unwrap();
+            if syncs.fail {
+                return Err(io::Error::other("disk full"));
+            }
+
+            syncs.count += 1;
+            syncs.durable = self.entries.last_index();
+            Ok(())
+        }
+    }
+
+    fn build_server_with_log(storage: SyncCountingStorage, sync_policy: SyncPolicy) -> Server {
+        let config = ServerConfig {
+            sync_policy: sync_policy,
+            log_storage: Some(Box::new(storage)),
+            ..ServerConfig::default()
+        };
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
+
+        Server::new(config, Vec::new(), address, "server_1".to_string()).unwrap()
+    }
+
+    fn build_peer(id: &str, port: u16) -> Peer {
+        Peer {
+            id: id.to_string(),
+            address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+            kind: NodeKind::Voter,
+        }
     }
 
     fn build_server() -> Server {
         let config = ServerConfig {
-            timeout: Duration::new(1, 0),
+            election_timeout_min: Duration::new(1, 0),
+            election_timeout_max: Duration::new(1, 0),
+            heartbeat_interval: Duration::from_millis(200),
+            ..ServerConfig::default()
         };
 
-        let number_of_peers = 2;
         let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9090);
         let id = "server_1".to_string();
+        let peers = vec![build_peer("server_2", 9091), build_peer("server_3", 9092)];
 
-        Server::new(config, number_of_peers, address, id)
+        Server::new(config, peers, address, id).unwrap()
     }
 }